@@ -0,0 +1,18 @@
+#!/bin/sh
+# OpenVPN client-connect hook: tells ovpn-admin which certificate serial just
+# connected, so a renewed cert can retire the one it replaced.
+#
+#   client-connect /etc/openvpn/ovpn-admin-connect.sh
+#   script-security 2
+#
+# OVPN_ADMIN_URL and OVPN_ADMIN_HOOK_TOKEN (matching [ovpn] hook_token) are
+# read from /etc/default/ovpn-admin-hook when present.
+[ -r /etc/default/ovpn-admin-hook ] && . /etc/default/ovpn-admin-hook
+: "${OVPN_ADMIN_URL:=http://127.0.0.1:8080}"
+
+# never block a connection on the panel being unreachable
+curl -fsS -m 3 -X POST "$OVPN_ADMIN_URL/api/vpn/connect" \
+    -H "Authorization: Bearer $OVPN_ADMIN_HOOK_TOKEN" \
+    -H 'Content-Type: application/json' \
+    -d "{\"cn\":\"$common_name\",\"serial\":\"$tls_serial_0\",\"ip\":\"$trusted_ip\"}" >/dev/null 2>&1 || true
+exit 0
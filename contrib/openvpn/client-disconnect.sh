@@ -0,0 +1,16 @@
+#!/bin/sh
+# OpenVPN client-disconnect hook: reports the end of a session to ovpn-admin
+# so the dashboard's live feed shows it.
+#
+#   client-disconnect /etc/openvpn/ovpn-admin-disconnect.sh
+#   script-security 2
+#
+# Reads the same /etc/default/ovpn-admin-hook as client-connect.sh.
+[ -r /etc/default/ovpn-admin-hook ] && . /etc/default/ovpn-admin-hook
+: "${OVPN_ADMIN_URL:=http://127.0.0.1:8080}"
+
+curl -fsS -m 3 -X POST "$OVPN_ADMIN_URL/api/vpn/disconnect" \
+    -H "Authorization: Bearer $OVPN_ADMIN_HOOK_TOKEN" \
+    -H 'Content-Type: application/json' \
+    -d "{\"cn\":\"$common_name\",\"serial\":\"$tls_serial_0\",\"ip\":\"$trusted_ip\",\"bytes_received\":${bytes_received:-0},\"bytes_sent\":${bytes_sent:-0},\"duration_secs\":${time_duration:-0}}" >/dev/null 2>&1 || true
+exit 0
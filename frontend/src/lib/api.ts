@@ -19,10 +19,10 @@ function isJsonBody(b: unknown): boolean {
         && !(ArrayBuffer.isView(b as any));
 }
 
-async function req(method: string, path: string, body?: any, expectBlob = false) {
+async function req(method: string, path: string, body?: any, expectBlob = false, extra: Record<string, string> = {}) {
     await ensureCsrf();
 
-    const headers: HeadersInit = { Accept: 'application/json' };
+    const headers: HeadersInit = { Accept: 'application/json', ...extra };
     const token = xsrf();
     if (token) (headers as Record<string, string>)['X-CSRF-Token'] = token;
 
@@ -49,7 +49,13 @@ async function req(method: string, path: string, body?: any, expectBlob = false)
     };
 
     const r = await fetch(`/api${path}`, init);
-    if (!r.ok) throw new Error(`${r.status} ${r.statusText}`);
+    if (!r.ok) {
+        // keep the JSON error body (e.g. line errors) for callers that want it
+        const e: any = new Error(`${r.status} ${r.statusText}`);
+        e.status = r.status;
+        e.body = await r.json().catch(() => null);
+        throw e;
+    }
 
     if (expectBlob) return await r.blob();
 
@@ -61,7 +67,7 @@ async function req(method: string, path: string, body?: any, expectBlob = false)
 export const api = {
     get: <T=any>(p: string) => req('GET', p) as Promise<T>,
     post: <T=any>(p: string, b?: any) => req('POST', p, b) as Promise<T>,
-    put:  <T=any>(p: string, b?: any) => req('PUT',  p, b) as Promise<T>,
+    put:  <T=any>(p: string, b?: any, h?: Record<string, string>) => req('PUT',  p, b, false, h) as Promise<T>,
     del:  <T=any>(p: string) => req('DELETE', p) as Promise<T>,
     postBlob: (p: string, b?: any)  => req('POST', p, b, true) as Promise<Blob>,
 
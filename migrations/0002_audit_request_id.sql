@@ -0,0 +1,2 @@
+-- audit: correlate rows written by the same request
+ALTER TABLE audit ADD COLUMN request_id TEXT NOT NULL DEFAULT '';
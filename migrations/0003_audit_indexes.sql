@@ -0,0 +1,6 @@
+-- audit search: newest-first paging on (ts, id) plus the common filters
+CREATE INDEX IF NOT EXISTS idx_audit_ts_id ON audit(ts, id);
+CREATE INDEX IF NOT EXISTS idx_audit_actor_ts ON audit(actor_user, ts);
+CREATE INDEX IF NOT EXISTS idx_audit_action_ts ON audit(action, ts);
+CREATE INDEX IF NOT EXISTS idx_audit_target ON audit(target);
+CREATE INDEX IF NOT EXISTS idx_audit_ip_ts ON audit(ip, ts);
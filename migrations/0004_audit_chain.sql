@@ -0,0 +1,5 @@
+-- audit hash chain: seq orders the chain, hash covers the row and prev_hash
+ALTER TABLE audit ADD COLUMN seq INTEGER;
+ALTER TABLE audit ADD COLUMN prev_hash TEXT;
+ALTER TABLE audit ADD COLUMN hash TEXT;
+CREATE UNIQUE INDEX IF NOT EXISTS idx_audit_seq ON audit(seq);
@@ -0,0 +1,11 @@
+-- audit retention: pruned chain rows keep their link so the chain still verifies
+CREATE TABLE IF NOT EXISTS audit_tombstones(
+  seq INTEGER PRIMARY KEY,
+  id TEXT NOT NULL,
+  prev_hash TEXT NOT NULL,
+  hash TEXT NOT NULL,
+  archived_to TEXT NOT NULL,
+  pruned_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_login_attempts_ts ON login_attempts(ts);
@@ -0,0 +1,25 @@
+-- email notifications
+ALTER TABLE users ADD COLUMN email TEXT;
+
+-- per-client metadata kept by the panel (the certs themselves live in vpncertd)
+CREATE TABLE IF NOT EXISTS clients(
+  cn TEXT PRIMARY KEY,
+  owner_email TEXT,
+  created_at INTEGER NOT NULL,
+  updated_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS mail_queue(
+  id TEXT PRIMARY KEY,
+  template TEXT NOT NULL,
+  to_addr TEXT NOT NULL,
+  subject TEXT NOT NULL,
+  body TEXT NOT NULL,
+  status TEXT NOT NULL DEFAULT 'pending',
+  attempts INTEGER NOT NULL DEFAULT 0,
+  next_attempt_at INTEGER NOT NULL,
+  last_error TEXT,
+  created_at INTEGER NOT NULL,
+  sent_at INTEGER
+);
+CREATE INDEX IF NOT EXISTS idx_mail_queue_due ON mail_queue(status, next_attempt_at);
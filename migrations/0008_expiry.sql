@@ -0,0 +1,8 @@
+-- one row per (certificate, threshold) warning already sent
+CREATE TABLE IF NOT EXISTS expiry_warnings (
+    serial         TEXT    NOT NULL,
+    threshold_days INTEGER NOT NULL,
+    cn             TEXT    NOT NULL,
+    sent_at        INTEGER NOT NULL,
+    PRIMARY KEY (serial, threshold_days)
+);
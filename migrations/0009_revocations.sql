@@ -0,0 +1,14 @@
+-- revocations deferred until a deadline, e.g. the old cert after a renewal
+CREATE TABLE IF NOT EXISTS scheduled_revocations (
+    serial      TEXT    PRIMARY KEY,
+    cn          TEXT    NOT NULL,
+    reason      TEXT    NOT NULL,
+    revoke_at   INTEGER NOT NULL,
+    created_by  TEXT    NOT NULL,
+    created_at  INTEGER NOT NULL,
+    status      TEXT    NOT NULL DEFAULT 'pending', -- pending | done | failed
+    attempts    INTEGER NOT NULL DEFAULT 0,
+    last_error  TEXT,
+    done_at     INTEGER
+);
+CREATE INDEX IF NOT EXISTS idx_sched_revocations_due ON scheduled_revocations(status, revoke_at);
@@ -0,0 +1,34 @@
+-- auto-renew policy: per client, falling back to the client's group
+ALTER TABLE clients ADD COLUMN group_name TEXT;
+ALTER TABLE clients ADD COLUMN auto_renew INTEGER;
+
+CREATE TABLE IF NOT EXISTS client_groups(
+  name TEXT PRIMARY KEY,
+  auto_renew INTEGER NOT NULL DEFAULT 0,
+  created_at INTEGER NOT NULL,
+  updated_at INTEGER NOT NULL
+);
+
+-- certs issued by the auto-renew job, with the encrypted bundle for the owner
+CREATE TABLE IF NOT EXISTS renewals(
+  id TEXT PRIMARY KEY,
+  cn TEXT NOT NULL,
+  old_serial TEXT NOT NULL UNIQUE,
+  new_serial TEXT,
+  not_after TEXT,
+  token_hash TEXT NOT NULL UNIQUE,
+  bundle_path TEXT NOT NULL,
+  created_at INTEGER NOT NULL,
+  downloaded_at INTEGER,
+  old_revoked_at INTEGER
+);
+CREATE INDEX IF NOT EXISTS idx_renewals_new_serial ON renewals(new_serial);
+
+-- serials seen connecting, reported by the client-connect hook
+CREATE TABLE IF NOT EXISTS vpn_seen(
+  serial TEXT PRIMARY KEY,
+  cn TEXT NOT NULL,
+  first_seen INTEGER NOT NULL,
+  last_seen INTEGER NOT NULL,
+  last_ip TEXT
+);
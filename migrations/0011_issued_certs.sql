@@ -0,0 +1,12 @@
+-- what the panel asked vpncertd for, per issued serial
+CREATE TABLE IF NOT EXISTS issued_certs(
+  serial TEXT PRIMARY KEY,
+  cn TEXT NOT NULL,
+  profile TEXT NOT NULL,
+  key_type TEXT NOT NULL,
+  via TEXT NOT NULL,
+  not_after TEXT,
+  created_by TEXT NOT NULL,
+  created_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_issued_certs_cn ON issued_certs(cn);
@@ -0,0 +1,24 @@
+-- long-running operations started from the API, with per-item results
+CREATE TABLE IF NOT EXISTS jobs(
+  id TEXT PRIMARY KEY,
+  kind TEXT NOT NULL,
+  status TEXT NOT NULL DEFAULT 'running', -- running | done | failed
+  total INTEGER NOT NULL,
+  done INTEGER NOT NULL DEFAULT 0,
+  failed INTEGER NOT NULL DEFAULT 0,
+  params TEXT NOT NULL,
+  result TEXT,
+  error TEXT,
+  created_by TEXT NOT NULL,
+  created_at INTEGER NOT NULL,
+  finished_at INTEGER
+);
+
+CREATE TABLE IF NOT EXISTS job_items(
+  job_id TEXT NOT NULL REFERENCES jobs(id) ON DELETE CASCADE,
+  idx INTEGER NOT NULL,
+  item TEXT NOT NULL,
+  status TEXT NOT NULL, -- ok | failed | skipped
+  detail TEXT NOT NULL,
+  PRIMARY KEY(job_id, idx)
+);
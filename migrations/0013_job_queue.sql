@@ -0,0 +1,5 @@
+-- jobs are queued and picked up by workers; status gains queued | cancelled
+ALTER TABLE jobs ADD COLUMN ctx TEXT;
+ALTER TABLE jobs ADD COLUMN cancel_requested INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE jobs ADD COLUMN started_at INTEGER;
+CREATE INDEX IF NOT EXISTS idx_jobs_status ON jobs(status, created_at);
@@ -0,0 +1,7 @@
+-- static VPN addresses handed out by the panel, one per CN
+CREATE TABLE IF NOT EXISTS ip_allocations(
+  ip TEXT PRIMARY KEY,
+  cn TEXT NOT NULL UNIQUE,
+  created_by TEXT NOT NULL,
+  created_at INTEGER NOT NULL
+);
@@ -0,0 +1,12 @@
+-- every CCD content the panel wrote or found on disk, per CN
+CREATE TABLE IF NOT EXISTS ccd_revisions(
+  cn TEXT NOT NULL,
+  rev INTEGER NOT NULL,
+  content TEXT NOT NULL,
+  sha256 TEXT NOT NULL,
+  author TEXT NOT NULL,
+  source TEXT NOT NULL, -- panel | external | rollback
+  restored_from INTEGER,
+  created_at INTEGER NOT NULL,
+  PRIMARY KEY(cn, rev)
+);
@@ -0,0 +1,18 @@
+-- named CCD bodies with {{variables}}, and the CNs whose CCD is generated from one
+CREATE TABLE IF NOT EXISTS ccd_templates(
+  name TEXT PRIMARY KEY,
+  description TEXT,
+  body TEXT NOT NULL,
+  defaults TEXT NOT NULL DEFAULT '{}', -- JSON object, variable -> value
+  updated_by TEXT NOT NULL,
+  updated_at INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS ccd_template_links(
+  cn TEXT PRIMARY KEY,
+  template TEXT NOT NULL REFERENCES ccd_templates(name),
+  vars TEXT NOT NULL DEFAULT '{}', -- JSON object, overrides the template defaults
+  extra TEXT NOT NULL DEFAULT '', -- CCD lines layered over the template
+  updated_by TEXT NOT NULL,
+  updated_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_ccd_template_links_template ON ccd_template_links(template);
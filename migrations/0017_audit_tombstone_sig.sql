@@ -0,0 +1,2 @@
+-- tombstones are signed with the chain key so a forged one cannot bridge a gap
+ALTER TABLE audit_tombstones ADD COLUMN sig TEXT;
@@ -0,0 +1,7 @@
+-- renewal links are single-use and expire; the key passphrase is kept apart
+-- from the bundle and only shown to admins
+ALTER TABLE renewals RENAME COLUMN downloaded_at TO consumed_at;
+ALTER TABLE renewals ADD COLUMN expires_at INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE renewals ADD COLUMN passphrase_sealed TEXT;
+UPDATE renewals SET expires_at = created_at + 72 * 3600;
+CREATE INDEX IF NOT EXISTS idx_renewals_expires ON renewals(expires_at);
@@ -0,0 +1,5 @@
+-- a running job belongs to the process holding its lease; only expired leases
+-- are taken over. job_items rows with status 'started' record an item begun
+-- but not finished.
+ALTER TABLE jobs ADD COLUMN lease_owner TEXT;
+ALTER TABLE jobs ADD COLUMN lease_until INTEGER;
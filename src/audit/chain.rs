@@ -0,0 +1,352 @@
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::json;
+use std::sync::OnceLock;
+
+use super::hex;
+use crate::db::{self, Db};
+use crate::security::hmac::hmac_sha256;
+
+/// `prev_hash` of the first row in the chain.
+pub const GENESIS: &str = "genesis";
+
+static HMAC_KEY: OnceLock<Option<Vec<u8>>> = OnceLock::new();
+
+/// Selects how new rows are hashed. With `hmac` the key is derived from the
+/// pepper, so a copy of the database alone is not enough to forge a chain.
+pub fn init(hmac: bool, pepper: &[u8]) {
+    let key = hmac.then(|| hmac_sha256(pepper, b"ovpn-admin/audit-chain/v1"));
+    let _ = HMAC_KEY.set(key);
+}
+
+fn key() -> Option<&'static [u8]> {
+    HMAC_KEY.get().and_then(|k| k.as_deref())
+}
+
+pub struct Link<'a> {
+    pub seq: i64,
+    pub id: &'a str,
+    pub ts: i64,
+    pub actor_user: &'a str,
+    pub action: &'a str,
+    pub target: &'a str,
+    pub ip: &'a str,
+    pub ua: &'a str,
+    pub request_id: &'a str,
+    pub details: &'a str,
+    pub prev_hash: &'a str,
+}
+
+fn canonical(l: &Link<'_>) -> Vec<u8> {
+    json!([l.seq, l.id, l.ts, l.actor_user, l.action, l.target, l.ip, l.ua, l.request_id, l.details, l.prev_hash])
+        .to_string()
+        .into_bytes()
+}
+
+/// What is left of a pruned chain row; signed so it cannot be forged to
+/// bridge a gap without the chain key.
+pub struct Tombstone<'a> {
+    pub seq: i64,
+    pub id: &'a str,
+    pub prev_hash: &'a str,
+    pub hash: &'a str,
+    pub archived_to: &'a str,
+    pub pruned_at: i64,
+}
+
+fn tombstone_canonical(t: &Tombstone<'_>) -> Vec<u8> {
+    json!(["tombstone", t.seq, t.id, t.prev_hash, t.hash, t.archived_to, t.pruned_at])
+        .to_string()
+        .into_bytes()
+}
+
+fn tag(data: &[u8]) -> String {
+    match key() {
+        Some(k) => format!("hmac-sha256:{}", hex(&hmac_sha256(k, data))),
+        None => format!("sha256:{}", super::sha256_hex(data)),
+    }
+}
+
+/// Hash for a new row, tagged with the scheme so verification can tell them apart.
+pub fn hash(l: &Link<'_>) -> String {
+    tag(&canonical(l))
+}
+
+/// Signature for a new tombstone, using the same scheme as `hash`.
+pub fn sign_tombstone(t: &Tombstone<'_>) -> String {
+    tag(&tombstone_canonical(t))
+}
+
+enum Check { Ok, Mismatch, NoKey }
+
+fn check_tag(data: &[u8], stored: &str) -> Check {
+    let expected = match stored.split_once(':') {
+        Some(("sha256", _)) => format!("sha256:{}", super::sha256_hex(data)),
+        Some(("hmac-sha256", _)) => match key() {
+            Some(k) => format!("hmac-sha256:{}", hex(&hmac_sha256(k, data))),
+            None => return Check::NoKey,
+        },
+        _ => return Check::Mismatch,
+    };
+    if expected == stored { Check::Ok } else { Check::Mismatch }
+}
+
+fn check(l: &Link<'_>, stored: &str) -> Check {
+    check_tag(&canonical(l), stored)
+}
+
+#[derive(Debug, Serialize)]
+pub struct BrokenLink {
+    pub seq: i64,
+    pub id: String,
+    pub reason: &'static str,
+}
+
+
+#[derive(Debug, Serialize)]
+pub struct VerifyReport {
+    pub ok: bool,
+    pub checked: u64,
+    /// Pruned rows whose links were followed via their tombstones.
+    pub pruned: u64,
+    pub unsealed: i64,
+    pub head_seq: Option<i64>,
+    pub head_hash: Option<String>,
+    pub broken: Option<BrokenLink>,
+}
+
+/// Follows tombstones for `from <= seq < to`; returns the first one that does
+/// not continue the chain or whose signature does not verify.
+async fn skip_pruned(pool: &Db, from: i64, to: Option<i64>, report: &mut VerifyReport, prev: &mut String) -> Result<Option<BrokenLink>> {
+    let mut expected = from;
+    for t in db::audit_tombstones(pool, from, to).await? {
+        if t.seq != expected {
+            return Ok(Some(BrokenLink { seq: expected, id: String::new(), reason: "missing_rows" }));
+        }
+        if t.prev_hash != *prev {
+            return Ok(Some(BrokenLink { seq: t.seq, id: t.id, reason: "prev_hash_mismatch" }));
+        }
+        let signed = Tombstone {
+            seq: t.seq, id: &t.id, prev_hash: &t.prev_hash, hash: &t.hash,
+            archived_to: &t.archived_to, pruned_at: t.pruned_at,
+        };
+        let reason = match t.sig.as_deref().map(|sig| check_tag(&tombstone_canonical(&signed), sig)) {
+            Some(Check::Ok) => None,
+            Some(Check::Mismatch) => Some("tombstone_sig_mismatch"),
+            Some(Check::NoKey) => Some("hmac_key_unavailable"),
+            // Pruned before tombstones were signed; nothing vouches for them.
+            None => Some("tombstone_unsigned"),
+        };
+        if let Some(reason) = reason {
+            return Ok(Some(BrokenLink { seq: t.seq, id: t.id, reason }));
+        }
+        report.pruned += 1;
+        report.head_seq = Some(t.seq);
+        *prev = t.hash;
+        expected += 1;
+    }
+    if let Some(to) = to && expected != to {
+        return Ok(Some(BrokenLink { seq: expected, id: String::new(), reason: "missing_rows" }));
+    }
+    Ok(None)
+}
+
+/// Walks the chain in `seq` order and stops at the first row whose position,
+/// back-link or hash does not match. Pruned rows are stepped over using their
+/// tombstones; rows outside the chain also fail the check.
+pub async fn verify(pool: &Db) -> Result<VerifyReport> {
+    let unsealed = db::audit_unsealed_count(pool).await?;
+    let mut report = VerifyReport {
+        ok: unsealed == 0, checked: 0, pruned: 0, unsealed, head_seq: None, head_hash: None, broken: None,
+    };
+
+    let mut expected_seq = 1i64;
+    let mut prev = GENESIS.to_string();
+    loop {
+        let rows = db::audit_chain_page(pool, expected_seq - 1, 500).await?;
+        let done = rows.len() < 500;
+        for r in rows {
+            let mut broken = None;
+            if r.seq != expected_seq {
+                broken = skip_pruned(pool, expected_seq, Some(r.seq), &mut report, &mut prev).await?;
+            }
+            if broken.is_none() {
+                let reason = if r.prev_hash != prev {
+                    Some("prev_hash_mismatch")
+                } else {
+                    let link = Link {
+                        seq: r.seq, id: &r.row.id, ts: r.row.ts, actor_user: &r.row.actor_user, action: &r.row.action,
+                        target: &r.row.target, ip: &r.row.ip, ua: &r.row.ua, request_id: &r.row.request_id,
+                        details: &r.row.details, prev_hash: &r.prev_hash,
+                    };
+                    match check(&link, &r.hash) {
+                        Check::Ok => None,
+                        Check::Mismatch => Some("hash_mismatch"),
+                        Check::NoKey => Some("hmac_key_unavailable"),
+                    }
+                };
+                broken = reason.map(|reason| BrokenLink { seq: r.seq, id: r.row.id.clone(), reason });
+            }
+            if let Some(b) = broken {
+                report.ok = false;
+                report.head_hash = report.head_seq.map(|_| prev);
+                report.broken = Some(b);
+                return Ok(report);
+            }
+            report.checked += 1;
+            report.head_seq = Some(r.seq);
+            expected_seq = r.seq + 1;
+            prev = r.hash;
+        }
+        if done { break; }
+    }
+    if let Some(b) = skip_pruned(pool, expected_seq, None, &mut report, &mut prev).await? {
+        report.ok = false;
+        report.broken = Some(b);
+    }
+    report.head_hash = report.head_seq.map(|_| prev);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn chain(actions: &[&str]) -> Db {
+        init(true, b"test-pepper");
+        let pool = db::test_db().await;
+        for a in actions {
+            append(&pool, a).await;
+        }
+        pool
+    }
+
+    async fn append(pool: &Db, action: &str) {
+        db::audit_insert(pool, &db::NewAudit {
+            actor_user: "alice", action, target: "bob", ip: "10.0.0.1", ua: "curl", request_id: "r1", details: "{}",
+        }).await.unwrap();
+    }
+
+    async fn sql(pool: &Db, q: &str) {
+        sqlx::query(q).execute(pool).await.unwrap();
+    }
+
+    async fn broken(pool: &Db) -> (i64, &'static str) {
+        let r = verify(pool).await.unwrap();
+        assert!(!r.ok);
+        let b = r.broken.unwrap();
+        (b.seq, b.reason)
+    }
+
+    async fn prune(pool: &Db, prefix: &str) {
+        let rule = db::PruneRule { prefix: prefix.into(), before: i64::MAX, except: Vec::new() };
+        let rows = db::audit_prunable(pool, &[rule], 100).await.unwrap();
+        db::audit_prune(pool, &rows, "/archive/a.jsonl.gz").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn clean_chain_verifies() {
+        let pool = chain(&["A", "B", "C"]).await;
+        let r = verify(&pool).await.unwrap();
+        assert!(r.ok && r.broken.is_none());
+        assert_eq!((r.checked, r.head_seq), (3, Some(3)));
+        assert!(r.head_hash.unwrap().starts_with("hmac-sha256:"));
+    }
+
+    #[tokio::test]
+    async fn abandoned_appends_roll_back() {
+        let pool = chain(&["A"]).await;
+        // As if the request writing it went away mid-transaction; the only
+        // connection goes back to the pool without a transaction open.
+        let mut tx = db::begin_immediate(&pool).await.unwrap();
+        sqlx::query("UPDATE audit SET details='{}x'").execute(&mut *tx).await.unwrap();
+        drop(tx);
+        append(&pool, "B").await;
+        assert!(verify(&pool).await.unwrap().ok);
+    }
+
+    #[tokio::test]
+    async fn edited_rows_are_caught() {
+        let pool = chain(&["A", "B", "C"]).await;
+        sql(&pool, "UPDATE audit SET details='{\"x\":1}' WHERE seq=2").await;
+        assert_eq!(broken(&pool).await, (2, "hash_mismatch"));
+
+        let pool = chain(&["A", "B", "C"]).await;
+        sql(&pool, "UPDATE audit SET actor_user='mallory' WHERE seq=3").await;
+        assert_eq!(broken(&pool).await, (3, "hash_mismatch"));
+    }
+
+    #[tokio::test]
+    async fn deleted_and_reordered_rows_are_caught() {
+        let pool = chain(&["A", "B", "C"]).await;
+        sql(&pool, "DELETE FROM audit WHERE seq=2").await;
+        assert_eq!(broken(&pool).await, (2, "missing_rows"));
+
+        let pool = chain(&["A", "B", "C"]).await;
+        sql(&pool, "UPDATE audit SET seq=-1 WHERE seq=2").await;
+        sql(&pool, "UPDATE audit SET seq=2 WHERE seq=3").await;
+        sql(&pool, "UPDATE audit SET seq=3 WHERE seq=-1").await;
+        assert_eq!(broken(&pool).await, (2, "prev_hash_mismatch"));
+    }
+
+    #[tokio::test]
+    async fn pruned_ranges_are_bridged_by_tombstones() {
+        let pool = chain(&["OLD_A", "OLD_B", "C", "OLD_D"]).await;
+        prune(&pool, "OLD_").await;
+        let r = verify(&pool).await.unwrap();
+        assert!(r.ok, "{:?}", r.broken);
+        assert_eq!((r.checked, r.pruned, r.head_seq), (1, 3, Some(4)));
+
+        // New rows continue from the newest tombstone.
+        append(&pool, "E").await;
+        assert_eq!(verify(&pool).await.unwrap().head_seq, Some(5));
+        assert!(verify(&pool).await.unwrap().ok);
+    }
+
+    #[tokio::test]
+    async fn forged_tombstones_are_rejected() {
+        let pool = chain(&["OLD_A", "B"]).await;
+        prune(&pool, "OLD_").await;
+        // Pointing the tombstone elsewhere breaks its signature.
+        sql(&pool, "UPDATE audit_tombstones SET archived_to='/tmp/other.jsonl.gz' WHERE seq=1").await;
+        assert_eq!(broken(&pool).await, (1, "tombstone_sig_mismatch"));
+
+        // A row deleted without the key cannot be covered by a made-up tombstone.
+        let pool = chain(&["A", "B", "C"]).await;
+        let (id, prev, hash): (String, String, String) = sqlx::query_as("SELECT id, prev_hash, hash FROM audit WHERE seq=2")
+            .fetch_one(&pool).await.unwrap();
+        sql(&pool, "DELETE FROM audit WHERE seq=2").await;
+        let t = Tombstone { seq: 2, id: &id, prev_hash: &prev, hash: &hash, archived_to: "/x", pruned_at: 0 };
+        let forged = format!("hmac-sha256:{}", hex(&hmac_sha256(b"guessed key", &tombstone_canonical(&t))));
+        sqlx::query("INSERT INTO audit_tombstones(seq, id, prev_hash, hash, archived_to, pruned_at, sig) VALUES(2,?,?,?,'/x',0,?)")
+            .bind(&id).bind(&prev).bind(&hash).bind(&forged).execute(&pool).await.unwrap();
+        assert_eq!(broken(&pool).await, (2, "tombstone_sig_mismatch"));
+    }
+
+    #[tokio::test]
+    async fn legacy_rows_are_sealed_once() {
+        init(true, b"test-pepper");
+        let pool = db::test_db().await;
+        for (id, ts) in [("01A", 2), ("01B", 1)] {
+            sqlx::query("INSERT INTO audit(id, ts, actor_user, action, target, ip, ua, details) VALUES(?,?,'alice','OLD','bob','','','{}')")
+                .bind(id).bind(ts).execute(&pool).await.unwrap();
+        }
+        assert_eq!(verify(&pool).await.unwrap().unsealed, 2);
+        assert_eq!(db::audit_seal_legacy(&pool).await.unwrap(), 2);
+        // Sealed in insertion order, not by timestamp.
+        let first: String = sqlx::query_scalar("SELECT id FROM audit WHERE seq=1").fetch_one(&pool).await.unwrap();
+        assert_eq!(first, "01A");
+
+        // A restart seals nothing again, and rows that show up unsealed later
+        // are reported rather than chained.
+        append(&pool, "NEW").await;
+        assert_eq!(db::audit_seal_legacy(&pool).await.unwrap(), 0);
+        let r = verify(&pool).await.unwrap();
+        assert!(r.ok);
+        assert_eq!((r.checked, r.head_seq), (3, Some(3)));
+        sql(&pool, "INSERT INTO audit(id, ts, actor_user, action, target, ip, ua, details) VALUES('01C',3,'x','OLD','y','','','{}')").await;
+        assert_eq!(db::audit_seal_legacy(&pool).await.unwrap(), 0);
+        let r = verify(&pool).await.unwrap();
+        assert_eq!((r.ok, r.unsealed), (false, 1));
+    }
+}
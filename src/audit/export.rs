@@ -0,0 +1,216 @@
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::db::{self, AuditFilter, AuditRow, Db};
+
+const PAGE: i64 = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    Jsonl,
+    Cef,
+}
+
+impl Format {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "csv" => Ok(Format::Csv),
+            "jsonl" | "ndjson" => Ok(Format::Jsonl),
+            "cef" => Ok(Format::Cef),
+            other => Err(anyhow!("unknown export format: {other}")),
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::Jsonl => "application/x-ndjson",
+            Format::Cef => "text/plain; charset=utf-8",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Jsonl => "jsonl",
+            Format::Cef => "cef",
+        }
+    }
+}
+
+/// The `AUDIT_EXPORT` details for an export of `filter` as `fmt`.
+pub fn details(fmt: Format, filter: &AuditFilter) -> Value {
+    json!({
+        "format": fmt.extension(),
+        "actor": filter.actor,
+        "actions": filter.actions,
+        "target_prefix": filter.target_prefix,
+        "ip": filter.ip,
+        "since": filter.since,
+        "until": filter.until,
+        "q": filter.text,
+    })
+}
+
+/// Quotes as RFC 4180 and defuses cells a spreadsheet would run as a formula
+/// by prefixing `'`. A lone `-`, the placeholder for no IP or user agent, is
+/// left alone.
+fn csv_field(s: &str) -> String {
+    let s = match s.starts_with(['=', '+', '-', '@', '\t', '\r']) && s != "-" {
+        true => format!("'{s}"),
+        false => s.to_string(),
+    };
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s
+    }
+}
+
+fn cef_header(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('|', "\\|")
+}
+
+fn cef_ext(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('=', "\\=").replace('\r', "\\r").replace('\n', "\\n")
+}
+
+fn cef_severity(action: &str) -> u8 {
+    if action.starts_with("LOGIN_FAIL") || action == "LOGIN_THROTTLE" {
+        5
+    } else if action == "CLIENT_REVOKE" {
+        6
+    } else {
+        3
+    }
+}
+
+fn line(fmt: Format, r: &AuditRow) -> String {
+    match fmt {
+        Format::Csv => {
+            let ts = r.ts.to_string();
+            let fields = [r.id.as_str(), ts.as_str(), &r.actor_user, &r.action, &r.target, &r.ip, &r.ua, &r.request_id, &r.details];
+            let mut s = fields.iter().map(|f| csv_field(f)).collect::<Vec<_>>().join(",");
+            s.push('\n');
+            s
+        }
+        Format::Jsonl => {
+            let details = serde_json::from_str::<Value>(&r.details).unwrap_or_else(|_| Value::String(r.details.clone()));
+            let mut s = json!({
+                "id": r.id,
+                "ts": r.ts,
+                "actor_user": r.actor_user,
+                "action": r.action,
+                "target": r.target,
+                "ip": r.ip,
+                "ua": r.ua,
+                "request_id": r.request_id,
+                "details": details,
+            }).to_string();
+            s.push('\n');
+            s
+        }
+        Format::Cef => {
+            let mut ext = vec![
+                format!("rt={}", r.ts * 1000),
+                format!("suser={}", cef_ext(&r.actor_user)),
+                format!("cs1Label=target cs1={}", cef_ext(&r.target)),
+                format!("cs2Label=requestId cs2={}", cef_ext(&r.request_id)),
+                format!("externalId={}", cef_ext(&r.id)),
+            ];
+            if r.ip.parse::<std::net::IpAddr>().is_ok() {
+                ext.push(format!("src={}", r.ip));
+            }
+            if r.ua != "-" {
+                ext.push(format!("requestClientApplication={}", cef_ext(&r.ua)));
+            }
+            ext.push(format!("msg={}", cef_ext(&r.details)));
+            format!(
+                "CEF:0|ovpn-admin|ovpn-admin|{}|{}|{}|{}|{}\n",
+                cef_header(env!("CARGO_PKG_VERSION")),
+                cef_header(&r.action),
+                cef_header(&r.action),
+                cef_severity(&r.action),
+                ext.join(" "),
+            )
+        }
+    }
+}
+
+/// Writes every row matching `filter` to `w`, newest first, one page at a time.
+/// Returns the number of rows written.
+pub async fn write_rows<W: AsyncWrite + Unpin>(pool: &Db, filter: &AuditFilter, fmt: Format, w: &mut W) -> Result<u64> {
+    if fmt == Format::Csv {
+        w.write_all(b"id,ts,actor_user,action,target,ip,ua,request_id,details\n").await?;
+    }
+    let mut after = None;
+    let mut n = 0u64;
+    loop {
+        let rows = db::audit_search(pool, filter, after, PAGE).await?;
+        let mut buf = String::new();
+        for r in &rows {
+            buf.push_str(&line(fmt, r));
+        }
+        w.write_all(buf.as_bytes()).await?;
+        n += rows.len() as u64;
+        if (rows.len() as i64) < PAGE {
+            break;
+        }
+        after = rows.last().map(|r| (r.ts, r.id.clone()));
+    }
+    w.flush().await?;
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(action: &str, target: &str, details: &str) -> AuditRow {
+        AuditRow {
+            id: "01J0000000000000000000000".into(), ts: 1_700_000_000, actor_user: "alice".into(), action: action.into(),
+            target: target.into(), ip: "192.0.2.7".into(), ua: "-".into(), request_id: "r1".into(), details: details.into(),
+        }
+    }
+
+    #[test]
+    fn csv_fields_are_quoted() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field(""), "");
+    }
+
+    #[test]
+    fn csv_formulas_are_defused() {
+        assert_eq!(csv_field("=HYPERLINK(\"http://x\")"), "\"'=HYPERLINK(\"\"http://x\"\")\"");
+        assert_eq!(csv_field("+1"), "'+1");
+        assert_eq!(csv_field("-2+3"), "'-2+3");
+        assert_eq!(csv_field("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(csv_field("\tx"), "'\tx");
+        assert_eq!(csv_field("\rx"), "\"'\rx\"");
+        assert_eq!(csv_field("-"), "-");
+        assert_eq!(csv_field("a=b"), "a=b");
+
+        let line = line(Format::Csv, &row("CLIENT_CREATE", "=cmd|' /C calc'!A0", r#"{"a":1}"#));
+        assert_eq!(line, "01J0000000000000000000000,1700000000,alice,CLIENT_CREATE,'=cmd|' /C calc'!A0,192.0.2.7,-,r1,\"{\"\"a\"\":1}\"\n");
+    }
+
+    #[test]
+    fn cef_escaping() {
+        assert_eq!(cef_header(r"a|b\c"), r"a\|b\\c");
+        assert_eq!(cef_ext("k=v\\x\r\ny|z"), r"k\=v\\x\r\ny|z");
+
+        let line = line(Format::Cef, &row("LOGIN_FAIL|x", "a=b c", "{\"why\":\"bad\\npw\"}"));
+        let (head, ext) = line.trim_end().rsplit_once('|').unwrap();
+        assert_eq!(head, format!("CEF:0|ovpn-admin|ovpn-admin|{}|LOGIN_FAIL\\|x|LOGIN_FAIL\\|x|5", env!("CARGO_PKG_VERSION")));
+        assert!(ext.contains("cs1Label=target cs1=a\\=b c "), "{ext}");
+        assert!(ext.contains("src=192.0.2.7 "), "{ext}");
+        assert!(!ext.contains("requestClientApplication"), "{ext}");
+        assert!(ext.ends_with(r#"msg={"why":"bad\\npw"}"#), "{ext}");
+        assert!(!line.trim_end().contains('\n'));
+    }
+}
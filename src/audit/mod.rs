@@ -0,0 +1,66 @@
+pub mod chain;
+pub mod export;
+pub mod retention;
+pub mod sink;
+
+use serde_json::Value;
+use ulid::Ulid;
+
+use crate::db::{self, Db};
+
+/// Who did something, from where, and as part of which request.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct AuditCtx {
+    pub actor: String,
+    pub ip: String,
+    pub ua: String,
+    pub request_id: String,
+}
+
+impl AuditCtx {
+    /// Context for work the panel does on its own behalf (CLI, schedulers).
+    pub fn system() -> Self {
+        AuditCtx {
+            actor: "system".into(),
+            ip: "-".into(),
+            ua: "-".into(),
+            request_id: Ulid::new().to_string(),
+        }
+    }
+
+    /// Writes the audit row, then queues any webhook or mail the action maps
+    /// to. Every audit write goes through here; `db::audit_insert` hands the
+    /// committed row to the sinks and `/api/events`.
+    pub async fn record(&self, pool: &Db, action: &str, target: &str, details: Value) -> anyhow::Result<()> {
+        let details_json = match &details {
+            Value::Null => "{}".to_string(),
+            v => v.to_string(),
+        };
+        db::audit_insert(pool, &db::NewAudit {
+            actor_user: &self.actor,
+            action,
+            target,
+            ip: &self.ip,
+            ua: &self.ua,
+            request_id: &self.request_id,
+            details: &details_json,
+        }).await?;
+
+        if let Some(event) = crate::webhooks::event_for_action(action)
+            && let Err(e) = crate::webhooks::notify(pool, event, self, target, &details).await {
+            tracing::error!("webhooks: queue {}: {}", event, e);
+        }
+        if let Err(e) = crate::mail::on_audit(pool, &self.actor, action, target, &details).await {
+            tracing::error!("mail: queue for {}: {}", action, e);
+        }
+        Ok(())
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex(&openssl::sha::sha256(data))
+}
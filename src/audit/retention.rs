@@ -0,0 +1,248 @@
+use anyhow::Result;
+use flate2::{write::GzEncoder, Compression};
+use serde::Serialize;
+use serde_json::json;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use time::OffsetDateTime;
+use ulid::Ulid;
+
+use super::AuditCtx;
+use crate::config::RetentionCfg;
+use crate::db::{self, Db, PruneRule};
+
+const BATCH: i64 = 5000;
+
+#[derive(Debug, Default, Serialize)]
+pub struct PruneReport {
+    pub dry_run: bool,
+    pub audit_pruned: u64,
+    pub login_attempts_pruned: u64,
+    pub archives: Vec<String>,
+}
+
+/// Turns the configured rules into SQL predicates: each rule with `days`
+/// excludes every longer prefix that refines it.
+fn compile_rules(cfg: &RetentionCfg, now: i64) -> Vec<PruneRule> {
+    cfg.rules.iter()
+        .filter_map(|r| {
+            let days = r.days?;
+            let except = cfg.rules.iter()
+                .filter(|o| o.prefix.len() > r.prefix.len() && o.prefix.starts_with(&r.prefix))
+                .map(|o| o.prefix.clone())
+                .collect();
+            Some(PruneRule { prefix: r.prefix.clone(), before: now - days * 86400, except })
+        })
+        .collect()
+}
+
+/// Writes `lines` to a new gzip file under `dir` and fsyncs it before returning.
+async fn write_archive(dir: &Path, kind: &str, lines: Vec<String>) -> Result<PathBuf> {
+    let name = format!("{kind}-{}-{}.jsonl.gz", OffsetDateTime::now_utc().unix_timestamp(), Ulid::new());
+    let path = dir.join(name);
+    let out = path.clone();
+    tokio::task::spawn_blocking(move || -> Result<()> {
+        let tmp = out.with_extension("gz.tmp");
+        let f = std::fs::File::create(&tmp)?;
+        let mut gz = GzEncoder::new(f, Compression::default());
+        for l in lines {
+            gz.write_all(l.as_bytes())?;
+            gz.write_all(b"\n")?;
+        }
+        gz.finish()?.sync_all()?;
+        std::fs::rename(&tmp, &out)?;
+        Ok(())
+    }).await??;
+    Ok(path)
+}
+
+/// Archives and deletes audit rows and login attempts past their retention.
+/// Chain rows are archived with their hashes and leave a tombstone behind,
+/// so `audit verify` keeps working across pruned ranges.
+pub async fn run(pool: &Db, cfg: &RetentionCfg, ctx: &AuditCtx, dry_run: bool) -> Result<PruneReport> {
+    run_at(pool, cfg, ctx, dry_run, OffsetDateTime::now_utc().unix_timestamp()).await
+}
+
+async fn run_at(pool: &Db, cfg: &RetentionCfg, ctx: &AuditCtx, dry_run: bool, now: i64) -> Result<PruneReport> {
+    let rules = compile_rules(cfg, now);
+    let dir = Path::new(&cfg.archive_dir);
+    let mut report = PruneReport { dry_run, ..Default::default() };
+
+    if dry_run {
+        report.audit_pruned = db::audit_prunable_count(pool, &rules).await? as u64;
+        if let Some(days) = cfg.login_attempts_days {
+            report.login_attempts_pruned = db::login_attempts_count_before(pool, now - days * 86400).await? as u64;
+        }
+        return Ok(report);
+    }
+
+    tokio::fs::create_dir_all(dir).await?;
+    loop {
+        let rows = db::audit_prunable(pool, &rules, BATCH).await?;
+        if rows.is_empty() { break; }
+        let lines = rows.iter().map(|r| json!({
+            "id": r.row.id,
+            "seq": r.seq,
+            "ts": r.row.ts,
+            "actor_user": r.row.actor_user,
+            "action": r.row.action,
+            "target": r.row.target,
+            "ip": r.row.ip,
+            "ua": r.row.ua,
+            "request_id": r.row.request_id,
+            "details": r.row.details,
+            "prev_hash": r.prev_hash,
+            "hash": r.hash,
+        }).to_string()).collect();
+        let path = write_archive(dir, "audit", lines).await?;
+        let path = path.to_string_lossy().into_owned();
+        db::audit_prune(pool, &rows, &path).await?;
+        report.audit_pruned += rows.len() as u64;
+        report.archives.push(path);
+    }
+
+    if let Some(days) = cfg.login_attempts_days {
+        let before = now - days * 86400;
+        loop {
+            let rows = db::login_attempts_before(pool, before, BATCH).await?;
+            if rows.is_empty() { break; }
+            let lines = rows.iter()
+                .map(|r| json!({ "username": r.username, "ts": r.ts, "ip": r.ip }).to_string())
+                .collect();
+            let path = write_archive(dir, "login_attempts", lines).await?;
+            let ids: Vec<i64> = rows.iter().map(|r| r.rowid).collect();
+            db::login_attempts_delete(pool, &ids).await?;
+            report.login_attempts_pruned += rows.len() as u64;
+            report.archives.push(path.to_string_lossy().into_owned());
+        }
+    }
+
+    if report.audit_pruned > 0 || report.login_attempts_pruned > 0 {
+        ctx.record(pool, "AUDIT_PRUNE", "-", json!({
+            "audit_pruned": report.audit_pruned,
+            "login_attempts_pruned": report.login_attempts_pruned,
+            "archives": report.archives,
+        })).await?;
+    }
+    Ok(report)
+}
+
+/// Runs the retention job every `interval_secs` while the server is up.
+pub fn spawn_scheduler(pool: Db, cfg: RetentionCfg) {
+    if !cfg.enabled { return; }
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(Duration::from_secs(cfg.interval_secs.max(60)));
+        loop {
+            tick.tick().await;
+            match run(&pool, &cfg, &AuditCtx::system(), false).await {
+                Ok(r) if r.audit_pruned > 0 || r.login_attempts_pruned > 0 => {
+                    tracing::info!("retention: pruned {} audit rows, {} login attempts", r.audit_pruned, r.login_attempts_pruned);
+                }
+                Ok(_) => {}
+                Err(e) => tracing::error!("retention: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{audit::chain, config::RetentionRule};
+    use std::io::Read;
+
+    const DAY: i64 = 86400;
+
+    fn cfg(dir: &Path, rules: &[(&str, Option<i64>)]) -> RetentionCfg {
+        RetentionCfg {
+            archive_dir: dir.to_string_lossy().into_owned(),
+            rules: rules.iter().map(|(p, d)| RetentionRule { prefix: p.to_string(), days: *d }).collect(),
+            ..Default::default()
+        }
+    }
+
+    async fn log(pool: &Db, actions: &[&str]) {
+        for a in actions {
+            db::audit_insert(pool, &db::NewAudit {
+                actor_user: "alice", action: a, target: "bob", ip: "", ua: "", request_id: "", details: "{}",
+            }).await.unwrap();
+        }
+    }
+
+    async fn actions(pool: &Db) -> Vec<String> {
+        sqlx::query_scalar("SELECT action FROM audit ORDER BY seq").fetch_all(pool).await.unwrap()
+    }
+
+    fn archived(path: &str) -> Vec<serde_json::Value> {
+        let mut text = String::new();
+        flate2::read::GzDecoder::new(std::fs::File::open(path).unwrap()).read_to_string(&mut text).unwrap();
+        text.lines().map(|l| serde_json::from_str(l).unwrap()).collect()
+    }
+
+    #[test]
+    fn longer_prefixes_are_excepted() {
+        let c = cfg(Path::new("/x"), &[("", Some(365)), ("AUTH_", Some(30)), ("AUTH_LOGIN_FAIL", None), ("CCD_", Some(90))]);
+        let rules = compile_rules(&c, 1000 * DAY);
+        let got: Vec<_> = rules.iter().map(|r| (r.prefix.as_str(), r.before, r.except.clone())).collect();
+        assert_eq!(got, [
+            ("", 635 * DAY, vec!["AUTH_".to_string(), "AUTH_LOGIN_FAIL".into(), "CCD_".into()]),
+            ("AUTH_", 970 * DAY, vec!["AUTH_LOGIN_FAIL".into()]),
+            ("CCD_", 910 * DAY, vec![]),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn rules_match_exact_prefixes() {
+        let pool = db::test_db().await;
+        let dir = tempfile::tempdir().unwrap();
+        log(&pool, &["AUTH_LOGIN", "auth_login", "AUTH_LOGIN_FAIL", "AUTHX", "CCD_WRITE"]).await;
+        let c = cfg(dir.path(), &[("AUTH_", Some(1)), ("AUTH_LOGIN_FAIL", None)]);
+        let r = run_at(&pool, &c, &AuditCtx::system(), false, OffsetDateTime::now_utc().unix_timestamp() + 2 * DAY).await.unwrap();
+        assert_eq!(r.audit_pruned, 1);
+        assert_eq!(actions(&pool).await, ["auth_login", "AUTH_LOGIN_FAIL", "AUTHX", "CCD_WRITE", "AUDIT_PRUNE"]);
+    }
+
+    #[tokio::test]
+    async fn dry_run_deletes_nothing() {
+        let pool = db::test_db().await;
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("archive");
+        log(&pool, &["A", "B"]).await;
+        let c = cfg(&archive, &[("", Some(1))]);
+        let r = run_at(&pool, &c, &AuditCtx::system(), true, OffsetDateTime::now_utc().unix_timestamp() + 2 * DAY).await.unwrap();
+        assert_eq!((r.dry_run, r.audit_pruned, r.archives.len()), (true, 2, 0));
+        assert_eq!(actions(&pool).await, ["A", "B"]);
+        assert!(!archive.exists());
+    }
+
+    #[tokio::test]
+    async fn rows_are_archived_then_tombstoned() {
+        let pool = db::test_db().await;
+        let dir = tempfile::tempdir().unwrap();
+        log(&pool, &["OLD_1", "OLD_2", "KEEP", "OLD_3", "KEEP"]).await;
+        let later = OffsetDateTime::now_utc().unix_timestamp() + 2 * DAY;
+
+        // Nothing is deleted when the archive cannot be written.
+        let blocked = dir.path().join("file");
+        std::fs::write(&blocked, b"").unwrap();
+        let c = cfg(&blocked.join("archive"), &[("OLD_", Some(1))]);
+        assert!(run_at(&pool, &c, &AuditCtx::system(), false, later).await.is_err());
+        assert_eq!(actions(&pool).await.len(), 5);
+
+        let c = cfg(dir.path(), &[("OLD_", Some(1))]);
+        let r = run_at(&pool, &c, &AuditCtx::system(), false, later).await.unwrap();
+        assert_eq!(r.audit_pruned, 3);
+        let lines = archived(&r.archives[0]);
+        assert_eq!(lines.iter().map(|l| l["seq"].as_i64().unwrap()).collect::<Vec<_>>(), [1, 2, 4]);
+        assert!(lines.iter().all(|l| l["hash"].as_str().is_some_and(|h| !h.is_empty())));
+
+        // One tombstone per pruned row, across both gaps, and the chain still verifies.
+        let seqs: Vec<i64> = sqlx::query_scalar("SELECT seq FROM audit_tombstones ORDER BY seq").fetch_all(&pool).await.unwrap();
+        assert_eq!(seqs, [1, 2, 4]);
+        assert_eq!(actions(&pool).await, ["KEEP", "KEEP", "AUDIT_PRUNE"]);
+        let v = chain::verify(&pool).await.unwrap();
+        assert!(v.ok, "{:?}", v.broken);
+        assert_eq!((v.checked, v.pruned), (3, 3));
+    }
+}
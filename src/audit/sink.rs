@@ -0,0 +1,398 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::{
+    fs,
+    io::AsyncWriteExt,
+    net::{UdpSocket, UnixDatagram},
+    sync::{mpsc, oneshot},
+};
+
+use crate::config::{AuditCfg, SinkCfg, SyslogTransport};
+
+/// An audit row as handed to the sinks, after it has been committed.
+#[derive(Debug, Serialize)]
+pub struct SinkEvent {
+    pub id: String,
+    pub seq: i64,
+    pub ts: i64,
+    pub actor_user: String,
+    pub action: String,
+    pub target: String,
+    pub ip: String,
+    pub ua: String,
+    pub request_id: String,
+    pub details: String,
+    pub hash: String,
+}
+
+#[derive(Default)]
+pub struct SinkStats {
+    pub sent: AtomicU64,
+    pub dropped: AtomicU64,
+    pub errors: AtomicU64,
+}
+
+enum Msg {
+    Event(Arc<SinkEvent>),
+    /// Answered once everything queued before it was written.
+    Flush(oneshot::Sender<()>),
+}
+
+struct Handle {
+    kind: &'static str,
+    tx: mpsc::Sender<Msg>,
+    stats: Arc<SinkStats>,
+}
+
+static SINKS: OnceLock<Vec<Handle>> = OnceLock::new();
+
+/// Spawns one writer task per configured sink. Each gets its own bounded
+/// queue so a slow or dead sink only drops its own events.
+pub fn start(cfg: &AuditCfg) {
+    let mut handles = Vec::new();
+    for (i, sink) in cfg.sinks.iter().enumerate() {
+        let (tx, rx) = mpsc::channel(cfg.sink_queue.max(1));
+        let stats = Arc::new(SinkStats::default());
+        let (kind, dest) = match sink {
+            SinkCfg::Syslog { address, .. } => ("syslog", address),
+            SinkCfg::Jsonl { path, .. } => ("jsonl", path),
+        };
+        tracing::info!("audit sink #{} {} {}", i, kind, dest);
+        tokio::spawn(run(sink.clone(), rx, stats.clone()));
+        handles.push(Handle { kind, tx, stats });
+    }
+    let _ = SINKS.set(handles);
+}
+
+pub fn publish(ev: SinkEvent) {
+    let Some(sinks) = SINKS.get() else { return };
+    fan_out(sinks, ev);
+}
+
+fn fan_out(sinks: &[Handle], ev: SinkEvent) {
+    if sinks.is_empty() { return; }
+    let ev = Arc::new(ev);
+    for h in sinks {
+        if h.tx.try_send(Msg::Event(ev.clone())).is_err() {
+            h.stats.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Waits up to `within` for every sink to write what it has queued, for a
+/// process about to exit.
+pub async fn flush(within: std::time::Duration) {
+    let Some(sinks) = SINKS.get() else { return };
+    let all = async {
+        for h in sinks {
+            let (tx, rx) = oneshot::channel();
+            if h.tx.send(Msg::Flush(tx)).await.is_ok() {
+                rx.await.ok();
+            }
+        }
+    };
+    if tokio::time::timeout(within, all).await.is_err() {
+        tracing::warn!("audit sink: gave up flushing after {:?}", within);
+    }
+}
+
+/// `(kind, counters)` for every running sink, in config order. Addresses and
+/// paths are left out: the index into `audit.sinks` identifies the sink.
+pub fn stats() -> Vec<(&'static str, Arc<SinkStats>)> {
+    SINKS.get()
+        .map(|v| v.iter().map(|h| (h.kind, h.stats.clone())).collect())
+        .unwrap_or_default()
+}
+
+async fn run(cfg: SinkCfg, mut rx: mpsc::Receiver<Msg>, stats: Arc<SinkStats>) {
+    let mut out = match Output::open(&cfg).await {
+        Ok(o) => o,
+        Err(e) => {
+            tracing::error!("audit sink: open failed: {}", e);
+            while let Some(msg) = rx.recv().await {
+                if let Msg::Event(_) = msg {
+                    stats.errors.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            return;
+        }
+    };
+    while let Some(msg) = rx.recv().await {
+        let ev = match msg {
+            Msg::Event(ev) => ev,
+            Msg::Flush(done) => {
+                done.send(()).ok();
+                continue;
+            }
+        };
+        match out.write(&ev).await {
+            Ok(()) => { stats.sent.fetch_add(1, Ordering::Relaxed); }
+            Err(e) => {
+                stats.errors.fetch_add(1, Ordering::Relaxed);
+                tracing::warn!("audit sink: {}", e);
+            }
+        }
+    }
+}
+
+enum Output {
+    Udp { sock: UdpSocket, addr: String, fmt: Syslog },
+    Unix { sock: UnixDatagram, path: String, fmt: Syslog },
+    Jsonl { file: RotatingFile },
+}
+
+impl Output {
+    async fn open(cfg: &SinkCfg) -> Result<Self> {
+        Ok(match cfg {
+            SinkCfg::Syslog { transport, address, facility, app_name } => {
+                let fmt = Syslog::new(*facility, app_name);
+                match transport {
+                    SyslogTransport::Udp => {
+                        let bind = if address.starts_with('[') { "[::]:0" } else { "0.0.0.0:0" };
+                        Output::Udp { sock: UdpSocket::bind(bind).await?, addr: address.clone(), fmt }
+                    }
+                    SyslogTransport::Unix => Output::Unix { sock: UnixDatagram::unbound()?, path: address.clone(), fmt },
+                }
+            }
+            SinkCfg::Jsonl { path, max_bytes, keep } => {
+                Output::Jsonl { file: RotatingFile::open(PathBuf::from(path), *max_bytes, *keep).await? }
+            }
+        })
+    }
+
+    async fn write(&mut self, ev: &SinkEvent) -> Result<()> {
+        match self {
+            Output::Udp { sock, addr, fmt } => { sock.send_to(fmt.line(ev).as_bytes(), addr.as_str()).await?; }
+            Output::Unix { sock, path, fmt } => { sock.send_to(fmt.line(ev).as_bytes(), path.as_str()).await?; }
+            Output::Jsonl { file } => {
+                let mut line = serde_json::to_string(ev)?;
+                line.push('\n');
+                file.write(line.as_bytes()).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// RFC 5424 message framing.
+struct Syslog {
+    facility: u8,
+    hostname: String,
+    app_name: String,
+    procid: u32,
+}
+
+fn sd_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace(']', "\\]")
+}
+
+/// PRINTUSASCII, no spaces, at most `max` chars; `-` when empty.
+fn header_field(s: &str, max: usize) -> String {
+    let v: String = s.chars().filter(|c| c.is_ascii_graphic()).take(max).collect();
+    if v.is_empty() { "-".into() } else { v }
+}
+
+impl Syslog {
+    fn new(facility: u8, app_name: &str) -> Self {
+        let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|s| s.trim().to_string())
+            .unwrap_or_default();
+        Syslog {
+            facility: facility.min(23),
+            hostname: header_field(&hostname, 255),
+            app_name: header_field(app_name, 48),
+            procid: std::process::id(),
+        }
+    }
+
+    fn severity(action: &str) -> u8 {
+        if action.starts_with("LOGIN_FAIL") || action == "LOGIN_THROTTLE" { 4 } else { 5 }
+    }
+
+    fn line(&self, ev: &SinkEvent) -> String {
+        let pri = self.facility as u32 * 8 + Self::severity(&ev.action) as u32;
+        let ts = OffsetDateTime::from_unix_timestamp(ev.ts)
+            .ok()
+            .and_then(|t| t.format(&Rfc3339).ok())
+            .unwrap_or_else(|| "-".into());
+        format!(
+            "<{pri}>1 {ts} {} {} {} {} [audit@32473 id=\"{}\" seq=\"{}\" actor=\"{}\" target=\"{}\" ip=\"{}\" requestId=\"{}\"] {}",
+            self.hostname,
+            self.app_name,
+            self.procid,
+            header_field(&ev.action, 32),
+            sd_escape(&ev.id),
+            ev.seq,
+            sd_escape(&ev.actor_user),
+            sd_escape(&ev.target),
+            sd_escape(&ev.ip),
+            sd_escape(&ev.request_id),
+            ev.details,
+        )
+    }
+}
+
+/// Append-only file that rolls over to `path.1 .. path.<keep>` past `max_bytes`.
+struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: u32,
+    file: fs::File,
+    size: u64,
+}
+
+fn rotated(path: &Path, n: u32) -> PathBuf {
+    let mut s = path.as_os_str().to_owned();
+    s.push(format!(".{n}"));
+    PathBuf::from(s)
+}
+
+impl RotatingFile {
+    async fn open(path: PathBuf, max_bytes: u64, keep: u32) -> Result<Self> {
+        if let Some(dir) = path.parent() { fs::create_dir_all(dir).await.ok(); }
+        let file = fs::OpenOptions::new().create(true).append(true).open(&path).await?;
+        let size = file.metadata().await?.len();
+        Ok(RotatingFile { path, max_bytes, keep, file, size })
+    }
+
+    async fn rotate(&mut self) -> Result<()> {
+        self.file.flush().await?;
+        if self.keep == 0 {
+            fs::remove_file(&self.path).await.ok();
+        } else {
+            for n in (1..self.keep).rev() {
+                fs::rename(rotated(&self.path, n), rotated(&self.path, n + 1)).await.ok();
+            }
+            fs::rename(&self.path, rotated(&self.path, 1)).await?;
+        }
+        self.file = fs::OpenOptions::new().create(true).append(true).open(&self.path).await?;
+        self.size = 0;
+        Ok(())
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<()> {
+        if self.size > 0 && self.size + data.len() as u64 > self.max_bytes {
+            self.rotate().await?;
+        }
+        self.file.write_all(data).await?;
+        self.file.flush().await?;
+        self.size += data.len() as u64;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(seq: i64) -> SinkEvent {
+        SinkEvent {
+            id: format!("01TEST{seq}"), seq, ts: 1_700_000_000, actor_user: "alice".into(),
+            action: "CLIENT_CREATE".into(), target: "bob\"]".into(), ip: "127.0.0.1".into(),
+            ua: "test".into(), request_id: "req-1".into(), details: "{}".into(), hash: "sha256:00".into(),
+        }
+    }
+
+    fn seq(msg: Option<Msg>) -> i64 {
+        match msg {
+            Some(Msg::Event(ev)) => ev.seq,
+            _ => panic!("expected an event"),
+        }
+    }
+
+    #[tokio::test]
+    async fn udp_syslog_sends_rfc5424_frame() {
+        let rx = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let cfg = SinkCfg::Syslog {
+            transport: SyslogTransport::Udp,
+            address: rx.local_addr().unwrap().to_string(),
+            facility: 13,
+            app_name: "ovpn admin".into(),
+        };
+        let mut out = Output::open(&cfg).await.unwrap();
+        out.write(&event(7)).await.unwrap();
+
+        let mut buf = [0u8; 2048];
+        let n = tokio::time::timeout(std::time::Duration::from_secs(5), rx.recv(&mut buf))
+            .await.unwrap().unwrap();
+        let frame = std::str::from_utf8(&buf[..n]).unwrap();
+        // facility 13 * 8 + notice (5)
+        assert!(frame.starts_with("<109>1 2023-11-14T22:13:20Z "), "{frame}");
+        let fields: Vec<&str> = frame.splitn(8, ' ').collect();
+        assert_eq!(fields[3], "ovpnadmin");
+        assert_eq!(fields[4], std::process::id().to_string());
+        assert_eq!(fields[5], "CLIENT_CREATE");
+        assert!(frame.contains("[audit@32473 id=\"01TEST7\" seq=\"7\" actor=\"alice\" target=\"bob\\\"\\]\""), "{frame}");
+        assert!(frame.ends_with("] {}"), "{frame}");
+    }
+
+    #[tokio::test]
+    async fn jsonl_rotates_and_keeps_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let line = format!("{}\n", serde_json::to_string(&event(1)).unwrap());
+        let max = line.len() as u64 * 2;
+        let mut f = RotatingFile::open(path.clone(), max, 2).await.unwrap();
+        for _ in 0..7 {
+            f.write(line.as_bytes()).await.unwrap();
+        }
+        drop(f);
+
+        let read = |p: PathBuf| std::fs::read_to_string(p).unwrap_or_default();
+        assert_eq!(read(path.clone()).lines().count(), 1);
+        assert_eq!(read(rotated(&path, 1)).lines().count(), 2);
+        assert_eq!(read(rotated(&path, 2)).lines().count(), 2);
+        assert!(!rotated(&path, 3).exists());
+
+        // Reopening picks up the current size instead of starting from zero.
+        let mut f = RotatingFile::open(path.clone(), max, 2).await.unwrap();
+        assert_eq!(f.size, line.len() as u64);
+        f.write(line.as_bytes()).await.unwrap();
+        f.write(line.as_bytes()).await.unwrap();
+        assert_eq!(read(path.clone()).lines().count(), 1);
+        assert_eq!(read(rotated(&path, 1)).lines().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn flush_waits_for_queued_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let (tx, rx) = mpsc::channel(8);
+        let stats = Arc::new(SinkStats::default());
+        tokio::spawn(run(SinkCfg::Jsonl { path: path.to_string_lossy().into(), max_bytes: 1 << 20, keep: 1 }, rx, stats.clone()));
+        for seq in 1..=3 {
+            tx.send(Msg::Event(Arc::new(event(seq)))).await.unwrap();
+        }
+        let (done, flushed) = oneshot::channel();
+        tx.send(Msg::Flush(done)).await.unwrap();
+        flushed.await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 3);
+        assert_eq!(stats.sent.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn full_queue_counts_drops() {
+        let (tx, mut rx) = mpsc::channel(2);
+        let (slow_tx, _slow_rx) = mpsc::channel(1);
+        let sinks = vec![
+            Handle { kind: "jsonl", tx, stats: Arc::new(SinkStats::default()) },
+            Handle { kind: "syslog", tx: slow_tx, stats: Arc::new(SinkStats::default()) },
+        ];
+        for seq in 1..=5 {
+            fan_out(&sinks, event(seq));
+        }
+        assert_eq!(sinks[0].stats.dropped.load(Ordering::Relaxed), 3);
+        assert_eq!(sinks[1].stats.dropped.load(Ordering::Relaxed), 4);
+        assert_eq!(seq(rx.recv().await), 1);
+        assert_eq!(seq(rx.recv().await), 2);
+
+        // Draining frees the slot again.
+        fan_out(&sinks, event(6));
+        assert_eq!(seq(rx.recv().await), 6);
+        assert_eq!(sinks[0].stats.dropped.load(Ordering::Relaxed), 3);
+    }
+}
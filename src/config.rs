@@ -14,13 +14,373 @@ pub struct DbCfg {
     pub url: String,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct AuditCfg {
+    /// Key the hash chain with HMAC-SHA256 derived from the pepper.
+    #[serde(default)]
+    pub chain_hmac: bool,
+    #[serde(default)]
+    pub sinks: Vec<SinkCfg>,
+    /// Events buffered per sink before new ones are dropped.
+    #[serde(default = "default_sink_queue")]
+    pub sink_queue: usize,
+    #[serde(default)]
+    pub retention: RetentionCfg,
+}
+
+impl Default for AuditCfg {
+    fn default() -> Self {
+        AuditCfg { chain_hmac: false, sinks: Vec::new(), sink_queue: default_sink_queue(), retention: RetentionCfg::default() }
+    }
+}
+
+/// Rows whose action starts with `prefix` are pruned after `days`; without
+/// `days` they are kept forever. The longest matching prefix wins.
+#[derive(Debug, Deserialize, Clone)]
+pub struct RetentionRule {
+    pub prefix: String,
+    #[serde(default)]
+    pub days: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RetentionCfg {
+    /// Run the pruning job in the background.
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_retention_interval")]
+    pub interval_secs: u64,
+    #[serde(default = "default_archive_dir")]
+    pub archive_dir: String,
+    #[serde(default)]
+    pub rules: Vec<RetentionRule>,
+    #[serde(default)]
+    pub login_attempts_days: Option<i64>,
+}
+
+impl Default for RetentionCfg {
+    fn default() -> Self {
+        RetentionCfg {
+            enabled: false,
+            interval_secs: default_retention_interval(),
+            archive_dir: default_archive_dir(),
+            rules: Vec::new(),
+            login_attempts_days: None,
+        }
+    }
+}
+
+fn default_retention_interval() -> u64 { 24 * 3600 }
+fn default_archive_dir() -> String { "var/audit-archive".into() }
+
+fn default_sink_queue() -> usize { 1024 }
+fn default_facility() -> u8 { 10 } // authpriv
+fn default_app_name() -> String { "ovpn-admin".into() }
+fn default_max_bytes() -> u64 { 10 * 1024 * 1024 }
+fn default_keep() -> u32 { 5 }
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogTransport { Udp, Unix }
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum SinkCfg {
+    /// RFC 5424 syslog; `address` is `host:port` for udp or a socket path for unix.
+    Syslog {
+        transport: SyslogTransport,
+        address: String,
+        #[serde(default = "default_facility")]
+        facility: u8,
+        #[serde(default = "default_app_name")]
+        app_name: String,
+    },
+    /// JSON lines, rotated to `path.1 .. path.<keep>` once `max_bytes` is reached.
+    Jsonl {
+        path: String,
+        #[serde(default = "default_max_bytes")]
+        max_bytes: u64,
+        #[serde(default = "default_keep")]
+        keep: u32,
+    },
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct AppCfg {
     pub server: ServerCfg,
     pub db: DbCfg,
     pub ovpn: Ovpn,
+    #[serde(default)]
+    pub audit: AuditCfg,
+    #[serde(default)]
+    pub mail: Option<MailCfg>,
+    #[serde(default)]
+    pub expiry: ExpiryCfg,
+    #[serde(default)]
+    pub autorenew: AutoRenewCfg,
+    #[serde(default)]
+    pub csr: CsrCfg,
+    #[serde(default)]
+    pub issuance: IssuanceCfg,
+    #[serde(default)]
+    pub jobs: JobsCfg,
+    /// Static client addresses; the IPAM endpoints answer 404 without it.
+    #[serde(default)]
+    pub ipam: Option<IpamCfg>,
+}
+
+/// Key algorithms vpncertd can generate, named as on the wire.
+#[derive(Debug, Deserialize, serde::Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyType {
+    Rsa2048,
+    Rsa3072,
+    Rsa4096,
+    EcdsaP256,
+    EcdsaP384,
+    Ed25519,
+}
+
+impl KeyType {
+    pub const ALL: [KeyType; 6] = [
+        KeyType::Rsa2048, KeyType::Rsa3072, KeyType::Rsa4096, KeyType::EcdsaP256, KeyType::EcdsaP384, KeyType::Ed25519,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            KeyType::Rsa2048 => "rsa2048",
+            KeyType::Rsa3072 => "rsa3072",
+            KeyType::Rsa4096 => "rsa4096",
+            KeyType::EcdsaP256 => "ecdsa-p256",
+            KeyType::EcdsaP384 => "ecdsa-p384",
+            KeyType::Ed25519 => "ed25519",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        KeyType::ALL.into_iter().find(|k| k.as_str() == s)
+    }
+}
+
+/// A named vpncertd profile and who may issue with it.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ProfileCfg {
+    pub name: String,
+    #[serde(default = "default_profile_key_types")]
+    pub key_types: Vec<KeyType>,
+    /// Used when a request names no key type; the first allowed one otherwise.
+    #[serde(default)]
+    pub default_key_type: Option<KeyType>,
+    /// Roles allowed to issue with this profile.
+    #[serde(default = "default_profile_roles")]
+    pub roles: Vec<String>,
+    /// Upper bound for every validity, the default included.
+    #[serde(default = "default_max_validity_days")]
+    pub max_validity_days: u32,
+    /// Used when a request gives none; `max_validity_days` when unset.
+    #[serde(default)]
+    pub default_validity_days: Option<u32>,
+}
+
+impl ProfileCfg {
+    pub fn default_key(&self) -> Option<KeyType> {
+        self.default_key_type.filter(|k| self.key_types.contains(k)).or_else(|| self.key_types.first().copied())
+    }
+
+    pub fn default_validity(&self) -> u32 {
+        self.default_validity_days.unwrap_or(self.max_validity_days)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct IssuanceCfg {
+    #[serde(default = "default_profile_name")]
+    pub default_profile: String,
+    #[serde(default = "default_profiles")]
+    pub profiles: Vec<ProfileCfg>,
+}
+
+impl Default for IssuanceCfg {
+    fn default() -> Self {
+        IssuanceCfg { default_profile: default_profile_name(), profiles: default_profiles() }
+    }
 }
 
+fn default_profile_key_types() -> Vec<KeyType> { KeyType::ALL.to_vec() }
+fn default_profile_roles() -> Vec<String> { vec!["ADMIN".into()] }
+fn default_max_validity_days() -> u32 { 825 }
+fn default_profile_name() -> String { "client".into() }
+fn default_profiles() -> Vec<ProfileCfg> {
+    vec![ProfileCfg {
+        name: default_profile_name(),
+        key_types: default_profile_key_types(),
+        default_key_type: Some(KeyType::Rsa4096),
+        roles: default_profile_roles(),
+        max_validity_days: default_max_validity_days(),
+        default_validity_days: None,
+    }]
+}
+
+/// Keys a client may bring in a CSR.
+#[derive(Debug, Deserialize, Clone)]
+pub struct CsrCfg {
+    #[serde(default = "default_min_rsa_bits")]
+    pub min_rsa_bits: u32,
+    /// OpenSSL short names, e.g. `prime256v1`, `secp384r1`.
+    #[serde(default = "default_ec_curves")]
+    pub ec_curves: Vec<String>,
+    #[serde(default = "default_true")]
+    pub allow_ed25519: bool,
+}
+
+impl Default for CsrCfg {
+    fn default() -> Self {
+        CsrCfg { min_rsa_bits: default_min_rsa_bits(), ec_curves: default_ec_curves(), allow_ed25519: true }
+    }
+}
+
+fn default_min_rsa_bits() -> u32 { 2048 }
+fn default_ec_curves() -> Vec<String> { vec!["prime256v1".into(), "secp384r1".into(), "secp521r1".into()] }
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct AutoRenewCfg {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_expiry_interval")]
+    pub interval_secs: u64,
+    /// Renew this many days before `not_after`.
+    #[serde(default = "default_renew_days_before")]
+    pub days_before: i64,
+    /// The old cert is revoked when the new one first connects, or after this.
+    #[serde(default = "default_renew_revoke_days")]
+    pub revoke_old_after_days: i64,
+    /// Encrypted bundles waiting for their owner.
+    #[serde(default = "default_renew_bundle_dir")]
+    pub bundle_dir: String,
+    /// How long the mailed download link works; it also stops after one use.
+    #[serde(default = "default_renew_link_ttl_hours")]
+    pub link_ttl_hours: i64,
+}
+
+impl Default for AutoRenewCfg {
+    fn default() -> Self {
+        AutoRenewCfg {
+            enabled: false,
+            interval_secs: default_expiry_interval(),
+            days_before: default_renew_days_before(),
+            revoke_old_after_days: default_renew_revoke_days(),
+            bundle_dir: default_renew_bundle_dir(),
+            link_ttl_hours: default_renew_link_ttl_hours(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct JobsCfg {
+    /// Reports and archives produced by jobs, one directory per job.
+    #[serde(default = "default_jobs_dir")]
+    pub dir: String,
+    /// Jobs run concurrently; items within a job run in order.
+    #[serde(default = "default_jobs_workers")]
+    pub workers: usize,
+    /// Files a job produced are deleted this long after it finished.
+    #[serde(default = "default_jobs_file_ttl_hours")]
+    pub file_ttl_hours: i64,
+}
+
+impl Default for JobsCfg {
+    fn default() -> Self {
+        JobsCfg { dir: default_jobs_dir(), workers: default_jobs_workers(), file_ttl_hours: default_jobs_file_ttl_hours() }
+    }
+}
+
+#[derive(Debug, Deserialize, serde::Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Topology {
+    #[default]
+    Subnet,
+    Net30,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct IpamCfg {
+    /// The network of the `server` directive, e.g. `10.8.0.0/24`.
+    pub subnet: String,
+    #[serde(default)]
+    pub topology: Topology,
+    /// Addresses never handed out: `10.8.0.5`, `10.8.0.100-10.8.0.199` or
+    /// `10.8.0.128/25`. The server's own address is always excluded.
+    #[serde(default)]
+    pub reserved: Vec<String>,
+}
+
+fn default_jobs_dir() -> String { "var/jobs".into() }
+fn default_jobs_workers() -> usize { 2 }
+fn default_jobs_file_ttl_hours() -> i64 { 24 }
+
+fn default_renew_days_before() -> i64 { 14 }
+fn default_renew_revoke_days() -> i64 { 7 }
+fn default_renew_bundle_dir() -> String { "var/renewals".into() }
+fn default_renew_link_ttl_hours() -> i64 { 72 }
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ExpiryCfg {
+    /// Check issued certificates in the background.
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default = "default_expiry_interval")]
+    pub interval_secs: u64,
+    /// Days before `not_after` at which an EXPIRY_WARNING is raised, once each.
+    #[serde(default = "default_expiry_thresholds")]
+    pub thresholds_days: Vec<i64>,
+}
+
+impl Default for ExpiryCfg {
+    fn default() -> Self {
+        ExpiryCfg {
+            enabled: true,
+            interval_secs: default_expiry_interval(),
+            thresholds_days: default_expiry_thresholds(),
+        }
+    }
+}
+
+fn default_expiry_interval() -> u64 { 6 * 3600 }
+fn default_expiry_thresholds() -> Vec<i64> { vec![30, 14, 7, 1] }
+
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    None,
+    #[default]
+    Starttls,
+    Tls,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MailCfg {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    pub smtp_host: String,
+    #[serde(default = "default_smtp_port")]
+    pub smtp_port: u16,
+    #[serde(default)]
+    pub tls: SmtpTls,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    /// Directory with `<name>.hbs` files that replace the built-in templates.
+    pub templates_dir: Option<String>,
+    /// Public base URL of the panel, used for links in mail.
+    pub panel_url: Option<String>,
+    #[serde(default = "default_panel_name")]
+    pub panel_name: String,
+}
+
+fn default_true() -> bool { true }
+fn default_smtp_port() -> u16 { 587 }
+fn default_panel_name() -> String { "ovpn-admin".into() }
+
 #[derive(Debug, serde::Deserialize, Clone)]
 pub struct Ovpn {
     #[serde(default)]
@@ -32,11 +392,14 @@ pub struct Ovpn {
     pub bundle_port: u16,
     pub bundle_proto: String,
     pub bundles_dir: String,
+    /// Bearer token the OpenVPN client-connect hook presents to `/api/vpn/*`.
+    #[serde(default)]
+    pub hook_token: Option<String>,
 }
 
 impl AppCfg {
     pub fn load() -> anyhow::Result<Self> {
-        let mut c = config::Config::builder()
+        let c = config::Config::builder()
             .add_source(config::File::with_name("config/dev").required(false))
             .add_source(config::Environment::with_prefix("OVPNADM").separator("__"))
             .build()?;
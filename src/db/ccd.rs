@@ -0,0 +1,116 @@
+use sqlx::Row;
+use time::OffsetDateTime;
+
+use super::Db;
+
+#[derive(Debug, Clone)]
+pub struct Revision {
+    pub rev: i64,
+    pub content: String,
+    pub sha256: String,
+    pub author: String,
+    pub source: String,
+    pub restored_from: Option<i64>,
+    pub created_at: i64,
+}
+
+const COLS: &str = "rev, content, sha256, author, source, restored_from, created_at";
+
+fn from_row(r: &sqlx::sqlite::SqliteRow) -> Revision {
+    Revision {
+        rev: r.try_get(0).unwrap(),
+        content: r.try_get(1).unwrap(),
+        sha256: r.try_get(2).unwrap(),
+        author: r.try_get(3).unwrap(),
+        source: r.try_get(4).unwrap(),
+        restored_from: r.try_get(5).unwrap(),
+        created_at: r.try_get(6).unwrap(),
+    }
+}
+
+pub struct NewRevision<'a> {
+    pub cn: &'a str,
+    pub content: &'a str,
+    pub sha256: &'a str,
+    pub author: &'a str,
+    pub source: &'a str,
+    pub restored_from: Option<i64>,
+}
+
+/// Appends the next revision number for the CN and returns it.
+pub async fn add(pool: &Db, n: &NewRevision<'_>) -> anyhow::Result<i64> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let row = sqlx::query("INSERT INTO ccd_revisions(cn, rev, content, sha256, author, source, restored_from, created_at) \
+                           SELECT ?, COALESCE(MAX(rev), 0) + 1, ?, ?, ?, ?, ?, ? FROM ccd_revisions WHERE cn=? RETURNING rev")
+        .bind(n.cn).bind(n.content).bind(n.sha256).bind(n.author).bind(n.source).bind(n.restored_from).bind(now)
+        .bind(n.cn)
+        .fetch_one(pool).await?;
+    Ok(row.try_get(0).unwrap())
+}
+
+/// Appends `n` unless it matches the latest revision (or is empty with no
+/// history), checked and written in one write transaction so concurrent
+/// readers noticing the same edit record it once. Returns the new revision
+/// and the one before it.
+pub async fn add_if_changed(pool: &Db, n: &NewRevision<'_>) -> anyhow::Result<Option<(i64, Option<Revision>)>> {
+    let mut tx = super::begin_immediate(pool).await?;
+    let prev = sqlx::query(&format!("SELECT {COLS} FROM ccd_revisions WHERE cn=? ORDER BY rev DESC LIMIT 1"))
+        .bind(n.cn).fetch_optional(&mut *tx).await?.as_ref().map(from_row);
+    match &prev {
+        Some(p) if p.sha256 == n.sha256 => return Ok(None),
+        None if n.content.is_empty() => return Ok(None),
+        _ => {}
+    }
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let rev: i64 = sqlx::query("INSERT INTO ccd_revisions(cn, rev, content, sha256, author, source, restored_from, created_at) \
+                                VALUES(?, ?, ?, ?, ?, ?, ?, ?) RETURNING rev")
+        .bind(n.cn).bind(prev.as_ref().map_or(1, |p| p.rev + 1)).bind(n.content).bind(n.sha256).bind(n.author)
+        .bind(n.source).bind(n.restored_from).bind(now)
+        .fetch_one(&mut *tx).await?.try_get(0)?;
+    tx.commit().await?;
+    Ok(Some((rev, prev)))
+}
+
+pub async fn latest(pool: &Db, cn: &str) -> anyhow::Result<Option<Revision>> {
+    let row = sqlx::query(&format!("SELECT {COLS} FROM ccd_revisions WHERE cn=? ORDER BY rev DESC LIMIT 1"))
+        .bind(cn).fetch_optional(pool).await?;
+    Ok(row.as_ref().map(from_row))
+}
+
+pub async fn get(pool: &Db, cn: &str, rev: i64) -> anyhow::Result<Option<Revision>> {
+    let row = sqlx::query(&format!("SELECT {COLS} FROM ccd_revisions WHERE cn=? AND rev=?"))
+        .bind(cn).bind(rev).fetch_optional(pool).await?;
+    Ok(row.as_ref().map(from_row))
+}
+
+/// Newest first.
+pub async fn list(pool: &Db, cn: &str) -> anyhow::Result<Vec<Revision>> {
+    let rows = sqlx::query(&format!("SELECT {COLS} FROM ccd_revisions WHERE cn=? ORDER BY rev DESC"))
+        .bind(cn).fetch_all(pool).await?;
+    Ok(rows.iter().map(from_row).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rev<'a>(content: &'a str, sha256: &'a str) -> NewRevision<'a> {
+        NewRevision { cn: "bob", content, sha256, author: "external", source: "external", restored_from: None }
+    }
+
+    #[tokio::test]
+    async fn external_edits_are_recorded_once() {
+        let pool = crate::db::test_db().await;
+        assert!(add_if_changed(&pool, &rev("", "e0")).await.unwrap().is_none());
+        let (first, prev) = add_if_changed(&pool, &rev("disable\n", "a1")).await.unwrap().unwrap();
+        assert_eq!((first, prev.map(|p| p.rev)), (1, None));
+
+        // Readers racing on the same file all see it unchanged after the first.
+        let edit = rev("push-reset\n", "b2");
+        let (a, b) = tokio::join!(add_if_changed(&pool, &edit), add_if_changed(&pool, &edit));
+        let added: Vec<_> = [a.unwrap(), b.unwrap()].into_iter().flatten().collect();
+        assert_eq!(added.len(), 1);
+        assert_eq!((added[0].0, added[0].1.as_ref().map(|p| p.rev)), (2, Some(1)));
+        assert_eq!(list(&pool, "bob").await.unwrap().len(), 2);
+    }
+}
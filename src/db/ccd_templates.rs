@@ -0,0 +1,118 @@
+use sqlx::Row;
+use std::collections::BTreeMap;
+use time::OffsetDateTime;
+
+use super::Db;
+
+pub type Vars = BTreeMap<String, String>;
+
+#[derive(Debug, Clone)]
+pub struct Template {
+    pub name: String,
+    pub description: Option<String>,
+    pub body: String,
+    pub defaults: Vars,
+    pub updated_by: String,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Link {
+    pub cn: String,
+    pub template: String,
+    pub vars: Vars,
+    pub extra: String,
+    pub updated_by: String,
+    pub updated_at: i64,
+}
+
+fn vars(r: &sqlx::sqlite::SqliteRow, i: usize) -> Vars {
+    serde_json::from_str(r.try_get(i).unwrap()).unwrap_or_default()
+}
+
+fn template_from_row(r: &sqlx::sqlite::SqliteRow) -> Template {
+    Template {
+        name: r.try_get(0).unwrap(),
+        description: r.try_get(1).unwrap(),
+        body: r.try_get(2).unwrap(),
+        defaults: vars(r, 3),
+        updated_by: r.try_get(4).unwrap(),
+        updated_at: r.try_get(5).unwrap(),
+    }
+}
+
+fn link_from_row(r: &sqlx::sqlite::SqliteRow) -> Link {
+    Link {
+        cn: r.try_get(0).unwrap(),
+        template: r.try_get(1).unwrap(),
+        vars: vars(r, 2),
+        extra: r.try_get(3).unwrap(),
+        updated_by: r.try_get(4).unwrap(),
+        updated_at: r.try_get(5).unwrap(),
+    }
+}
+
+const TEMPLATE_COLS: &str = "name, description, body, defaults, updated_by, updated_at";
+const LINK_COLS: &str = "cn, template, vars, extra, updated_by, updated_at";
+
+pub async fn list(pool: &Db) -> anyhow::Result<Vec<Template>> {
+    let rows = sqlx::query(&format!("SELECT {TEMPLATE_COLS} FROM ccd_templates ORDER BY name"))
+        .fetch_all(pool).await?;
+    Ok(rows.iter().map(template_from_row).collect())
+}
+
+pub async fn get(pool: &Db, name: &str) -> anyhow::Result<Option<Template>> {
+    let row = sqlx::query(&format!("SELECT {TEMPLATE_COLS} FROM ccd_templates WHERE name=?"))
+        .bind(name).fetch_optional(pool).await?;
+    Ok(row.as_ref().map(template_from_row))
+}
+
+/// Inserts or replaces; returns true if the template is new.
+pub async fn upsert(pool: &Db, name: &str, description: Option<&str>, body: &str, defaults: &Vars, by: &str) -> anyhow::Result<bool> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let existed = get(pool, name).await?.is_some();
+    sqlx::query("INSERT INTO ccd_templates(name, description, body, defaults, updated_by, updated_at) VALUES(?,?,?,?,?,?) \
+                 ON CONFLICT(name) DO UPDATE SET description=excluded.description, body=excluded.body, \
+                 defaults=excluded.defaults, updated_by=excluded.updated_by, updated_at=excluded.updated_at")
+        .bind(name).bind(description).bind(body).bind(serde_json::to_string(defaults)?).bind(by).bind(now)
+        .execute(pool).await?;
+    Ok(!existed)
+}
+
+pub async fn delete(pool: &Db, name: &str) -> anyhow::Result<bool> {
+    let res = sqlx::query("DELETE FROM ccd_templates WHERE name=?").bind(name).execute(pool).await?;
+    Ok(res.rows_affected() > 0)
+}
+
+/// Every CN generated from `template`, by CN.
+pub async fn links(pool: &Db, template: &str) -> anyhow::Result<Vec<Link>> {
+    let rows = sqlx::query(&format!("SELECT {LINK_COLS} FROM ccd_template_links WHERE template=? ORDER BY cn"))
+        .bind(template).fetch_all(pool).await?;
+    Ok(rows.iter().map(link_from_row).collect())
+}
+
+/// Number of linked CNs per template.
+pub async fn link_counts(pool: &Db) -> anyhow::Result<BTreeMap<String, i64>> {
+    let rows = sqlx::query("SELECT template, COUNT(*) FROM ccd_template_links GROUP BY template")
+        .fetch_all(pool).await?;
+    Ok(rows.iter().map(|r| (r.try_get(0).unwrap(), r.try_get(1).unwrap())).collect())
+}
+
+pub async fn link(pool: &Db, cn: &str) -> anyhow::Result<Option<Link>> {
+    let row = sqlx::query(&format!("SELECT {LINK_COLS} FROM ccd_template_links WHERE cn=?"))
+        .bind(cn).fetch_optional(pool).await?;
+    Ok(row.as_ref().map(link_from_row))
+}
+
+pub async fn set_link(pool: &Db, cn: &str, template: &str, vars: &Vars, extra: &str, by: &str) -> anyhow::Result<()> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    sqlx::query("INSERT OR REPLACE INTO ccd_template_links(cn, template, vars, extra, updated_by, updated_at) VALUES(?,?,?,?,?,?)")
+        .bind(cn).bind(template).bind(serde_json::to_string(vars)?).bind(extra).bind(by).bind(now)
+        .execute(pool).await?;
+    Ok(())
+}
+
+pub async fn delete_link(pool: &Db, cn: &str) -> anyhow::Result<bool> {
+    let res = sqlx::query("DELETE FROM ccd_template_links WHERE cn=?").bind(cn).execute(pool).await?;
+    Ok(res.rows_affected() > 0)
+}
@@ -0,0 +1,98 @@
+use sqlx::Row;
+use time::OffsetDateTime;
+
+use super::Db;
+
+pub async fn set_owner_email(pool: &Db, cn: &str, email: Option<&str>) -> anyhow::Result<()> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    sqlx::query("INSERT INTO clients(cn, owner_email, created_at, updated_at) VALUES(?,?,?,?) \
+                 ON CONFLICT(cn) DO UPDATE SET owner_email=excluded.owner_email, updated_at=excluded.updated_at")
+        .bind(cn).bind(email).bind(now).bind(now)
+        .execute(pool).await?;
+    Ok(())
+}
+
+pub async fn owner_email(pool: &Db, cn: &str) -> anyhow::Result<Option<String>> {
+    let row = sqlx::query("SELECT owner_email FROM clients WHERE cn=?")
+        .bind(cn).fetch_optional(pool).await?;
+    Ok(row.and_then(|r| r.try_get::<Option<String>, _>(0).unwrap()))
+}
+
+/// Moves `cn` into `group` (or out of any group with `None`).
+pub async fn set_group(pool: &Db, cn: &str, group: Option<&str>) -> anyhow::Result<()> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    sqlx::query("INSERT INTO clients(cn, group_name, created_at, updated_at) VALUES(?,?,?,?) \
+                 ON CONFLICT(cn) DO UPDATE SET group_name=excluded.group_name, updated_at=excluded.updated_at")
+        .bind(cn).bind(group).bind(now).bind(now)
+        .execute(pool).await?;
+    Ok(())
+}
+
+/// `None` makes the client follow its group again.
+pub async fn set_auto_renew(pool: &Db, cn: &str, enabled: Option<bool>) -> anyhow::Result<()> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    sqlx::query("INSERT INTO clients(cn, auto_renew, created_at, updated_at) VALUES(?,?,?,?) \
+                 ON CONFLICT(cn) DO UPDATE SET auto_renew=excluded.auto_renew, updated_at=excluded.updated_at")
+        .bind(cn).bind(enabled.map(i64::from)).bind(now).bind(now)
+        .execute(pool).await?;
+    Ok(())
+}
+
+pub async fn set_group_auto_renew(pool: &Db, group: &str, enabled: bool) -> anyhow::Result<()> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    sqlx::query("INSERT INTO client_groups(name, auto_renew, created_at, updated_at) VALUES(?,?,?,?) \
+                 ON CONFLICT(name) DO UPDATE SET auto_renew=excluded.auto_renew, updated_at=excluded.updated_at")
+        .bind(group).bind(i64::from(enabled)).bind(now).bind(now)
+        .execute(pool).await?;
+    Ok(())
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ClientPolicy {
+    pub cn: String,
+    pub group: Option<String>,
+    /// Set on the client itself; `None` inherits from the group.
+    pub auto_renew: Option<bool>,
+    pub effective: bool,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct GroupPolicy {
+    pub name: String,
+    pub auto_renew: bool,
+    pub members: i64,
+}
+
+pub async fn policies(pool: &Db) -> anyhow::Result<Vec<ClientPolicy>> {
+    let rows = sqlx::query("SELECT c.cn, c.group_name, c.auto_renew, COALESCE(c.auto_renew, g.auto_renew, 0) \
+                            FROM clients c LEFT JOIN client_groups g ON g.name = c.group_name ORDER BY c.cn")
+        .fetch_all(pool).await?;
+    Ok(rows.iter().map(|r| ClientPolicy {
+        cn: r.try_get(0).unwrap(),
+        group: r.try_get(1).unwrap(),
+        auto_renew: r.try_get::<Option<i64>, _>(2).unwrap().map(|v| v != 0),
+        effective: r.try_get::<i64, _>(3).unwrap() != 0,
+    }).collect())
+}
+
+pub async fn groups(pool: &Db) -> anyhow::Result<Vec<GroupPolicy>> {
+    let rows = sqlx::query("SELECT g.name, g.auto_renew, (SELECT COUNT(*) FROM clients c WHERE c.group_name = g.name) \
+                            FROM client_groups g \
+                            UNION SELECT DISTINCT c.group_name, 0, (SELECT COUNT(*) FROM clients d WHERE d.group_name = c.group_name) \
+                            FROM clients c WHERE c.group_name IS NOT NULL \
+                              AND c.group_name NOT IN (SELECT name FROM client_groups) \
+                            ORDER BY 1")
+        .fetch_all(pool).await?;
+    Ok(rows.iter().map(|r| GroupPolicy {
+        name: r.try_get(0).unwrap(),
+        auto_renew: r.try_get::<i64, _>(1).unwrap() != 0,
+        members: r.try_get(2).unwrap(),
+    }).collect())
+}
+
+/// CNs currently assigned to `group`.
+pub async fn in_group(pool: &Db, group: &str) -> anyhow::Result<Vec<String>> {
+    let rows = sqlx::query("SELECT cn FROM clients WHERE group_name=? ORDER BY cn")
+        .bind(group).fetch_all(pool).await?;
+    Ok(rows.iter().map(|r| r.try_get(0).unwrap()).collect())
+}
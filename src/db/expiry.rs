@@ -0,0 +1,20 @@
+use anyhow::Result;
+use time::OffsetDateTime;
+
+use super::Db;
+
+/// Records that `serial` crossed `threshold_days`. Returns false when that
+/// warning was already sent.
+pub async fn mark_warned(pool: &Db, serial: &str, cn: &str, threshold_days: i64) -> Result<bool> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let r = sqlx::query(
+        "INSERT OR IGNORE INTO expiry_warnings(serial, threshold_days, cn, sent_at) VALUES (?,?,?,?)"
+    )
+        .bind(serial)
+        .bind(threshold_days)
+        .bind(cn)
+        .bind(now)
+        .execute(pool)
+        .await?;
+    Ok(r.rows_affected() > 0)
+}
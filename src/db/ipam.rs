@@ -0,0 +1,47 @@
+use sqlx::Row;
+use time::OffsetDateTime;
+
+use super::Db;
+
+#[derive(Debug, Clone)]
+pub struct Allocation {
+    pub ip: String,
+    pub cn: String,
+    pub created_by: String,
+    pub created_at: i64,
+}
+
+fn from_row(r: &sqlx::sqlite::SqliteRow) -> Allocation {
+    Allocation {
+        ip: r.try_get(0).unwrap(),
+        cn: r.try_get(1).unwrap(),
+        created_by: r.try_get(2).unwrap(),
+        created_at: r.try_get(3).unwrap(),
+    }
+}
+
+pub async fn all(pool: &Db) -> anyhow::Result<Vec<Allocation>> {
+    let rows = sqlx::query("SELECT ip, cn, created_by, created_at FROM ip_allocations ORDER BY cn")
+        .fetch_all(pool).await?;
+    Ok(rows.iter().map(from_row).collect())
+}
+
+pub async fn for_cn(pool: &Db, cn: &str) -> anyhow::Result<Option<Allocation>> {
+    let row = sqlx::query("SELECT ip, cn, created_by, created_at FROM ip_allocations WHERE cn=?")
+        .bind(cn).fetch_optional(pool).await?;
+    Ok(row.as_ref().map(from_row))
+}
+
+/// Fails on the unique indexes if the address or the CN is already taken.
+pub async fn insert(pool: &Db, ip: &str, cn: &str, created_by: &str) -> anyhow::Result<()> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    sqlx::query("INSERT INTO ip_allocations(ip, cn, created_by, created_at) VALUES(?,?,?,?)")
+        .bind(ip).bind(cn).bind(created_by).bind(now)
+        .execute(pool).await?;
+    Ok(())
+}
+
+pub async fn delete(pool: &Db, cn: &str) -> anyhow::Result<bool> {
+    let res = sqlx::query("DELETE FROM ip_allocations WHERE cn=?").bind(cn).execute(pool).await?;
+    Ok(res.rows_affected() > 0)
+}
@@ -0,0 +1,46 @@
+use sqlx::Row;
+use std::collections::HashMap;
+use time::OffsetDateTime;
+
+use super::Db;
+
+pub struct NewIssued<'a> {
+    pub serial: Option<&'a str>,
+    pub cn: &'a str,
+    pub profile: &'a str,
+    pub key_type: &'a str,
+    /// `genkey`, `csr` or `renew`.
+    pub via: &'a str,
+    pub not_after: Option<&'a str>,
+    pub created_by: &'a str,
+}
+
+/// No-op when the daemon reported no serial.
+pub async fn record(pool: &Db, n: &NewIssued<'_>) -> anyhow::Result<()> {
+    let Some(serial) = n.serial else { return Ok(()) };
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    sqlx::query("INSERT OR REPLACE INTO issued_certs(serial, cn, profile, key_type, via, not_after, created_by, created_at) \
+                 VALUES(?,?,?,?,?,?,?,?)")
+        .bind(serial).bind(n.cn).bind(n.profile).bind(n.key_type).bind(n.via).bind(n.not_after)
+        .bind(n.created_by).bind(now)
+        .execute(pool).await?;
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct IssuedRecord {
+    pub key_type: String,
+    pub via: String,
+    pub created_at: i64,
+}
+
+/// Everything the panel recorded, keyed by serial.
+pub async fn all(pool: &Db) -> anyhow::Result<HashMap<String, IssuedRecord>> {
+    let rows = sqlx::query("SELECT serial, key_type, via, created_at FROM issued_certs")
+        .fetch_all(pool).await?;
+    Ok(rows.iter().map(|r| (r.try_get(0).unwrap(), IssuedRecord {
+        key_type: r.try_get(1).unwrap(),
+        via: r.try_get(2).unwrap(),
+        created_at: r.try_get(3).unwrap(),
+    })).collect())
+}
@@ -0,0 +1,223 @@
+use sqlx::Row;
+use std::collections::HashSet;
+use time::OffsetDateTime;
+use ulid::Ulid;
+
+use super::Db;
+
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub id: String,
+    pub kind: String,
+    pub status: String,
+    pub total: i64,
+    pub done: i64,
+    pub failed: i64,
+    pub params: String,
+    pub result: Option<String>,
+    pub error: Option<String>,
+    pub created_by: String,
+    pub created_at: i64,
+    pub finished_at: Option<i64>,
+    /// Serialized `AuditCtx` of the request that started the job.
+    pub ctx: Option<String>,
+    pub cancel_requested: bool,
+    pub started_at: Option<i64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct JobItem {
+    pub idx: i64,
+    pub item: String,
+    pub status: String,
+    pub detail: String,
+}
+
+const COLS: &str = "id, kind, status, total, done, failed, params, result, error, created_by, created_at, finished_at, \
+                    ctx, cancel_requested, started_at";
+
+fn job_from_row(r: &sqlx::sqlite::SqliteRow) -> Job {
+    Job {
+        id: r.try_get(0).unwrap(),
+        kind: r.try_get(1).unwrap(),
+        status: r.try_get(2).unwrap(),
+        total: r.try_get(3).unwrap(),
+        done: r.try_get(4).unwrap(),
+        failed: r.try_get(5).unwrap(),
+        params: r.try_get(6).unwrap(),
+        result: r.try_get(7).unwrap(),
+        error: r.try_get(8).unwrap(),
+        created_by: r.try_get(9).unwrap(),
+        created_at: r.try_get(10).unwrap(),
+        finished_at: r.try_get(11).unwrap(),
+        ctx: r.try_get(12).unwrap(),
+        cancel_requested: r.try_get::<i64, _>(13).unwrap() != 0,
+        started_at: r.try_get(14).unwrap(),
+    }
+}
+
+pub async fn create(pool: &Db, kind: &str, total: i64, params: &str, ctx: &str, created_by: &str) -> anyhow::Result<String> {
+    let id = Ulid::new().to_string();
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    sqlx::query("INSERT INTO jobs(id, kind, status, total, params, ctx, created_by, created_at) VALUES(?,?,'queued',?,?,?,?,?)")
+        .bind(&id).bind(kind).bind(total).bind(params).bind(ctx).bind(created_by).bind(now)
+        .execute(pool).await?;
+    Ok(id)
+}
+
+pub async fn list(pool: &Db, limit: i64) -> anyhow::Result<Vec<Job>> {
+    let rows = sqlx::query(&format!("SELECT {COLS} FROM jobs ORDER BY created_at DESC, id DESC LIMIT ?"))
+        .bind(limit).fetch_all(pool).await?;
+    Ok(rows.iter().map(job_from_row).collect())
+}
+
+/// Marks the oldest queued job (or `only`, if given and still queued) as
+/// running under `owner`'s lease until `lease_until` and returns it.
+pub async fn claim(pool: &Db, only: Option<&str>, owner: &str, lease_until: i64) -> anyhow::Result<Option<Job>> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let row = sqlx::query(&format!(
+        "UPDATE jobs SET status='running', started_at=COALESCE(started_at, ?), lease_owner=?, lease_until=? WHERE id=( \
+           SELECT id FROM jobs WHERE status='queued' AND (? IS NULL OR id=?) ORDER BY created_at, id LIMIT 1 \
+         ) AND status='queued' RETURNING {COLS}"))
+        .bind(now).bind(owner).bind(lease_until).bind(only).bind(only)
+        .fetch_optional(pool).await?;
+    Ok(row.as_ref().map(job_from_row))
+}
+
+/// Extends `owner`'s lease on a running job. `false` once the lease was lost,
+/// i.e. another process took the job over.
+pub async fn renew_lease(pool: &Db, id: &str, owner: &str, lease_until: i64) -> anyhow::Result<bool> {
+    let res = sqlx::query("UPDATE jobs SET lease_until=? WHERE id=? AND status='running' AND lease_owner=?")
+        .bind(lease_until).bind(id).bind(owner).execute(pool).await?;
+    Ok(res.rows_affected() > 0)
+}
+
+/// Puts running jobs whose lease ran out (their process died) back in the
+/// queue. Jobs without a lease predate leases and are requeued too.
+pub async fn requeue_expired(pool: &Db, now: i64) -> anyhow::Result<u64> {
+    let res = sqlx::query("UPDATE jobs SET status='queued', lease_owner=NULL, lease_until=NULL \
+                           WHERE status='running' AND (lease_until IS NULL OR lease_until < ?)")
+        .bind(now).execute(pool).await?;
+    Ok(res.rows_affected())
+}
+
+/// Jobs that ended before `cutoff`; their files are due for deletion.
+pub async fn finished_before(pool: &Db, cutoff: i64) -> anyhow::Result<Vec<String>> {
+    let rows = sqlx::query("SELECT id FROM jobs WHERE finished_at IS NOT NULL AND finished_at < ?")
+        .bind(cutoff).fetch_all(pool).await?;
+    Ok(rows.iter().map(|r| r.try_get(0).unwrap()).collect())
+}
+
+/// A queued job is cancelled at once, a running one at its next item.
+/// Returns the status afterwards, or `None` for jobs that already ended.
+pub async fn request_cancel(pool: &Db, id: &str) -> anyhow::Result<Option<String>> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let queued = sqlx::query("UPDATE jobs SET status='cancelled', cancel_requested=1, finished_at=? WHERE id=? AND status='queued'")
+        .bind(now).bind(id).execute(pool).await?;
+    if queued.rows_affected() > 0 {
+        return Ok(Some("cancelled".into()));
+    }
+    let running = sqlx::query("UPDATE jobs SET cancel_requested=1 WHERE id=? AND status='running'")
+        .bind(id).execute(pool).await?;
+    Ok((running.rows_affected() > 0).then(|| "running".into()))
+}
+
+pub async fn cancel_requested(pool: &Db, id: &str) -> anyhow::Result<bool> {
+    let row = sqlx::query("SELECT cancel_requested FROM jobs WHERE id=?").bind(id).fetch_optional(pool).await?;
+    Ok(row.is_some_and(|r| r.try_get::<i64, _>(0).unwrap() != 0))
+}
+
+/// Items that already have a result, so a resumed job can skip them.
+pub async fn done_indices(pool: &Db, id: &str) -> anyhow::Result<HashSet<i64>> {
+    indices(pool, id, "status <> 'started'").await
+}
+
+/// Items begun without a result: the process may have died halfway through.
+pub async fn started_indices(pool: &Db, id: &str) -> anyhow::Result<HashSet<i64>> {
+    indices(pool, id, "status = 'started'").await
+}
+
+async fn indices(pool: &Db, id: &str, cond: &str) -> anyhow::Result<HashSet<i64>> {
+    let rows = sqlx::query(&format!("SELECT idx FROM job_items WHERE job_id=? AND {cond}")).bind(id).fetch_all(pool).await?;
+    Ok(rows.iter().map(|r| r.try_get(0).unwrap()).collect())
+}
+
+/// Records that work on an item is about to start. Counters only move with
+/// `item_result`.
+pub async fn item_started(pool: &Db, job: &str, idx: i64, item: &str) -> anyhow::Result<()> {
+    sqlx::query("INSERT OR IGNORE INTO job_items(job_id, idx, item, status, detail) VALUES(?,?,?,'started','{}')")
+        .bind(job).bind(idx).bind(item).execute(pool).await?;
+    Ok(())
+}
+
+pub async fn get(pool: &Db, id: &str) -> anyhow::Result<Option<Job>> {
+    let row = sqlx::query(&format!("SELECT {COLS} FROM jobs WHERE id=?"))
+        .bind(id).fetch_optional(pool).await?;
+    Ok(row.as_ref().map(job_from_row))
+}
+
+/// Stores the outcome of one item and bumps the job's counters.
+pub async fn item_result(pool: &Db, job: &str, idx: i64, item: &str, status: &str, detail: &str) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("INSERT OR REPLACE INTO job_items(job_id, idx, item, status, detail) VALUES(?,?,?,?,?)")
+        .bind(job).bind(idx).bind(item).bind(status).bind(detail)
+        .execute(&mut *tx).await?;
+    sqlx::query("UPDATE jobs SET done=done+1, failed=failed+? WHERE id=?")
+        .bind(i64::from(status == "failed")).bind(job)
+        .execute(&mut *tx).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Ends a job `owner` holds the lease on; `false` if it lost the lease.
+pub async fn finish(pool: &Db, job: &str, owner: &str, status: &str, result: Option<&str>, error: Option<&str>) -> anyhow::Result<bool> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let res = sqlx::query("UPDATE jobs SET status=?, result=?, error=?, finished_at=?, lease_owner=NULL, lease_until=NULL \
+                           WHERE id=? AND lease_owner=?")
+        .bind(status).bind(result).bind(error).bind(now).bind(job).bind(owner)
+        .execute(pool).await?;
+    Ok(res.rows_affected() > 0)
+}
+
+pub async fn items(pool: &Db, job: &str) -> anyhow::Result<Vec<JobItem>> {
+    let rows = sqlx::query("SELECT idx, item, status, detail FROM job_items WHERE job_id=? ORDER BY idx")
+        .bind(job).fetch_all(pool).await?;
+    Ok(rows.iter().map(|r| JobItem {
+        idx: r.try_get(0).unwrap(),
+        item: r.try_get(1).unwrap(),
+        status: r.try_get(2).unwrap(),
+        detail: r.try_get(3).unwrap(),
+    }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn leases_and_started_items() {
+        let pool = crate::db::test_db().await;
+        let id = create(&pool, "import", 2, "{}", "{}", "alice").await.unwrap();
+        let job = claim(&pool, None, "a", 100).await.unwrap().unwrap();
+        assert_eq!(job.id, id);
+
+        // A live lease is left alone; another process cannot extend or end it.
+        assert_eq!(requeue_expired(&pool, 50).await.unwrap(), 0);
+        assert!(!renew_lease(&pool, &id, "b", 200).await.unwrap());
+        assert!(renew_lease(&pool, &id, "a", 200).await.unwrap());
+
+        item_started(&pool, &id, 0, "bob").await.unwrap();
+        item_result(&pool, &id, 0, "bob", "ok", "{}").await.unwrap();
+        item_started(&pool, &id, 1, "eve").await.unwrap();
+        assert_eq!(done_indices(&pool, &id).await.unwrap(), HashSet::from([0]));
+        assert_eq!(started_indices(&pool, &id).await.unwrap(), HashSet::from([1]));
+        assert_eq!(get(&pool, &id).await.unwrap().unwrap().done, 1);
+
+        // Once it expires the job goes back to the queue for someone else.
+        assert_eq!(requeue_expired(&pool, 201).await.unwrap(), 1);
+        assert_eq!(claim(&pool, Some(&id), "b", 300).await.unwrap().unwrap().id, id);
+        assert!(!finish(&pool, &id, "a", "done", None, None).await.unwrap());
+        assert!(finish(&pool, &id, "b", "done", None, None).await.unwrap());
+        assert_eq!(get(&pool, &id).await.unwrap().unwrap().status, "done");
+    }
+}
@@ -0,0 +1,73 @@
+use sqlx::Row;
+use time::OffsetDateTime;
+use ulid::Ulid;
+
+use super::Db;
+
+pub struct QueuedMail {
+    pub id: String,
+    pub to_addr: String,
+    pub subject: String,
+    pub body: String,
+    pub attempts: i64,
+}
+
+pub async fn enqueue(pool: &Db, template: &str, to: &str, subject: &str, body: &str) -> anyhow::Result<()> {
+    let id = Ulid::new().to_string();
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    sqlx::query("INSERT INTO mail_queue(id, template, to_addr, subject, body, next_attempt_at, created_at) VALUES(?,?,?,?,?,?,?)")
+        .bind(id).bind(template).bind(to).bind(subject).bind(body).bind(now).bind(now)
+        .execute(pool).await?;
+    Ok(())
+}
+
+pub async fn due(pool: &Db, now: i64, limit: i64) -> anyhow::Result<Vec<QueuedMail>> {
+    let rows = sqlx::query("SELECT id, to_addr, subject, body, attempts FROM mail_queue WHERE status='pending' AND next_attempt_at <= ? ORDER BY next_attempt_at LIMIT ?")
+        .bind(now).bind(limit).fetch_all(pool).await?;
+    Ok(rows.into_iter().map(|r| QueuedMail {
+        id: r.try_get(0).unwrap(),
+        to_addr: r.try_get(1).unwrap(),
+        subject: r.try_get(2).unwrap(),
+        body: r.try_get(3).unwrap(),
+        attempts: r.try_get(4).unwrap(),
+    }).collect())
+}
+
+pub async fn mark_sent(pool: &Db, id: &str) -> anyhow::Result<()> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    sqlx::query("UPDATE mail_queue SET status='sent', attempts=attempts+1, last_error=NULL, sent_at=? WHERE id=?")
+        .bind(now).bind(id).execute(pool).await?;
+    Ok(())
+}
+
+/// Records a failed attempt; `next_attempt_at = None` gives up on the message.
+pub async fn mark_failed(pool: &Db, id: &str, error: &str, next_attempt_at: Option<i64>) -> anyhow::Result<()> {
+    let (state, next) = match next_attempt_at {
+        Some(t) => ("pending", t),
+        None => ("failed", OffsetDateTime::now_utc().unix_timestamp()),
+    };
+    sqlx::query("UPDATE mail_queue SET status=?, attempts=attempts+1, last_error=?, next_attempt_at=? WHERE id=?")
+        .bind(state).bind(error).bind(next).bind(id).execute(pool).await?;
+    Ok(())
+}
+
+/// Addresses of enabled ADMIN users that have one.
+pub async fn admin_emails(pool: &Db) -> anyhow::Result<Vec<String>> {
+    let rows = sqlx::query("SELECT u.email FROM users u JOIN user_roles r ON r.user_id=u.id \
+                            WHERE r.role_name='ADMIN' AND u.disabled=0 AND u.email IS NOT NULL AND u.email<>''")
+        .fetch_all(pool).await?;
+    Ok(rows.into_iter().map(|r| r.try_get::<String, _>(0).unwrap()).collect())
+}
+
+pub async fn user_email(pool: &Db, username: &str) -> anyhow::Result<Option<String>> {
+    let row = sqlx::query("SELECT email FROM users WHERE username=?")
+        .bind(username).fetch_optional(pool).await?;
+    Ok(row.and_then(|r| r.try_get::<Option<String>, _>(0).unwrap()))
+}
+
+pub async fn set_user_email(pool: &Db, username: &str, email: Option<&str>) -> anyhow::Result<bool> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let res = sqlx::query("UPDATE users SET email=?, updated_at=? WHERE username=?")
+        .bind(email).bind(now).bind(username).execute(pool).await?;
+    Ok(res.rows_affected() > 0)
+}
@@ -1,7 +1,22 @@
-use sqlx::{sqlite::SqlitePoolOptions, Pool, Sqlite, Row};
+use sqlx::{sqlite::SqlitePoolOptions, Pool, QueryBuilder, Sqlite, Row};
 use time::OffsetDateTime;
 use ulid::Ulid;
 
+use crate::{audit::{chain, sink}, events};
+
+pub mod ccd;
+pub mod ccd_templates;
+pub mod clients;
+pub mod expiry;
+pub mod ipam;
+pub mod issued;
+pub mod jobs;
+pub mod mail;
+pub mod renewals;
+pub mod revocations;
+pub mod vpn;
+pub mod webhooks;
+
 pub type Db = Pool<Sqlite>;
 
 #[derive(Debug, Clone)]
@@ -21,6 +36,15 @@ pub async fn migrate_db(pool: &Db) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// A fresh, migrated in-memory database. One connection, since every
+/// `:memory:` connection is a database of its own.
+#[cfg(test)]
+pub async fn test_db() -> Db {
+    let pool = SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await.unwrap();
+    migrate_db(&pool).await.unwrap();
+    pool
+}
+
 pub async fn create_user(pool: &Db, username: &str, pw_hash: &str) -> anyhow::Result<String> {
     let id = Ulid::new().to_string();
     let now = OffsetDateTime::now_utc().unix_timestamp();
@@ -64,6 +88,8 @@ pub async fn create_session(pool: &Db, user_id: &str, ttl_secs: i64) -> anyhow::
     Ok(id)
 }
 
+// step-up re-authentication is not wired into any handler yet
+#[allow(dead_code)]
 pub struct SessionRecord { pub user_id: String, pub expires_at: i64, pub last_stepup: i64 }
 
 pub async fn load_session(pool: &Db, sid: &str) -> anyhow::Result<Option<SessionRecord>> {
@@ -76,6 +102,7 @@ pub async fn load_session(pool: &Db, sid: &str) -> anyhow::Result<Option<Session
     }))
 }
 
+#[allow(dead_code)]
 pub async fn touch_stepup(pool: &Db, sid: &str) -> anyhow::Result<()> {
     let now = OffsetDateTime::now_utc().unix_timestamp();
     sqlx::query("UPDATE sessions SET last_auth_stepup=? WHERE id=?")
@@ -108,37 +135,507 @@ pub async fn login_counts(pool: &Db, username: &str, ip: &str, window_secs: i64)
 
 
 
-pub async fn audit_record(pool: &Db, actor_user: &str, action: &str, target: &str, ip: &str, ua: &str, details_json: &str) -> anyhow::Result<()> {
+pub struct NewAudit<'a> {
+    pub actor_user: &'a str,
+    pub action: &'a str,
+    pub target: &'a str,
+    pub ip: &'a str,
+    pub ua: &'a str,
+    pub request_id: &'a str,
+    pub details: &'a str,
+}
+
+// Serialises chain appends within this process; across processes (the CLI
+// next to the server) `begin_immediate` takes SQLite's write lock before the
+// head is read.
+static AUDIT_APPEND: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+const AUDIT_APPEND_ATTEMPTS: u32 = 5;
+
+/// The last link, live or pruned: once the newest rows have been pruned the
+/// chain continues from their tombstones.
+async fn audit_head(conn: &mut sqlx::SqliteConnection) -> anyhow::Result<(i64, String)> {
+    let row = sqlx::query("SELECT seq, hash FROM (SELECT seq, hash FROM audit WHERE seq IS NOT NULL \
+                           UNION ALL SELECT seq, hash FROM audit_tombstones) ORDER BY seq DESC LIMIT 1")
+        .fetch_optional(&mut *conn).await?;
+    Ok(match row {
+        Some(r) => (r.try_get(0)?, r.try_get(1)?),
+        None => (0, chain::GENESIS.to_string()),
+    })
+}
+
+/// A lost race on the `seq` index, or the lock still held by the other process.
+fn append_conflict(e: &anyhow::Error) -> bool {
+    match e.downcast_ref::<sqlx::Error>() {
+        Some(sqlx::Error::Database(d)) => d.is_unique_violation() || d.message().contains("database is locked"),
+        _ => false,
+    }
+}
+
+/// A transaction that holds SQLite's write lock from its first statement, as
+/// `BEGIN IMMEDIATE` would, so reads in it cannot go stale before the write.
+/// Unlike a raw `BEGIN` it is rolled back if dropped before `commit`, e.g.
+/// when the request awaiting it goes away.
+pub async fn begin_immediate(pool: &Db) -> anyhow::Result<sqlx::Transaction<'static, Sqlite>> {
+    let mut tx = pool.begin().await?;
+    // Any write statement takes the lock, even one that matches no rows.
+    sqlx::query("DELETE FROM audit WHERE 0").execute(&mut *tx).await?;
+    Ok(tx)
+}
+
+/// Reads the head and appends one row inside a single write transaction.
+async fn audit_append(pool: &Db, id: &str, ts: i64, ev: &NewAudit<'_>) -> anyhow::Result<(i64, String)> {
+    let mut tx = begin_immediate(pool).await?;
+    let (head_seq, prev_hash) = audit_head(&mut tx).await?;
+    let seq = head_seq + 1;
+    let hash = chain::hash(&chain::Link {
+        seq, id, ts, actor_user: ev.actor_user, action: ev.action, target: ev.target,
+        ip: ev.ip, ua: ev.ua, request_id: ev.request_id, details: ev.details, prev_hash: &prev_hash,
+    });
+    sqlx::query("INSERT INTO audit(id, ts, actor_user, action, target, ip, ua, request_id, details, seq, prev_hash, hash) VALUES(?,?,?,?,?,?,?,?,?,?,?,?)")
+        .bind(id).bind(ts).bind(ev.actor_user).bind(ev.action).bind(ev.target).bind(ev.ip).bind(ev.ua)
+        .bind(ev.request_id).bind(ev.details).bind(seq).bind(&prev_hash).bind(&hash)
+        .execute(&mut *tx).await?;
+    tx.commit().await?;
+    Ok((seq, hash))
+}
+
+/// Appends to the chain, retrying when another process appended first. The
+/// error is returned once the attempts are used up; the row is never dropped
+/// silently. Once committed the row goes to the sinks and `/api/events`.
+pub async fn audit_insert(pool: &Db, ev: &NewAudit<'_>) -> anyhow::Result<()> {
     let id = Ulid::new().to_string();
     let ts = OffsetDateTime::now_utc().unix_timestamp();
-    sqlx::query("INSERT INTO audit(id, ts, actor_user, action, target, ip, ua, details) VALUES(?,?,?,?,?,?,?,?)")
-        .bind(id).bind(ts).bind(actor_user).bind(action).bind(target).bind(ip).bind(ua).bind(details_json)
-        .execute(pool).await?;
+
+    let _guard = AUDIT_APPEND.lock().await;
+    let mut attempt = 1;
+    let (seq, hash) = loop {
+        match audit_append(pool, &id, ts, ev).await {
+            Ok(v) => break v,
+            Err(e) if attempt < AUDIT_APPEND_ATTEMPTS && append_conflict(&e) => {
+                tracing::warn!("audit append conflict (attempt {}): {}", attempt, e);
+                tokio::time::sleep(std::time::Duration::from_millis(25 * u64::from(attempt))).await;
+                attempt += 1;
+            }
+            Err(e) => {
+                // Most callers cannot undo what they audited, so at least make the loss loud.
+                tracing::error!(action=%ev.action, target=%ev.target, "audit row not written: {:#}", e);
+                return Err(e.context(format!("audit append {} {}", ev.action, ev.target)));
+            }
+        }
+    };
+
+    sink::publish(sink::SinkEvent {
+        id: id.clone(), seq, ts,
+        actor_user: ev.actor_user.to_string(),
+        action: ev.action.to_string(),
+        target: ev.target.to_string(),
+        ip: ev.ip.to_string(),
+        ua: ev.ua.to_string(),
+        request_id: ev.request_id.to_string(),
+        details: ev.details.to_string(),
+        hash,
+    });
+    events::publish("audit", serde_json::json!({
+        "id": id, "seq": seq, "ts": ts, "actor_user": ev.actor_user, "action": ev.action, "target": ev.target,
+        "request_id": ev.request_id,
+        "details": serde_json::from_str::<serde_json::Value>(ev.details).unwrap_or_else(|_| ev.details.into()),
+    }));
     Ok(())
 }
 
+/// Chains rows written before hashing existed, in insertion order. Only runs
+/// while the chain is empty; later unsealed rows are reported by verification.
+pub async fn audit_seal_legacy(pool: &Db) -> anyhow::Result<u64> {
+    let _guard = AUDIT_APPEND.lock().await;
+    let mut tx = begin_immediate(pool).await?;
+    let (mut seq, mut prev_hash) = audit_head(&mut tx).await?;
+    if seq != 0 {
+        return Ok(0);
+    }
+    let rows = sqlx::query("SELECT id, ts, actor_user, action, target, ip, ua, request_id, details FROM audit WHERE seq IS NULL ORDER BY rowid")
+        .fetch_all(&mut *tx).await?;
+    for r in rows.iter().map(audit_row) {
+        seq += 1;
+        let hash = chain::hash(&chain::Link {
+            seq, id: &r.id, ts: r.ts, actor_user: &r.actor_user, action: &r.action, target: &r.target,
+            ip: &r.ip, ua: &r.ua, request_id: &r.request_id, details: &r.details, prev_hash: &prev_hash,
+        });
+        sqlx::query("UPDATE audit SET seq=?, prev_hash=?, hash=? WHERE id=?")
+            .bind(seq).bind(&prev_hash).bind(&hash).bind(&r.id)
+            .execute(&mut *tx).await?;
+        prev_hash = hash;
+    }
+    tx.commit().await?;
+    Ok(seq as u64)
+}
+
+pub async fn audit_unsealed_count(pool: &Db) -> anyhow::Result<i64> {
+    Ok(sqlx::query("SELECT COUNT(*) FROM audit WHERE seq IS NULL OR hash IS NULL OR prev_hash IS NULL")
+        .fetch_one(pool).await?.try_get(0)?)
+}
+
+pub struct ChainRow {
+    pub seq: i64,
+    pub prev_hash: String,
+    pub hash: String,
+    pub row: AuditRow,
+}
+
+pub async fn audit_chain_page(pool: &Db, after_seq: i64, limit: i64) -> anyhow::Result<Vec<ChainRow>> {
+    let rows = sqlx::query("SELECT id, ts, actor_user, action, target, ip, ua, request_id, details, seq, prev_hash, hash FROM audit WHERE seq > ? AND hash IS NOT NULL AND prev_hash IS NOT NULL ORDER BY seq LIMIT ?")
+        .bind(after_seq).bind(limit).fetch_all(pool).await?;
+    Ok(rows.iter().map(|r| ChainRow {
+        seq: r.try_get(9).unwrap(),
+        prev_hash: r.try_get(10).unwrap(),
+        hash: r.try_get(11).unwrap(),
+        row: audit_row(r),
+    }).collect())
+}
+
+
 #[derive(Debug, Clone)]
 pub struct AuditRow {
+    pub id: String,
     pub ts: i64,
     pub actor_user: String,
     pub action: String,
     pub target: String,
     pub ip: String,
     pub ua: String,
+    pub request_id: String,
     pub details: String,
 }
 
-pub async fn audit_list(pool: &Db, limit: i64, offset: i64) -> anyhow::Result<Vec<AuditRow>> {
+#[derive(Debug, Clone, Default)]
+pub struct AuditFilter {
+    pub actor: Option<String>,
+    pub actions: Vec<String>,
+    pub target_prefix: Option<String>,
+    pub ip: Option<String>,
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    pub text: Option<String>,
+}
+
+/// Escapes `s` for a `LIKE ... ESCAPE '\\'` pattern.
+fn like_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+/// `GLOB` pattern for values starting with `prefix`; unlike `LIKE` it is
+/// case-sensitive and can use an index.
+fn glob_prefix(prefix: &str) -> String {
+    let mut p = String::with_capacity(prefix.len() + 1);
+    for c in prefix.chars() {
+        match c {
+            '*' | '?' | '[' => { p.push('['); p.push(c); p.push(']'); }
+            _ => p.push(c),
+        }
+    }
+    p.push('*');
+    p
+}
+
+fn push_audit_filter(qb: &mut QueryBuilder<'_, Sqlite>, f: &AuditFilter) {
+    qb.push(" WHERE 1=1");
+    if let Some(actor) = &f.actor {
+        qb.push(" AND actor_user = ").push_bind(actor.clone());
+    }
+    if !f.actions.is_empty() {
+        qb.push(" AND action IN (");
+        let mut sep = qb.separated(", ");
+        for a in &f.actions { sep.push_bind(a.clone()); }
+        qb.push(")");
+    }
+    if let Some(prefix) = &f.target_prefix {
+        qb.push(" AND target GLOB ").push_bind(glob_prefix(prefix));
+    }
+    if let Some(ip) = &f.ip {
+        qb.push(" AND ip = ").push_bind(ip.clone());
+    }
+    if let Some(since) = f.since {
+        qb.push(" AND ts >= ").push_bind(since);
+    }
+    if let Some(until) = f.until {
+        qb.push(" AND ts < ").push_bind(until);
+    }
+    if let Some(text) = &f.text {
+        qb.push(" AND details LIKE ").push_bind(format!("%{}%", like_escape(text))).push(" ESCAPE '\\'");
+    }
+}
+
+fn audit_row(r: &sqlx::sqlite::SqliteRow) -> AuditRow {
+    AuditRow {
+        id: r.try_get(0).unwrap(),
+        ts: r.try_get(1).unwrap(),
+        actor_user: r.try_get(2).unwrap(),
+        action: r.try_get(3).unwrap(),
+        target: r.try_get(4).unwrap(),
+        ip: r.try_get(5).unwrap(),
+        ua: r.try_get(6).unwrap(),
+        request_id: r.try_get(7).unwrap(),
+        details: r.try_get(8).unwrap(),
+    }
+}
+
+pub async fn audit_count(pool: &Db, f: &AuditFilter) -> anyhow::Result<i64> {
+    let mut qb = QueryBuilder::new("SELECT COUNT(*) FROM audit");
+    push_audit_filter(&mut qb, f);
+    Ok(qb.build().fetch_one(pool).await?.try_get(0)?)
+}
+
+/// Newest-first page of rows matching `f`, strictly older than the `(ts, id)` cursor `after`.
+pub async fn audit_search(pool: &Db, f: &AuditFilter, after: Option<(i64, String)>, limit: i64) -> anyhow::Result<Vec<AuditRow>> {
     let limit = limit.clamp(1, 200);
-    let rows = sqlx::query("SELECT ts, actor_user, action, target, ip, ua, details FROM audit ORDER BY ts DESC LIMIT ? OFFSET ?")
-        .bind(limit).bind(offset).fetch_all(pool).await?;
-    Ok(rows.into_iter().map(|r| AuditRow {
-        ts: r.try_get(0).unwrap(),
-        actor_user: r.try_get(1).unwrap(),
-        action: r.try_get(2).unwrap(),
-        target: r.try_get(3).unwrap(),
-        ip: r.try_get(4).unwrap(),
-        ua: r.try_get(5).unwrap(),
-        details: r.try_get(6).unwrap(),
+    let mut qb = QueryBuilder::new("SELECT id, ts, actor_user, action, target, ip, ua, request_id, details FROM audit");
+    push_audit_filter(&mut qb, f);
+    if let Some((ts, id)) = after {
+        qb.push(" AND (ts < ").push_bind(ts)
+            .push(" OR (ts = ").push_bind(ts).push(" AND id < ").push_bind(id).push("))");
+    }
+    qb.push(" ORDER BY ts DESC, id DESC LIMIT ").push_bind(limit);
+    let rows = qb.build().fetch_all(pool).await?;
+    Ok(rows.iter().map(audit_row).collect())
+}
+
+/// Rows with `action` starting with `prefix` (case-sensitive) and `ts < before`, unless the
+/// action also starts with one of the longer prefixes in `except`.
+#[derive(Debug, Clone)]
+pub struct PruneRule {
+    pub prefix: String,
+    pub before: i64,
+    pub except: Vec<String>,
+}
+
+pub struct PrunableAudit {
+    pub seq: Option<i64>,
+    pub prev_hash: Option<String>,
+    pub hash: Option<String>,
+    pub row: AuditRow,
+}
+
+fn push_prune_rules(qb: &mut QueryBuilder<'_, Sqlite>, rules: &[PruneRule]) {
+    qb.push(" WHERE 1=0");
+    for r in rules {
+        qb.push(" OR (action GLOB ").push_bind(glob_prefix(&r.prefix)).push(" AND ts < ").push_bind(r.before);
+        for e in &r.except {
+            qb.push(" AND action NOT GLOB ").push_bind(glob_prefix(e));
+        }
+        qb.push(")");
+    }
+}
+
+pub async fn audit_prunable_count(pool: &Db, rules: &[PruneRule]) -> anyhow::Result<i64> {
+    let mut qb = QueryBuilder::new("SELECT COUNT(*) FROM audit");
+    push_prune_rules(&mut qb, rules);
+    Ok(qb.build().fetch_one(pool).await?.try_get(0)?)
+}
+
+pub async fn audit_prunable(pool: &Db, rules: &[PruneRule], limit: i64) -> anyhow::Result<Vec<PrunableAudit>> {
+    let mut qb = QueryBuilder::new("SELECT id, ts, actor_user, action, target, ip, ua, request_id, details, seq, prev_hash, hash FROM audit");
+    push_prune_rules(&mut qb, rules);
+    qb.push(" ORDER BY seq LIMIT ").push_bind(limit);
+    let rows = qb.build().fetch_all(pool).await?;
+    Ok(rows.iter().map(|r| PrunableAudit {
+        seq: r.try_get(9).unwrap(),
+        prev_hash: r.try_get(10).unwrap(),
+        hash: r.try_get(11).unwrap(),
+        row: audit_row(r),
+    }).collect())
+}
+
+/// Deletes archived rows, leaving a tombstone for each one that was part of the chain.
+pub async fn audit_prune(pool: &Db, rows: &[PrunableAudit], archived_to: &str) -> anyhow::Result<()> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let mut tx = pool.begin().await?;
+    for r in rows {
+        if let (Some(seq), Some(prev_hash), Some(hash)) = (r.seq, &r.prev_hash, &r.hash) {
+            let sig = chain::sign_tombstone(&chain::Tombstone {
+                seq, id: &r.row.id, prev_hash, hash, archived_to, pruned_at: now,
+            });
+            sqlx::query("INSERT INTO audit_tombstones(seq, id, prev_hash, hash, archived_to, pruned_at, sig) VALUES(?,?,?,?,?,?,?)")
+                .bind(seq).bind(&r.row.id).bind(prev_hash).bind(hash).bind(archived_to).bind(now).bind(&sig)
+                .execute(&mut *tx).await?;
+        }
+        sqlx::query("DELETE FROM audit WHERE id=?").bind(&r.row.id).execute(&mut *tx).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+pub struct Tombstone {
+    pub seq: i64,
+    pub id: String,
+    pub prev_hash: String,
+    pub hash: String,
+    pub archived_to: String,
+    pub pruned_at: i64,
+    /// `None` for tombstones written before they were signed.
+    pub sig: Option<String>,
+}
+
+/// Tombstones with `from <= seq < to` (`to = None` for no upper bound), in chain order.
+pub async fn audit_tombstones(pool: &Db, from: i64, to: Option<i64>) -> anyhow::Result<Vec<Tombstone>> {
+    let rows = sqlx::query("SELECT seq, id, prev_hash, hash, archived_to, pruned_at, sig FROM audit_tombstones WHERE seq >= ? AND seq < ? ORDER BY seq")
+        .bind(from).bind(to.unwrap_or(i64::MAX)).fetch_all(pool).await?;
+    Ok(rows.into_iter().map(|r| Tombstone {
+        seq: r.try_get(0).unwrap(),
+        id: r.try_get(1).unwrap(),
+        prev_hash: r.try_get(2).unwrap(),
+        hash: r.try_get(3).unwrap(),
+        archived_to: r.try_get(4).unwrap(),
+        pruned_at: r.try_get(5).unwrap(),
+        sig: r.try_get(6).unwrap(),
+    }).collect())
+}
+
+pub struct LoginAttempt {
+    pub rowid: i64,
+    pub username: String,
+    pub ts: i64,
+    pub ip: String,
+}
+
+pub async fn login_attempts_count_before(pool: &Db, before: i64) -> anyhow::Result<i64> {
+    Ok(sqlx::query("SELECT COUNT(*) FROM login_attempts WHERE ts < ?")
+        .bind(before).fetch_one(pool).await?.try_get(0)?)
+}
+
+pub async fn login_attempts_before(pool: &Db, before: i64, limit: i64) -> anyhow::Result<Vec<LoginAttempt>> {
+    let rows = sqlx::query("SELECT rowid, username, ts, ip FROM login_attempts WHERE ts < ? ORDER BY ts LIMIT ?")
+        .bind(before).bind(limit).fetch_all(pool).await?;
+    Ok(rows.into_iter().map(|r| LoginAttempt {
+        rowid: r.try_get(0).unwrap(),
+        username: r.try_get(1).unwrap(),
+        ts: r.try_get(2).unwrap(),
+        ip: r.try_get(3).unwrap(),
     }).collect())
 }
+
+pub async fn login_attempts_delete(pool: &Db, rowids: &[i64]) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+    for id in rowids {
+        sqlx::query("DELETE FROM login_attempts WHERE rowid=?").bind(id).execute(&mut *tx).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn log(pool: &Db, rows: &[(i64, &str, &str, &str, &str)]) {
+        for (ts, actor_user, action, target, details) in rows {
+            audit_insert(pool, &NewAudit { actor_user, action, target, ip: "10.0.0.1", ua: "", request_id: "", details })
+                .await.unwrap();
+            sqlx::query("UPDATE audit SET ts=? WHERE seq=(SELECT MAX(seq) FROM audit)").bind(ts).execute(pool).await.unwrap();
+        }
+    }
+
+    async fn targets(pool: &Db, f: &AuditFilter) -> Vec<String> {
+        audit_search(pool, f, None, 200).await.unwrap().into_iter().map(|r| r.target).collect()
+    }
+
+    #[tokio::test]
+    async fn appended_rows_are_announced() {
+        let pool = test_db().await;
+        let mut rx = events::subscribe();
+        let target = Ulid::new().to_string();
+        audit_insert(&pool, &NewAudit {
+            actor_user: "alice", action: "LOGOUT", target: &target, ip: "", ua: "", request_id: "r1", details: r#"{"a":1}"#,
+        }).await.unwrap();
+        // The bus is shared with other tests; skip their events.
+        let ev = loop {
+            let ev = rx.recv().await.unwrap();
+            if ev.data["target"] == target.as_str() { break ev; }
+        };
+        assert_eq!(ev.kind, "audit");
+        assert_eq!((ev.data["action"].as_str(), ev.data["seq"].as_i64()), (Some("LOGOUT"), Some(1)));
+        assert_eq!(ev.data["details"], serde_json::json!({ "a": 1 }));
+    }
+
+    #[test]
+    fn like_and_glob_escaping() {
+        assert_eq!(like_escape(r"50%_off\x"), r"50\%\_off\\x");
+        assert_eq!(like_escape("plain"), "plain");
+        assert_eq!(glob_prefix("a*b?c[d]"), "a[*]b[?]c[[]d]*");
+        assert_eq!(glob_prefix(""), "*");
+    }
+
+    #[tokio::test]
+    async fn target_prefix_is_exact() {
+        let pool = test_db().await;
+        log(&pool, &[
+            (1, "alice", "CLIENT_CREATE", "bob", "{}"),
+            (2, "alice", "CLIENT_CREATE", "Bobby", "{}"),
+            (3, "alice", "CLIENT_CREATE", "bob_2", "{}"),
+            (4, "alice", "CLIENT_CREATE", "bobx2", "{}"),
+            (5, "alice", "CLIENT_CREATE", "b*ob", "{}"),
+        ]).await;
+        let by = |p: &str| AuditFilter { target_prefix: Some(p.into()), ..Default::default() };
+        assert_eq!(targets(&pool, &by("bob")).await, ["bobx2", "bob_2", "bob"]);
+        assert_eq!(targets(&pool, &by("bob_")).await, ["bob_2"]);
+        assert_eq!(targets(&pool, &by("b*")).await, ["b*ob"]);
+        assert_eq!(targets(&pool, &by("Bob")).await, ["Bobby"]);
+
+        let plan: Vec<String> = sqlx::query("EXPLAIN QUERY PLAN SELECT id FROM audit WHERE target GLOB ?").bind(glob_prefix("bob"))
+            .fetch_all(&pool).await.unwrap().iter().map(|r| r.try_get::<String, _>(3).unwrap()).collect();
+        assert!(plan.iter().any(|d| d.contains("idx_audit_target")), "{plan:?}");
+    }
+
+    #[tokio::test]
+    async fn filters_combine() {
+        let pool = test_db().await;
+        log(&pool, &[
+            (10, "alice", "CLIENT_CREATE", "bob", r#"{"profile":"client"}"#),
+            (20, "alice", "CLIENT_REVOKE", "bob", r#"{"reason":"50%_off"}"#),
+            (30, "carol", "CLIENT_REVOKE", "bob", r#"{"reason":"keyCompromise"}"#),
+            (40, "alice", "CLIENT_REVOKE", "dave", r#"{"reason":"keyCompromise"}"#),
+            (50, "alice", "CLIENT_REVOKE", "bob", r#"{"reason":"superseded"}"#),
+        ]).await;
+        let f = AuditFilter {
+            actor: Some("alice".into()),
+            actions: vec!["CLIENT_REVOKE".into(), "CCD_WRITE".into()],
+            target_prefix: Some("bo".into()),
+            since: Some(20),
+            until: Some(50),
+            ..Default::default()
+        };
+        let ts = |rows: Vec<AuditRow>| rows.into_iter().map(|r| r.ts).collect::<Vec<_>>();
+        assert_eq!(ts(audit_search(&pool, &f, None, 200).await.unwrap()), [20]);
+        assert_eq!(audit_count(&pool, &f).await.unwrap(), 1);
+
+        // `%` and `_` in the text are literal; case is not.
+        let text = |q: &str| AuditFilter { text: Some(q.into()), ..Default::default() };
+        assert_eq!(ts(audit_search(&pool, &text("50%_off"), None, 200).await.unwrap()), [20]);
+        assert!(audit_search(&pool, &text("50%off"), None, 200).await.unwrap().is_empty());
+        assert_eq!(audit_count(&pool, &text("keycompromise")).await.unwrap(), 2);
+
+        let ip = AuditFilter { ip: Some("10.0.0.2".into()), ..Default::default() };
+        assert_eq!(audit_count(&pool, &ip).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn cursor_pages_are_stable() {
+        let pool = test_db().await;
+        // Three rows share each timestamp, so the id breaks ties.
+        let rows: Vec<(i64, &str, &str, &str, &str)> = (0..12).map(|i| (100 + i / 3, "alice", "CLIENT_CREATE", "bob", "{}")).collect();
+        log(&pool, &rows).await;
+        let all = audit_search(&pool, &AuditFilter::default(), None, 200).await.unwrap();
+        let f = AuditFilter::default();
+
+        let mut seen = Vec::new();
+        let mut after = None;
+        loop {
+            let page = audit_search(&pool, &f, after.clone(), 5).await.unwrap();
+            // Rows written while paging are newer than the cursor and never shift a page.
+            log(&pool, &[(999, "alice", "LOGIN_OK", "alice", "{}")]).await;
+            seen.extend(page.iter().map(|r| r.id.clone()));
+            if page.len() < 5 { break; }
+            after = page.last().map(|r| (r.ts, r.id.clone()));
+        }
+        assert_eq!(seen, all.iter().map(|r| r.id.clone()).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,154 @@
+use sqlx::Row;
+use time::OffsetDateTime;
+
+use super::Db;
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Renewal {
+    pub id: String,
+    pub cn: String,
+    pub old_serial: String,
+    pub new_serial: Option<String>,
+    pub not_after: Option<String>,
+    #[serde(skip)]
+    pub bundle_path: String,
+    pub created_at: i64,
+    /// When the download link stops working.
+    pub expires_at: i64,
+    /// When the link was used; it works only once.
+    pub consumed_at: Option<i64>,
+    pub old_revoked_at: Option<i64>,
+}
+
+const COLS: &str = "id, cn, old_serial, new_serial, not_after, bundle_path, created_at, expires_at, consumed_at, old_revoked_at";
+
+fn renewal_row(r: &sqlx::sqlite::SqliteRow) -> Renewal {
+    Renewal {
+        id: r.try_get(0).unwrap(),
+        cn: r.try_get(1).unwrap(),
+        old_serial: r.try_get(2).unwrap(),
+        new_serial: r.try_get(3).unwrap(),
+        not_after: r.try_get(4).unwrap(),
+        bundle_path: r.try_get(5).unwrap(),
+        created_at: r.try_get(6).unwrap(),
+        expires_at: r.try_get(7).unwrap(),
+        consumed_at: r.try_get(8).unwrap(),
+        old_revoked_at: r.try_get(9).unwrap(),
+    }
+}
+
+pub struct NewRenewal<'a> {
+    pub id: &'a str,
+    pub cn: &'a str,
+    pub old_serial: &'a str,
+    pub new_serial: Option<&'a str>,
+    pub not_after: Option<&'a str>,
+    pub token_hash: &'a str,
+    pub bundle_path: &'a str,
+    pub expires_at: i64,
+    pub passphrase_sealed: &'a str,
+}
+
+pub async fn insert(pool: &Db, r: &NewRenewal<'_>) -> anyhow::Result<()> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    sqlx::query("INSERT INTO renewals(id, cn, old_serial, new_serial, not_after, token_hash, bundle_path, created_at, expires_at, passphrase_sealed) \
+                 VALUES(?,?,?,?,?,?,?,?,?,?)")
+        .bind(r.id).bind(r.cn).bind(r.old_serial).bind(r.new_serial).bind(r.not_after)
+        .bind(r.token_hash).bind(r.bundle_path).bind(now).bind(r.expires_at).bind(r.passphrase_sealed)
+        .execute(pool).await?;
+    Ok(())
+}
+
+pub async fn exists_for_old(pool: &Db, old_serial: &str) -> anyhow::Result<bool> {
+    let row = sqlx::query("SELECT 1 FROM renewals WHERE old_serial=?")
+        .bind(old_serial).fetch_optional(pool).await?;
+    Ok(row.is_some())
+}
+
+/// Uses up the link behind `token_hash` if it is unused and not expired. The
+/// check and the update are one statement, so concurrent requests cannot both win.
+pub async fn consume(pool: &Db, token_hash: &str, now: i64) -> anyhow::Result<Option<Renewal>> {
+    let row = sqlx::query(&format!("UPDATE renewals SET consumed_at=? \
+                                    WHERE token_hash=? AND consumed_at IS NULL AND expires_at > ? RETURNING {COLS}"))
+        .bind(now).bind(token_hash).bind(now).fetch_optional(pool).await?;
+    Ok(row.as_ref().map(renewal_row))
+}
+
+pub async fn get(pool: &Db, id: &str) -> anyhow::Result<Option<Renewal>> {
+    let row = sqlx::query(&format!("SELECT {COLS} FROM renewals WHERE id=?"))
+        .bind(id).fetch_optional(pool).await?;
+    Ok(row.as_ref().map(renewal_row))
+}
+
+pub async fn passphrase_sealed(pool: &Db, id: &str) -> anyhow::Result<Option<String>> {
+    let row = sqlx::query("SELECT passphrase_sealed FROM renewals WHERE id=?")
+        .bind(id).fetch_optional(pool).await?;
+    Ok(row.and_then(|r| r.try_get(0).unwrap()))
+}
+
+/// Bundles whose link was used or has expired but are still on disk.
+pub async fn stale_bundles(pool: &Db, now: i64) -> anyhow::Result<Vec<Renewal>> {
+    let rows = sqlx::query(&format!("SELECT {COLS} FROM renewals WHERE bundle_path<>'' AND (consumed_at IS NOT NULL OR expires_at <= ?) ORDER BY id"))
+        .bind(now).fetch_all(pool).await?;
+    Ok(rows.iter().map(renewal_row).collect())
+}
+
+pub async fn clear_bundle(pool: &Db, id: &str) -> anyhow::Result<()> {
+    sqlx::query("UPDATE renewals SET bundle_path='' WHERE id=?").bind(id).execute(pool).await?;
+    Ok(())
+}
+
+/// The renewal that issued `new_serial` while its old cert is still valid.
+pub async fn awaiting_first_connect(pool: &Db, new_serial: &str) -> anyhow::Result<Option<Renewal>> {
+    let row = sqlx::query(&format!("SELECT {COLS} FROM renewals WHERE new_serial=? AND old_revoked_at IS NULL"))
+        .bind(new_serial).fetch_optional(pool).await?;
+    Ok(row.as_ref().map(renewal_row))
+}
+
+pub async fn list(pool: &Db, limit: i64) -> anyhow::Result<Vec<Renewal>> {
+    let rows = sqlx::query(&format!("SELECT {COLS} FROM renewals ORDER BY created_at DESC LIMIT ?"))
+        .bind(limit.clamp(1, 500)).fetch_all(pool).await?;
+    Ok(rows.iter().map(renewal_row).collect())
+}
+
+pub async fn mark_old_revoked(pool: &Db, old_serial: &str) -> anyhow::Result<()> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    sqlx::query("UPDATE renewals SET old_revoked_at=COALESCE(old_revoked_at, ?) WHERE old_serial=?")
+        .bind(now).bind(old_serial).execute(pool).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn renewal<'a>(id: &'a str, old_serial: &'a str, token_hash: &'a str, expires_at: i64) -> NewRenewal<'a> {
+        NewRenewal {
+            id, cn: "bob", old_serial, new_serial: Some("02"), not_after: None, token_hash,
+            bundle_path: "var/renewals/x.sealed", expires_at, passphrase_sealed: "",
+        }
+    }
+
+    #[tokio::test]
+    async fn links_are_single_use_and_expire() {
+        let pool = crate::db::test_db().await;
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        insert(&pool, &renewal("R1", "01", "h1", now + 3600)).await.unwrap();
+        insert(&pool, &renewal("R2", "03", "h2", now - 1)).await.unwrap();
+
+        assert!(consume(&pool, "nope", now).await.unwrap().is_none());
+        let (a, b) = tokio::join!(consume(&pool, "h1", now), consume(&pool, "h1", now));
+        let won: Vec<Renewal> = [a.unwrap(), b.unwrap()].into_iter().flatten().collect();
+        assert_eq!(won.len(), 1);
+        assert_eq!(won[0].id, "R1");
+        assert_eq!(won[0].consumed_at, Some(now));
+        assert!(consume(&pool, "h1", now).await.unwrap().is_none());
+
+        assert!(consume(&pool, "h2", now).await.unwrap().is_none(), "expired link");
+
+        let stale: Vec<String> = stale_bundles(&pool, now).await.unwrap().into_iter().map(|r| r.id).collect();
+        assert_eq!(stale, ["R1", "R2"]);
+        clear_bundle(&pool, "R1").await.unwrap();
+        assert_eq!(stale_bundles(&pool, now).await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,62 @@
+use sqlx::Row;
+use time::OffsetDateTime;
+
+use super::Db;
+
+#[derive(Debug, Clone)]
+pub struct Scheduled {
+    pub serial: String,
+    pub cn: String,
+    pub reason: String,
+    pub revoke_at: i64,
+    pub created_by: String,
+    pub attempts: i64,
+}
+
+/// Schedules `serial` for revocation at `revoke_at`; rescheduling a pending
+/// one moves its deadline.
+pub async fn schedule(pool: &Db, serial: &str, cn: &str, reason: &str, revoke_at: i64, created_by: &str) -> anyhow::Result<()> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    sqlx::query("INSERT INTO scheduled_revocations(serial, cn, reason, revoke_at, created_by, created_at) VALUES(?,?,?,?,?,?) \
+                 ON CONFLICT(serial) DO UPDATE SET revoke_at=excluded.revoke_at, reason=excluded.reason \
+                 WHERE scheduled_revocations.status='pending'")
+        .bind(serial).bind(cn).bind(reason).bind(revoke_at).bind(created_by).bind(now)
+        .execute(pool).await?;
+    Ok(())
+}
+
+pub async fn due(pool: &Db, now: i64, limit: i64) -> anyhow::Result<Vec<Scheduled>> {
+    let rows = sqlx::query("SELECT serial, cn, reason, revoke_at, created_by, attempts FROM scheduled_revocations \
+                            WHERE status='pending' AND revoke_at <= ? ORDER BY revoke_at LIMIT ?")
+        .bind(now).bind(limit)
+        .fetch_all(pool).await?;
+    Ok(rows.iter().map(|r| Scheduled {
+        serial: r.try_get(0).unwrap(),
+        cn: r.try_get(1).unwrap(),
+        reason: r.try_get(2).unwrap(),
+        revoke_at: r.try_get(3).unwrap(),
+        created_by: r.try_get(4).unwrap(),
+        attempts: r.try_get(5).unwrap(),
+    }).collect())
+}
+
+pub async fn mark_done(pool: &Db, serial: &str) -> anyhow::Result<()> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    sqlx::query("UPDATE scheduled_revocations SET status='done', attempts=attempts+1, last_error=NULL, done_at=? WHERE serial=?")
+        .bind(now).bind(serial)
+        .execute(pool).await?;
+    Ok(())
+}
+
+/// Records a failed attempt; with `retry_at` the row stays pending until then.
+pub async fn mark_failed(pool: &Db, serial: &str, error: &str, retry_at: Option<i64>) -> anyhow::Result<()> {
+    match retry_at {
+        Some(at) => sqlx::query("UPDATE scheduled_revocations SET attempts=attempts+1, last_error=?, revoke_at=? WHERE serial=?")
+            .bind(error).bind(at).bind(serial)
+            .execute(pool).await?,
+        None => sqlx::query("UPDATE scheduled_revocations SET status='failed', attempts=attempts+1, last_error=? WHERE serial=?")
+            .bind(error).bind(serial)
+            .execute(pool).await?,
+    };
+    Ok(())
+}
@@ -0,0 +1,18 @@
+use time::OffsetDateTime;
+
+use super::Db;
+
+/// Notes that `serial` connected; returns true the first time it is seen.
+pub async fn seen(pool: &Db, serial: &str, cn: &str, ip: Option<&str>) -> anyhow::Result<bool> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let first = sqlx::query("INSERT OR IGNORE INTO vpn_seen(serial, cn, first_seen, last_seen, last_ip) VALUES(?,?,?,?,?)")
+        .bind(serial).bind(cn).bind(now).bind(now).bind(ip)
+        .execute(pool).await?
+        .rows_affected() > 0;
+    if !first {
+        sqlx::query("UPDATE vpn_seen SET last_seen=?, last_ip=COALESCE(?, last_ip) WHERE serial=?")
+            .bind(now).bind(ip).bind(serial)
+            .execute(pool).await?;
+    }
+    Ok(first)
+}
@@ -0,0 +1,157 @@
+use sqlx::Row;
+use time::OffsetDateTime;
+use ulid::Ulid;
+
+use super::Db;
+
+#[derive(Clone)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    pub events: Vec<String>,
+    pub secret: String,
+    pub enabled: bool,
+    pub created_by: String,
+    pub created_at: i64,
+}
+
+// Keeps the signing secret out of logs.
+impl std::fmt::Debug for Webhook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Webhook")
+            .field("id", &self.id)
+            .field("url", &self.url)
+            .field("events", &self.events)
+            .field("enabled", &self.enabled)
+            .finish_non_exhaustive()
+    }
+}
+
+fn webhook_row(r: &sqlx::sqlite::SqliteRow) -> Webhook {
+    let events: String = r.try_get(2).unwrap();
+    let enabled: i64 = r.try_get(4).unwrap();
+    Webhook {
+        id: r.try_get(0).unwrap(),
+        url: r.try_get(1).unwrap(),
+        events: events.split(',').filter(|s| !s.is_empty()).map(str::to_string).collect(),
+        secret: r.try_get(3).unwrap(),
+        enabled: enabled != 0,
+        created_by: r.try_get(5).unwrap(),
+        created_at: r.try_get(6).unwrap(),
+    }
+}
+
+pub async fn create(pool: &Db, url: &str, events: &[String], secret: &str, created_by: &str) -> anyhow::Result<String> {
+    let id = Ulid::new().to_string();
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    sqlx::query("INSERT INTO webhooks(id, url, events, secret, created_by, created_at) VALUES(?,?,?,?,?,?)")
+        .bind(&id).bind(url).bind(events.join(",")).bind(secret).bind(created_by).bind(now)
+        .execute(pool).await?;
+    Ok(id)
+}
+
+pub async fn list(pool: &Db) -> anyhow::Result<Vec<Webhook>> {
+    let rows = sqlx::query("SELECT id, url, events, secret, enabled, created_by, created_at FROM webhooks ORDER BY created_at")
+        .fetch_all(pool).await?;
+    Ok(rows.iter().map(webhook_row).collect())
+}
+
+pub async fn get(pool: &Db, id: &str) -> anyhow::Result<Option<Webhook>> {
+    let row = sqlx::query("SELECT id, url, events, secret, enabled, created_by, created_at FROM webhooks WHERE id=?")
+        .bind(id).fetch_optional(pool).await?;
+    Ok(row.as_ref().map(webhook_row))
+}
+
+pub async fn update(pool: &Db, id: &str, url: &str, events: &[String], enabled: bool) -> anyhow::Result<bool> {
+    let res = sqlx::query("UPDATE webhooks SET url=?, events=?, enabled=? WHERE id=?")
+        .bind(url).bind(events.join(",")).bind(enabled as i64).bind(id)
+        .execute(pool).await?;
+    Ok(res.rows_affected() > 0)
+}
+
+pub async fn delete(pool: &Db, id: &str) -> anyhow::Result<bool> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE FROM webhook_deliveries WHERE webhook_id=?").bind(id).execute(&mut *tx).await?;
+    let res = sqlx::query("DELETE FROM webhooks WHERE id=?").bind(id).execute(&mut *tx).await?;
+    tx.commit().await?;
+    Ok(res.rows_affected() > 0)
+}
+
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    pub id: String,
+    pub webhook_id: String,
+    pub event: String,
+    pub payload: String,
+    pub status: String,
+    pub attempts: i64,
+    pub next_attempt_at: i64,
+    pub last_status: Option<i64>,
+    pub last_error: Option<String>,
+    pub created_at: i64,
+    pub delivered_at: Option<i64>,
+}
+
+const DELIVERY_COLS: &str = "id, webhook_id, event, payload, status, attempts, next_attempt_at, last_status, last_error, created_at, delivered_at";
+
+fn delivery_row(r: &sqlx::sqlite::SqliteRow) -> Delivery {
+    Delivery {
+        id: r.try_get(0).unwrap(),
+        webhook_id: r.try_get(1).unwrap(),
+        event: r.try_get(2).unwrap(),
+        payload: r.try_get(3).unwrap(),
+        status: r.try_get(4).unwrap(),
+        attempts: r.try_get(5).unwrap(),
+        next_attempt_at: r.try_get(6).unwrap(),
+        last_status: r.try_get(7).unwrap(),
+        last_error: r.try_get(8).unwrap(),
+        created_at: r.try_get(9).unwrap(),
+        delivered_at: r.try_get(10).unwrap(),
+    }
+}
+
+pub async fn enqueue(pool: &Db, id: &str, webhook_id: &str, event: &str, payload: &str) -> anyhow::Result<()> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    sqlx::query("INSERT INTO webhook_deliveries(id, webhook_id, event, payload, next_attempt_at, created_at) VALUES(?,?,?,?,?,?)")
+        .bind(id).bind(webhook_id).bind(event).bind(payload).bind(now).bind(now)
+        .execute(pool).await?;
+    Ok(())
+}
+
+pub async fn due(pool: &Db, now: i64, limit: i64) -> anyhow::Result<Vec<Delivery>> {
+    let rows = sqlx::query(&format!("SELECT {DELIVERY_COLS} FROM webhook_deliveries WHERE status='pending' AND next_attempt_at <= ? ORDER BY next_attempt_at LIMIT ?"))
+        .bind(now).bind(limit).fetch_all(pool).await?;
+    Ok(rows.iter().map(delivery_row).collect())
+}
+
+pub async fn mark_delivered(pool: &Db, id: &str, status: i64) -> anyhow::Result<()> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    sqlx::query("UPDATE webhook_deliveries SET status='delivered', attempts=attempts+1, last_status=?, last_error=NULL, delivered_at=? WHERE id=?")
+        .bind(status).bind(now).bind(id).execute(pool).await?;
+    Ok(())
+}
+
+/// Records a failed attempt; `next_attempt_at = None` gives up on the delivery.
+pub async fn mark_failed(pool: &Db, id: &str, status: Option<i64>, error: &str, next_attempt_at: Option<i64>) -> anyhow::Result<()> {
+    let (state, next) = match next_attempt_at {
+        Some(t) => ("pending", t),
+        None => ("failed", OffsetDateTime::now_utc().unix_timestamp()),
+    };
+    sqlx::query("UPDATE webhook_deliveries SET status=?, attempts=attempts+1, last_status=?, last_error=?, next_attempt_at=? WHERE id=?")
+        .bind(state).bind(status).bind(error).bind(next).bind(id).execute(pool).await?;
+    Ok(())
+}
+
+pub async fn deliveries(pool: &Db, webhook_id: &str, limit: i64) -> anyhow::Result<Vec<Delivery>> {
+    let rows = sqlx::query(&format!("SELECT {DELIVERY_COLS} FROM webhook_deliveries WHERE webhook_id=? ORDER BY created_at DESC, id DESC LIMIT ?"))
+        .bind(webhook_id).bind(limit.clamp(1, 200)).fetch_all(pool).await?;
+    Ok(rows.iter().map(delivery_row).collect())
+}
+
+/// Puts a failed delivery back in the queue for an immediate attempt.
+pub async fn redeliver(pool: &Db, webhook_id: &str, id: &str) -> anyhow::Result<bool> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let res = sqlx::query("UPDATE webhook_deliveries SET status='pending', attempts=0, next_attempt_at=? WHERE id=? AND webhook_id=? AND status='failed'")
+        .bind(now).bind(id).bind(webhook_id).execute(pool).await?;
+    Ok(res.rows_affected() > 0)
+}
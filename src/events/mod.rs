@@ -0,0 +1,105 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::sync::broadcast;
+use ulid::Ulid;
+
+use crate::AppState;
+
+/// Events a slow subscriber may fall behind by before it is told to refetch.
+const CAPACITY: usize = 256;
+const HEALTH_INTERVAL_SECS: u64 = 15;
+
+static BUS: OnceLock<broadcast::Sender<Event>> = OnceLock::new();
+static HEALTH: Mutex<Option<Value>> = Mutex::new(None);
+
+fn bus() -> &'static broadcast::Sender<Event> {
+    BUS.get_or_init(|| broadcast::channel(CAPACITY).0)
+}
+
+/// Something that just happened, as pushed to `GET /api/events`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub id: String,
+    /// `audit`, `job`, `health`, `vpn.connect` or `vpn.disconnect`.
+    pub kind: &'static str,
+    pub ts: i64,
+    pub data: Value,
+}
+
+impl Event {
+    /// Audit rows and jobs are admin-only like their endpoints; sessions
+    /// are visible to operators too, health to everyone signed in.
+    pub fn visible_to(&self, roles: &[String]) -> bool {
+        let required: &[&str] = match self.kind {
+            "health" => return true,
+            "vpn.connect" | "vpn.disconnect" => &["ADMIN", "OPS"],
+            _ => &["ADMIN"],
+        };
+        roles.iter().any(|r| required.contains(&r.as_str()))
+    }
+}
+
+/// Fire and forget; nothing is buffered while nobody listens.
+pub fn publish(kind: &'static str, data: Value) {
+    let _ = bus().send(Event {
+        id: Ulid::new().to_string(),
+        ts: OffsetDateTime::now_utc().unix_timestamp(),
+        kind,
+        data,
+    });
+}
+
+pub fn subscribe() -> broadcast::Receiver<Event> {
+    bus().subscribe()
+}
+
+/// Last health seen by the monitor, sent to new subscribers first.
+pub fn last_health() -> Option<Value> {
+    HEALTH.lock().unwrap().clone()
+}
+
+/// Polls the same checks as `/api/health` and publishes when any of them flips.
+pub fn spawn_health_monitor(st: AppState) {
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(Duration::from_secs(HEALTH_INTERVAL_SECS));
+        loop {
+            tick.tick().await;
+            let now = crate::http::health_status(&st).await;
+            let prev = HEALTH.lock().unwrap().replace(now.clone());
+            if let Some(prev) = prev
+                && prev != now {
+                tracing::info!("health changed: {}", now);
+                publish("health", now);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn visible(kind: &'static str, roles: &[&str]) -> bool {
+        let ev = Event { id: String::new(), kind, ts: 0, data: Value::Null };
+        ev.visible_to(&roles.iter().map(|r| r.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn events_are_gated_by_role() {
+        for kind in ["audit", "job"] {
+            assert!(visible(kind, &["ADMIN"]));
+            assert!(!visible(kind, &["OPS"]));
+            assert!(!visible(kind, &["VIEWER"]));
+        }
+        for kind in ["vpn.connect", "vpn.disconnect"] {
+            assert!(visible(kind, &["ADMIN"]));
+            assert!(visible(kind, &["VIEWER", "OPS"]));
+            assert!(!visible(kind, &["VIEWER"]));
+        }
+        assert!(visible("health", &[]));
+        assert!(!visible("audit", &[]));
+    }
+}
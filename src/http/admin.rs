@@ -1,28 +1,42 @@
 use axum::{
-    extract::{Path, State},
+    extract::{ConnectInfo, Path, State},
     http::{header, HeaderMap, StatusCode, HeaderValue},
     response::Response,
-    routing::{get, post, put},
-    body::{Body, boxed},
+    routing::{get, post},
     Json, Router,
 };
 use axum::extract::Query;
 use axum::response::IntoResponse;
+use base64::Engine;
 use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
 
-use crate::{db, http::guards, openvpn, vpncertd, AppState};
+use crate::{db, http::{guards, ErrorMsg}, openvpn, AppState};
 use crate::http::guards::AuthSession;
 
 #[derive(Deserialize)]
 struct NewClient {
     cn: String,
     passphrase: Option<String>,
+    // not read here; the bundle endpoint takes its own include_key
+    #[allow(dead_code)]
     include_key: Option<bool>,
+    /// CCD text, or the name of a CCD template to generate it from.
     ccd: Option<String>,
+    owner_email: Option<String>,
+    /// Falls back to `[issuance].default_profile`.
+    profile: Option<String>,
+    /// Falls back to the profile's default key type.
+    key_type: Option<String>,
+    /// Either a day count or an explicit expiry; vpncertd's default otherwise.
+    validity_days: Option<u32>,
+    not_after: Option<String>,
 }
 #[derive(Deserialize, Default)]
 struct BundleReq {
     include_key: Option<bool>,
+    /// Queue a job instead of streaming the zip; answers 202 `{job}`.
+    background: Option<bool>,
 }
 
 #[derive(Serialize)]
@@ -31,32 +45,174 @@ struct ClientCreated {
     passphrase: String,
     serial: Option<String>,
     not_after: Option<String>,
+    /// What was asked for, after turning `not_after` into whole days, or the
+    /// profile default.
+    requested_validity_days: u32,
+    /// What vpncertd actually issued, from `not_after`.
+    validity_days: Option<i64>,
+    /// Set when the certificate was issued but its CCD was not written.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ccd_error: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct RevokeReq {
+    /// Defaults to the newest unrevoked cert of the CN.
+    serial: Option<String>,
+    /// RFC 5280 reason name; `keyCompromise` when omitted.
+    reason: Option<String>,
 }
 
 #[derive(Serialize)]
-struct ErrorMsg { error: String }
+struct ClientRevoked {
+    cn: String,
+    serial: String,
+    reason: String,
+}
+
+#[derive(Deserialize)]
+struct BulkRevokeReq {
+    #[serde(flatten)]
+    selection: openvpn::bulk::Selection,
+    reason: Option<String>,
+    /// Defaults to true: only return what would be revoked.
+    dry_run: Option<bool>,
+    /// From the preview; required to execute.
+    plan_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct UnrevokeReq {
+    serial: String,
+}
+
+#[derive(Deserialize, Default)]
+struct RenewReq {
+    passphrase: Option<String>,
+    /// Grace period before the old serial is revoked, e.g. `0`, `24h`, `7d`.
+    /// Without it the old cert stays valid until it expires.
+    revoke_old_after: Option<String>,
+    /// Same default as the bundle endpoint: no key unless asked for.
+    include_key: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct ClientRenewed {
+    cn: String,
+    passphrase: String,
+    serial: Option<String>,
+    not_after: Option<String>,
+    old_serial: String,
+    old_revoke_at: Option<i64>,
+    bundle_filename: Option<String>,
+    bundle_b64: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CsrReq {
+    csr_pem: String,
+    /// Also return a key-less bundle built around the signed cert.
+    bundle: Option<bool>,
+    /// Sign even though the CN still holds a valid cert.
+    renew: Option<bool>,
+    owner_email: Option<String>,
+    profile: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ClientSigned {
+    cn: String,
+    serial: Option<String>,
+    not_after: Option<String>,
+    key_type: String,
+    cert_pem: String,
+    bundle_filename: Option<String>,
+    bundle_b64: Option<String>,
+}
+
+/// Maps profile, key-type and validity failures to a response.
+fn issuance_error(msg: &str) -> Option<Response> {
+    let error = [
+        "profile_forbidden", "profile_unknown", "key_type_unknown", "key_type_not_allowed",
+        "validity_conflict", "validity_invalid", "validity_too_long",
+    ].into_iter().find(|c| msg.contains(c))?;
+    let code = if error == "profile_forbidden" { StatusCode::FORBIDDEN } else { StatusCode::UNPROCESSABLE_ENTITY };
+    Some((code, Json(ErrorMsg { error: error.into() })).into_response())
+}
 
 async fn create_client(
     State(st): State<AppState>,
     sess: AuthSession,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Json(req): Json<NewClient>,
 ) -> Result<Response, StatusCode> {
     guards::ensure_role(&sess, &["ADMIN"]).map_err(|_| StatusCode::FORBIDDEN)?;
-    match openvpn::create_client(&st, &req.cn, req.passphrase.as_deref()).await {
+    let ctx = guards::audit_ctx(&sess, &peer, &headers);
+    let resolved = openvpn::profiles::resolve(&st.cfg.issuance, &sess.roles, req.profile.as_deref(), req.key_type.as_deref())
+        .and_then(|(p, k)| {
+            let validity_days = openvpn::profiles::validity_days(p, req.validity_days, req.not_after.as_deref())?;
+            Ok(openvpn::IssueOpts { profile: &p.name, key_type: k, validity_days })
+        });
+    let opts = match resolved {
+        Ok(o) => o,
+        Err(e) => return Ok(issuance_error(&e.to_string()).unwrap_or_else(|| StatusCode::INTERNAL_SERVER_ERROR.into_response())),
+    };
+    // A bare template name instead of CCD text links the new client to it.
+    let template = req.ccd.as_deref().and_then(openvpn::ccd_templates::as_name);
+    if let Some(name) = template {
+        match openvpn::ccd_templates::check(&st, &req.cn, name).await {
+            Ok(Ok(())) => {}
+            Ok(Err(lines)) => {
+                return Ok((StatusCode::UNPROCESSABLE_ENTITY, Json(serde_json::json!({ "error": "ccd_invalid", "lines": lines })))
+                    .into_response());
+            }
+            Err(e) if e.to_string() == "unknown_template" => {
+                return Ok((StatusCode::UNPROCESSABLE_ENTITY, Json(ErrorMsg { error: "unknown_template".into() })).into_response());
+            }
+            Err(e) => {
+                tracing::error!("create_client: ccd template {}: {}", name, e);
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+    } else if let Some(resp) = req.ccd.as_deref().and_then(ccd_error) {
+        return Ok(resp);
+    }
+    match openvpn::create_client(&st, &ctx, &req.cn, req.passphrase.as_deref(), &opts).await {
         Ok(res) => {
-            if let Some(ccd_text) = req.ccd.as_deref() {
-                if let Err(e) = openvpn::write_ccd(&st, &res.cn, ccd_text).await {
-                    tracing::error!("save CCD after create ({}): {}", res.cn, e);
-                } else {
-                    let _ = db::audit_record(&st.db, "", "ADMIN_SAVE_CCD", &res.cn, "-", "-", "{}").await;
-                }
+            if let Some(email) = req.owner_email.as_deref().map(str::trim).filter(|e| !e.is_empty())
+                && let Err(e) = db::clients::set_owner_email(&st.db, &res.cn, Some(email)).await {
+                tracing::error!("save owner after create ({}): {}", res.cn, e);
             }
+            // A CCD file left over from an earlier client of this name is
+            // not overwritten; the caller is told and can review it.
+            let new_file = openvpn::ccd_etag("");
+            let ccd_error = if let Some(name) = template {
+                match openvpn::ccd_templates::attach(&st, &ctx, &res.cn, name, &Default::default(), "", Some(&new_file)).await {
+                    Ok(Ok(_)) => None,
+                    Ok(Err(lines)) => Some(format!("ccd_invalid: {} invalid line(s)", lines.len())),
+                    Err(e) => Some(e.to_string()),
+                }
+            } else if let Some(ccd_text) = req.ccd.as_deref() {
+                openvpn::write_ccd(&st, &ctx, &res.cn, ccd_text, Some(&new_file)).await.err().map(|e| e.to_string())
+            } else {
+                None
+            };
+            let ccd_error = ccd_error.map(|e| {
+                tracing::error!("save CCD after create ({}): {}", res.cn, e);
+                if e == "etag_mismatch" { "ccd_exists".to_string() } else { e }
+            });
 
+            let validity_days = res.not_after.as_deref().and_then(openvpn::expiry::parse_not_after)
+                .map(|ts| (ts - time::OffsetDateTime::now_utc().unix_timestamp() + 43200).div_euclid(86400));
             let body = Json(ClientCreated {
                 cn:        res.cn,
                 passphrase:res.passphrase,
                 serial:    res.serial,
                 not_after: res.not_after,
+                requested_validity_days: opts.validity_days,
+                validity_days,
+                ccd_error,
             });
 
             let mut resp = body.into_response();
@@ -82,45 +238,316 @@ async fn create_client(
 async fn revoke_client(
     State(st): State<AppState>,
     sess: AuthSession,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Path(cn): Path<String>,
+    body: Option<Json<RevokeReq>>,
 ) -> Result<Response, StatusCode> {
     guards::ensure_role(&sess, &["ADMIN"]).map_err(|_| StatusCode::FORBIDDEN)?;
+    let ctx = guards::audit_ctx(&sess, &peer, &headers);
+    let req = body.map(|Json(b)| b).unwrap_or_default();
+    let reason = req.reason.as_deref().unwrap_or("keyCompromise");
 
-    match openvpn::revoke_client(&st, &cn).await {
+    match openvpn::revoke_client(&st, &ctx, &cn, req.serial.as_deref(), reason).await {
+        Ok(serial) => Ok(Json(ClientRevoked { cn, serial, reason: reason.to_string() }).into_response()),
+        Err(e) => Ok(revoke_error(&cn, &e.to_string())),
+    }
+}
+
+async fn unrevoke_client(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(cn): Path<String>,
+    Json(req): Json<UnrevokeReq>,
+) -> Result<Response, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"]).map_err(|_| StatusCode::FORBIDDEN)?;
+    let ctx = guards::audit_ctx(&sess, &peer, &headers);
+    match openvpn::unrevoke_client(&st, &ctx, &cn, &req.serial).await {
         Ok(()) => Ok(StatusCode::NO_CONTENT.into_response()),
+        Err(e) => Ok(revoke_error(&cn, &e.to_string())),
+    }
+}
+
+async fn bulk_revoke(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<BulkRevokeReq>,
+) -> Result<Response, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"]).map_err(|_| StatusCode::FORBIDDEN)?;
+    let reason = req.reason.as_deref().unwrap_or("cessationOfOperation");
+    let plan = match openvpn::bulk::plan_revoke(&st, &req.selection).await {
+        Ok(p) => p,
+        Err(e) if e.to_string().contains("too_many_items") => {
+            return Ok((StatusCode::UNPROCESSABLE_ENTITY, Json(ErrorMsg { error: "too_many_items".into() })).into_response());
+        }
+        Err(e) => {
+            tracing::error!("bulk revoke plan: {}", e);
+            return Ok((StatusCode::BAD_GATEWAY, Json(ErrorMsg { error: "daemon_error".into() })).into_response());
+        }
+    };
+    let plan_id = openvpn::bulk::plan_id(reason, &plan);
+    if req.dry_run.unwrap_or(true) {
+        let count = plan.iter().filter(|p| p.action == "revoke").count();
+        return Ok(Json(serde_json::json!({
+            "dry_run": true, "plan_id": plan_id, "reason": reason, "revoke": count, "items": plan,
+        })).into_response());
+    }
+    // The selection may resolve differently since the preview (a CN renewed,
+    // a group gained members); make the admin look again.
+    match req.plan_id.as_deref() {
+        None => return Ok((StatusCode::UNPROCESSABLE_ENTITY, Json(ErrorMsg { error: "plan_id_required".into() })).into_response()),
+        Some(id) if id != plan_id => return Ok((StatusCode::CONFLICT, Json(ErrorMsg { error: "plan_changed".into() })).into_response()),
+        Some(_) => {}
+    }
+
+    let ctx = guards::audit_ctx(&sess, &peer, &headers);
+    match openvpn::bulk::start_revoke(&st, &ctx, plan, reason).await {
+        Ok(job) => Ok((StatusCode::ACCEPTED, Json(serde_json::json!({ "job": job }))).into_response()),
         Err(e) => {
             let msg = e.to_string();
+            let error = ["invalid_reason", "nothing_to_revoke"].into_iter().find(|c| msg.contains(c));
+            match error {
+                Some(error) => Ok((StatusCode::UNPROCESSABLE_ENTITY, Json(ErrorMsg { error: error.into() })).into_response()),
+                None => {
+                    tracing::error!("bulk revoke: {}", msg);
+                    Err(StatusCode::INTERNAL_SERVER_ERROR)
+                }
+            }
+        }
+    }
+}
 
-            let resp = if msg.to_lowercase().contains("invalid cn") {
+#[derive(Deserialize)]
+struct ImportQ {
+    /// Only validate the rows.
+    dry_run: Option<bool>,
+    /// Put each client's private key in its bundle.
+    include_key: Option<bool>,
+}
+
+/// Takes a CSV (`text/csv`) or JSON body; rows are validated up front and
+/// issued by a background job.
+async fn import_clients(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(q): Query<ImportQ>,
+    body: axum::body::Bytes,
+) -> Result<Response, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"]).map_err(|_| StatusCode::FORBIDDEN)?;
+    let ctype = headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or("");
+    let format = if ctype.starts_with("text/csv") { openvpn::import::Format::Csv } else { openvpn::import::Format::Json };
+
+    let unprocessable = |error: &str| (StatusCode::UNPROCESSABLE_ENTITY, Json(ErrorMsg { error: error.into() })).into_response();
+    let rows = match openvpn::import::parse(&body, format) {
+        Ok(r) => r,
+        Err(e) => {
+            tracing::warn!("import parse: {}", e);
+            return Ok(unprocessable("import_unparsable"));
+        }
+    };
+    let planned = match openvpn::import::validate(&st, &sess.roles, rows).await {
+        Ok(Ok(p)) => p,
+        Ok(Err(errors)) => {
+            return Ok((StatusCode::UNPROCESSABLE_ENTITY, Json(serde_json::json!({ "error": "import_invalid", "rows": errors })))
+                .into_response());
+        }
+        Err(e) => {
+            let msg = e.to_string();
+            if let Some(code) = ["import_empty", "too_many_items"].into_iter().find(|c| msg.contains(c)) {
+                return Ok(unprocessable(code));
+            }
+            tracing::error!("import validate: {}", msg);
+            return Ok((StatusCode::BAD_GATEWAY, Json(ErrorMsg { error: "daemon_error".into() })).into_response());
+        }
+    };
+    if q.dry_run.unwrap_or(false) {
+        return Ok(Json(serde_json::json!({ "dry_run": true, "rows": planned })).into_response());
+    }
+
+    let ctx = guards::audit_ctx(&sess, &peer, &headers);
+    match openvpn::import::start(&st, &ctx, &planned, q.include_key.unwrap_or(false)).await {
+        Ok(job) => Ok((StatusCode::ACCEPTED, Json(serde_json::json!({ "job": job }))).into_response()),
+        Err(e) => {
+            tracing::error!("import: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+fn revoke_error(cn: &str, msg: &str) -> Response {
+    let lower = msg.to_lowercase();
+    let (code, error) = if lower.contains("invalid cn") {
+        (StatusCode::UNPROCESSABLE_ENTITY, "invalid_cn")
+    } else if lower.contains("invalid_reason") {
+        (StatusCode::UNPROCESSABLE_ENTITY, "invalid_reason")
+    } else if lower.contains("serial_cn_mismatch") {
+        (StatusCode::UNPROCESSABLE_ENTITY, "serial_cn_mismatch")
+    } else if lower.contains("not_found: serial") {
+        (StatusCode::NOT_FOUND, "serial_not_found")
+    } else if lower.contains("unrevoke_unsupported") {
+        (StatusCode::NOT_IMPLEMENTED, "unrevoke_unsupported")
+    } else if lower.contains("not_revoked") {
+        (StatusCode::CONFLICT, "not_revoked")
+    } else if lower.contains("not_on_hold") {
+        (StatusCode::CONFLICT, "not_on_hold")
+    } else if lower.contains("not_found") || lower.contains("no such") || lower.contains("unknown") {
+        (StatusCode::NOT_FOUND, "cn_not_found")
+    } else if lower.contains("already") && lower.contains("revoke") {
+        (StatusCode::CONFLICT, "already_revoked")
+    } else {
+        tracing::error!(%cn, error=%msg, "revoke failed");
+        (StatusCode::BAD_GATEWAY, "daemon_error")
+    };
+    (code, Json(ErrorMsg { error: error.into() })).into_response()
+}
+
+async fn renew_client(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(cn): Path<String>,
+    Json(req): Json<RenewReq>,
+) -> Result<Response, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"]).map_err(|_| StatusCode::FORBIDDEN)?;
+    let ctx = guards::audit_ctx(&sess, &peer, &headers);
+    let grace = match req.revoke_old_after.as_deref().map(parse_window) {
+        None => None,
+        Some(Some(s)) => Some(s),
+        Some(None) => {
+            return Ok((StatusCode::BAD_REQUEST, Json(ErrorMsg { error: "invalid_revoke_old_after".into() })).into_response());
+        }
+    };
+    let include_key = req.include_key.unwrap_or(false);
+
+    match openvpn::renew::renew_client(&st, &ctx, &sess.roles, &cn, req.passphrase.as_deref(), grace, include_key).await {
+        Ok(r) => {
+            let (bundle_filename, bundle_b64) = match r.bundle {
+                Some((name, bytes)) => (Some(name), Some(base64::engine::general_purpose::STANDARD.encode(bytes))),
+                None => (None, None),
+            };
+            Ok(Json(ClientRenewed {
+                cn: r.issue.cn,
+                passphrase: r.issue.passphrase,
+                serial: r.issue.serial,
+                not_after: r.issue.not_after,
+                old_serial: r.old_serial,
+                old_revoke_at: r.old_revoke_at,
+                bundle_filename,
+                bundle_b64,
+            }).into_response())
+        }
+        Err(e) => {
+            let msg = e.to_string();
+            let resp = if msg.contains("invalid_cn") {
                 (StatusCode::UNPROCESSABLE_ENTITY, Json(ErrorMsg { error: "invalid_cn".into() })).into_response()
-            } else if msg.to_lowercase().contains("not_found")
-                || msg.to_lowercase().contains("no such")
-                || msg.to_lowercase().contains("unknown")
-            {
+            } else if let Some(resp) = issuance_error(&msg) {
+                resp
+            } else if msg.contains("cn_not_found") {
                 (StatusCode::NOT_FOUND, Json(ErrorMsg { error: "cn_not_found".into() })).into_response()
-            } else if msg.to_lowercase().contains("already") && msg.to_lowercase().contains("revoke") {
-                (StatusCode::CONFLICT, Json(ErrorMsg { error: "already_revoked".into() })).into_response()
             } else {
-                tracing::error!(%cn, error=%msg, "revoke_client failed");
+                tracing::error!(%cn, error=%msg, "renew_client failed");
                 (StatusCode::BAD_GATEWAY, Json(ErrorMsg { error: "daemon_error".into() })).into_response()
             };
-
             Ok(resp)
         }
     }
 }
 
+async fn sign_csr(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(cn): Path<String>,
+    Json(req): Json<CsrReq>,
+) -> Result<Response, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"]).map_err(|_| StatusCode::FORBIDDEN)?;
+    let ctx = guards::audit_ctx(&sess, &peer, &headers);
+    let profile = match openvpn::profiles::resolve_profile(&st.cfg.issuance, &sess.roles, req.profile.as_deref()) {
+        Ok(p) => p,
+        Err(e) => return Ok(issuance_error(&e.to_string()).unwrap_or_else(|| StatusCode::INTERNAL_SERVER_ERROR.into_response())),
+    };
+
+    let signed = match openvpn::csr::sign(&st, &ctx, &cn, &req.csr_pem, profile, req.renew.unwrap_or(false)).await {
+        Ok(s) => s,
+        Err(e) => {
+            let msg = e.to_string();
+            let resp = if msg.contains("invalid_cn") {
+                (StatusCode::UNPROCESSABLE_ENTITY, Json(ErrorMsg { error: "invalid_cn".into() })).into_response()
+            } else if let Some(resp) = issuance_error(&msg) {
+                resp
+            } else if let Some(code) = ["csr_invalid", "csr_bad_signature", "csr_cn_mismatch", "csr_key_weak"]
+                .into_iter().find(|c| msg.contains(c)) {
+                (StatusCode::UNPROCESSABLE_ENTITY, Json(ErrorMsg { error: code.into() })).into_response()
+            } else if msg.contains("cn_exists_active") {
+                (StatusCode::CONFLICT, Json(ErrorMsg { error: "cn_exists_active".into() })).into_response()
+            } else {
+                tracing::error!(%cn, error=%msg, "sign_csr failed");
+                (StatusCode::BAD_GATEWAY, Json(ErrorMsg { error: "daemon_error".into() })).into_response()
+            };
+            return Ok(resp);
+        }
+    };
+
+    if let Some(email) = req.owner_email.as_deref().map(str::trim).filter(|e| !e.is_empty())
+        && let Err(e) = db::clients::set_owner_email(&st.db, &cn, Some(email)).await {
+        tracing::error!("save owner after csr ({}): {}", cn, e);
+    }
+
+    let (mut bundle_filename, mut bundle_b64) = (None, None);
+    if req.bundle.unwrap_or(false) {
+        match openvpn::build_bundle(&st, &ctx, &cn, false).await {
+            Ok(b) => match tokio::fs::read(&b.path).await {
+                Ok(bytes) => {
+                    bundle_filename = Some(b.filename);
+                    bundle_b64 = Some(base64::engine::general_purpose::STANDARD.encode(bytes));
+                }
+                Err(e) => tracing::error!("csr bundle read({}): {}", cn, e),
+            },
+            Err(e) => tracing::error!("csr bundle({}): {}", cn, e),
+        }
+    }
 
+    let mut resp = Json(ClientSigned {
+        cn: signed.cn,
+        serial: signed.serial,
+        not_after: signed.not_after,
+        key_type: signed.key_type,
+        cert_pem: signed.cert_pem,
+        bundle_filename,
+        bundle_b64,
+    }).into_response();
+    *resp.status_mut() = StatusCode::CREATED;
+    Ok(resp)
+}
 
 async fn bundle(
     State(st): State<AppState>,
     sess: AuthSession,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Path(cn): Path<String>,
     Json(req): Json<BundleReq>,
 ) -> Result<Response, StatusCode> {
     guards::ensure_role(&sess, &["ADMIN"]).map_err(|_| StatusCode::FORBIDDEN)?;
+    let ctx = guards::audit_ctx(&sess, &peer, &headers);
     let include_key = req.include_key.unwrap_or(false);
-    let b = openvpn::build_bundle(&st, &cn, include_key)
+    if req.background.unwrap_or(false) {
+        let params = serde_json::json!({ "cn": cn, "include_key": include_key });
+        let job = crate::jobs::enqueue(&st, &ctx, "bundle", 1, &params).await.map_err(|e| {
+            tracing::error!("bundle job({}): {}", cn, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        return Ok((StatusCode::ACCEPTED, Json(serde_json::json!({ "job": job }))).into_response());
+    }
+    let b = openvpn::build_bundle(&st, &ctx, &cn, include_key)
         .await
         .map_err(|e| {
             tracing::error!("bundle({}): {}", cn, e);
@@ -165,12 +592,13 @@ async fn get_ccd(
     State(st): State<AppState>,
     sess: AuthSession,
     Path(cn): Path<String>,
-) -> Result<Json<CcdDto>, StatusCode> {
+) -> Result<Response, StatusCode> {
     guards::ensure_role(&sess, &["ADMIN"]).map_err(|_| StatusCode::FORBIDDEN)?;
     let content = openvpn::read_ccd(&st, &cn)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    Ok(Json(CcdDto { cn, content }))
+    let etag = etag_value(&openvpn::ccd_etag(&content));
+    Ok(([(header::ETAG, etag)], Json(CcdDto { cn, content })).into_response())
 }
 
 #[derive(Deserialize)]
@@ -181,14 +609,345 @@ struct CcdBody {
 async fn put_ccd(
     State(st): State<AppState>,
     sess: AuthSession,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Path(cn): Path<String>,
     Json(body): Json<CcdBody>,
-) -> Result<StatusCode, StatusCode> {
+) -> Result<Response, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"]).map_err(|_| StatusCode::FORBIDDEN)?;
+    let Some(tag) = if_match(&headers) else {
+        return Ok(precondition_required());
+    };
+    if let Some(resp) = ccd_error(&body.content) {
+        return Ok(resp);
+    }
+    let ctx = guards::audit_ctx(&sess, &peer, &headers);
+    if let Err(e) = openvpn::write_ccd(&st, &ctx, &cn, &body.content, Some(&tag)).await {
+        return ccd_write_error(&st, &cn, &e).await;
+    }
+    let etag = etag_value(&openvpn::ccd_etag(&body.content.replace("\r\n", "\n")));
+    Ok((StatusCode::NO_CONTENT, [(header::ETAG, etag)]).into_response())
+}
+
+fn etag_value(tag: &str) -> HeaderValue {
+    HeaderValue::from_str(&format!("\"{tag}\"")).unwrap()
+}
+
+/// `None` when the header is missing; `*` is kept as is. A list is
+/// reduced to its first tag, and weak tags compare like strong ones since
+/// both are the content hash.
+fn if_match(headers: &HeaderMap) -> Option<String> {
+    let raw = headers.get(header::IF_MATCH)?.to_str().ok()?.trim();
+    let first = raw.split(',').next().unwrap_or_default().trim();
+    Some(first.trim_start_matches("W/").trim_matches('"').to_string())
+}
+
+/// 428: CCD writes must say which version they were based on.
+fn precondition_required() -> Response {
+    (StatusCode::PRECONDITION_REQUIRED, Json(serde_json::json!({
+        "error": "if_match_required", "detail": "send If-Match with the ETag from GET /admin/ccd/:cn",
+    }))).into_response()
+}
+
+/// 412 with the file as it is now, so the client can merge and retry; 409
+/// while `cn` is generated from a template, since the next template change
+/// would overwrite a manual edit; any other failure is a 500.
+async fn ccd_write_error(st: &AppState, cn: &str, e: &anyhow::Error) -> Result<Response, StatusCode> {
+    let msg = e.to_string();
+    if let Some(template) = msg.strip_prefix("template_linked: ") {
+        return Ok((StatusCode::CONFLICT, Json(serde_json::json!({
+            "error": "template_linked", "template": template,
+            "detail": "edit the template overrides, or DELETE /admin/ccd/:cn/template first",
+        }))).into_response());
+    }
+    if msg != "etag_mismatch" {
+        tracing::error!("write ccd({}): {}", cn, e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    let content = openvpn::read_ccd(st, cn).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let etag = openvpn::ccd_etag(&content);
+    Ok((StatusCode::PRECONDITION_FAILED, [(header::ETAG, etag_value(&etag))], Json(serde_json::json!({
+        "error": "etag_mismatch", "etag": etag, "content": content,
+    }))).into_response())
+}
+
+/// 422 `{error: "ccd_invalid", lines: [{line, directive, error, value}]}`.
+fn ccd_error(content: &str) -> Option<Response> {
+    let lines = openvpn::ccd::parse(&content.replace("\r\n", "\n")).err()?;
+    Some((StatusCode::UNPROCESSABLE_ENTITY, Json(serde_json::json!({ "error": "ccd_invalid", "lines": lines }))).into_response())
+}
+
+#[derive(Serialize)]
+struct StructuredCcdDto {
+    cn: String,
+    structured: openvpn::ccd::Structured,
+    /// The canonical text the fields render to.
+    content: String,
+}
+
+/// A file that does not parse can only be edited raw; 422 with its line errors.
+async fn get_ccd_structured(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    Path(cn): Path<String>,
+) -> Result<Response, StatusCode> {
     guards::ensure_role(&sess, &["ADMIN"]).map_err(|_| StatusCode::FORBIDDEN)?;
-    openvpn::write_ccd(&st, &cn, &body.content)
+    let content = openvpn::read_ccd(&st, &cn)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    Ok(StatusCode::NO_CONTENT)
+    match openvpn::ccd::Structured::from_text(&content.replace("\r\n", "\n")) {
+        Ok(structured) => {
+            let etag = etag_value(&openvpn::ccd_etag(&content));
+            let content = structured.render().0;
+            Ok(([(header::ETAG, etag)], Json(StructuredCcdDto { cn, structured, content })).into_response())
+        }
+        Err(lines) => Ok((StatusCode::UNPROCESSABLE_ENTITY, Json(serde_json::json!({ "error": "ccd_invalid", "lines": lines })))
+            .into_response()),
+    }
+}
+
+/// Renders the fields to canonical CCD text and saves it; errors name the field.
+/// `If-Match` is checked against the file as stored, as for the raw PUT.
+async fn put_ccd_structured(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(cn): Path<String>,
+    Json(structured): Json<openvpn::ccd::Structured>,
+) -> Result<Response, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"]).map_err(|_| StatusCode::FORBIDDEN)?;
+    let Some(tag) = if_match(&headers) else {
+        return Ok(precondition_required());
+    };
+    let content = match structured.to_text() {
+        Ok(t) => t,
+        Err(fields) => {
+            return Ok((StatusCode::UNPROCESSABLE_ENTITY, Json(serde_json::json!({ "error": "ccd_invalid", "fields": fields })))
+                .into_response());
+        }
+    };
+    let ctx = guards::audit_ctx(&sess, &peer, &headers);
+    if let Err(e) = openvpn::write_ccd(&st, &ctx, &cn, &content, Some(&tag)).await {
+        return ccd_write_error(&st, &cn, &e).await;
+    }
+    let etag = etag_value(&openvpn::ccd_etag(&content));
+    Ok(([(header::ETAG, etag)], Json(StructuredCcdDto { cn, structured, content })).into_response())
+}
+
+#[derive(Serialize)]
+struct CcdRevisionDto {
+    rev: i64,
+    sha256: String,
+    author: String,
+    /// `panel`, `external` or `rollback`.
+    source: String,
+    restored_from: Option<i64>,
+    created_at: i64,
+    size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+}
+
+impl CcdRevisionDto {
+    fn new(r: db::ccd::Revision, with_content: bool) -> Self {
+        CcdRevisionDto {
+            size: r.content.len(),
+            content: with_content.then_some(r.content),
+            rev: r.rev, sha256: r.sha256, author: r.author, source: r.source, restored_from: r.restored_from,
+            created_at: r.created_at,
+        }
+    }
+}
+
+/// An edit made on disk shows up here once the CCD is next saved or rolled back.
+async fn ccd_revisions(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    Path(cn): Path<String>,
+) -> Result<Json<Vec<CcdRevisionDto>>, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"]).map_err(|_| StatusCode::FORBIDDEN)?;
+    openvpn::read_ccd(&st, &cn).await.map_err(|_| StatusCode::UNPROCESSABLE_ENTITY)?;
+    let list = db::ccd::list(&st.db, &cn).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(list.into_iter().map(|r| CcdRevisionDto::new(r, false)).collect()))
+}
+
+async fn ccd_revision(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    Path((cn, rev)): Path<(String, i64)>,
+) -> Result<Json<CcdRevisionDto>, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"]).map_err(|_| StatusCode::FORBIDDEN)?;
+    let r = db::ccd::get(&st.db, &cn, rev).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(CcdRevisionDto::new(r, true)))
+}
+
+#[derive(Deserialize)]
+struct DiffQ {
+    /// Defaults to the revision before `to`.
+    from: Option<i64>,
+    /// Defaults to the newest revision.
+    to: Option<i64>,
+}
+
+/// Unified diff between two revisions; revision 0 is the empty file.
+async fn ccd_diff(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    Path(cn): Path<String>,
+    Query(q): Query<DiffQ>,
+) -> Result<Response, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"]).map_err(|_| StatusCode::FORBIDDEN)?;
+    openvpn::read_ccd(&st, &cn).await.map_err(|_| StatusCode::UNPROCESSABLE_ENTITY)?;
+    let latest = db::ccd::latest(&st.db, &cn).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let to = q.to.unwrap_or(latest.rev);
+    let from = q.from.unwrap_or(to - 1).max(0);
+    let content = |rev: i64| {
+        let st = st.clone();
+        let cn = cn.clone();
+        async move {
+            if rev == 0 { return Ok(String::new()); }
+            db::ccd::get(&st.db, &cn, rev).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+                .map(|r| r.content).ok_or(StatusCode::NOT_FOUND)
+        }
+    };
+    let (old, new) = (content(from).await?, content(to).await?);
+    let diff = openvpn::ccd::unified_diff(&old, &new, &format!("{cn}@{from}"), &format!("{cn}@{to}"));
+    Ok(Json(serde_json::json!({ "cn": cn, "from": from, "to": to, "diff": diff })).into_response())
+}
+
+#[derive(Deserialize)]
+struct RollbackReq {
+    rev: i64,
+}
+
+/// `If-Match` is required as for PUT, so a rollback from a stale view
+/// cannot undo a newer edit.
+async fn ccd_rollback(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(cn): Path<String>,
+    Json(req): Json<RollbackReq>,
+) -> Result<Response, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"]).map_err(|_| StatusCode::FORBIDDEN)?;
+    let Some(tag) = if_match(&headers) else {
+        return Ok(precondition_required());
+    };
+    let ctx = guards::audit_ctx(&sess, &peer, &headers);
+    match openvpn::rollback_ccd(&st, &ctx, &cn, req.rev, Some(&tag)).await {
+        Ok(rev) => Ok(Json(serde_json::json!({ "cn": cn, "rev": rev, "restored_from": req.rev })).into_response()),
+        Err(e) => {
+            let msg = e.to_string();
+            if msg.starts_with("not_found") {
+                return Err(StatusCode::NOT_FOUND);
+            }
+            if msg == "etag_mismatch" || msg.starts_with("template_linked: ") {
+                return ccd_write_error(&st, &cn, &e).await;
+            }
+            // e.g. an external edit that never passed validation
+            if let Some(detail) = msg.strip_prefix("ccd_invalid: ") {
+                return Ok((StatusCode::UNPROCESSABLE_ENTITY, Json(serde_json::json!({ "error": "ccd_invalid", "detail": detail })))
+                    .into_response());
+            }
+            tracing::error!("ccd rollback({}): {}", cn, msg);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct CcdTemplateLinkDto {
+    cn: String,
+    template: String,
+    vars: db::ccd_templates::Vars,
+    extra: String,
+    updated_by: String,
+    updated_at: i64,
+}
+
+async fn get_ccd_template(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    Path(cn): Path<String>,
+) -> Result<Json<CcdTemplateLinkDto>, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"]).map_err(|_| StatusCode::FORBIDDEN)?;
+    let l = db::ccd_templates::link(&st.db, &cn).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(CcdTemplateLinkDto {
+        cn: l.cn, template: l.template, vars: l.vars, extra: l.extra, updated_by: l.updated_by, updated_at: l.updated_at,
+    }))
+}
+
+#[derive(Deserialize)]
+struct CcdTemplateReq {
+    template: String,
+    /// Overrides the template's defaults.
+    #[serde(default)]
+    vars: db::ccd_templates::Vars,
+    /// CCD lines on top of the template; a once-only directive replaces the template's.
+    #[serde(default)]
+    extra: String,
+}
+
+/// Generates the CCD from a template and keeps it generated. `If-Match` as for PUT.
+async fn put_ccd_template(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(cn): Path<String>,
+    Json(req): Json<CcdTemplateReq>,
+) -> Result<Response, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"]).map_err(|_| StatusCode::FORBIDDEN)?;
+    let Some(tag) = if_match(&headers) else {
+        return Ok(precondition_required());
+    };
+    let ctx = guards::audit_ctx(&sess, &peer, &headers);
+    match openvpn::ccd_templates::attach(&st, &ctx, &cn, &req.template, &req.vars, &req.extra, Some(&tag)).await {
+        Ok(Ok(content)) => {
+            let etag = etag_value(&openvpn::ccd_etag(&content));
+            Ok(([(header::ETAG, etag)], Json(serde_json::json!({ "cn": cn, "template": req.template, "content": content })))
+                .into_response())
+        }
+        Ok(Err(lines)) => Ok((StatusCode::UNPROCESSABLE_ENTITY, Json(serde_json::json!({ "error": "ccd_invalid", "lines": lines })))
+            .into_response()),
+        Err(e) => {
+            let msg = e.to_string();
+            if msg == "unknown_template" || msg.to_lowercase().contains("invalid cn") {
+                let error = if msg == "unknown_template" { msg } else { "invalid_cn".into() };
+                return Ok((StatusCode::UNPROCESSABLE_ENTITY, Json(ErrorMsg { error })).into_response());
+            }
+            if let Some((error @ ("reserved_variable" | "invalid_variable"), detail)) = msg.split_once(": ") {
+                return Ok((StatusCode::UNPROCESSABLE_ENTITY, Json(serde_json::json!({ "error": error, "detail": detail })))
+                    .into_response());
+            }
+            ccd_write_error(&st, &cn, &e).await
+        }
+    }
+}
+
+/// Unlinks the CN; its CCD stays as it is.
+async fn delete_ccd_template(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(cn): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"]).map_err(|_| StatusCode::FORBIDDEN)?;
+    let ctx = guards::audit_ctx(&sess, &peer, &headers);
+    match openvpn::ccd_templates::detach(&st, &ctx, &cn).await {
+        Ok(()) => Ok(StatusCode::NO_CONTENT),
+        Err(e) if e.to_string().starts_with("not_found") => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            tracing::error!("detach ccd template({}): {}", cn, e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
 }
 
 async fn list_ccd(
@@ -207,7 +966,7 @@ async fn list_ccd(
 }
 
 #[derive(Deserialize)]
-struct IssuedQ { limit: Option<usize> }
+pub struct IssuedQ { limit: Option<usize> }
 
 pub async fn issued(
     State(st): State<AppState>,
@@ -221,13 +980,116 @@ pub async fn issued(
     Ok(Json(list))
 }
 
+#[derive(Deserialize)]
+struct ExpiringQ { within: Option<String> }
+
+/// `30d`, `12h`, `2w`, `90m`, `3600s`; a bare number is days.
+fn parse_window(s: &str) -> Option<i64> {
+    let s = s.trim();
+    let (num, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "d"),
+    };
+    let n: i64 = num.parse().ok()?;
+    let mult = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        "w" => 7 * 86400,
+        _ => return None,
+    };
+    n.checked_mul(mult).filter(|&v| v <= 3650 * 86400)
+}
+
+fn expiry_list(res: anyhow::Result<Vec<openvpn::expiry::Expiring>>) -> Response {
+    match res {
+        Ok(list) => Json(list).into_response(),
+        Err(e) => {
+            tracing::error!("expiry: {}", e);
+            (StatusCode::BAD_GATEWAY, Json(ErrorMsg { error: "daemon_error".into() })).into_response()
+        }
+    }
+}
+
+fn invalid_within() -> Response {
+    (StatusCode::BAD_REQUEST, Json(ErrorMsg { error: "invalid_within".into() })).into_response()
+}
+
+/// Valid certificates expiring within `within` (default 30 days).
+async fn expiring(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    Query(q): Query<ExpiringQ>,
+) -> Result<Response, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"]).map_err(|_| StatusCode::FORBIDDEN)?;
+    let Some(within) = q.within.as_deref().map_or(Some(30 * 86400), parse_window) else {
+        return Ok(invalid_within());
+    };
+    Ok(expiry_list(openvpn::expiry::expiring(&st, within).await))
+}
+
+/// Certificates that expired within the last `within` (default 30 days) and were not renewed.
+async fn expired(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    Query(q): Query<ExpiringQ>,
+) -> Result<Response, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"]).map_err(|_| StatusCode::FORBIDDEN)?;
+    let Some(within) = q.within.as_deref().map_or(Some(30 * 86400), parse_window) else {
+        return Ok(invalid_within());
+    };
+    Ok(expiry_list(openvpn::expiry::expired(&st, within).await))
+}
+
+#[derive(Serialize)]
+struct ProfileDto {
+    name: String,
+    key_types: Vec<&'static str>,
+    default_key_type: Option<&'static str>,
+    max_validity_days: u32,
+    default_validity_days: u32,
+    default: bool,
+}
+
+/// Profiles the caller may issue with.
+async fn profiles(
+    State(st): State<AppState>,
+    sess: AuthSession,
+) -> Result<Json<Vec<ProfileDto>>, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"]).map_err(|_| StatusCode::FORBIDDEN)?;
+    let cfg = &st.cfg.issuance;
+    Ok(Json(openvpn::profiles::usable(cfg, &sess.roles).into_iter().map(|p| ProfileDto {
+        name: p.name.clone(),
+        key_types: p.key_types.iter().map(|k| k.as_str()).collect(),
+        default_key_type: p.default_key().map(|k| k.as_str()),
+        max_validity_days: p.max_validity_days,
+        default_validity_days: p.default_validity(),
+        default: p.name == cfg.default_profile,
+    }).collect()))
+}
+
 pub fn routes() -> Router<AppState> {
     Router::new()
         .route("/admin/issued", axum::routing::get(issued))
+        .route("/admin/expiring", get(expiring))
+        .route("/admin/expired", get(expired))
+        .route("/admin/profiles", get(profiles))
         .route("/admin/clients", post(create_client))
+        .route("/admin/clients/import", post(import_clients))
         .route("/admin/clients/:cn/revoke", post(revoke_client))
+        .route("/admin/clients/:cn/unrevoke", post(unrevoke_client))
+        .route("/admin/revocations/bulk", post(bulk_revoke))
+        .route("/admin/clients/:cn/renew", post(renew_client))
+        .route("/admin/clients/:cn/csr", post(sign_csr))
         .route("/admin/clients/:cn/bundle", post(bundle))
         .route("/admin/ccd", get(list_ccd))
         .route("/admin/ccd/:cn", get(get_ccd).put(put_ccd))
+        .route("/admin/ccd/:cn/structured", get(get_ccd_structured).put(put_ccd_structured))
+        .route("/admin/ccd/:cn/revisions", get(ccd_revisions))
+        .route("/admin/ccd/:cn/revisions/:rev", get(ccd_revision))
+        .route("/admin/ccd/:cn/diff", get(ccd_diff))
+        .route("/admin/ccd/:cn/rollback", post(ccd_rollback))
+        .route("/admin/ccd/:cn/template", get(get_ccd_template).put(put_ccd_template).delete(delete_ccd_template))
 }
 
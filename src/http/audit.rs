@@ -0,0 +1,186 @@
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::Response,
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use time::OffsetDateTime;
+use tokio_stream::StreamExt;
+use tokio_util::io::ReaderStream;
+
+use crate::{audit::{chain, export}, db, http::guards::{self, AuthSession}, AppState};
+
+#[derive(Deserialize, Default)]
+pub struct AuditQuery {
+    actor: Option<String>,
+    /// Comma-separated list of actions.
+    action: Option<String>,
+    target: Option<String>,
+    ip: Option<String>,
+    since: Option<i64>,
+    until: Option<i64>,
+    q: Option<String>,
+    limit: Option<i64>,
+    cursor: Option<String>,
+    format: Option<String>,
+}
+
+fn non_empty(v: &Option<String>) -> Option<String> {
+    v.as_deref().map(str::trim).filter(|s| !s.is_empty()).map(str::to_string)
+}
+
+impl AuditQuery {
+    pub fn filter(&self) -> db::AuditFilter {
+        db::AuditFilter {
+            actor: non_empty(&self.actor),
+            actions: self.action.as_deref().unwrap_or("")
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(str::to_string)
+                .collect(),
+            target_prefix: non_empty(&self.target),
+            ip: non_empty(&self.ip),
+            since: self.since,
+            until: self.until,
+            text: non_empty(&self.q),
+        }
+    }
+}
+
+fn encode_cursor(ts: i64, id: &str) -> String {
+    format!("{ts}.{id}")
+}
+
+fn decode_cursor(c: &str) -> Option<(i64, String)> {
+    let (ts, id) = c.split_once('.')?;
+    Some((ts.parse().ok()?, id.to_string()))
+}
+
+#[derive(Serialize)]
+pub struct AuditDto {
+    id: String,
+    ts: i64,
+    actor_user: String,
+    action: String,
+    target: String,
+    ip: String,
+    ua: String,
+    request_id: String,
+    details: String,
+}
+
+impl From<db::AuditRow> for AuditDto {
+    fn from(r: db::AuditRow) -> Self {
+        AuditDto {
+            id: r.id, ts: r.ts, actor_user: r.actor_user, action: r.action, target: r.target,
+            ip: r.ip, ua: r.ua, request_id: r.request_id, details: r.details,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AuditPage {
+    items: Vec<AuditDto>,
+    /// Counted for the first page only; later pages page through the same set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total: Option<i64>,
+    next_cursor: Option<String>,
+}
+
+async fn audit_list(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    Query(q): Query<AuditQuery>,
+) -> Result<Json<AuditPage>, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"])?;
+    let after = match q.cursor.as_deref().filter(|c| !c.is_empty()) {
+        Some(c) => Some(decode_cursor(c).ok_or(StatusCode::BAD_REQUEST)?),
+        None => None,
+    };
+    let limit = q.limit.unwrap_or(50).clamp(1, 200);
+    let filter = q.filter();
+
+    let total = match after {
+        Some(_) => None,
+        None => Some(db::audit_count(&st.db, &filter).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?),
+    };
+    let rows = db::audit_search(&st.db, &filter, after, limit).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let next_cursor = if rows.len() as i64 == limit {
+        rows.last().map(|r| encode_cursor(r.ts, &r.id))
+    } else {
+        None
+    };
+
+    Ok(Json(AuditPage { items: rows.into_iter().map(AuditDto::from).collect(), total, next_cursor }))
+}
+
+async fn audit_export(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(q): Query<AuditQuery>,
+) -> Result<Response, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"])?;
+    let fmt = export::Format::parse(q.format.as_deref().unwrap_or("csv")).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let filter = q.filter();
+
+    let ctx = guards::audit_ctx(&sess, &peer, &headers);
+    ctx.record(&st.db, "AUDIT_EXPORT", "-", export::details(fmt, &filter))
+        .await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // rows are paged out of SQLite by a writer task; the pipe keeps at most 64 KiB in flight
+    let (mut tx, rx) = tokio::io::duplex(64 * 1024);
+    let (done_tx, mut done) = tokio::sync::oneshot::channel();
+    let db = st.db.clone();
+    tokio::spawn(async move {
+        let res = export::write_rows(&db, &filter, fmt, &mut tx).await;
+        if let Err(e) = &res {
+            tracing::error!("audit export: {}", e);
+        }
+        // sent before `tx` closes the pipe, so the body can end in the error
+        done_tx.send(res.err().map(|e| std::io::Error::other(e.to_string()))).ok();
+    });
+    // a failed export aborts the response instead of ending it like a complete file
+    let outcome = tokio_stream::iter([()]).filter_map(move |()| match done.try_recv() {
+        Ok(None) => None,
+        Ok(Some(e)) => Some(Err(e)),
+        Err(_) => Some(Err(std::io::Error::other("audit export stopped"))),
+    });
+
+    let filename = format!("audit-{}.{}", OffsetDateTime::now_utc().unix_timestamp(), fmt.extension());
+    let mut resp = Response::builder()
+        .status(StatusCode::OK)
+        .body(axum::body::boxed(Body::wrap_stream(ReaderStream::new(rx).chain(outcome))))
+        .unwrap();
+    resp.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static(fmt.content_type()));
+    resp.headers_mut().insert(
+        header::CONTENT_DISPOSITION,
+        HeaderValue::from_str(&format!("attachment; filename=\"{}\"", filename)).unwrap(),
+    );
+    Ok(resp)
+}
+
+async fn audit_verify(
+    State(st): State<AppState>,
+    sess: AuthSession,
+) -> Result<Json<chain::VerifyReport>, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"])?;
+    let report = chain::verify(&st.db).await.map_err(|e| {
+        tracing::error!("audit verify: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(report))
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/admin/audit", get(audit_list))
+        .route("/admin/audit/export", get(audit_export))
+        .route("/admin/audit/verify", get(audit_verify))
+}
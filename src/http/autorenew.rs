@@ -0,0 +1,203 @@
+use axum::{
+    extract::{ConnectInfo, Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, put},
+    Json, Router,
+};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::net::SocketAddr;
+
+use crate::{
+    db::{self, clients::{ClientPolicy, GroupPolicy}, renewals::Renewal},
+    http::{guards::{self, AuthSession}, ErrorMsg},
+    openvpn::autorenew,
+    AppState,
+};
+
+fn err(code: StatusCode, e: &str) -> Response {
+    (code, Json(ErrorMsg { error: e.into() })).into_response()
+}
+
+fn valid_group(g: &str) -> bool {
+    !g.is_empty() && g.len() <= 64 && g.chars().all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+}
+
+#[derive(Serialize)]
+struct PolicyDto {
+    clients: Vec<ClientPolicy>,
+    groups: Vec<GroupPolicy>,
+}
+
+async fn policies(
+    State(st): State<AppState>,
+    sess: AuthSession,
+) -> Result<Json<PolicyDto>, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"])?;
+    let clients = db::clients::policies(&st.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let groups = db::clients::groups(&st.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(PolicyDto { clients, groups }))
+}
+
+#[derive(Deserialize)]
+struct ClientGroupReq { group: Option<String> }
+
+async fn set_client_group(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(cn): Path<String>,
+    Json(req): Json<ClientGroupReq>,
+) -> Result<Response, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"])?;
+    let re = Regex::new(&st.cfg.ovpn.cn_pattern).unwrap();
+    if !re.is_match(&cn) { return Ok(err(StatusCode::UNPROCESSABLE_ENTITY, "invalid_cn")); }
+    let group = req.group.as_deref().map(str::trim).filter(|g| !g.is_empty());
+    if group.is_some_and(|g| !valid_group(g)) { return Ok(err(StatusCode::UNPROCESSABLE_ENTITY, "invalid_group")); }
+
+    let ctx = guards::audit_ctx(&sess, &peer, &headers);
+    db::clients::set_group(&st.db, &cn, group).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    ctx.record(&st.db, "CLIENT_SET_GROUP", &cn, json!({ "group": group })).await.ok();
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[derive(Deserialize)]
+struct ClientPolicyReq {
+    /// `null` follows the group.
+    enabled: Option<bool>,
+}
+
+async fn set_client_policy(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(cn): Path<String>,
+    Json(req): Json<ClientPolicyReq>,
+) -> Result<Response, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"])?;
+    let re = Regex::new(&st.cfg.ovpn.cn_pattern).unwrap();
+    if !re.is_match(&cn) { return Ok(err(StatusCode::UNPROCESSABLE_ENTITY, "invalid_cn")); }
+
+    let ctx = guards::audit_ctx(&sess, &peer, &headers);
+    db::clients::set_auto_renew(&st.db, &cn, req.enabled).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    ctx.record(&st.db, "AUTORENEW_SET", &cn, json!({ "scope": "client", "enabled": req.enabled })).await.ok();
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[derive(Deserialize)]
+struct GroupPolicyReq { enabled: bool }
+
+async fn set_group_policy(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    Json(req): Json<GroupPolicyReq>,
+) -> Result<Response, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"])?;
+    if !valid_group(&name) { return Ok(err(StatusCode::UNPROCESSABLE_ENTITY, "invalid_group")); }
+
+    let ctx = guards::audit_ctx(&sess, &peer, &headers);
+    db::clients::set_group_auto_renew(&st.db, &name, req.enabled).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    ctx.record(&st.db, "AUTORENEW_SET", &name, json!({ "scope": "group", "enabled": req.enabled })).await.ok();
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Dry run: what the job would renew if it ran now.
+async fn preview(
+    State(st): State<AppState>,
+    sess: AuthSession,
+) -> Result<Response, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"])?;
+    match autorenew::plan(&st, &st.cfg.autorenew).await {
+        Ok(list) => Ok(Json(list).into_response()),
+        Err(e) => {
+            tracing::error!("autorenew preview: {}", e);
+            Ok(err(StatusCode::BAD_GATEWAY, "daemon_error"))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RenewalsQ { limit: Option<i64> }
+
+async fn renewals(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    Query(q): Query<RenewalsQ>,
+) -> Result<Json<Vec<Renewal>>, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"])?;
+    let rows = db::renewals::list(&st.db, q.limit.unwrap_or(100)).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(rows))
+}
+
+/// The key passphrase for a renewal, so an admin can hand it over separately
+/// from the mailed link.
+async fn renewal_passphrase(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Response, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"])?;
+    let Some(r) = db::renewals::get(&st.db, &id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? else {
+        return Ok(err(StatusCode::NOT_FOUND, "not_found"));
+    };
+    match autorenew::passphrase(&st, &id).await {
+        Ok(Some(passphrase)) => {
+            let ctx = guards::audit_ctx(&sess, &peer, &headers);
+            ctx.record(&st.db, "RENEWAL_PASSPHRASE_VIEW", &r.cn, json!({ "renewal": r.id, "serial": r.new_serial })).await.ok();
+            Ok(Json(json!({ "cn": r.cn, "passphrase": passphrase })).into_response())
+        }
+        Ok(None) => Ok(err(StatusCode::NOT_FOUND, "not_found")),
+        Err(e) => {
+            tracing::error!("renewal passphrase {}: {}", id, e);
+            Ok(err(StatusCode::INTERNAL_SERVER_ERROR, "unavailable"))
+        }
+    }
+}
+
+/// Hands out a renewed bundle to whoever holds the mailed token; no session.
+/// The link works once and only until it expires; the passphrase is not included.
+async fn download(
+    State(st): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(token): Path<String>,
+) -> Result<Response, StatusCode> {
+    if token.len() != 43 || !token.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Ok(err(StatusCode::NOT_FOUND, "not_found"));
+    }
+    match autorenew::open_bundle(&st, &token).await {
+        Ok(Some((r, bundle))) => {
+            let ctx = guards::request_ctx("renewal-link", &peer, &headers);
+            ctx.record(&st.db, "RENEWAL_DOWNLOAD", &r.cn, json!({
+                "renewal": r.id, "serial": r.new_serial,
+            })).await.ok();
+            Ok(Json(bundle).into_response())
+        }
+        Ok(None) => Ok(err(StatusCode::NOT_FOUND, "not_found")),
+        Err(e) => {
+            tracing::error!("renewal download: {}", e);
+            Ok(err(StatusCode::INTERNAL_SERVER_ERROR, "unavailable"))
+        }
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/admin/autorenew", get(policies))
+        .route("/admin/autorenew/preview", get(preview))
+        .route("/admin/autorenew/renewals", get(renewals))
+        .route("/admin/autorenew/renewals/:id/passphrase", get(renewal_passphrase))
+        .route("/admin/autorenew/clients/:cn", put(set_client_policy))
+        .route("/admin/autorenew/groups/:name", put(set_group_policy))
+        .route("/admin/clients/:cn/group", put(set_client_group))
+        .route("/renewals/:token", get(download))
+}
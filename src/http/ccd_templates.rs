@@ -0,0 +1,166 @@
+use axum::{
+    extract::{ConnectInfo, Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::net::SocketAddr;
+
+use crate::{
+    db::{self, ccd_templates::Vars},
+    http::{guards::{self, AuthSession}, ErrorMsg},
+    openvpn::ccd_templates::{self, Rejected},
+    AppState,
+};
+
+fn template_error(e: &anyhow::Error) -> Response {
+    let msg = e.to_string();
+    let code = match msg.split(':').next().unwrap_or("") {
+        "not_found" => StatusCode::NOT_FOUND,
+        "template_in_use" => StatusCode::CONFLICT,
+        "invalid_template_name" | "reserved_variable" | "invalid_variable" => StatusCode::UNPROCESSABLE_ENTITY,
+        _ => {
+            tracing::error!("ccd template: {}", msg);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(ErrorMsg { error: "template_error".into() })).into_response();
+        }
+    };
+    if let Some((error, detail)) = msg.split_once(": ") {
+        return (code, Json(json!({ "error": error, "detail": detail }))).into_response();
+    }
+    (code, Json(ErrorMsg { error: msg })).into_response()
+}
+
+/// 422 `{error: "ccd_invalid", lines}` for the template itself, or
+/// `{error: "dependents_invalid", affected}` when linked CNs would break.
+fn rejected(r: Rejected) -> Response {
+    let body = match r {
+        Rejected::Template(lines) => json!({ "error": "ccd_invalid", "lines": lines }),
+        Rejected::Dependents(affected) => json!({ "error": "dependents_invalid", "affected": affected }),
+    };
+    (StatusCode::UNPROCESSABLE_ENTITY, Json(body)).into_response()
+}
+
+#[derive(Serialize)]
+struct TemplateDto {
+    name: String,
+    description: Option<String>,
+    defaults: Vars,
+    updated_by: String,
+    updated_at: i64,
+    clients: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    linked: Option<Vec<String>>,
+}
+
+impl TemplateDto {
+    fn new(t: db::ccd_templates::Template, clients: i64) -> Self {
+        TemplateDto {
+            name: t.name, description: t.description, defaults: t.defaults, updated_by: t.updated_by,
+            updated_at: t.updated_at, clients, body: None, linked: None,
+        }
+    }
+}
+
+async fn list(
+    State(st): State<AppState>,
+    sess: AuthSession,
+) -> Result<Json<Vec<TemplateDto>>, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"])?;
+    let counts = db::ccd_templates::link_counts(&st.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let list = db::ccd_templates::list(&st.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(list.into_iter().map(|t| {
+        let n = counts.get(&t.name).copied().unwrap_or(0);
+        TemplateDto::new(t, n)
+    }).collect()))
+}
+
+/// With the body and the CNs generated from it.
+async fn get_one(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    Path(name): Path<String>,
+) -> Result<Json<TemplateDto>, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"])?;
+    let t = db::ccd_templates::get(&st.db, &name).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let links = db::ccd_templates::links(&st.db, &name).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let body = t.body.clone();
+    let mut dto = TemplateDto::new(t, links.len() as i64);
+    dto.body = Some(body);
+    dto.linked = Some(links.into_iter().map(|l| l.cn).collect());
+    Ok(Json(dto))
+}
+
+#[derive(Deserialize)]
+struct TemplateBody {
+    description: Option<String>,
+    body: String,
+    /// Values for the template's own variables; clients may override them.
+    #[serde(default)]
+    defaults: Vars,
+}
+
+/// Per linked CN: `unchanged`, `changed` with a diff, or `skipped` or
+/// `invalid` with line errors.
+async fn preview(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    Path(name): Path<String>,
+    Json(req): Json<TemplateBody>,
+) -> Result<Response, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"])?;
+    Ok(match ccd_templates::preview(&st, &name, &req.body, &req.defaults).await {
+        Ok(Ok(affected)) => Json(json!({ "name": name, "affected": affected })).into_response(),
+        Ok(Err(r)) => rejected(r),
+        Err(e) => template_error(&e),
+    })
+}
+
+/// Creates or replaces the template and regenerates every linked CCD.
+async fn put(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    Json(req): Json<TemplateBody>,
+) -> Result<Response, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"])?;
+    let ctx = guards::audit_ctx(&sess, &peer, &headers);
+    let description = req.description.as_deref().map(str::trim).filter(|d| !d.is_empty());
+    Ok(match ccd_templates::save(&st, &ctx, &name, description, &req.body, &req.defaults).await {
+        Ok(Ok(saved)) => {
+            let code = if saved.created { StatusCode::CREATED } else { StatusCode::OK };
+            (code, Json(saved)).into_response()
+        }
+        Ok(Err(r)) => rejected(r),
+        Err(e) => template_error(&e),
+    })
+}
+
+async fn delete(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+) -> Result<Response, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"])?;
+    let ctx = guards::audit_ctx(&sess, &peer, &headers);
+    Ok(match ccd_templates::delete(&st, &ctx, &name).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => template_error(&e),
+    })
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/admin/ccd-templates", get(list))
+        .route("/admin/ccd-templates/:name", get(get_one).put(put).delete(delete))
+        .route("/admin/ccd-templates/:name/preview", post(preview))
+}
@@ -10,9 +10,8 @@ fn get_cookie(headers: &axum::http::HeaderMap, name: &str) -> Option<String> {
     let s = h.to_str().ok()?;
     for part in s.split(';') {
         let t = part.trim();
-        if let Some(eq) = t.find('=') {
-            if &t[..eq] == name { return Some(t[eq+1..].to_string()); }
-        }
+        if let Some(eq) = t.find('=')
+            && &t[..eq] == name { return Some(t[eq+1..].to_string()); }
     }
     None
 }
@@ -30,7 +29,7 @@ pub async fn issue_token() -> impl axum::response::IntoResponse {
     (StatusCode::NO_CONTENT, [(header::SET_COOKIE, v)])
 }
 
-pub async fn protect<B>(mut req: Request<B>, next: Next<B>) -> Result<Response, StatusCode> {
+pub async fn protect<B>(req: Request<B>, next: Next<B>) -> Result<Response, StatusCode> {
     use axum::http::Method;
     let m = req.method();
     let needs = matches!(m, &Method::POST | &Method::PUT | &Method::PATCH | &Method::DELETE);
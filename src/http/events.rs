@@ -0,0 +1,153 @@
+use axum::{
+    extract::State,
+    response::sse::{Event as SseEvent, KeepAlive, Sse},
+    routing::get,
+    Router,
+};
+use serde_json::json;
+use std::convert::Infallible;
+use std::time::Duration;
+use tokio::sync::{broadcast::{self, error::RecvError}, mpsc};
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+
+use crate::{events::{self, Event}, http::guards::{self, AuthSession}, AppState};
+
+/// How often an open stream re-checks its session, so logouts, disabled
+/// users and role changes take effect without a lookup per event.
+const REVALIDATE: Duration = Duration::from_secs(10);
+
+/// What `forward` hands one client's stream.
+#[derive(Debug)]
+enum Frame {
+    Event(Event),
+    /// The client missed this many events and should refetch.
+    Lagged(u64),
+    SessionEnded,
+}
+
+impl Frame {
+    fn sse(self) -> SseEvent {
+        match self {
+            Frame::Event(ev) => {
+                let out = SseEvent::default().event(ev.kind).json_data(&ev).unwrap();
+                if ev.id.is_empty() { out } else { out.id(&ev.id) }
+            }
+            Frame::Lagged(n) => SseEvent::default().event("lagged").json_data(json!({ "missed": n })).unwrap(),
+            Frame::SessionEnded => SseEvent::default().event("session_ended").data("{}"),
+        }
+    }
+}
+
+/// Live feed for the dashboard. Each SSE `event:` is the kind; `data:` is the
+/// whole event as JSON. Starts with the current health, and sends `lagged`
+/// when this client missed events and should refetch. Ends with
+/// `session_ended` once the session is no longer valid.
+async fn stream(State(st): State<AppState>, sess: AuthSession) -> Sse<impl Stream<Item = Result<SseEvent, Infallible>>> {
+    let rx = events::subscribe();
+    let (tx, out) = mpsc::channel(32);
+    let now = time::OffsetDateTime::now_utc().unix_timestamp();
+    if let Some(h) = events::last_health() {
+        let _ = tx.try_send(Frame::Event(Event { id: String::new(), kind: "health", ts: now, data: h }));
+    }
+    tokio::spawn(forward(st, sess, rx, tx, REVALIDATE));
+    let out = ReceiverStream::new(out).map(|f| Ok(f.sse()));
+    Sse::new(out).keep_alive(KeepAlive::new().interval(Duration::from_secs(15)))
+}
+
+/// Copies bus events the session may see into one client's stream until the
+/// client goes away or the session ends, re-checking the session `every` so.
+async fn forward(st: AppState, sess: AuthSession, mut rx: broadcast::Receiver<Event>, tx: mpsc::Sender<Frame>, every: Duration) {
+    let mut roles = sess.roles;
+    let mut tick = tokio::time::interval(every);
+    tick.tick().await;
+    loop {
+        let frame = tokio::select! {
+            _ = tx.closed() => return,
+            _ = tick.tick() => match guards::load_session(&st, &sess.sid).await {
+                Ok(Some(s)) => {
+                    roles = s.roles;
+                    continue;
+                }
+                Ok(None) => {
+                    let _ = tx.send(Frame::SessionEnded).await;
+                    return;
+                }
+                Err(e) => {
+                    tracing::warn!("events: revalidate session: {}", e);
+                    continue;
+                }
+            },
+            msg = rx.recv() => match msg {
+                Ok(ev) if ev.visible_to(&roles) => Frame::Event(ev),
+                Ok(_) => continue,
+                Err(RecvError::Lagged(n)) => Frame::Lagged(n),
+                Err(RecvError::Closed) => return,
+            },
+        };
+        if tx.send(frame).await.is_err() {
+            return;
+        }
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/events", get(stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    /// The next frame carrying `mark`, or the session ending; other tests
+    /// publish on the same bus.
+    async fn next(out: &mut mpsc::Receiver<Frame>, mark: &str) -> Option<Frame> {
+        let wait = async {
+            loop {
+                match out.recv().await? {
+                    Frame::Event(ev) if ev.data["mark"] != mark => continue,
+                    Frame::Lagged(_) => continue,
+                    f => return Some(f),
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(2), wait).await.ok().flatten()
+    }
+
+    fn kind(f: Option<Frame>) -> &'static str {
+        match f {
+            Some(Frame::Event(ev)) => ev.kind,
+            Some(Frame::SessionEnded) => "session_ended",
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn events_follow_the_sessions_roles() {
+        let st = crate::test_state().await;
+        let uid = db::create_user(&st.db, "olivia", "x").await.unwrap();
+        db::assign_role(&st.db, &uid, "OPS").await.unwrap();
+        let sid = db::create_session(&st.db, &uid, 900).await.unwrap();
+        let sess = AuthSession { sid: sid.clone(), user_id: uid.clone(), username: "olivia".into(), roles: vec!["OPS".into()] };
+        let (tx, mut out) = mpsc::channel(32);
+        let every = Duration::from_millis(20);
+        tokio::spawn(forward(st.clone(), sess, events::subscribe(), tx, every));
+
+        // Operators see sessions but not the audit log.
+        events::publish("audit", json!({ "mark": "m1" }));
+        events::publish("vpn.connect", json!({ "mark": "m1" }));
+        assert_eq!(kind(next(&mut out, "m1").await), "vpn.connect");
+
+        // A role granted mid-stream applies from the next check on.
+        db::assign_role(&st.db, &uid, "ADMIN").await.unwrap();
+        tokio::time::sleep(every * 5).await;
+        events::publish("audit", json!({ "mark": "m2" }));
+        assert_eq!(kind(next(&mut out, "m2").await), "audit");
+
+        // Logging out ends the stream at the next check.
+        db::delete_session(&st.db, &sid).await.unwrap();
+        assert_eq!(kind(next(&mut out, "m3").await), "session_ended");
+        assert!(out.recv().await.is_none());
+    }
+}
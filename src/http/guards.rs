@@ -4,12 +4,17 @@ use axum::{
 };
 use serde::Serialize;
 use sqlx::Row;
+use std::net::SocketAddr;
 use time::OffsetDateTime;
+use ulid::Ulid;
 
-use crate::{db, AppState};
+use crate::{audit::AuditCtx, db, AppState};
 
 #[derive(Debug, Clone, Serialize)]
 pub struct AuthSession {
+    /// The session cookie, for requests that outlive their first check.
+    #[serde(skip)]
+    pub sid: String,
     pub user_id: String,
     pub username: String,
     pub roles: Vec<String>,
@@ -20,9 +25,8 @@ fn get_cookie(headers: &HeaderMap, name: &str) -> Option<String> {
     let s = h.to_str().ok()?;
     for part in s.split(';') {
         let t = part.trim();
-        if let Some(eq) = t.find('=') {
-            if &t[..eq] == name { return Some(t[eq+1..].to_string()); }
-        }
+        if let Some(eq) = t.find('=')
+            && &t[..eq] == name { return Some(t[eq+1..].to_string()); }
     }
     None
 }
@@ -36,28 +40,38 @@ impl FromRequestParts<AppState> for AuthSession {
             return Err(StatusCode::UNAUTHORIZED);
         };
 
-        let Some(sess) = db::load_session(&state.db, &sid).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? else {
-            return Err(StatusCode::UNAUTHORIZED);
-        };
-
-        let now = OffsetDateTime::now_utc().unix_timestamp();
-        if now >= sess.expires_at {
-            return Err(StatusCode::UNAUTHORIZED);
-        }
+        load_session(state, &sid).await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .ok_or(StatusCode::UNAUTHORIZED)
+    }
+}
 
-        let row = sqlx::query("SELECT username FROM users WHERE id=?")
-            .bind(&sess.user_id)
-            .fetch_one(&state.db)
-            .await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-        let username: String = row.try_get(0).unwrap();
+/// The session behind `sid` with its user's current roles, or `None` once it
+/// expired, was logged out or its user was disabled.
+pub async fn load_session(state: &AppState, sid: &str) -> anyhow::Result<Option<AuthSession>> {
+    let Some(sess) = db::load_session(&state.db, sid).await? else {
+        return Ok(None);
+    };
 
-        let roles = db::roles_for_user(&state.db, &sess.user_id)
-            .await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    if now >= sess.expires_at {
+        return Ok(None);
+    }
 
-        Ok(AuthSession { user_id: sess.user_id, username, roles })
+    let Some(row) = sqlx::query("SELECT username, disabled FROM users WHERE id=?")
+        .bind(&sess.user_id)
+        .fetch_optional(&state.db)
+        .await? else {
+        return Ok(None);
+    };
+    let username: String = row.try_get(0).unwrap();
+    if row.try_get::<i64, _>(1).unwrap() != 0 {
+        return Ok(None);
     }
+
+    let roles = db::roles_for_user(&state.db, &sess.user_id).await?;
+
+    Ok(Some(AuthSession { sid: sid.to_string(), user_id: sess.user_id, username, roles }))
 }
 
 pub fn ensure_role(sess: &AuthSession, required: &[&str]) -> Result<(), StatusCode> {
@@ -68,3 +82,24 @@ pub fn ensure_role(sess: &AuthSession, required: &[&str]) -> Result<(), StatusCo
     }
     Err(StatusCode::FORBIDDEN)
 }
+
+/// Audit context for a request made by `sess`. A well-formed `X-Request-Id`
+/// from the reverse proxy is kept so rows can be matched with its logs.
+pub fn audit_ctx(sess: &AuthSession, peer: &SocketAddr, headers: &HeaderMap) -> AuditCtx {
+    request_ctx(&sess.username, peer, headers)
+}
+
+/// Like `audit_ctx`, for requests authenticated by something other than a
+/// session (hook tokens, mailed links).
+pub fn request_ctx(actor: &str, peer: &SocketAddr, headers: &HeaderMap) -> AuditCtx {
+    let ua = headers.get(axum::http::header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("-")
+        .to_string();
+    let request_id = headers.get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+        .filter(|s| !s.is_empty() && s.len() <= 128 && s.chars().all(|c| c.is_ascii_graphic()))
+        .map(str::to_string)
+        .unwrap_or_else(|| Ulid::new().to_string());
+    AuditCtx { actor: actor.to_string(), ip: peer.ip().to_string(), ua, request_id }
+}
@@ -0,0 +1,87 @@
+use axum::{
+    extract::{ConnectInfo, Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::net::SocketAddr;
+
+use crate::{http::{guards::{self, AuthSession}, ErrorMsg}, openvpn::ipam, AppState};
+
+fn ipam_error(e: &anyhow::Error) -> Response {
+    let msg = e.to_string();
+    let code = match msg.split(':').next().unwrap_or("") {
+        "ipam_not_configured" => StatusCode::NOT_FOUND,
+        "already_allocated" | "ip_unavailable" | "pool_exhausted" | "static_ip_in_ccd" | "template_linked" => StatusCode::CONFLICT,
+        "not_allocated" => StatusCode::NOT_FOUND,
+        "invalid_ip" | "ip_outside_pool" | "ccd_invalid" => StatusCode::UNPROCESSABLE_ENTITY,
+        _ if msg.to_lowercase().contains("invalid cn") => StatusCode::UNPROCESSABLE_ENTITY,
+        _ => {
+            tracing::error!("ipam: {}", msg);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(ErrorMsg { error: "ipam_error".into() })).into_response();
+        }
+    };
+    if let Some((error, detail)) = msg.split_once(": ") {
+        return (code, Json(json!({ "error": error, "detail": detail }))).into_response();
+    }
+    (code, Json(ErrorMsg { error: msg })).into_response()
+}
+
+/// Pool size, who holds what, and addresses claimed twice or out of range.
+async fn usage(
+    State(st): State<AppState>,
+    sess: AuthSession,
+) -> Result<Response, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"])?;
+    Ok(match ipam::usage(&st).await {
+        Ok(u) => Json(u).into_response(),
+        Err(e) => ipam_error(&e),
+    })
+}
+
+#[derive(Deserialize, Default)]
+struct ReserveReq {
+    /// A specific address; the next free one when omitted.
+    ip: Option<String>,
+}
+
+async fn reserve(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(cn): Path<String>,
+    body: Option<Json<ReserveReq>>,
+) -> Result<Response, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"])?;
+    let req = body.map(|Json(r)| r).unwrap_or_default();
+    let ctx = guards::audit_ctx(&sess, &peer, &headers);
+    Ok(match ipam::reserve(&st, &ctx, &cn, req.ip.as_deref()).await {
+        Ok(r) => (StatusCode::CREATED, Json(r)).into_response(),
+        Err(e) => ipam_error(&e),
+    })
+}
+
+async fn release(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(cn): Path<String>,
+) -> Result<Response, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"])?;
+    let ctx = guards::audit_ctx(&sess, &peer, &headers);
+    Ok(match ipam::release(&st, &ctx, &cn).await {
+        Ok(ip) => Json(json!({ "cn": cn, "released": ip })).into_response(),
+        Err(e) => ipam_error(&e),
+    })
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/admin/ipam", get(usage))
+        .route("/admin/ipam/:cn", post(reserve).delete(release))
+}
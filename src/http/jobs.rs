@@ -0,0 +1,164 @@
+use axum::{
+    extract::{ConnectInfo, Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::Response,
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+
+use crate::{db::jobs as store, http::guards::{self, AuthSession}, jobs, openvpn, AppState};
+
+#[derive(Serialize)]
+struct JobDto {
+    id: String,
+    kind: String,
+    status: String,
+    total: i64,
+    done: i64,
+    failed: i64,
+    params: Value,
+    result: Value,
+    error: Option<String>,
+    created_by: String,
+    created_at: i64,
+    started_at: Option<i64>,
+    finished_at: Option<i64>,
+    cancel_requested: bool,
+}
+
+#[derive(Serialize)]
+struct JobItemDto {
+    idx: i64,
+    item: String,
+    status: String,
+    detail: Value,
+}
+
+fn parse(s: Option<&str>) -> Value {
+    s.and_then(|s| serde_json::from_str(s).ok()).unwrap_or(Value::Null)
+}
+
+impl From<store::Job> for JobDto {
+    fn from(j: store::Job) -> Self {
+        JobDto {
+            params: parse(Some(&j.params)),
+            result: parse(j.result.as_deref()),
+            id: j.id, kind: j.kind, status: j.status, total: j.total, done: j.done, failed: j.failed,
+            error: j.error, created_by: j.created_by, created_at: j.created_at, started_at: j.started_at,
+            finished_at: j.finished_at, cancel_requested: j.cancel_requested,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    limit: Option<i64>,
+}
+
+async fn list_jobs(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    Query(q): Query<ListQuery>,
+) -> Result<Json<Vec<JobDto>>, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"])?;
+    let limit = q.limit.unwrap_or(50).clamp(1, 500);
+    let list = store::list(&st.db, limit).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(list.into_iter().map(JobDto::from).collect()))
+}
+
+async fn get_job(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    Path(id): Path<String>,
+) -> Result<Json<JobDto>, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"])?;
+    let j = store::get(&st.db, &id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(j.into()))
+}
+
+/// Queued jobs stop at once (200); running ones after their current item (202).
+async fn cancel_job(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<(StatusCode, Json<Value>), StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"])?;
+    let j = store::get(&st.db, &id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let Some(status) = store::request_cancel(&st.db, &id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? else {
+        return Ok((StatusCode::CONFLICT, Json(json!({ "error": "job_finished", "status": j.status }))));
+    };
+    jobs::announce(&st, &id).await;
+    let ctx = guards::audit_ctx(&sess, &peer, &headers);
+    ctx.record(&st.db, "JOB_CANCEL", &id, json!({ "kind": j.kind, "status": status })).await.ok();
+    let code = if status == "running" { StatusCode::ACCEPTED } else { StatusCode::OK };
+    Ok((code, Json(json!({ "job": id, "status": status }))))
+}
+
+async fn job_items(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<JobItemDto>>, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"])?;
+    if store::get(&st.db, &id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?.is_none() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let items = store::items(&st.db, &id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(items.into_iter().map(|i| JobItemDto {
+        detail: parse(Some(&i.detail)), idx: i.idx, item: i.item, status: i.status,
+    }).collect()))
+}
+
+/// Downloads a file a finished job listed in `result.files`. Any admin may,
+/// including for jobs started from the CLI, until `[jobs].file_ttl_hours`
+/// after the job finished; every download is audited.
+async fn job_file(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path((id, name)): Path<(String, String)>,
+) -> Result<Response, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"])?;
+    let j = store::get(&st.db, &id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let listed = parse(j.result.as_deref())["files"].as_array()
+        .is_some_and(|f| f.iter().any(|f| f.as_str() == Some(name.as_str())));
+    if !listed {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let path = jobs::dir(&st, &id).join(&name);
+    // Purged after `[jobs].file_ttl_hours`.
+    let (mut stream_headers, body) = openvpn::stream_file(&path.to_string_lossy()).await.map_err(|_| StatusCode::GONE)?;
+    let ctx = guards::audit_ctx(&sess, &peer, &headers);
+    ctx.record(&st.db, "JOB_FILE_DOWNLOAD", &id, json!({ "kind": j.kind, "file": name })).await.ok();
+    if let Some(mime) = mime_guess::from_path(&name).first_raw() {
+        stream_headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(mime));
+    }
+    let mut resp = Response::builder()
+        .status(StatusCode::OK)
+        .body(axum::body::boxed(body))
+        .unwrap();
+    resp.headers_mut().extend(stream_headers);
+    resp.headers_mut().insert(
+        header::CONTENT_DISPOSITION,
+        HeaderValue::from_str(&format!("attachment; filename=\"{id}-{name}\"")).unwrap(),
+    );
+    Ok(resp)
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/jobs", get(list_jobs))
+        .route("/jobs/:id", get(get_job))
+        .route("/jobs/:id/cancel", post(cancel_job))
+        .route("/jobs/:id/items", get(job_items))
+        .route("/jobs/:id/files/:name", get(job_file))
+}
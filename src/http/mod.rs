@@ -1,13 +1,22 @@
-use axum::{middleware, routing::{get, post}, Json, Router};
+use axum::{middleware, routing::get, Json, Router};
 use axum::extract::State;
 use tower_http::set_header::SetResponseHeaderLayer;
-use axum::http::{HeaderValue, StatusCode};
+use axum::http::HeaderValue;
 use axum::response::Redirect;
 use serde_json::{json, Value};
-use crate::{openvpn, AppState};
-pub mod auth; pub mod guards; pub mod csrf; pub mod admin;
+use crate::AppState;
+pub mod auth; pub mod guards; pub mod csrf; pub mod admin; pub mod audit; pub mod webhooks; pub mod autorenew; pub mod jobs; pub mod events; pub mod ipam; pub mod ccd_templates; pub mod vpn;
+
+/// The `{"error": "..."}` body API errors share.
+#[derive(serde::Serialize)]
+pub(crate) struct ErrorMsg { pub error: String }
 
 pub async fn health(State(st): State<AppState>) -> Json<Value> {
+    Json(health_status(&st).await)
+}
+
+/// Shared with the health monitor that feeds `/api/events`.
+pub async fn health_status(st: &AppState) -> Value {
     let api_ok = true;
 
     let daemon_ok = crate::vpncertd::health(&st.cfg.ovpn.socket_path)
@@ -15,11 +24,29 @@ pub async fn health(State(st): State<AppState>) -> Json<Value> {
         .is_ok();
     let agent_ok = false;
 
-    Json(json!({
+    json!({
         "api":    { "ok": api_ok },
         "daemon": { "ok": daemon_ok },
         "agent":  { "ok": agent_ok }
-    }))
+    })
+}
+
+/// Prometheus text exposition of the panel's own counters. Scrapers do not
+/// log in, so sinks are labelled by their index in `audit.sinks` and kind only.
+async fn metrics() -> String {
+    let mut out = String::new();
+    out.push_str("# TYPE ovpn_admin_audit_sink_sent_total counter\n");
+    out.push_str("# TYPE ovpn_admin_audit_sink_dropped_total counter\n");
+    out.push_str("# TYPE ovpn_admin_audit_sink_errors_total counter\n");
+    for (i, (kind, s)) in crate::audit::sink::stats().into_iter().enumerate() {
+        for (metric, v) in [("sent", &s.sent), ("dropped", &s.dropped), ("errors", &s.errors)] {
+            out.push_str(&format!(
+                "ovpn_admin_audit_sink_{metric}_total{{sink=\"{i}\",kind=\"{kind}\"}} {}\n",
+                v.load(std::sync::atomic::Ordering::Relaxed)
+            ));
+        }
+    }
+    out
 }
 
 async fn admin_ping(sess: guards::AuthSession) -> Result<&'static str, axum::http::StatusCode> {
@@ -56,11 +83,20 @@ pub fn router() -> Router<AppState> {
 
     let api = Router::new()
         .route("/health", get(health))
+        .route("/metrics", get(metrics))
         .route("/protected/admin-ping", get(admin_ping))
         .route("/auth/csrf", get(csrf::issue_token))
         .merge(auth::routes())
         .merge(admin::routes())
-        .layer(middleware::from_fn(csrf::protect));
+        .merge(audit::routes())
+        .merge(webhooks::routes())
+        .merge(autorenew::routes())
+        .merge(jobs::routes())
+        .merge(events::routes())
+        .merge(ipam::routes())
+        .merge(ccd_templates::routes())
+        .layer(middleware::from_fn(csrf::protect))
+        .merge(vpn::routes());
 
     Router::new()
         .route("/", get(|| async { Redirect::permanent("/ui/") }))
@@ -0,0 +1,83 @@
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    routing::post,
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+use crate::{events, openvpn::autorenew, AppState};
+
+/// Sent by the OpenVPN `client-connect` script (see contrib/openvpn).
+#[derive(Deserialize)]
+struct ConnectReq {
+    cn: String,
+    /// `tls_serial_0`, decimal.
+    serial: String,
+    ip: Option<String>,
+}
+
+/// Sent by the `client-disconnect` script; counters as OpenVPN reports them.
+#[derive(Deserialize)]
+struct DisconnectReq {
+    cn: String,
+    serial: Option<String>,
+    ip: Option<String>,
+    bytes_received: Option<u64>,
+    bytes_sent: Option<u64>,
+    duration_secs: Option<u64>,
+}
+
+fn hook_authorized(st: &AppState, headers: &HeaderMap) -> Result<(), StatusCode> {
+    let Some(expected) = st.cfg.ovpn.hook_token.as_deref().filter(|t| !t.is_empty()) else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let presented = headers.get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or("");
+    if presented.len() == expected.len() && openssl::memcmp::eq(presented.as_bytes(), expected.as_bytes()) {
+        Ok(())
+    } else {
+        Err(StatusCode::UNAUTHORIZED)
+    }
+}
+
+async fn connect(
+    State(st): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<ConnectReq>,
+) -> Result<StatusCode, StatusCode> {
+    hook_authorized(&st, &headers)?;
+    if req.serial.is_empty() || !req.serial.chars().all(|c| c.is_ascii_digit()) {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    autorenew::on_connect(&st, &req.cn, &req.serial, req.ip.as_deref())
+        .await
+        .map_err(|e| {
+            tracing::error!(cn=%req.cn, serial=%req.serial, "vpn connect hook: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    events::publish("vpn.connect", json!({ "cn": req.cn, "serial": req.serial, "ip": req.ip }));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn disconnect(
+    State(st): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<DisconnectReq>,
+) -> Result<StatusCode, StatusCode> {
+    hook_authorized(&st, &headers)?;
+    events::publish("vpn.disconnect", json!({
+        "cn": req.cn, "serial": req.serial, "ip": req.ip,
+        "bytes_received": req.bytes_received, "bytes_sent": req.bytes_sent, "duration_secs": req.duration_secs,
+    }));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Token-authenticated, so these routes sit outside the CSRF layer.
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/vpn/connect", post(connect))
+        .route("/vpn/disconnect", post(disconnect))
+}
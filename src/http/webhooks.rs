@@ -0,0 +1,218 @@
+use axum::{
+    extract::{ConnectInfo, Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use base64::Engine;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::net::SocketAddr;
+
+use crate::{db::webhooks as store, http::{guards::{self, AuthSession}, ErrorMsg}, webhooks, AppState};
+
+fn err(code: StatusCode, e: &str) -> Response {
+    (code, Json(ErrorMsg { error: e.into() })).into_response()
+}
+
+#[derive(Serialize)]
+struct WebhookDto {
+    id: String,
+    url: String,
+    events: Vec<String>,
+    enabled: bool,
+    created_by: String,
+    created_at: i64,
+}
+
+impl From<store::Webhook> for WebhookDto {
+    fn from(h: store::Webhook) -> Self {
+        WebhookDto { id: h.id, url: h.url, events: h.events, enabled: h.enabled, created_by: h.created_by, created_at: h.created_at }
+    }
+}
+
+#[derive(Serialize)]
+struct WebhookCreated {
+    #[serde(flatten)]
+    hook: WebhookDto,
+    /// Only when the panel generated it, and only in this response; a secret
+    /// supplied by the caller is never echoed back.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NewWebhook {
+    url: String,
+    events: Vec<String>,
+    secret: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct WebhookPatch {
+    url: Option<String>,
+    events: Option<Vec<String>>,
+    enabled: Option<bool>,
+}
+
+fn valid_url(u: &str) -> bool {
+    (u.starts_with("https://") || u.starts_with("http://")) && u.len() <= 2048 && !u.chars().any(char::is_whitespace)
+}
+
+fn valid_events(ev: &[String]) -> bool {
+    !ev.is_empty() && ev.iter().all(|e| e == "*" || webhooks::EVENTS.contains(&e.as_str()))
+}
+
+async fn list(
+    State(st): State<AppState>,
+    sess: AuthSession,
+) -> Result<Json<Vec<WebhookDto>>, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"])?;
+    let hooks = store::list(&st.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(hooks.into_iter().map(WebhookDto::from).collect()))
+}
+
+async fn create(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<NewWebhook>,
+) -> Result<Response, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"])?;
+    if !valid_url(&req.url) { return Ok(err(StatusCode::UNPROCESSABLE_ENTITY, "invalid_url")); }
+    if !valid_events(&req.events) { return Ok(err(StatusCode::UNPROCESSABLE_ENTITY, "invalid_events")); }
+    let (secret, generated) = match req.secret.filter(|s| !s.is_empty()) {
+        Some(s) if s.len() < 16 => return Ok(err(StatusCode::UNPROCESSABLE_ENTITY, "secret_too_short")),
+        Some(s) => (s, false),
+        None => {
+            let mut raw = [0u8; 32];
+            rand::rngs::OsRng.fill_bytes(&mut raw);
+            (base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(raw), true)
+        }
+    };
+
+    let ctx = guards::audit_ctx(&sess, &peer, &headers);
+    let id = store::create(&st.db, &req.url, &req.events, &secret, &sess.username)
+        .await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    ctx.record(&st.db, "WEBHOOK_CREATE", &id, json!({ "url": req.url, "events": req.events })).await.ok();
+
+    let hook = store::get(&st.db, &id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+    let mut resp = Json(WebhookCreated { hook: hook.into(), secret: generated.then_some(secret) }).into_response();
+    *resp.status_mut() = StatusCode::CREATED;
+    Ok(resp)
+}
+
+async fn update(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Json(req): Json<WebhookPatch>,
+) -> Result<Response, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"])?;
+    let Some(cur) = store::get(&st.db, &id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? else {
+        return Ok(err(StatusCode::NOT_FOUND, "not_found"));
+    };
+    let url = req.url.unwrap_or(cur.url);
+    let events = req.events.unwrap_or(cur.events);
+    let enabled = req.enabled.unwrap_or(cur.enabled);
+    if !valid_url(&url) { return Ok(err(StatusCode::UNPROCESSABLE_ENTITY, "invalid_url")); }
+    if !valid_events(&events) { return Ok(err(StatusCode::UNPROCESSABLE_ENTITY, "invalid_events")); }
+
+    let ctx = guards::audit_ctx(&sess, &peer, &headers);
+    store::update(&st.db, &id, &url, &events, enabled).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    ctx.record(&st.db, "WEBHOOK_UPDATE", &id, json!({ "url": url, "events": events, "enabled": enabled })).await.ok();
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+async fn remove(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Response, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"])?;
+    let ctx = guards::audit_ctx(&sess, &peer, &headers);
+    if !store::delete(&st.db, &id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? {
+        return Ok(err(StatusCode::NOT_FOUND, "not_found"));
+    }
+    ctx.record(&st.db, "WEBHOOK_DELETE", &id, json!({})).await.ok();
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+async fn test(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Response, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"])?;
+    let Some(hook) = store::get(&st.db, &id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? else {
+        return Ok(err(StatusCode::NOT_FOUND, "not_found"));
+    };
+    let ctx = guards::audit_ctx(&sess, &peer, &headers);
+    let delivery = webhooks::ping(&st.db, &hook, &ctx).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok((StatusCode::ACCEPTED, Json(json!({ "delivery": delivery }))).into_response())
+}
+
+#[derive(Deserialize)]
+struct DeliveriesQ { limit: Option<i64> }
+
+#[derive(Serialize)]
+struct DeliveryDto {
+    id: String,
+    event: String,
+    status: String,
+    attempts: i64,
+    next_attempt_at: i64,
+    last_status: Option<i64>,
+    last_error: Option<String>,
+    created_at: i64,
+    delivered_at: Option<i64>,
+    payload: serde_json::Value,
+}
+
+async fn deliveries(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    Path(id): Path<String>,
+    Query(q): Query<DeliveriesQ>,
+) -> Result<Json<Vec<DeliveryDto>>, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"])?;
+    let rows = store::deliveries(&st.db, &id, q.limit.unwrap_or(50))
+        .await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(rows.into_iter().map(|d| DeliveryDto {
+        payload: serde_json::from_str(&d.payload).unwrap_or(serde_json::Value::Null),
+        id: d.id, event: d.event, status: d.status, attempts: d.attempts, next_attempt_at: d.next_attempt_at,
+        last_status: d.last_status, last_error: d.last_error, created_at: d.created_at, delivered_at: d.delivered_at,
+    }).collect()))
+}
+
+async fn redeliver(
+    State(st): State<AppState>,
+    sess: AuthSession,
+    Path((id, delivery)): Path<(String, String)>,
+) -> Result<StatusCode, StatusCode> {
+    guards::ensure_role(&sess, &["ADMIN"])?;
+    if store::redeliver(&st.db, &id, &delivery).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? {
+        Ok(StatusCode::ACCEPTED)
+    } else {
+        Err(StatusCode::CONFLICT)
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/admin/webhooks", get(list).post(create))
+        .route("/admin/webhooks/:id", axum::routing::put(update).delete(remove))
+        .route("/admin/webhooks/:id/test", post(test))
+        .route("/admin/webhooks/:id/deliveries", get(deliveries))
+        .route("/admin/webhooks/:id/deliveries/:delivery/redeliver", post(redeliver))
+}
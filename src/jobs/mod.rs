@@ -0,0 +1,301 @@
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::{audit::AuditCtx, db, events, openvpn, AppState};
+
+static WAKE: OnceLock<Notify> = OnceLock::new();
+static OWNER: OnceLock<String> = OnceLock::new();
+
+/// How long a claimed job stays ours without a heartbeat.
+const LEASE_SECS: i64 = 60;
+const HEARTBEAT: Duration = Duration::from_secs(20);
+/// A job whose lease another process took over stops with this error.
+const LEASE_LOST: &str = "lease_lost";
+
+fn wake() -> &'static Notify {
+    WAKE.get_or_init(Notify::new)
+}
+
+/// Identifies this process as a lease holder.
+fn owner() -> &'static str {
+    OWNER.get_or_init(|| format!("{}:{}", std::process::id(), ulid::Ulid::new()))
+}
+
+fn lease_until() -> i64 {
+    time::OffsetDateTime::now_utc().unix_timestamp() + LEASE_SECS
+}
+
+/// A claimed job as seen by the code doing its work.
+pub struct JobRun {
+    pub id: String,
+    pub ctx: AuditCtx,
+    pub params: Value,
+    done: HashSet<i64>,
+    started: HashSet<i64>,
+}
+
+impl JobRun {
+    /// Whether `idx` finished before a restart.
+    pub fn is_done(&self, idx: usize) -> bool {
+        self.done.contains(&(idx as i64))
+    }
+
+    /// Whether work on `idx` began before a restart without recording a
+    /// result, so it may or may not have taken effect.
+    pub fn interrupted(&self, idx: usize) -> bool {
+        self.started.contains(&(idx as i64))
+    }
+
+    pub fn resumed(&self) -> bool {
+        !self.done.is_empty() || !self.started.is_empty()
+    }
+
+    /// Call before an item's side effects, so a resume can tell it apart
+    /// from one never tried. Fails once another process holds the job, so
+    /// the item is not done twice.
+    pub async fn start_item(&self, st: &AppState, idx: usize, item: &str) -> Result<()> {
+        if !db::jobs::renew_lease(&st.db, &self.id, owner(), lease_until()).await? {
+            return Err(anyhow!(LEASE_LOST));
+        }
+        db::jobs::item_started(&st.db, &self.id, idx as i64, item).await
+    }
+
+    pub async fn item(&self, st: &AppState, idx: usize, item: &str, status: &str, detail: &Value) -> Result<()> {
+        db::jobs::item_result(&st.db, &self.id, idx as i64, item, status, &detail.to_string()).await?;
+        announce(st, &self.id).await;
+        Ok(())
+    }
+
+    /// Checked between items; a job that stops early says so with
+    /// `"cancelled": true` in its result.
+    pub async fn cancelled(&self, st: &AppState) -> bool {
+        db::jobs::cancel_requested(&st.db, &self.id).await.unwrap_or(false)
+    }
+
+    /// Item counts by status across runs, for results that survive a resume.
+    pub async fn tally(&self, st: &AppState, status: &str) -> Result<i64> {
+        Ok(db::jobs::items(&st.db, &self.id).await?.iter().filter(|i| i.status == status).count() as i64)
+    }
+
+    pub fn dir(&self, st: &AppState) -> PathBuf {
+        dir(st, &self.id)
+    }
+}
+
+/// Where a job keeps the files listed in its result.
+pub fn dir(st: &AppState, id: &str) -> PathBuf {
+    PathBuf::from(&st.cfg.jobs.dir).join(id)
+}
+
+/// Job files hold passphrases and keys, so everything is owner-only.
+pub async fn private_dir(dir: &std::path::Path) -> Result<()> {
+    let mut b = tokio::fs::DirBuilder::new();
+    b.recursive(true);
+    #[cfg(unix)]
+    b.mode(0o700);
+    b.create(dir).await?;
+    Ok(())
+}
+
+/// Files are created owner-only rather than narrowed after the write.
+pub fn private_options() -> tokio::fs::OpenOptions {
+    let mut o = tokio::fs::OpenOptions::new();
+    o.create(true).write(true);
+    #[cfg(unix)]
+    o.mode(0o600);
+    o
+}
+
+pub async fn write_private(dir: &std::path::Path, name: &str, bytes: &[u8]) -> Result<()> {
+    use tokio::io::AsyncWriteExt;
+    let path = dir.join(name);
+    // A file an interrupted run left behind keeps whatever mode it had.
+    tokio::fs::remove_file(&path).await.ok();
+    let mut f = private_options().create_new(true).open(&path).await?;
+    f.write_all(bytes).await?;
+    f.flush().await?;
+    Ok(())
+}
+
+/// Publishes the job's status and counters to `/api/events`.
+pub async fn announce(st: &AppState, id: &str) {
+    if let Ok(Some(j)) = db::jobs::get(&st.db, id).await {
+        events::publish("job", json!({
+            "id": j.id, "kind": j.kind, "status": j.status, "total": j.total, "done": j.done, "failed": j.failed,
+            "cancel_requested": j.cancel_requested,
+        }));
+    }
+}
+
+/// Queues a job for the workers; `total` is the number of items.
+pub async fn enqueue(st: &AppState, ctx: &AuditCtx, kind: &str, total: usize, params: &Value) -> Result<String> {
+    let id = db::jobs::create(&st.db, kind, total as i64, &params.to_string(), &serde_json::to_string(ctx)?, &ctx.actor).await?;
+    wake().notify_one();
+    announce(st, &id).await;
+    Ok(id)
+}
+
+/// Runs a queued job in the calling task, e.g. from the CLI. Returns `false`
+/// if a worker got to it first.
+pub async fn run_now(st: &AppState, id: &str) -> Result<bool> {
+    match db::jobs::claim(&st.db, Some(id), owner(), lease_until()).await? {
+        Some(job) => {
+            execute(st, job).await;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+async fn execute(st: &AppState, job: db::jobs::Job) {
+    let ctx = job.ctx.as_deref().and_then(|c| serde_json::from_str(c).ok()).unwrap_or_else(AuditCtx::system);
+    let run = JobRun {
+        params: serde_json::from_str(&job.params).unwrap_or(Value::Null),
+        done: db::jobs::done_indices(&st.db, &job.id).await.unwrap_or_default(),
+        started: db::jobs::started_indices(&st.db, &job.id).await.unwrap_or_default(),
+        id: job.id.clone(),
+        ctx,
+    };
+    announce(st, &run.id).await;
+    if !run.done.is_empty() {
+        tracing::info!(job=%run.id, kind=%job.kind, "resuming after {} items", run.done.len());
+    }
+    let work = async {
+        match job.kind.as_str() {
+            "revoke" => openvpn::bulk::run(st, &run).await,
+            "import" => openvpn::import::run(st, &run).await,
+            "bundle" => openvpn::bundle_job(st, &run).await,
+            other => Err(anyhow!("unknown job kind '{other}'")),
+        }
+    };
+    // The work is dropped as soon as the lease is gone; the process that
+    // took the job over resumes it and records the outcome.
+    let res = tokio::select! {
+        res = work => res,
+        () = hold_lease(&st.db, &run.id, owner(), HEARTBEAT) => Err(anyhow!(LEASE_LOST)),
+    };
+    if res.as_ref().is_err_and(|e| e.to_string() == LEASE_LOST) {
+        tracing::error!(job=%run.id, kind=%job.kind, "jobs: lease lost to another process, stopped");
+        return;
+    }
+    let saved = match res {
+        Ok(result) => {
+            let status = if result["cancelled"] == true { "cancelled" } else { "done" };
+            db::jobs::finish(&st.db, &run.id, owner(), status, Some(&result.to_string()), None).await
+        }
+        Err(e) => {
+            tracing::error!(job=%run.id, kind=%job.kind, "job failed: {}", e);
+            db::jobs::finish(&st.db, &run.id, owner(), "failed", None, Some(&e.to_string())).await
+        }
+    };
+    match saved {
+        Ok(true) => {}
+        Ok(false) => tracing::error!(job=%run.id, "jobs: outcome dropped, the lease was lost"),
+        Err(e) => tracing::error!(job=%run.id, "jobs: save outcome: {}", e),
+    }
+    announce(st, &run.id).await;
+}
+
+/// Renews `owner`'s lease every `every` and returns once it is lost.
+async fn hold_lease(pool: &db::Db, id: &str, owner: &str, every: Duration) {
+    loop {
+        tokio::time::sleep(every).await;
+        match db::jobs::renew_lease(pool, id, owner, lease_until()).await {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(e) => tracing::warn!(job=%id, "jobs: renew lease: {}", e),
+        }
+    }
+}
+
+/// Deletes the directories of jobs that finished over `[jobs].file_ttl_hours`
+/// ago; the reports in them hold passphrases and keys.
+pub async fn purge_files(st: &AppState) -> Result<usize> {
+    let cutoff = time::OffsetDateTime::now_utc().unix_timestamp() - st.cfg.jobs.file_ttl_hours * 3600;
+    let mut purged = 0;
+    for id in db::jobs::finished_before(&st.db, cutoff).await? {
+        match tokio::fs::remove_dir_all(dir(st, &id)).await {
+            Ok(()) => purged += 1,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => tracing::warn!(job=%id, "jobs: purge files: {}", e),
+        }
+    }
+    Ok(purged)
+}
+
+/// Requeues running jobs whose lease expired.
+async fn requeue_expired(st: &AppState) -> Result<()> {
+    let n = db::jobs::requeue_expired(&st.db, time::OffsetDateTime::now_utc().unix_timestamp()).await?;
+    if n > 0 { tracing::info!("requeued {} interrupted jobs", n); }
+    Ok(())
+}
+
+/// Starts `[jobs].workers` workers. Jobs another process is still running
+/// keep their lease; those it abandoned are picked up once it expires.
+pub async fn spawn_workers(st: AppState) -> Result<()> {
+    requeue_expired(&st).await?;
+    let purger = st.clone();
+    tokio::spawn(async move {
+        loop {
+            match purge_files(&purger).await {
+                Ok(n) if n > 0 => tracing::info!("jobs: purged files of {} jobs", n),
+                Ok(_) => {}
+                Err(e) => tracing::error!("jobs: purge files: {}", e),
+            }
+            tokio::time::sleep(Duration::from_secs(3600)).await;
+        }
+    });
+    for _ in 0..st.cfg.jobs.workers.max(1) {
+        let st = st.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = requeue_expired(&st).await { tracing::error!("jobs: requeue: {}", e); }
+                match db::jobs::claim(&st.db, None, owner(), lease_until()).await {
+                    Ok(Some(job)) => {
+                        execute(&st, job).await;
+                        continue;
+                    }
+                    Ok(None) => {}
+                    Err(e) => tracing::error!("jobs: claim: {}", e),
+                }
+                let _ = tokio::time::timeout(Duration::from_secs(30), wake().notified()).await;
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn work_stops_when_the_lease_is_taken_over() {
+        let pool = db::test_db().await;
+        let id = db::jobs::create(&pool, "import", 1, "{}", "{}", "alice").await.unwrap();
+        db::jobs::claim(&pool, None, "a", 0).await.unwrap().unwrap();
+
+        // While "a" holds the lease its work runs on.
+        let every = Duration::from_millis(5);
+        let held = tokio::time::timeout(Duration::from_millis(50), hold_lease(&pool, &id, "a", every)).await;
+        assert!(held.is_err());
+
+        // "a" stalled past its lease and "b" took the job over: "a" stops
+        // before its work ends and cannot record an outcome.
+        assert_eq!(db::jobs::requeue_expired(&pool, lease_until() + 1).await.unwrap(), 1);
+        db::jobs::claim(&pool, Some(&id), "b", lease_until()).await.unwrap().unwrap();
+        let lost = tokio::select! {
+            () = std::future::pending::<()>() => false,
+            () = hold_lease(&pool, &id, "a", every) => true,
+        };
+        assert!(lost);
+        assert!(!db::jobs::finish(&pool, &id, "a", "done", None, None).await.unwrap());
+        assert_eq!(db::jobs::get(&pool, &id).await.unwrap().unwrap().status, "running");
+        assert!(db::jobs::renew_lease(&pool, &id, "b", lease_until()).await.unwrap());
+    }
+}
@@ -0,0 +1,359 @@
+use anyhow::{anyhow, Result};
+use handlebars::Handlebars;
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use serde_json::{json, Value};
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::sync::Notify;
+
+use crate::config::{MailCfg, SmtpTls};
+use crate::db::{self, Db};
+
+const MAX_ATTEMPTS: i64 = 8;
+
+/// Built-in templates; a file with the same name in `templates_dir` replaces one.
+/// The first line is `Subject: ...`, the body follows after a blank line.
+const TEMPLATES: &[(&str, &str)] = &[
+    ("cert_expiring", include_str!("../../templates/mail/cert_expiring.hbs")),
+    ("bundle_ready", include_str!("../../templates/mail/bundle_ready.hbs")),
+    ("account_locked", include_str!("../../templates/mail/account_locked.hbs")),
+    ("cert_renewed", include_str!("../../templates/mail/cert_renewed.hbs")),
+];
+
+struct Mailer {
+    cfg: MailCfg,
+    hb: Handlebars<'static>,
+}
+
+static MAILER: OnceLock<Mailer> = OnceLock::new();
+static WAKE: OnceLock<Notify> = OnceLock::new();
+
+fn wake() -> &'static Notify {
+    WAKE.get_or_init(Notify::new)
+}
+
+/// Loads templates and enables queueing. Without a `[mail]` section every
+/// notification is a no-op.
+pub fn init(cfg: Option<&MailCfg>) -> Result<()> {
+    let Some(cfg) = cfg.filter(|c| c.enabled) else { return Ok(()) };
+    let _ = MAILER.set(Mailer::load(cfg)?);
+    Ok(())
+}
+
+impl Mailer {
+    fn load(cfg: &MailCfg) -> Result<Self> {
+        let mut hb = Handlebars::new();
+        hb.register_escape_fn(handlebars::no_escape);
+        hb.set_strict_mode(false);
+        for (name, builtin) in TEMPLATES {
+            let path = cfg.templates_dir.as_deref().map(|d| Path::new(d).join(format!("{name}.hbs")));
+            let src = match path.filter(|p| p.exists()) {
+                Some(p) => std::fs::read_to_string(&p)?,
+                None => builtin.to_string(),
+            };
+            hb.register_template_string(name, src).map_err(|e| anyhow!("mail template {name}: {e}"))?;
+        }
+        Ok(Mailer { cfg: cfg.clone(), hb })
+    }
+}
+
+fn render(m: &Mailer, template: &str, data: &Value) -> Result<(String, String)> {
+    let mut data = data.clone();
+    if let Value::Object(o) = &mut data {
+        o.entry("panel").or_insert_with(|| Value::String(m.cfg.panel_name.clone()));
+    }
+    let text = m.hb.render(template, &data)?;
+    let (head, body) = text.split_once("\n\n").unwrap_or((text.as_str(), ""));
+    let subject = head.strip_prefix("Subject:").map(str::trim).unwrap_or(template).to_string();
+    Ok((subject, body.to_string()))
+}
+
+/// Renders `template` once per recipient and queues the messages.
+pub async fn send(pool: &Db, template: &str, to: &[String], data: &Value) -> Result<()> {
+    let Some(m) = MAILER.get() else { return Ok(()) };
+    queue(pool, m, template, to, data).await
+}
+
+async fn queue(pool: &Db, m: &Mailer, template: &str, to: &[String], data: &Value) -> Result<()> {
+    let (subject, body) = render(m, template, data)?;
+    let mut to: Vec<&String> = to.iter().filter(|a| !a.is_empty()).collect();
+    to.sort();
+    to.dedup();
+    for addr in &to {
+        db::mail::enqueue(pool, template, addr, &subject, &body).await?;
+    }
+    if !to.is_empty() { wake().notify_one(); }
+    Ok(())
+}
+
+async fn owner_and_admins(pool: &Db, cn: &str) -> Result<Vec<String>> {
+    let mut to = db::mail::admin_emails(pool).await?;
+    to.extend(db::clients::owner_email(pool, cn).await?);
+    Ok(to)
+}
+
+/// Mail that follows from an audit event, if any.
+pub async fn on_audit(pool: &Db, actor: &str, action: &str, target: &str, details: &Value) -> Result<()> {
+    if MAILER.get().is_none() { return Ok(()); }
+    match action {
+        "EXPIRY_WARNING" => {
+            let mut data = details.clone();
+            data["cn"] = json!(target);
+            send(pool, "cert_expiring", &owner_and_admins(pool, target).await?, &data).await
+        }
+        "CLIENT_BUNDLE" => {
+            let Some(owner) = db::clients::owner_email(pool, target).await? else { return Ok(()) };
+            let data = json!({ "cn": target, "actor": actor, "include_key": details["include_key"] });
+            send(pool, "bundle_ready", &[owner], &data).await
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Tells the owner of `cn` (or the admins, when it has none) that a renewed
+/// bundle is waiting behind `token`.
+pub async fn cert_renewed(pool: &Db, cn: &str, token: &str, details: &Value) -> Result<()> {
+    let Some(m) = MAILER.get() else { return Ok(()) };
+    let to = match db::clients::owner_email(pool, cn).await? {
+        Some(owner) => vec![owner],
+        None => db::mail::admin_emails(pool).await?,
+    };
+    let mut data = details.clone();
+    data["cn"] = json!(cn);
+    if let Some(base) = &m.cfg.panel_url {
+        data["link"] = json!(format!("{}/ui/#/renewal/{token}", base.trim_end_matches('/')));
+    }
+    send(pool, "cert_renewed", &to, &data).await
+}
+
+/// Tells the account owner and the admins that sign-in was throttled.
+pub async fn account_locked(pool: &Db, username: &str, ip: &str, window_secs: i64) -> Result<()> {
+    if MAILER.get().is_none() { return Ok(()); }
+    let mut to = db::mail::admin_emails(pool).await?;
+    to.extend(db::mail::user_email(pool, username).await?);
+    let data = json!({ "username": username, "ip": ip, "window_mins": window_secs / 60 });
+    send(pool, "account_locked", &to, &data).await
+}
+
+fn transport(cfg: &MailCfg) -> Result<AsyncSmtpTransport<Tokio1Executor>> {
+    let mut b = match cfg.tls {
+        SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&cfg.smtp_host),
+        SmtpTls::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&cfg.smtp_host)?,
+        SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&cfg.smtp_host)?,
+    };
+    b = b.port(cfg.smtp_port).timeout(Some(Duration::from_secs(20)));
+    if let (Some(u), Some(p)) = (&cfg.username, &cfg.password) {
+        b = b.credentials(Credentials::new(u.clone(), p.clone()));
+    }
+    Ok(b.build())
+}
+
+fn backoff(attempts: i64) -> i64 {
+    (60i64 << attempts.clamp(0, 10) as u32).min(6 * 3600)
+}
+
+/// SMTP replies that will not get better by retrying (5xx) give up at once.
+fn permanent(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<lettre::transport::smtp::Error>().is_some_and(|e| e.is_permanent())
+}
+
+/// Sends what is due at `now`; transient failures are retried with backoff.
+async fn send_due(smtp: &AsyncSmtpTransport<Tokio1Executor>, from: &Mailbox, pool: &Db, now: i64) -> Result<usize> {
+    let batch = db::mail::due(pool, now, 20).await?;
+    let n = batch.len();
+    for q in batch {
+        let res = async {
+            let msg = Message::builder()
+                .from(from.clone())
+                .to(q.to_addr.parse()?)
+                .subject(q.subject.clone())
+                .header(ContentType::TEXT_PLAIN)
+                .body(q.body.clone())?;
+            smtp.send(msg).await?;
+            anyhow::Ok(())
+        }.await;
+        let saved = match res {
+            Ok(()) => db::mail::mark_sent(pool, &q.id).await,
+            Err(e) => {
+                tracing::warn!(mail=%q.id, to=%q.to_addr, "mail send failed: {}", e);
+                let retry = q.attempts + 1 < MAX_ATTEMPTS && !permanent(&e);
+                let next = retry.then(|| now + backoff(q.attempts));
+                db::mail::mark_failed(pool, &q.id, &e.to_string(), next).await
+            }
+        };
+        if let Err(e) = saved { tracing::error!("mail: {}", e); }
+    }
+    Ok(n)
+}
+
+/// Sends queued mail until the process exits.
+pub fn spawn_worker(pool: Db) {
+    let Some(m) = MAILER.get() else { return };
+    let smtp = match transport(&m.cfg) {
+        Ok(t) => t,
+        Err(e) => {
+            tracing::error!("mail: transport: {}", e);
+            return;
+        }
+    };
+    let from: Mailbox = match m.cfg.from.parse() {
+        Ok(f) => f,
+        Err(e) => {
+            tracing::error!("mail: invalid from address {:?}: {}", m.cfg.from, e);
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        loop {
+            let now = OffsetDateTime::now_utc().unix_timestamp();
+            if let Err(e) = send_due(&smtp, &from, &pool, now).await {
+                tracing::error!("mail: load queue: {}", e);
+            }
+            let _ = tokio::time::timeout(Duration::from_secs(10), wake().notified()).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::Row;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    /// Scripted SMTP server: each `MAIL FROM` takes the next reply from
+    /// `replies` (`250 OK` once they run out); accepted messages are kept.
+    struct Smtp {
+        port: u16,
+        replies: Arc<Mutex<VecDeque<&'static str>>>,
+        received: Arc<Mutex<Vec<String>>>,
+    }
+
+    async fn smtp_server() -> Smtp {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let replies = Arc::new(Mutex::new(VecDeque::new()));
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let (r, m) = (replies.clone(), received.clone());
+        tokio::spawn(async move {
+            while let Ok((sock, _)) = listener.accept().await {
+                let (rd, mut wr) = sock.into_split();
+                let mut lines = BufReader::new(rd).lines();
+                wr.write_all(b"220 test ESMTP\r\n").await.unwrap();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let verb = line.split(' ').next().unwrap_or("").to_ascii_uppercase();
+                    let reply = match verb.as_str() {
+                        "EHLO" | "HELO" => "250 test".to_string(),
+                        "MAIL" => r.lock().unwrap().pop_front().unwrap_or("250 OK").to_string(),
+                        "DATA" => {
+                            wr.write_all(b"354 go ahead\r\n").await.unwrap();
+                            let mut data = String::new();
+                            while let Ok(Some(l)) = lines.next_line().await {
+                                if l == "." { break; }
+                                data.push_str(&l);
+                                data.push('\n');
+                            }
+                            m.lock().unwrap().push(data);
+                            "250 queued".to_string()
+                        }
+                        "QUIT" => {
+                            wr.write_all(b"221 bye\r\n").await.ok();
+                            break;
+                        }
+                        _ => "250 OK".to_string(),
+                    };
+                    wr.write_all(format!("{reply}\r\n").as_bytes()).await.unwrap();
+                }
+            }
+        });
+        Smtp { port, replies, received }
+    }
+
+    async fn status(pool: &Db) -> Vec<(String, i64, Option<String>, i64)> {
+        sqlx::query("SELECT status, attempts, last_error, next_attempt_at FROM mail_queue ORDER BY created_at, id")
+            .fetch_all(pool).await.unwrap()
+            .iter().map(|r| (r.get(0), r.get(1), r.get(2), r.get(3))).collect()
+    }
+
+    fn cfg(port: u16, templates_dir: &Path) -> MailCfg {
+        MailCfg {
+            enabled: true,
+            smtp_host: "127.0.0.1".into(),
+            smtp_port: port,
+            tls: SmtpTls::None,
+            username: None,
+            password: None,
+            from: "VPN <panel@example.test>".into(),
+            templates_dir: Some(templates_dir.to_string_lossy().into_owned()),
+            panel_url: None,
+            panel_name: "Test VPN".into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn override_template_and_retry_transient_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("cert_expiring.hbs"),
+            "Subject: Custom expiry for {{cn}}\n\n{{cn}} expires in {{days}} days ({{panel}})\n").unwrap();
+        let server = smtp_server().await;
+        server.replies.lock().unwrap().push_back("451 4.3.0 Try again later");
+
+        let m = Mailer::load(&cfg(server.port, dir.path())).unwrap();
+        // Templates without an override file stay built in.
+        let (subject, _) = render(&m, "bundle_ready", &json!({ "cn": "bob" })).unwrap();
+        assert!(!subject.starts_with("Custom"));
+
+        let pool = crate::db::test_db().await;
+        let to = ["owner@example.test".to_string(), String::new(), "owner@example.test".to_string()];
+        queue(&pool, &m, "cert_expiring", &to, &json!({ "cn": "bob", "days": 7 })).await.unwrap();
+
+        let smtp = transport(&m.cfg).unwrap();
+        let from: Mailbox = m.cfg.from.parse().unwrap();
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        assert_eq!(send_due(&smtp, &from, &pool, now).await.unwrap(), 1);
+        let rows = status(&pool).await;
+        assert_eq!(rows.len(), 1, "blank and duplicate recipients are dropped");
+        let (state, attempts, error, next) = &rows[0];
+        assert_eq!((state.as_str(), *attempts), ("pending", 1));
+        assert!(error.as_deref().unwrap_or("").contains("451"), "{error:?}");
+        assert_eq!(*next, now + backoff(0));
+        assert!(server.received.lock().unwrap().is_empty());
+
+        assert_eq!(send_due(&smtp, &from, &pool, now).await.unwrap(), 0);
+        assert_eq!(send_due(&smtp, &from, &pool, *next).await.unwrap(), 1);
+        let rows = status(&pool).await;
+        assert_eq!((rows[0].0.as_str(), rows[0].1, rows[0].2.clone()), ("sent", 2, None));
+
+        let received = server.received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert!(received[0].contains("Subject: Custom expiry for bob"), "{}", received[0]);
+        assert!(received[0].contains("bob expires in 7 days (Test VPN)"), "{}", received[0]);
+        assert!(received[0].contains("To: owner@example.test"), "{}", received[0]);
+    }
+
+    #[tokio::test]
+    async fn permanent_failure_is_not_retried() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = smtp_server().await;
+        server.replies.lock().unwrap().push_back("550 5.7.1 Relaying denied");
+        let m = Mailer::load(&cfg(server.port, dir.path())).unwrap();
+        let pool = crate::db::test_db().await;
+        queue(&pool, &m, "account_locked", &["admin@example.test".into()], &json!({ "username": "bob" })).await.unwrap();
+
+        let smtp = transport(&m.cfg).unwrap();
+        let from: Mailbox = m.cfg.from.parse().unwrap();
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        send_due(&smtp, &from, &pool, now).await.unwrap();
+        let rows = status(&pool).await;
+        assert_eq!((rows[0].0.as_str(), rows[0].1), ("failed", 1));
+        assert!(rows[0].2.as_deref().unwrap_or("").contains("550"));
+    }
+}
@@ -1,17 +1,19 @@
+mod audit;
 mod config;
 mod db;
+mod events;
 mod http;
+mod jobs;
+mod mail;
 mod security;
 mod vpncertd;
 mod openvpn;
 mod web;
+mod webhooks;
 
 use crate::config::AppCfg;
-use axum::Router;
 use clap::{Parser, Subcommand};
-use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::net::TcpListener;
 
 #[derive(Clone)]
 pub struct AppState {
@@ -20,6 +22,21 @@ pub struct AppState {
     pub db: db::Db,
 }
 
+/// State over `db::test_db` with a minimal config; nothing listens on the
+/// vpncertd socket and no directories are set.
+#[cfg(test)]
+pub async fn test_state() -> AppState {
+    let cfg = serde_json::from_value(serde_json::json!({
+        "server": { "bind": "127.0.0.1:0", "cookie_name": "s", "session_ttl_secs": 900, "pepper_file": "" },
+        "db": { "url": "sqlite::memory:" },
+        "ovpn": {
+            "socket_path": "", "ccd_dir": "", "cn_pattern": "^[A-Za-z0-9._-]{3,64}$",
+            "bundle_remote": "vpn.example.org", "bundle_port": 1194, "bundle_proto": "udp", "bundles_dir": "",
+        },
+    })).unwrap();
+    AppState { cfg: Arc::new(cfg), pepper: Arc::new(vec![0; 16]), db: db::test_db().await }
+}
+
 #[derive(Parser)]
 #[command(name="ovpn-admin", version, about="OpenVPN admin panel")]
 struct Cli {
@@ -29,7 +46,51 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Cmd {
-    UserAdd { #[arg(long)] username: String, #[arg(long, value_parser=["ADMIN","OPS","READONLY"])] role: String },
+    UserAdd { #[arg(long)] username: String, #[arg(long, value_parser=["ADMIN","OPS","READONLY"])] role: String, #[arg(long)] email: Option<String> },
+    /// Set or clear (empty string) the notification address of a user
+    UserSetEmail { #[arg(long)] username: String, #[arg(long)] email: String },
+    Audit { #[command(subcommand)] cmd: AuditCmd },
+    /// Renew certificates covered by an auto-renew policy that are due
+    Autorenew { #[arg(long)] dry_run: bool },
+    Clients { #[command(subcommand)] cmd: ClientsCmd },
+}
+
+#[derive(Subcommand)]
+enum ClientsCmd {
+    /// Issue clients from a CSV or JSON file, writing a report and a zip of bundles
+    Import {
+        #[arg(long)] file: String,
+        /// Defaults to the file extension
+        #[arg(long, value_parser=["csv","json"])] format: Option<String>,
+        /// Only validate the rows
+        #[arg(long)] dry_run: bool,
+        /// Put each client's private key in its bundle
+        #[arg(long)] include_key: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum AuditCmd {
+    /// Write audit rows to a file (or stdout), newest first
+    Export {
+        #[arg(long, value_parser=["csv","jsonl","cef"], default_value="csv")] format: String,
+        #[arg(long)] out: Option<String>,
+        #[arg(long)] actor: Option<String>,
+        /// Comma-separated list of actions
+        #[arg(long, value_delimiter=',')] action: Vec<String>,
+        #[arg(long)] target: Option<String>,
+        #[arg(long)] ip: Option<String>,
+        /// Unix timestamp, inclusive
+        #[arg(long)] since: Option<i64>,
+        /// Unix timestamp, exclusive
+        #[arg(long)] until: Option<i64>,
+        /// Free text matched against details
+        #[arg(long)] q: Option<String>,
+    },
+    /// Check the audit hash chain and report the first broken link
+    Verify,
+    /// Archive and delete audit rows and login attempts past their retention
+    Prune { #[arg(long)] dry_run: bool },
 }
 
 #[tokio::main]
@@ -40,18 +101,32 @@ async fn main() -> anyhow::Result<()> {
     let pepper = Arc::new(cfg.load_pepper()?);
     let db = db::connect_db(&cfg.db.url).await?;
     db::migrate_db(&db).await?;
+    audit::chain::init(cfg.audit.chain_hmac, &pepper);
+    let sealed = db::audit_seal_legacy(&db).await?;
+    if sealed > 0 { tracing::info!("sealed {} pre-existing audit rows into the hash chain", sealed); }
 
     let cli = Cli::parse();
-    if let Some(Cmd::UserAdd { username, role }) = cli.cmd {
-        let pw = rpassword::prompt_password("Password: ")?;
-        let phc = security::password::hash_password(&pw, &pepper)?;
-        let uid = db::create_user(&db, &username, &phc).await?;
-        db::assign_role(&db, &uid, &role).await?;
-        println!("created user '{}' with role '{}'", username, role);
-        return Ok(());
+    audit::sink::start(&cfg.audit);
+    if let Some(cmd) = cli.cmd {
+        let res = command(cmd, cfg, pepper, db).await;
+        // the rows a command wrote reach the sinks before the process exits
+        audit::sink::flush(std::time::Duration::from_secs(5)).await;
+        return res;
     }
 
+    audit::retention::spawn_scheduler(db.clone(), cfg.audit.retention.clone());
+    webhooks::spawn_worker(db.clone());
+    mail::init(cfg.mail.as_ref())?;
+    mail::spawn_worker(db.clone());
+    if let Some(ipam) = cfg.ipam.as_ref() {
+        openvpn::ipam::Pool::from_cfg(ipam)?;
+    }
     let state = AppState { cfg: cfg.clone(), pepper, db };
+    jobs::spawn_workers(state.clone()).await?;
+    events::spawn_health_monitor(state.clone());
+    openvpn::expiry::spawn_scheduler(state.clone());
+    openvpn::renew::spawn_revoker(state.clone());
+    openvpn::autorenew::spawn_scheduler(state.clone());
     let app = http::router().with_state(state);
 
     let addr: std::net::SocketAddr = cfg.server.bind.parse()?;
@@ -63,3 +138,103 @@ async fn main() -> anyhow::Result<()> {
 
 
 }
+
+/// Runs a one-off CLI command instead of the server.
+async fn command(cmd: Cmd, cfg: Arc<AppCfg>, pepper: Arc<Vec<u8>>, db: db::Db) -> anyhow::Result<()> {
+    match cmd {
+        Cmd::UserAdd { username, role, email } => {
+            let pw = rpassword::prompt_password("Password: ")?;
+            let phc = security::password::hash_password(&pw, &pepper)?;
+            let uid = db::create_user(&db, &username, &phc).await?;
+            db::assign_role(&db, &uid, &role).await?;
+            if let Some(email) = email.as_deref() {
+                db::mail::set_user_email(&db, &username, Some(email)).await?;
+            }
+            println!("created user '{}' with role '{}'", username, role);
+            Ok(())
+        }
+        Cmd::UserSetEmail { username, email } => {
+            let email = Some(email.trim()).filter(|e| !e.is_empty());
+            if !db::mail::set_user_email(&db, &username, email).await? {
+                anyhow::bail!("no such user '{}'", username);
+            }
+            println!("updated email for '{}'", username);
+            Ok(())
+        }
+        Cmd::Audit { cmd: AuditCmd::Export { format, out, actor, action, target, ip, since, until, q } } => {
+            let fmt = audit::export::Format::parse(&format)?;
+            let filter = db::AuditFilter { actor, actions: action, target_prefix: target, ip, since, until, text: q };
+            let mut details = audit::export::details(fmt, &filter);
+            details["out"] = serde_json::json!(out);
+            audit::AuditCtx::system().record(&db, "AUDIT_EXPORT", "-", details).await?;
+            let n = match out {
+                Some(path) => {
+                    let mut f = tokio::fs::File::create(&path).await?;
+                    match audit::export::write_rows(&db, &filter, fmt, &mut f).await {
+                        Ok(n) => n,
+                        Err(e) => {
+                            tokio::fs::remove_file(&path).await.ok();
+                            return Err(e.context(format!("export to {path} failed and was removed")));
+                        }
+                    }
+                }
+                None => audit::export::write_rows(&db, &filter, fmt, &mut tokio::io::stdout()).await?,
+            };
+            eprintln!("exported {} audit rows", n);
+            Ok(())
+        }
+        Cmd::Audit { cmd: AuditCmd::Verify } => {
+            let report = audit::chain::verify(&db).await?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            if !report.ok { std::process::exit(1); }
+            Ok(())
+        }
+        Cmd::Audit { cmd: AuditCmd::Prune { dry_run } } => {
+            let report = audit::retention::run(&db, &cfg.audit.retention, &audit::AuditCtx::system(), dry_run).await?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            Ok(())
+        }
+        Cmd::Autorenew { dry_run } => {
+            let st = AppState { cfg: cfg.clone(), pepper: pepper.clone(), db: db.clone() };
+            if dry_run {
+                let plan = openvpn::autorenew::plan(&st, &cfg.autorenew).await?;
+                println!("{}", serde_json::to_string_pretty(&plan)?);
+            } else {
+                mail::init(cfg.mail.as_ref())?;
+                let done = openvpn::autorenew::run(&st, &cfg.autorenew).await?;
+                println!("renewed {} clients: {}", done.len(), done.join(", "));
+            }
+            Ok(())
+        }
+        Cmd::Clients { cmd: ClientsCmd::Import { file, format, dry_run, include_key } } => {
+            let st = AppState { cfg: cfg.clone(), pepper: pepper.clone(), db: db.clone() };
+            let format = format.unwrap_or_else(|| if file.ends_with(".json") { "json".into() } else { "csv".into() });
+            let rows = openvpn::import::parse(&std::fs::read(&file)?, openvpn::import::Format::parse(&format)?)?;
+            let planned = match openvpn::import::validate(&st, &["ADMIN".to_string()], rows).await? {
+                Ok(p) => p,
+                Err(errors) => {
+                    for e in &errors { eprintln!("row {} ({}): {}", e.row, e.cn, e.error); }
+                    anyhow::bail!("{} invalid rows, nothing imported", errors.len());
+                }
+            };
+            if dry_run {
+                println!("{} rows valid", planned.len());
+                return Ok(());
+            }
+            mail::init(cfg.mail.as_ref())?;
+            let ctx = audit::AuditCtx::system();
+            let job = openvpn::import::start(&st, &ctx, &planned, include_key).await?;
+            if !jobs::run_now(&st, &job).await? {
+                println!("job {job} was picked up by a running server; follow it at /api/jobs/{job}");
+                return Ok(());
+            }
+            let j = db::jobs::get(&st.db, &job).await?.ok_or_else(|| anyhow::anyhow!("job {job} vanished"))?;
+            match j.result {
+                Some(r) => println!("{}", serde_json::to_string_pretty(&serde_json::from_str::<serde_json::Value>(&r)?)?),
+                None => anyhow::bail!("import failed: {}", j.error.unwrap_or_default()),
+            }
+            println!("files in {}", jobs::dir(&st, &job).display());
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,241 @@
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use ulid::Ulid;
+
+use super::{expiry, renew};
+use crate::{
+    audit::{self, AuditCtx},
+    config::AutoRenewCfg,
+    db::{self, renewals::{NewRenewal, Renewal}},
+    security::{hmac::hmac_sha256, seal},
+    AppState,
+};
+
+/// Only admins can opt clients in, so the job renews with their role.
+const AUTORENEW_ROLE: &str = "ADMIN";
+
+#[derive(Debug, Serialize)]
+pub struct Candidate {
+    pub cn: String,
+    pub serial: String,
+    pub not_after: String,
+    pub days_left: i64,
+    /// `client` when the client opted in itself, `group` when inherited.
+    pub via: &'static str,
+}
+
+/// What the job would renew right now.
+pub async fn plan(st: &AppState, cfg: &AutoRenewCfg) -> Result<Vec<Candidate>> {
+    let policies: HashMap<String, db::clients::ClientPolicy> = db::clients::policies(&st.db).await?
+        .into_iter()
+        .filter(|p| p.effective)
+        .map(|p| (p.cn.clone(), p))
+        .collect();
+    if policies.is_empty() { return Ok(Vec::new()); }
+
+    let mut out = Vec::new();
+    for e in expiry::expiring(st, cfg.days_before * 86400).await? {
+        let Some(p) = policies.get(&e.cn) else { continue };
+        if db::renewals::exists_for_old(&st.db, &e.serial).await? { continue; }
+        out.push(Candidate {
+            cn: e.cn,
+            serial: e.serial,
+            not_after: e.not_after,
+            days_left: e.days_left,
+            via: if p.auto_renew.is_some() { "client" } else { "group" },
+        });
+    }
+    Ok(out)
+}
+
+/// The bundle key needs both the pepper and the token from the mail, so the
+/// files and the database alone are not enough to read a bundle.
+fn bundle_key(pepper: &[u8], token: &str) -> [u8; 32] {
+    let mac = hmac_sha256(pepper, format!("ovpn-admin/renewal/v1:{token}").as_bytes());
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&mac);
+    key
+}
+
+/// The key passphrase never travels with the link; admins hand it over.
+fn passphrase_key(pepper: &[u8]) -> [u8; 32] {
+    let mac = hmac_sha256(pepper, b"ovpn-admin/renewal-passphrase/v1");
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&mac);
+    key
+}
+
+/// Contents of a sealed renewal bundle.
+#[derive(Serialize, Deserialize)]
+pub struct RenewedBundle {
+    pub cn: String,
+    pub serial: Option<String>,
+    pub not_after: Option<String>,
+    pub bundle_filename: Option<String>,
+    pub bundle_b64: Option<String>,
+}
+
+async fn renew_one(st: &AppState, cfg: &AutoRenewCfg, c: &Candidate) -> Result<()> {
+    let ctx = AuditCtx { actor: "autorenew".into(), ..AuditCtx::system() };
+    let grace = cfg.revoke_old_after_days.max(0) * 86400;
+    let r = renew::renew_client(st, &ctx, &[AUTORENEW_ROLE.to_string()], &c.cn, None, Some(grace), true).await?;
+
+    let mut raw = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut raw);
+    let token = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(raw);
+    let id = Ulid::new().to_string();
+
+    let (bundle_filename, bundle_b64) = match r.bundle {
+        Some((name, bytes)) => (Some(name), Some(base64::engine::general_purpose::STANDARD.encode(bytes))),
+        None => (None, None),
+    };
+    let payload = serde_json::to_vec(&RenewedBundle {
+        cn: c.cn.clone(),
+        serial: r.issue.serial.clone(),
+        not_after: r.issue.not_after.clone(),
+        bundle_filename,
+        bundle_b64,
+    })?;
+    let sealed = seal::seal(&bundle_key(&st.pepper, &token), id.as_bytes(), &payload)?;
+    let passphrase = seal::seal(&passphrase_key(&st.pepper), id.as_bytes(), r.issue.passphrase.as_bytes())?;
+    let expires_at = OffsetDateTime::now_utc().unix_timestamp() + cfg.link_ttl_hours.max(1) * 3600;
+
+    let dir = Path::new(&cfg.bundle_dir);
+    tokio::fs::create_dir_all(dir).await?;
+    let path = dir.join(format!("{id}.sealed"));
+    tokio::fs::write(&path, &sealed).await?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        tokio::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).await.ok();
+    }
+
+    db::renewals::insert(&st.db, &NewRenewal {
+        id: &id,
+        cn: &c.cn,
+        old_serial: &r.old_serial,
+        new_serial: r.issue.serial.as_deref(),
+        not_after: r.issue.not_after.as_deref(),
+        token_hash: &audit::sha256_hex(token.as_bytes()),
+        bundle_path: &path.to_string_lossy(),
+        expires_at,
+        passphrase_sealed: &base64::engine::general_purpose::STANDARD.encode(passphrase),
+    }).await?;
+
+    let rfc3339 = |t: i64| OffsetDateTime::from_unix_timestamp(t).ok().and_then(|t| t.format(&Rfc3339).ok());
+    let deadline = r.old_revoke_at.and_then(rfc3339);
+    mail_owner(st, &c.cn, &token, json!({
+        "serial": r.issue.serial,
+        "not_after": r.issue.not_after,
+        "old_serial": r.old_serial,
+        "old_not_after": c.not_after,
+        "revoke_deadline": deadline,
+        "link_expires": rfc3339(expires_at),
+    })).await;
+    Ok(())
+}
+
+async fn mail_owner(st: &AppState, cn: &str, token: &str, data: serde_json::Value) {
+    if let Err(e) = crate::mail::cert_renewed(&st.db, cn, token, &data).await {
+        tracing::error!(%cn, "autorenew: queue mail: {}", e);
+    }
+}
+
+/// Deletes bundles whose link was used or has expired.
+async fn purge_stale(st: &AppState) -> Result<()> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    for r in db::renewals::stale_bundles(&st.db, now).await? {
+        match tokio::fs::remove_file(&r.bundle_path).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                tracing::warn!(renewal=%r.id, "autorenew: remove {}: {}", r.bundle_path, e);
+                continue;
+            }
+        }
+        db::renewals::clear_bundle(&st.db, &r.id).await?;
+    }
+    Ok(())
+}
+
+/// Renews every candidate; one failure does not stop the others.
+/// Returns the CNs renewed.
+pub async fn run(st: &AppState, cfg: &AutoRenewCfg) -> Result<Vec<String>> {
+    if let Err(e) = purge_stale(st).await {
+        tracing::warn!("autorenew: purge: {}", e);
+    }
+    let mut done = Vec::new();
+    for c in plan(st, cfg).await? {
+        match renew_one(st, cfg, &c).await {
+            Ok(()) => done.push(c.cn),
+            Err(e) => tracing::error!(cn=%c.cn, serial=%c.serial, "autorenew failed: {}", e),
+        }
+    }
+    Ok(done)
+}
+
+/// Uses up a mailed token and decrypts its bundle. `None` once the link has
+/// been used, has expired or never existed. The file is deleted afterwards.
+pub async fn open_bundle(st: &AppState, token: &str) -> Result<Option<(Renewal, RenewedBundle)>> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let Some(r) = db::renewals::consume(&st.db, &audit::sha256_hex(token.as_bytes()), now).await? else {
+        return Ok(None);
+    };
+    let sealed = tokio::fs::read(&r.bundle_path).await?;
+    let plain = seal::open(&bundle_key(&st.pepper, token), r.id.as_bytes(), &sealed)?;
+    let bundle: RenewedBundle = serde_json::from_slice(&plain).map_err(|e| anyhow!("renewal {}: {}", r.id, e))?;
+    match tokio::fs::remove_file(&r.bundle_path).await {
+        Ok(()) => db::renewals::clear_bundle(&st.db, &r.id).await?,
+        // purge_stale retries on the next run.
+        Err(e) => tracing::warn!(renewal=%r.id, "renewal: remove {}: {}", r.bundle_path, e),
+    }
+    Ok(Some((r, bundle)))
+}
+
+/// The key passphrase of a renewal, for an admin to pass on out of band.
+pub async fn passphrase(st: &AppState, id: &str) -> Result<Option<String>> {
+    let Some(b64) = db::renewals::passphrase_sealed(&st.db, id).await? else { return Ok(None) };
+    let sealed = base64::engine::general_purpose::STANDARD.decode(b64)?;
+    let plain = seal::open(&passphrase_key(&st.pepper), id.as_bytes(), &sealed)?;
+    Ok(Some(String::from_utf8(plain)?))
+}
+
+/// Called when a client connects. The first time a renewed cert is seen, the
+/// cert it replaced is revoked without waiting for the deadline. Only when the
+/// connecting CN is the one the renewal was for.
+pub async fn on_connect(st: &AppState, cn: &str, serial: &str, ip: Option<&str>) -> Result<()> {
+    db::vpn::seen(&st.db, serial, cn, ip).await?;
+    if let Some(r) = db::renewals::awaiting_first_connect(&st.db, serial).await? {
+        if r.cn != cn {
+            tracing::warn!(renewal=%r.id, %serial, "autorenew: serial renewed for {} connected as {}", r.cn, cn);
+            return Ok(());
+        }
+        let ctx = AuditCtx { actor: "autorenew".into(), ..AuditCtx::system() };
+        renew::revoke_superseded(st, &ctx, &r.cn, &r.old_serial, Some(serial), "first_connect").await?;
+    }
+    Ok(())
+}
+
+/// Runs the job every `interval_secs` while the server is up.
+pub fn spawn_scheduler(st: AppState) {
+    let cfg = st.cfg.autorenew.clone();
+    if !cfg.enabled { return; }
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(Duration::from_secs(cfg.interval_secs.max(60)));
+        loop {
+            tick.tick().await;
+            match run(&st, &cfg).await {
+                Ok(done) if !done.is_empty() => tracing::info!("autorenew: renewed {}", done.join(", ")),
+                Ok(_) => {}
+                Err(e) => tracing::warn!("autorenew: {}", e),
+            }
+        }
+    });
+}
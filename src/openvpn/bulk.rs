@@ -0,0 +1,180 @@
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+
+use super::list_issued_with_status;
+use crate::{audit::{self, AuditCtx}, db, jobs::{self, JobRun}, vpncertd, AppState};
+
+const MAX_ITEMS: usize = 1000;
+
+/// Which certs to revoke; the three lists are combined.
+#[derive(Debug, Default, Deserialize)]
+pub struct Selection {
+    #[serde(default)]
+    pub cns: Vec<String>,
+    #[serde(default)]
+    pub serials: Vec<String>,
+    pub group: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Planned {
+    pub cn: String,
+    pub serial: Option<String>,
+    /// `revoke` or `skip`.
+    pub action: String,
+    pub note: Option<String>,
+}
+
+impl Planned {
+    fn revoke(cn: &str, serial: &str) -> Self {
+        Planned { cn: cn.into(), serial: Some(serial.into()), action: "revoke".into(), note: None }
+    }
+    fn skip(cn: &str, serial: Option<&str>, note: &str) -> Self {
+        Planned { cn: cn.into(), serial: serial.map(Into::into), action: "skip".into(), note: Some(note.into()) }
+    }
+}
+
+/// Resolves a selection to serials without touching anything. A CN stands
+/// for every unrevoked cert it holds, so certs in a renewal grace period go too.
+pub async fn plan_revoke(st: &AppState, sel: &Selection) -> Result<Vec<Planned>> {
+    let re = Regex::new(&st.cfg.ovpn.cn_pattern).unwrap();
+    let issued = list_issued_with_status(st, None).await?;
+
+    let mut cns = sel.cns.clone();
+    if let Some(g) = sel.group.as_deref().filter(|g| !g.is_empty()) {
+        cns.extend(db::clients::in_group(&st.db, g).await?);
+    }
+
+    let mut seen = HashSet::new();
+    let mut out = Vec::new();
+    for cn in &cns {
+        if !seen.insert(format!("cn:{cn}")) { continue; }
+        if !re.is_match(cn) {
+            out.push(Planned::skip(cn, None, "invalid_cn"));
+            continue;
+        }
+        let held: Vec<_> = issued.iter().filter(|it| &it.cn == cn).collect();
+        let active: Vec<_> = held.iter().filter(|it| !it.revoked).collect();
+        if held.is_empty() {
+            out.push(Planned::skip(cn, None, "cn_not_found"));
+        } else if active.is_empty() {
+            out.push(Planned::skip(cn, None, "no_active_cert"));
+        }
+        for it in active {
+            if seen.insert(it.serial.clone()) {
+                out.push(Planned::revoke(cn, &it.serial));
+            }
+        }
+    }
+    for serial in &sel.serials {
+        if !seen.insert(serial.clone()) { continue; }
+        match issued.iter().find(|it| &it.serial == serial) {
+            None => out.push(Planned::skip("", Some(serial), "serial_not_found")),
+            Some(it) if it.revoked => out.push(Planned::skip(&it.cn, Some(serial), "already_revoked")),
+            Some(it) => out.push(Planned::revoke(&it.cn, serial)),
+        }
+    }
+    if out.len() > MAX_ITEMS {
+        return Err(anyhow!("too_many_items"));
+    }
+    Ok(out)
+}
+
+/// Identifies a preview; executing requires it back so the certs revoked are
+/// the ones the admin saw.
+pub fn plan_id(reason: &str, plan: &[Planned]) -> String {
+    let body = json!({ "reason": reason, "items": plan });
+    audit::sha256_hex(body.to_string().as_bytes())
+}
+
+/// Records the parent audit event and queues the revocations; returns the
+/// job id. Every item, skipped ones included, gets a result row.
+pub async fn start_revoke(st: &AppState, ctx: &AuditCtx, plan: Vec<Planned>, reason: &str) -> Result<String> {
+    if !vpncertd::REVOKE_REASONS.contains(&reason) {
+        return Err(anyhow!("invalid_reason"));
+    }
+    let serials: Vec<&str> = plan.iter().filter(|p| p.action == "revoke").filter_map(|p| p.serial.as_deref()).collect();
+    if serials.is_empty() {
+        return Err(anyhow!("nothing_to_revoke"));
+    }
+    let params = json!({ "reason": reason, "items": plan });
+    let job = jobs::enqueue(st, ctx, "revoke", plan.len(), &params).await?;
+    ctx.record(&st.db, "CLIENT_REVOKE_BULK", &job, json!({
+        "job": job, "reason": reason, "serials": serials, "skipped": plan.len() - serials.len(),
+    })).await.ok();
+    Ok(job)
+}
+
+pub async fn run(st: &AppState, job: &JobRun) -> Result<Value> {
+    let plan: Vec<Planned> = serde_json::from_value(job.params["items"].clone())?;
+    let reason = job.params["reason"].as_str().ok_or_else(|| anyhow!("job without reason"))?;
+    let socket = &st.cfg.ovpn.socket_path;
+    // Without GEN_CRL every revoke rebuilds the CRL itself.
+    let batch_crl = match vpncertd::capabilities(socket).await {
+        Ok(ops) => ops.iter().any(|o| o == vpncertd::OP_GEN_CRL),
+        Err(e) => {
+            tracing::warn!(job=%job.id, "daemon capabilities: {}", e);
+            false
+        }
+    };
+    let (mut revoked, mut cancelled) = (0, false);
+    for (idx, p) in plan.iter().enumerate() {
+        if job.is_done(idx) { continue; }
+        if job.cancelled(st).await {
+            cancelled = true;
+            break;
+        }
+        let item = p.serial.clone().unwrap_or_else(|| p.cn.clone());
+        let (status, detail) = match p.serial.as_deref().filter(|_| p.action == "revoke") {
+            None => ("skipped", json!({ "cn": p.cn, "serial": p.serial, "note": p.note })),
+            Some(serial) => match vpncertd::revoke_serial(socket, serial, reason, !batch_crl).await {
+                Ok(()) => {
+                    revoked += 1;
+                    job.ctx.record(&st.db, "CLIENT_REVOKE", &p.cn, json!({ "serial": serial, "reason": reason, "parent": job.id }))
+                        .await.ok();
+                    ("ok", json!({ "cn": p.cn, "serial": serial }))
+                }
+                Err(e) if e.to_string().contains("already") => {
+                    ("skipped", json!({ "cn": p.cn, "serial": serial, "note": "already_revoked" }))
+                }
+                Err(e) => {
+                    ("failed", json!({ "cn": p.cn, "serial": serial, "error": e.to_string() }))
+                }
+            },
+        };
+        job.item(st, idx, &item, status, &detail).await?;
+    }
+
+    // After a restart the earlier revocations may not be in the CRL yet.
+    let crl_regenerated = if !batch_crl {
+        revoked > 0
+    } else {
+        (revoked > 0 || job.resumed()) && match vpncertd::regen_crl(socket).await {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::warn!(job=%job.id, "regenerate CRL after bulk revoke: {}", e);
+                false
+            }
+        }
+    };
+    let (revoked, failed) = (job.tally(st, "ok").await?, job.tally(st, "failed").await?);
+    Ok(json!({ "revoked": revoked, "failed": failed, "crl_regenerated": crl_regenerated, "cancelled": cancelled }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plan_id_covers_reason_and_items() {
+        let plan = vec![Planned::revoke("bob", "01"), Planned::skip("eve", None, "cn_not_found")];
+        let id = plan_id("keyCompromise", &plan);
+        assert_eq!(id, plan_id("keyCompromise", &plan.clone()));
+        assert_ne!(id, plan_id("superseded", &plan));
+        assert_ne!(id, plan_id("keyCompromise", &plan[..1]));
+        assert_ne!(id, plan_id("keyCompromise", &[Planned::revoke("bob", "02"), plan[1].clone()]));
+    }
+}
@@ -0,0 +1,702 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// A CCD directive OpenVPN accepts in `client-config-dir` files, typed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "directive", rename_all = "kebab-case")]
+pub enum Directive {
+    /// `remote` is the netmask with `topology subnet`, the peer address with net30.
+    /// `alias` is the optional third argument, passed through as given.
+    IfconfigPush { local: Ipv4Addr, remote: Ipv4Addr, alias: Option<String> },
+    IfconfigIpv6Push { local: String, remote: Option<Ipv6Addr> },
+    Iroute { network: Ipv4Addr, netmask: Ipv4Addr },
+    IrouteIpv6 { network: String },
+    /// `push "route ..."`.
+    Route { network: Ipv4Addr, netmask: Ipv4Addr, gateway: Option<String>, metric: Option<u32> },
+    /// `push "route-ipv6 ..."`.
+    RouteIpv6 { network: String, gateway: Option<String>, metric: Option<u32> },
+    /// `push "dhcp-option ..."`.
+    DhcpOption { kind: String, value: Option<String> },
+    /// Any other allowed pushed option, e.g. `redirect-gateway def1`, verbatim.
+    Push { option: String },
+    PushReset,
+    PushRemove { option: String },
+    Disable,
+    CompLzo { mode: Option<String> },
+    Compress { algorithm: Option<String> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Line {
+    pub line: usize,
+    #[serde(flatten)]
+    pub directive: Directive,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LineError {
+    /// 1-based.
+    pub line: usize,
+    pub directive: Option<String>,
+    pub error: String,
+    /// The offending token, when there is one.
+    pub value: Option<String>,
+}
+
+/// Never allowed: they run programs, read other files or change the server.
+const FORBIDDEN: &[&str] = &[
+    "config", "up", "down", "route-up", "route-pre-down", "ipchange", "client-connect", "client-disconnect",
+    "learn-address", "auth-user-pass-verify", "tls-verify", "script-security", "plugin", "setenv", "setenv-safe",
+    "management", "daemon", "log", "log-append", "writepid", "cd", "chroot", "user", "group", "dev", "status",
+];
+
+/// Options we let the server push; everything else is rejected.
+const PUSHABLE: &[&str] = &[
+    "route", "route-ipv6", "dhcp-option", "redirect-gateway", "redirect-private", "route-gateway", "route-metric",
+    "block-outside-dns", "register-dns", "ping", "ping-restart", "inactive", "explicit-exit-notify", "topology",
+    "comp-lzo", "compress",
+];
+
+/// Directives `directive` understands.
+const KNOWN: &[&str] = &[
+    "ifconfig-push", "ifconfig-ipv6-push", "iroute", "iroute-ipv6", "push", "push-reset", "disable", "push-remove",
+    "comp-lzo", "compress",
+];
+
+/// Directives a file may contain once.
+const UNIQUE: &[&str] = &["ifconfig-push", "ifconfig-ipv6-push", "disable", "push-reset", "comp-lzo", "compress"];
+
+const GATEWAY_KEYWORDS: &[&str] = &["vpn_gateway", "net_gateway", "remote_host", "default"];
+const REDIRECT_FLAGS: &[&str] = &["local", "autolocal", "def1", "bypass-dhcp", "bypass-dns", "block-local", "ipv6", "!ipv4"];
+
+#[derive(Debug)]
+struct Fail {
+    error: &'static str,
+    value: Option<String>,
+}
+
+fn fail(error: &'static str, value: &str) -> Fail {
+    Fail { error, value: Some(value.to_string()) }
+}
+
+fn bare(error: &'static str) -> Fail {
+    Fail { error, value: None }
+}
+
+/// Parses and checks a whole file. Blank lines and comments are dropped; any
+/// error rejects the file, with one entry per offending line.
+pub fn parse(text: &str) -> Result<Vec<Line>, Vec<LineError>> {
+    let mut out = Vec::new();
+    let mut errors = Vec::new();
+    let mut once = HashSet::new();
+    for (i, raw) in text.lines().enumerate() {
+        let line = i + 1;
+        let tokens = match tokenize(raw) {
+            Ok(t) => t,
+            Err(f) => {
+                errors.push(LineError { line, directive: None, error: f.error.into(), value: f.value });
+                continue;
+            }
+        };
+        let Some((name, args)) = tokens.split_first() else { continue };
+        match directive(name, args) {
+            Ok(d) => {
+                if UNIQUE.contains(&name.as_str()) && !once.insert(name.clone()) {
+                    errors.push(LineError { line, directive: Some(name.clone()), error: "duplicate_directive".into(), value: None });
+                    continue;
+                }
+                out.push(Line { line, directive: d });
+            }
+            Err(f) => errors.push(LineError { line, directive: Some(name.clone()), error: f.error.into(), value: f.value }),
+        }
+    }
+    if errors.is_empty() { Ok(out) } else { Err(errors) }
+}
+
+/// The `ifconfig-push` address of a file, even if other lines are invalid.
+pub fn static_ip(text: &str) -> Option<Ipv4Addr> {
+    text.lines().filter_map(|l| tokenize(l).ok()).find_map(|t| match t.as_slice() {
+        [name, local, ..] if name == "ifconfig-push" => local.parse().ok(),
+        _ => None,
+    })
+}
+
+/// Both `ifconfig-push` addresses of a file, wherever the line sits.
+pub fn ifconfig_push(text: &str) -> Option<(Ipv4Addr, Ipv4Addr)> {
+    text.lines().filter_map(|l| tokenize(l).ok()).find_map(|t| match t.as_slice() {
+        [name, local, remote, ..] if name == "ifconfig-push" => Some((local.parse().ok()?, remote.parse().ok()?)),
+        _ => None,
+    })
+}
+
+/// Whether `name` is a directive keyword, allowed or not.
+pub fn is_keyword(name: &str) -> bool {
+    KNOWN.contains(&name) || FORBIDDEN.contains(&name)
+}
+
+/// `top` layered over `base`: a once-only directive in `top` replaces the one
+/// in `base`, everything else is added after it. `push-reset` goes first
+/// since it also drops pushes that precede it.
+pub fn overlay(base: &str, top: &str) -> String {
+    let keyword = |l: &str| tokenize(l).ok().and_then(|t| t.into_iter().next());
+    let replaced: HashSet<String> = top.lines().filter_map(keyword).filter(|k| UNIQUE.contains(&k.as_str())).collect();
+    let (reset, rest): (Vec<&str>, Vec<&str>) = top.lines().partition(|l| keyword(l).as_deref() == Some("push-reset"));
+    reset.into_iter()
+        .chain(base.lines().filter(|l| keyword(l).is_none_or(|k| !replaced.contains(&k))))
+        .chain(rest)
+        .map(|l| format!("{l}\n"))
+        .collect()
+}
+
+/// One-line summary for callers that only have an `anyhow` error to give.
+pub fn summary(errors: &[LineError]) -> String {
+    let first = &errors[0];
+    format!("ccd_invalid: line {}: {}{}", first.line, first.error,
+            if errors.len() > 1 { format!(" (+{} more)", errors.len() - 1) } else { String::new() })
+}
+
+/// Splits like OpenVPN's config parser: whitespace separated, `"..."` and
+/// `'...'` quoting, and `#` or `;` at a token start begins a comment.
+fn tokenize(line: &str) -> Result<Vec<String>, Fail> {
+    tokenize_commented(line).map(|(t, _)| t)
+}
+
+/// `tokenize`, also telling whether the line ends in a comment.
+fn tokenize_commented(line: &str) -> Result<(Vec<String>, bool), Fail> {
+    let mut tokens = Vec::new();
+    let mut comment = false;
+    let mut cur = String::new();
+    let mut in_token = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '#' | ';' if !in_token => {
+                comment = true;
+                break;
+            }
+            '"' | '\'' => {
+                let mut closed = false;
+                while let Some(q) = chars.next() {
+                    if q == c { closed = true; break; }
+                    if q == '\\' && c == '"' && let Some(&n) = chars.peek() && (n == '"' || n == '\\') {
+                        cur.push(n);
+                        chars.next();
+                        continue;
+                    }
+                    cur.push(q);
+                }
+                if !closed { return Err(bare("unterminated_quote")); }
+                in_token = true;
+            }
+            c if c.is_whitespace() => {
+                if in_token { tokens.push(std::mem::take(&mut cur)); }
+                in_token = false;
+            }
+            c => {
+                cur.push(c);
+                in_token = true;
+            }
+        }
+    }
+    if in_token { tokens.push(cur); }
+    Ok((tokens, comment))
+}
+
+fn arity(args: &[String], min: usize, max: usize) -> Result<(), Fail> {
+    if args.len() < min { return Err(bare("missing_argument")); }
+    if args.len() > max { return Err(fail("unexpected_argument", &args[max])); }
+    Ok(())
+}
+
+fn directive(name: &str, args: &[String]) -> Result<Directive, Fail> {
+    match name {
+        "ifconfig-push" => {
+            arity(args, 2, 3)?;
+            let local = ipv4(&args[0])?;
+            let remote = ipv4(&args[1])?;
+            if remote.octets()[0] == 255 {
+                let mask = netmask(&args[1])?;
+                let m = u32::from(mask);
+                let host = u32::from(local) & !m;
+                if m != u32::MAX && (host == 0 || host == !m) { return Err(fail("reserved_address", &args[0])); }
+            } else if local == remote {
+                return Err(fail("peer_equals_local", &args[1]));
+            }
+            Ok(Directive::IfconfigPush { local, remote, alias: args.get(2).cloned() })
+        }
+        "ifconfig-ipv6-push" => {
+            arity(args, 1, 2)?;
+            ipv6_net(&args[0])?;
+            let remote = args.get(1).map(|r| r.parse::<Ipv6Addr>().map_err(|_| fail("invalid_ip", r))).transpose()?;
+            Ok(Directive::IfconfigIpv6Push { local: args[0].clone(), remote })
+        }
+        "iroute" => {
+            arity(args, 1, 2)?;
+            let (network, netmask) = network(&args[0], args.get(1).map(String::as_str))?;
+            Ok(Directive::Iroute { network, netmask })
+        }
+        "iroute-ipv6" => {
+            arity(args, 1, 1)?;
+            ipv6_net(&args[0])?;
+            Ok(Directive::IrouteIpv6 { network: args[0].clone() })
+        }
+        "push" => {
+            arity(args, 1, 1)?;
+            push(&args[0])
+        }
+        "push-reset" => { arity(args, 0, 0)?; Ok(Directive::PushReset) }
+        "disable" => { arity(args, 0, 0)?; Ok(Directive::Disable) }
+        "push-remove" => {
+            arity(args, 1, 1)?;
+            Ok(Directive::PushRemove { option: args[0].clone() })
+        }
+        "comp-lzo" => {
+            arity(args, 0, 1)?;
+            one_of(args.first(), &["yes", "no", "adaptive"])?;
+            Ok(Directive::CompLzo { mode: args.first().cloned() })
+        }
+        "compress" => {
+            arity(args, 0, 1)?;
+            one_of(args.first(), &["lzo", "lz4", "lz4-v2", "stub", "stub-v2", "migrate"])?;
+            Ok(Directive::Compress { algorithm: args.first().cloned() })
+        }
+        n if FORBIDDEN.contains(&n) => Err(bare("forbidden_directive")),
+        _ => Err(bare("unknown_directive")),
+    }
+}
+
+fn push(option: &str) -> Result<Directive, Fail> {
+    let parts: Vec<&str> = option.split_whitespace().collect();
+    let Some((&name, args)) = parts.split_first() else { return Err(bare("empty_push")) };
+    if FORBIDDEN.contains(&name) { return Err(fail("forbidden_push_option", name)); }
+    if !PUSHABLE.contains(&name) { return Err(fail("push_option_not_allowed", name)); }
+    let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+    let verbatim = || Directive::Push { option: parts.join(" ") };
+    match name {
+        "route" => {
+            arity(&args, 1, 4)?;
+            let (network, netmask) = network(&args[0], args.get(1).map(String::as_str))?;
+            let gateway = args.get(2).map(|g| gateway(g, false)).transpose()?;
+            let metric = args.get(3).map(|m| number(m)).transpose()?;
+            Ok(Directive::Route { network, netmask, gateway, metric })
+        }
+        "route-ipv6" => {
+            arity(&args, 1, 3)?;
+            ipv6_net(&args[0])?;
+            let gateway = args.get(1).map(|g| gateway(g, true)).transpose()?;
+            let metric = args.get(2).map(|m| number(m)).transpose()?;
+            Ok(Directive::RouteIpv6 { network: args[0].clone(), gateway, metric })
+        }
+        "dhcp-option" => {
+            arity(&args, 1, 2)?;
+            let kind = args[0].to_ascii_uppercase();
+            let value = args.get(1).cloned();
+            dhcp_option(&kind, value.as_deref())?;
+            Ok(Directive::DhcpOption { kind, value })
+        }
+        "redirect-gateway" | "redirect-private" => {
+            for f in &args {
+                if !REDIRECT_FLAGS.contains(&f.as_str()) { return Err(fail("invalid_flag", f)); }
+            }
+            Ok(verbatim())
+        }
+        "route-gateway" => {
+            arity(&args, 1, 1)?;
+            if args[0] != "dhcp" { ipv4(&args[0])?; }
+            Ok(verbatim())
+        }
+        "route-metric" | "ping" | "ping-restart" => {
+            arity(&args, 1, 1)?;
+            number(&args[0])?;
+            Ok(verbatim())
+        }
+        "inactive" => {
+            arity(&args, 1, 2)?;
+            for a in &args { number(a)?; }
+            Ok(verbatim())
+        }
+        "explicit-exit-notify" => {
+            arity(&args, 0, 1)?;
+            if let Some(a) = args.first() { number(a)?; }
+            Ok(verbatim())
+        }
+        "topology" => {
+            arity(&args, 1, 1)?;
+            one_of(args.first(), &["net30", "p2p", "subnet"])?;
+            Ok(verbatim())
+        }
+        "comp-lzo" => {
+            arity(&args, 0, 1)?;
+            one_of(args.first(), &["yes", "no", "adaptive"])?;
+            Ok(verbatim())
+        }
+        "compress" => {
+            arity(&args, 0, 1)?;
+            one_of(args.first(), &["lzo", "lz4", "lz4-v2", "stub", "stub-v2"])?;
+            Ok(verbatim())
+        }
+        _ => {
+            arity(&args, 0, 0)?;
+            Ok(verbatim())
+        }
+    }
+}
+
+fn dhcp_option(kind: &str, value: Option<&str>) -> Result<(), Fail> {
+    fn need(v: Option<&str>) -> Result<&str, Fail> {
+        v.ok_or_else(|| bare("missing_argument"))
+    }
+    match kind {
+        "DNS" => { need(value).and_then(|v| v.parse::<IpAddr>().map_err(|_| fail("invalid_ip", v)))?; }
+        "DNS6" => { need(value).and_then(|v| v.parse::<Ipv6Addr>().map_err(|_| fail("invalid_ip", v)))?; }
+        "WINS" | "NBDD" | "NTP" => { need(value).and_then(ipv4)?; }
+        "DOMAIN" | "DOMAIN-SEARCH" | "ADAPTER_DOMAIN_SUFFIX" => {
+            let v = need(value)?;
+            if !domain_ok(v) { return Err(fail("invalid_domain", v)); }
+        }
+        "NBT" => {
+            let v = need(value)?;
+            if !["1", "2", "4", "8"].contains(&v) { return Err(fail("invalid_value", v)); }
+        }
+        "NBS" => { need(value)?; }
+        "DISABLE-NBT" => if let Some(v) = value { return Err(fail("unexpected_argument", v)); },
+        _ => return Err(fail("unknown_dhcp_option", kind)),
+    }
+    Ok(())
+}
+
+pub fn domain_ok(d: &str) -> bool {
+    let d = d.strip_suffix('.').unwrap_or(d);
+    !d.is_empty() && d.len() <= 253 && d.split('.').all(|l| {
+        !l.is_empty() && l.len() <= 63 && !l.starts_with('-') && !l.ends_with('-')
+            && l.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    })
+}
+
+fn one_of(v: Option<&String>, allowed: &[&str]) -> Result<(), Fail> {
+    match v {
+        Some(v) if !allowed.contains(&v.as_str()) => Err(fail("invalid_value", v)),
+        _ => Ok(()),
+    }
+}
+
+fn number(s: &str) -> Result<u32, Fail> {
+    s.parse().map_err(|_| fail("invalid_number", s))
+}
+
+fn ipv4(s: &str) -> Result<Ipv4Addr, Fail> {
+    s.parse().map_err(|_| fail("invalid_ip", s))
+}
+
+pub fn netmask_ok(m: Ipv4Addr) -> bool {
+    let m = u32::from(m);
+    m.leading_ones() + m.trailing_zeros() == 32
+}
+
+fn netmask(s: &str) -> Result<Ipv4Addr, Fail> {
+    let m = ipv4(s).map_err(|_| fail("invalid_netmask", s))?;
+    if netmask_ok(m) { Ok(m) } else { Err(fail("invalid_netmask", s)) }
+}
+
+/// A network and its mask (a host route when the mask is omitted); host bits
+/// must be clear, which is what OpenVPN silently trips over otherwise.
+fn network(net: &str, mask: Option<&str>) -> Result<(Ipv4Addr, Ipv4Addr), Fail> {
+    let n = ipv4(net)?;
+    let m = match mask { Some(m) => netmask(m)?, None => Ipv4Addr::BROADCAST };
+    if u32::from(n) & !u32::from(m) != 0 { return Err(fail("host_bits_set", net)); }
+    Ok((n, m))
+}
+
+fn ipv6_net(s: &str) -> Result<(), Fail> {
+    let (addr, bits) = s.split_once('/').unwrap_or((s, "128"));
+    addr.parse::<Ipv6Addr>().map_err(|_| fail("invalid_ip", s))?;
+    match bits.parse::<u8>() {
+        Ok(b) if b <= 128 => Ok(()),
+        _ => Err(fail("invalid_prefix", s)),
+    }
+}
+
+fn gateway(g: &str, v6: bool) -> Result<String, Fail> {
+    let ok = GATEWAY_KEYWORDS.contains(&g)
+        || if v6 { g.parse::<Ipv6Addr>().is_ok() } else { g.parse::<Ipv4Addr>().is_ok() };
+    if ok { Ok(g.to_string()) } else { Err(fail("invalid_gateway", g)) }
+}
+
+/// The common parts of a CCD as form fields. Everything else, comments
+/// included, is kept verbatim in `extra` so a round trip loses nothing.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Structured {
+    pub static_ip: Option<StaticIp>,
+    #[serde(default)]
+    pub iroutes: Vec<Subnet>,
+    #[serde(default)]
+    pub routes: Vec<PushRoute>,
+    #[serde(default)]
+    pub dns: Vec<String>,
+    #[serde(default)]
+    pub search_domains: Vec<String>,
+    #[serde(default)]
+    pub disable: bool,
+    #[serde(default)]
+    pub extra: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StaticIp {
+    pub ip: String,
+    /// Netmask with `topology subnet`, peer address with net30.
+    pub remote: String,
+    /// The optional third `ifconfig-push` argument.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+}
+
+impl StaticIp {
+    fn args(&self) -> String {
+        match &self.alias {
+            Some(a) => format!("{} {} {a}", self.ip, self.remote),
+            None => format!("{} {}", self.ip, self.remote),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Subnet {
+    pub network: String,
+    pub netmask: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PushRoute {
+    pub network: String,
+    pub netmask: String,
+    pub gateway: Option<String>,
+    pub metric: Option<u32>,
+}
+
+impl Structured {
+    pub fn from_text(text: &str) -> Result<Self, Vec<LineError>> {
+        let parsed = parse(text)?;
+        let raw: Vec<&str> = text.lines().collect();
+        let mut s = Structured::default();
+        let mut used = HashSet::new();
+        for l in parsed {
+            // A trailing comment has no field to go in; keep the line whole.
+            if tokenize_commented(raw[l.line - 1]).is_ok_and(|(_, c)| c) { continue; }
+            let mapped = match l.directive {
+                Directive::IfconfigPush { local, remote, alias } => {
+                    s.static_ip = Some(StaticIp { ip: local.to_string(), remote: remote.to_string(), alias });
+                    true
+                }
+                Directive::Iroute { network, netmask } => {
+                    s.iroutes.push(Subnet { network: network.to_string(), netmask: netmask.to_string() });
+                    true
+                }
+                Directive::Route { network, netmask, gateway, metric } => {
+                    s.routes.push(PushRoute { network: network.to_string(), netmask: netmask.to_string(), gateway, metric });
+                    true
+                }
+                Directive::DhcpOption { kind, value: Some(v) } if kind == "DNS" => { s.dns.push(v); true }
+                Directive::DhcpOption { kind, value: Some(v) } if kind == "DOMAIN-SEARCH" => { s.search_domains.push(v); true }
+                Directive::Disable => { s.disable = true; true }
+                _ => false,
+            };
+            if mapped { used.insert(l.line); }
+        }
+        s.extra = raw.iter().enumerate()
+            .filter(|(i, l)| !used.contains(&(i + 1)) && !l.trim().is_empty())
+            .map(|(_, l)| l.trim().to_string())
+            .collect();
+        Ok(s)
+    }
+
+    /// Canonical CCD text, plus the field each line came from so parse
+    /// errors can be reported against the form (`routes[1]`, `extra[0]`, ...).
+    pub fn render(&self) -> (String, Vec<String>) {
+        let mut out: Vec<(String, String)> = Vec::new();
+        if self.disable {
+            out.push(("disable".into(), "disable".into()));
+        }
+        if let Some(ip) = &self.static_ip {
+            out.push(("static_ip".into(), format!("ifconfig-push {}", ip.args())));
+        }
+        // push-reset and friends have to come before our own pushes.
+        for (i, l) in self.extra.iter().enumerate() {
+            out.push((format!("extra[{i}]"), l.trim().to_string()));
+        }
+        for (i, r) in self.iroutes.iter().enumerate() {
+            out.push((format!("iroutes[{i}]"), format!("iroute {} {}", r.network, r.netmask)));
+        }
+        for (i, r) in self.routes.iter().enumerate() {
+            let mut line = format!("route {} {}", r.network, r.netmask);
+            match (&r.gateway, r.metric) {
+                (Some(g), Some(m)) => line.push_str(&format!(" {g} {m}")),
+                (Some(g), None) => line.push_str(&format!(" {g}")),
+                (None, Some(m)) => line.push_str(&format!(" vpn_gateway {m}")),
+                (None, None) => {}
+            }
+            out.push((format!("routes[{i}]"), format!("push \"{line}\"")));
+        }
+        for (i, d) in self.dns.iter().enumerate() {
+            out.push((format!("dns[{i}]"), format!("push \"dhcp-option DNS {d}\"")));
+        }
+        for (i, d) in self.search_domains.iter().enumerate() {
+            out.push((format!("search_domains[{i}]"), format!("push \"dhcp-option DOMAIN-SEARCH {d}\"")));
+        }
+        let fields = out.iter().map(|(f, _)| f.clone()).collect();
+        (out.into_iter().map(|(_, l)| l + "\n").collect(), fields)
+    }
+
+    /// Renders and checks the result with `parse`, reporting errors per field.
+    pub fn to_text(&self) -> Result<String, Vec<FieldError>> {
+        let (text, fields) = self.render();
+        // A line break or quote in a value would smuggle in lines of its own.
+        let bad: Vec<FieldError> = fields.iter().zip(self.values())
+            .filter(|(f, v)| v.contains(['\n', '\r']) || (!f.starts_with("extra") && v.contains('"')))
+            .map(|(f, v)| FieldError { field: f.clone(), error: "invalid_characters".into(), value: Some(v) })
+            .collect();
+        if !bad.is_empty() { return Err(bad); }
+        parse(&text).map(|_| text).map_err(|errs| errs.into_iter().map(|e| FieldError {
+            field: fields.get(e.line - 1).cloned().unwrap_or_default(),
+            error: e.error,
+            value: e.value,
+        }).collect())
+    }
+
+    /// Field values in `render` order, for error reporting.
+    fn values(&self) -> Vec<String> {
+        let mut v = Vec::new();
+        if self.disable { v.push(String::new()); }
+        if let Some(ip) = &self.static_ip { v.push(ip.args()); }
+        v.extend(self.extra.iter().cloned());
+        v.extend(self.iroutes.iter().map(|r| format!("{} {}", r.network, r.netmask)));
+        v.extend(self.routes.iter().map(|r| format!("{} {} {}", r.network, r.netmask, r.gateway.as_deref().unwrap_or(""))));
+        v.extend(self.dns.iter().cloned());
+        v.extend(self.search_domains.iter().cloned());
+        v
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    /// e.g. `static_ip`, `routes[1]`, `extra[0]`.
+    pub field: String,
+    pub error: String,
+    pub value: Option<String>,
+}
+
+/// `diff -u` style output with three lines of context.
+pub fn unified_diff(old: &str, new: &str, old_label: &str, new_label: &str) -> String {
+    similar::TextDiff::from_lines(old, new)
+        .unified_diff()
+        .context_radius(3)
+        .header(old_label, new_label)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(text: &str) -> Vec<(usize, String, Option<String>)> {
+        parse(text).unwrap_err().into_iter().map(|e| (e.line, e.error, e.value)).collect()
+    }
+
+    /// Form fields survive a render and the rendered text means the same to
+    /// OpenVPN as the original, whatever the order.
+    fn round_trip(text: &str) -> Structured {
+        let s = Structured::from_text(text).unwrap();
+        let out = s.to_text().unwrap();
+        assert_eq!(Structured::from_text(&out).unwrap(), s, "{out}");
+        let directives = |t: &str| {
+            let mut d: Vec<String> = parse(t).unwrap().into_iter().map(|l| format!("{:?}", l.directive)).collect();
+            d.sort();
+            d
+        };
+        assert_eq!(directives(&out), directives(text), "{out}");
+        s
+    }
+
+    #[test]
+    fn comments_are_kept() {
+        let text = "# office router\nifconfig-push 10.8.0.5 255.255.255.0\n; legacy\niroute 10.1.0.0 255.255.0.0 # lab\n";
+        let s = round_trip(text);
+        assert_eq!(s.static_ip.unwrap().ip, "10.8.0.5");
+        // The commented iroute stays verbatim rather than losing its comment.
+        assert!(s.iroutes.is_empty());
+        assert_eq!(s.extra, ["# office router", "; legacy", "iroute 10.1.0.0 255.255.0.0 # lab"]);
+        assert_eq!(parse("push \"route 10.2.0.0 255.255.0.0\" # via hq\n").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn quoting() {
+        let text = "push \"route 10.2.0.0 255.255.0.0 vpn_gateway 5\"\npush 'dhcp-option DNS 10.0.0.1'\npush \"dhcp-option DOMAIN-SEARCH corp.example\"\n";
+        let s = round_trip(text);
+        assert_eq!(s.routes, [PushRoute {
+            network: "10.2.0.0".into(), netmask: "255.255.0.0".into(), gateway: Some("vpn_gateway".into()), metric: Some(5),
+        }]);
+        assert_eq!(s.dns, ["10.0.0.1"]);
+        assert_eq!(s.search_domains, ["corp.example"]);
+        // `#` inside quotes is not a comment; an escaped quote stays in the token.
+        assert_eq!(tokenize_commented("push \"a#b\" 'c;d'").unwrap(), (vec!["push".into(), "a#b".into(), "c;d".into()], false));
+        assert_eq!(tokenize(r#"x "a\"b""#).unwrap(), ["x", "a\"b"]);
+        assert_eq!(errors("push \"route 10.2.0.0\n"), [(1, "unterminated_quote".into(), None)]);
+    }
+
+    #[test]
+    fn duplicate_directives() {
+        let text = "ifconfig-push 10.8.0.5 255.255.255.0\ndisable\nifconfig-push 10.8.0.6 255.255.255.0\ndisable\n";
+        assert_eq!(errors(text), [(3, "duplicate_directive".into(), None), (4, "duplicate_directive".into(), None)]);
+        // Repeatable ones are fine and all kept.
+        let s = round_trip("iroute 10.1.0.0 255.255.0.0\niroute 10.2.0.0 255.255.0.0\n");
+        assert_eq!(s.iroutes.len(), 2);
+    }
+
+    #[test]
+    fn unknown_and_forbidden_directives() {
+        assert_eq!(errors("frobnicate 1\nup /bin/sh\npush \"up /bin/sh\"\npush \"auth-nocache\"\n"), [
+            (1, "unknown_directive".into(), None),
+            (2, "forbidden_directive".into(), None),
+            (3, "forbidden_push_option".into(), Some("up".into())),
+            (4, "push_option_not_allowed".into(), Some("auth-nocache".into())),
+        ]);
+        // Known but unmapped directives go to `extra` and come back unchanged.
+        let s = round_trip("push-reset\npush \"redirect-gateway def1 bypass-dhcp\"\ncompress lz4-v2\n");
+        assert_eq!(s.extra, ["push-reset", "push \"redirect-gateway def1 bypass-dhcp\"", "compress lz4-v2"]);
+    }
+
+    #[test]
+    fn invalid_addresses() {
+        assert_eq!(errors("ifconfig-push 10.8.0.256 255.255.255.0\n"), [(1, "invalid_ip".into(), Some("10.8.0.256".into()))]);
+        assert_eq!(errors("ifconfig-push 10.8.0.5 255.0.255.0\n"), [(1, "invalid_netmask".into(), Some("255.0.255.0".into()))]);
+        assert_eq!(errors("ifconfig-push 10.8.0.0 255.255.255.0\n"), [(1, "reserved_address".into(), Some("10.8.0.0".into()))]);
+        assert_eq!(errors("ifconfig-push 10.8.0.5 10.8.0.5\n"), [(1, "peer_equals_local".into(), Some("10.8.0.5".into()))]);
+        assert_eq!(errors("iroute 10.1.0.1 255.255.0.0\n"), [(1, "host_bits_set".into(), Some("10.1.0.1".into()))]);
+        assert_eq!(errors("push \"dhcp-option DNS dns.example\"\n"), [(1, "invalid_ip".into(), Some("dns.example".into()))]);
+        assert_eq!(errors("ifconfig-ipv6-push fd00::5/129\n"), [(1, "invalid_prefix".into(), Some("fd00::5/129".into()))]);
+    }
+
+    #[test]
+    fn ifconfig_push_alias() {
+        let s = round_trip("ifconfig-push 10.8.0.5 255.255.255.0 office\n");
+        assert_eq!(s.static_ip, Some(StaticIp { ip: "10.8.0.5".into(), remote: "255.255.255.0".into(), alias: Some("office".into()) }));
+        assert!(s.extra.is_empty());
+        assert_eq!(s.render().0, "ifconfig-push 10.8.0.5 255.255.255.0 office\n");
+        assert_eq!(errors("ifconfig-push 10.8.0.5 255.255.255.0 a b\n"), [(1, "unexpected_argument".into(), Some("b".into()))]);
+    }
+
+    #[test]
+    fn overlay_replaces_once_only_directives() {
+        let base = "ifconfig-push 10.8.0.5 255.255.255.0\npush \"route 10.1.0.0 255.255.0.0\"\ncompress lz4\n";
+        let top = "compress lz4-v2\npush \"route 10.2.0.0 255.255.0.0\"\npush-reset\n";
+        assert_eq!(overlay(base, top), concat!(
+            "push-reset\n",
+            "ifconfig-push 10.8.0.5 255.255.255.0\n",
+            "push \"route 10.1.0.0 255.255.0.0\"\n",
+            "compress lz4-v2\n",
+            "push \"route 10.2.0.0 255.255.0.0\"\n",
+        ));
+        assert_eq!(overlay(base, ""), base);
+    }
+}
@@ -0,0 +1,390 @@
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use serde_json::json;
+use tokio::sync::Mutex;
+
+use super::{ccd::{self, LineError, StaticIp}, ccd_etag, ipam, read_ccd, write_generated};
+use crate::{audit::AuditCtx, db::{self, ccd_templates::{Template, Vars}}, AppState};
+
+/// Filled in by the panel; clients and defaults cannot set them.
+pub const BUILTIN: &[&str] = &["cn", "static_ip", "static_remote"];
+
+// One template change or attach at a time, so a regeneration never races
+// another one over the same CCD files.
+static LOCK: Mutex<()> = Mutex::const_new(());
+
+/// Lower-case letters, digits, `-` and `_`; never a directive name, so a bare
+/// name in place of CCD text is unambiguous.
+pub fn name_ok(name: &str) -> bool {
+    (1..=64).contains(&name.len())
+        && name.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+        && !ccd::is_keyword(name)
+}
+
+/// The template a `ccd` field refers to, when it holds a bare name instead of CCD text.
+pub fn as_name(ccd: &str) -> Option<&str> {
+    Some(ccd.trim()).filter(|n| name_ok(n))
+}
+
+fn var_ok(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+/// A line error and whether it is in the template body or the client's `extra`.
+#[derive(Debug, Clone, Serialize)]
+pub struct RenderError {
+    /// `template` or `extra`.
+    pub part: &'static str,
+    #[serde(flatten)]
+    pub error: LineError,
+}
+
+fn in_part(part: &'static str, errors: Vec<LineError>) -> Vec<RenderError> {
+    errors.into_iter().map(|error| RenderError { part, error }).collect()
+}
+
+/// Replaces every `{{ name }}`. Values cannot contain line breaks, so a
+/// variable never adds lines of its own.
+fn substitute(body: &str, vars: &Vars) -> Result<String, Vec<LineError>> {
+    let mut out = String::new();
+    let mut errors = Vec::new();
+    for (i, raw) in body.lines().enumerate() {
+        let err = |error: &str, value: &str| LineError {
+            line: i + 1,
+            directive: raw.split_whitespace().next().map(str::to_string),
+            error: error.into(),
+            value: Some(value.to_string()),
+        };
+        let mut rest = raw;
+        let mut line = String::new();
+        while let Some(start) = rest.find("{{") {
+            line.push_str(&rest[..start]);
+            let Some(len) = rest[start + 2..].find("}}") else {
+                errors.push(err("unterminated_variable", &rest[start..]));
+                rest = "";
+                break;
+            };
+            let name = rest[start + 2..start + 2 + len].trim();
+            match vars.get(name) {
+                _ if !var_ok(name) => errors.push(err("invalid_variable", name)),
+                Some(v) if v.chars().any(char::is_control) => errors.push(err("invalid_variable_value", name)),
+                Some(v) => line.push_str(v),
+                None if BUILTIN.contains(&name) => errors.push(err("missing_variable", name)),
+                None => errors.push(err("unknown_variable", name)),
+            }
+            rest = &rest[start + 2 + len + 2..];
+        }
+        line.push_str(rest);
+        out.push_str(&line);
+        out.push('\n');
+    }
+    if errors.is_empty() { Ok(out) } else { Err(errors) }
+}
+
+/// Checks client variables before they are stored.
+fn check_vars(vars: &Vars) -> Result<()> {
+    if let Some(k) = vars.keys().find(|k| BUILTIN.contains(&k.as_str())) {
+        return Err(anyhow!("reserved_variable: {k}"));
+    }
+    if let Some(k) = vars.keys().find(|k| !var_ok(k)) {
+        return Err(anyhow!("invalid_variable: {k}"));
+    }
+    Ok(())
+}
+
+/// The CCD text for `cn`: the template with its variables filled in, then
+/// `extra` on top. An address the panel allocated is kept even if the
+/// template does not mention `{{static_ip}}`.
+pub fn render(tpl: &Template, cn: &str, static_ip: Option<&StaticIp>, vars: &Vars, extra: &str) -> Result<String, Vec<RenderError>> {
+    let mut all = tpl.defaults.clone();
+    all.extend(vars.iter().map(|(k, v)| (k.clone(), v.clone())));
+    all.insert("cn".into(), cn.into());
+    if let Some(s) = static_ip {
+        all.insert("static_ip".into(), s.ip.clone());
+        all.insert("static_remote".into(), s.remote.clone());
+    }
+    let extra = extra.replace("\r\n", "\n");
+    let body = substitute(&tpl.body.replace("\r\n", "\n"), &all).map_err(|e| in_part("template", e))?;
+    ccd::parse(&body).map_err(|e| in_part("template", e))?;
+    ccd::parse(&extra).map_err(|e| in_part("extra", e))?;
+
+    let mut text = format!("# generated from CCD template '{}'; edit the template or its overrides instead\n", tpl.name);
+    text.push_str(&ccd::overlay(&body, &extra));
+    if let Some(s) = static_ip
+        && ccd::static_ip(&text).is_none() {
+        text.push_str(&format!("ifconfig-push {} {}\n", s.ip, s.remote));
+    }
+    ccd::parse(&text).map_err(|e| in_part("extra", e))?;
+    Ok(text)
+}
+
+/// Renders for a CN that may not exist yet, with its allocation if it has one.
+async fn render_for(st: &AppState, tpl: &Template, cn: &str, vars: &Vars, extra: &str) -> Result<Result<String, Vec<RenderError>>> {
+    let static_ip = ipam::assigned(st, cn).await?;
+    Ok(render(tpl, cn, static_ip.as_ref(), vars, extra))
+}
+
+/// Checks a template on its own, with made-up values for the built-in variables.
+fn check_template(tpl: &Template) -> Result<(), Vec<RenderError>> {
+    let sample = StaticIp { ip: "10.8.0.2".into(), remote: "255.255.255.0".into(), alias: None };
+    render(tpl, "example", Some(&sample), &Vars::new(), "").map(drop)
+}
+
+/// What a template change does to one linked CN.
+#[derive(Debug, Serialize)]
+pub struct Affected {
+    pub cn: String,
+    /// `unchanged`, `changed`, `skipped` or `invalid` in a preview; after
+    /// saving, `changed` files are `updated` or `failed`. `skipped` CNs lack
+    /// a built-in variable, e.g. `static_ip` without an allocation, and keep
+    /// their CCD as it is.
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<RenderError>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip)]
+    text: Option<String>,
+    #[serde(skip)]
+    etag: String,
+}
+
+/// Why a template was not saved.
+pub enum Rejected {
+    Template(Vec<RenderError>),
+    /// At least one linked CN would get an invalid CCD for a reason other
+    /// than a missing built-in variable; all of them are listed.
+    Dependents(Vec<Affected>),
+}
+
+async fn affected(st: &AppState, tpl: &Template) -> Result<Vec<Affected>> {
+    let mut out = Vec::new();
+    for l in db::ccd_templates::links(&st.db, &tpl.name).await? {
+        let current = read_ccd(st, &l.cn).await?;
+        let mut a = Affected {
+            cn: l.cn.clone(), status: "unchanged", diff: None, errors: Vec::new(), error: None, text: None,
+            etag: ccd_etag(&current),
+        };
+        match render_for(st, tpl, &l.cn, &l.vars, &l.extra).await? {
+            Ok(text) if text == current => {}
+            Ok(text) => {
+                a.status = "changed";
+                a.diff = Some(ccd::unified_diff(&current, &text, &format!("{} (current)", l.cn), &format!("{} (template)", l.cn)));
+                a.text = Some(text);
+            }
+            Err(errors) => {
+                a.status = if errors.iter().all(|e| e.error.error == "missing_variable") { "skipped" } else { "invalid" };
+                a.errors = errors;
+            }
+        }
+        out.push(a);
+    }
+    Ok(out)
+}
+
+fn candidate(name: &str, description: Option<&str>, body: &str, defaults: &Vars) -> Result<Template> {
+    if !name_ok(name) { return Err(anyhow!("invalid_template_name")); }
+    check_vars(defaults)?;
+    Ok(Template {
+        name: name.into(), description: description.map(str::to_string), body: body.into(), defaults: defaults.clone(),
+        updated_by: String::new(), updated_at: 0,
+    })
+}
+
+/// What saving `body` as `name` would do to every linked CN. Nothing is written.
+pub async fn preview(st: &AppState, name: &str, body: &str, defaults: &Vars) -> Result<Result<Vec<Affected>, Rejected>> {
+    let tpl = candidate(name, None, body, defaults)?;
+    if let Err(e) = check_template(&tpl) { return Ok(Err(Rejected::Template(e))); }
+    Ok(Ok(affected(st, &tpl).await?))
+}
+
+#[derive(Debug, Serialize)]
+pub struct Saved {
+    pub name: String,
+    pub created: bool,
+    pub affected: Vec<Affected>,
+}
+
+/// Stores the template and rewrites every linked CCD that changes. Refused
+/// as a whole if any of them would not be valid; CNs that only lack a
+/// built-in variable are skipped and reported.
+pub async fn save(
+    st: &AppState, ctx: &AuditCtx, name: &str, description: Option<&str>, body: &str, defaults: &Vars,
+) -> Result<Result<Saved, Rejected>> {
+    let tpl = candidate(name, description, body, defaults)?;
+    if let Err(e) = check_template(&tpl) { return Ok(Err(Rejected::Template(e))); }
+    let _guard = LOCK.lock().await;
+    let mut affected = affected(st, &tpl).await?;
+    if affected.iter().any(|a| a.status == "invalid") {
+        return Ok(Err(Rejected::Dependents(affected)));
+    }
+    let created = db::ccd_templates::upsert(&st.db, name, description, body, defaults, &ctx.actor).await?;
+    for a in affected.iter_mut() {
+        let Some(text) = a.text.take() else { continue };
+        // The file is compared with the version the diff was made from.
+        match write_generated(st, ctx, &a.cn, &text, Some(&a.etag)).await {
+            Ok(()) => a.status = "updated",
+            Err(e) => {
+                tracing::error!(cn=%a.cn, "regenerate CCD from template {}: {}", name, e);
+                a.status = "failed";
+                a.error = Some(e.to_string());
+            }
+        }
+    }
+    let cns = |status: &str| affected.iter().filter(|a| a.status == status).map(|a| a.cn.clone()).collect::<Vec<_>>();
+    ctx.record(&st.db, "CCD_TEMPLATE_SAVE", name, json!({
+        "created": created, "linked": affected.len(),
+        "updated": cns("updated"), "skipped": cns("skipped"), "failed": cns("failed"),
+    })).await.ok();
+    Ok(Ok(Saved { name: name.into(), created, affected }))
+}
+
+/// Only templates no CN uses can go.
+pub async fn delete(st: &AppState, ctx: &AuditCtx, name: &str) -> Result<()> {
+    let _guard = LOCK.lock().await;
+    let links = db::ccd_templates::links(&st.db, name).await?;
+    if !links.is_empty() {
+        return Err(anyhow!("template_in_use: {} client(s)", links.len()));
+    }
+    if !db::ccd_templates::delete(&st.db, name).await? {
+        return Err(anyhow!("not_found: template"));
+    }
+    ctx.record(&st.db, "CCD_TEMPLATE_DELETE", name, json!({})).await.ok();
+    Ok(())
+}
+
+/// Renders `template` for `cn` without writing, e.g. before a client is issued.
+pub async fn check(st: &AppState, cn: &str, template: &str) -> Result<Result<(), Vec<RenderError>>> {
+    let tpl = db::ccd_templates::get(&st.db, template).await?.ok_or_else(|| anyhow!("unknown_template"))?;
+    Ok(render_for(st, &tpl, cn, &Vars::new(), "").await?.map(drop))
+}
+
+/// Generates `cn`'s CCD from `template` with its overrides and remembers the
+/// link, so later template changes reach this CN. `if_match` as for `write_ccd`.
+pub async fn attach(
+    st: &AppState, ctx: &AuditCtx, cn: &str, template: &str, vars: &Vars, extra: &str, if_match: Option<&str>,
+) -> Result<Result<String, Vec<RenderError>>> {
+    check_vars(vars)?;
+    let _guard = LOCK.lock().await;
+    let tpl = db::ccd_templates::get(&st.db, template).await?.ok_or_else(|| anyhow!("unknown_template"))?;
+    let text = match render_for(st, &tpl, cn, vars, extra).await? {
+        Ok(t) => t,
+        Err(e) => return Ok(Err(e)),
+    };
+    write_generated(st, ctx, cn, &text, if_match).await?;
+    db::ccd_templates::set_link(&st.db, cn, template, vars, extra, &ctx.actor).await?;
+    ctx.record(&st.db, "CCD_TEMPLATE_ATTACH", cn, json!({
+        "template": template, "vars": vars, "extra_lines": extra.lines().filter(|l| !l.trim().is_empty()).count(),
+    })).await.ok();
+    Ok(Ok(text))
+}
+
+/// Renders a linked CN again with `static_ip` in place of its allocation,
+/// for IPAM changing the address. `Ok(false)` if `cn` is not linked.
+pub async fn regenerate(st: &AppState, ctx: &AuditCtx, cn: &str, static_ip: Option<&StaticIp>) -> Result<bool> {
+    let _guard = LOCK.lock().await;
+    let Some(l) = db::ccd_templates::link(&st.db, cn).await? else { return Ok(false) };
+    let tpl = db::ccd_templates::get(&st.db, &l.template).await?.ok_or_else(|| anyhow!("unknown_template"))?;
+    let text = render(&tpl, cn, static_ip, &l.vars, &l.extra).map_err(|e| {
+        anyhow!("ccd_invalid: {} line {}: {}", e[0].part, e[0].error.line, e[0].error.error)
+    })?;
+    write_generated(st, ctx, cn, &text, None).await?;
+    Ok(true)
+}
+
+/// Stops regenerating `cn`; its CCD stays as last written.
+pub async fn detach(st: &AppState, ctx: &AuditCtx, cn: &str) -> Result<()> {
+    let _guard = LOCK.lock().await;
+    let Some(link) = db::ccd_templates::link(&st.db, cn).await? else {
+        return Err(anyhow!("not_found: link"));
+    };
+    db::ccd_templates::delete_link(&st.db, cn).await?;
+    ctx.record(&st.db, "CCD_TEMPLATE_DETACH", cn, json!({ "template": link.template })).await.ok();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> Vars {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    fn template(body: &str, defaults: &[(&str, &str)]) -> Template {
+        Template {
+            name: "office".into(), description: None, body: body.into(), defaults: vars(defaults),
+            updated_by: String::new(), updated_at: 0,
+        }
+    }
+
+    fn errors<T: std::fmt::Debug>(r: Result<T, Vec<LineError>>) -> Vec<(usize, String, Option<String>)> {
+        r.unwrap_err().into_iter().map(|e| (e.line, e.error, e.value)).collect()
+    }
+
+    fn ip() -> StaticIp {
+        StaticIp { ip: "10.8.0.7".into(), remote: "255.255.255.0".into(), alias: None }
+    }
+
+    #[test]
+    fn substitute_variables() {
+        let v = vars(&[("dns", "10.0.0.1"), ("net", "10.1.0.0")]);
+        assert_eq!(substitute("push \"dhcp-option DNS {{dns}}\"\niroute {{ net }} 255.255.0.0\n", &v).unwrap(),
+                   "push \"dhcp-option DNS 10.0.0.1\"\niroute 10.1.0.0 255.255.0.0\n");
+        assert_eq!(errors(substitute("push \"route {{lan}} 255.255.0.0\"\niroute {{static_ip}} 255.255.255.255\n", &v)), [
+            (1, "unknown_variable".into(), Some("lan".into())),
+            (2, "missing_variable".into(), Some("static_ip".into())),
+        ]);
+        assert_eq!(errors(substitute("iroute {{Net}} 255.255.0.0\npush {{dns\n", &v)), [
+            (1, "invalid_variable".into(), Some("Net".into())),
+            (2, "unterminated_variable".into(), Some("{{dns".into())),
+        ]);
+    }
+
+    #[test]
+    fn substituted_values_stay_literal() {
+        // A value is never expanded again and cannot start a line of its own.
+        let v = vars(&[("a", "{{b}}"), ("b", "x"), ("nl", "1\npush \"up /bin/sh\"")]);
+        assert_eq!(substitute("push \"x {{a}}\"\n", &v).unwrap(), "push \"x {{b}}\"\n");
+        assert_eq!(errors(substitute("push \"x {{nl}}\"\n", &v)), [(1, "invalid_variable_value".into(), Some("nl".into()))]);
+        // Single braces are plain text.
+        assert_eq!(substitute("# {a} }}\n", &v).unwrap(), "# {a} }}\n");
+    }
+
+    #[test]
+    fn render_with_and_without_static_ip() {
+        let tpl = template("ifconfig-push {{static_ip}} {{static_remote}}\npush \"dhcp-option DNS {{dns}}\"\n", &[("dns", "10.0.0.1")]);
+        let text = render(&tpl, "alice", Some(&ip()), &vars(&[("dns", "10.0.0.53")]), "").unwrap();
+        assert_eq!(text, "# generated from CCD template 'office'; edit the template or its overrides instead\n\
+                          ifconfig-push 10.8.0.7 255.255.255.0\npush \"dhcp-option DNS 10.0.0.53\"\n");
+        let e = render(&tpl, "alice", None, &Vars::new(), "").unwrap_err();
+        assert_eq!(e.iter().map(|e| (e.part, e.error.value.as_deref())).collect::<Vec<_>>(),
+                   [("template", Some("static_ip")), ("template", Some("static_remote"))]);
+        assert!(e.iter().all(|e| e.error.error == "missing_variable"));
+
+        // An allocation the template does not mention is still pushed.
+        let plain = template("push \"route 10.1.0.0 255.255.0.0\"\n", &[]);
+        assert!(render(&plain, "alice", Some(&ip()), &Vars::new(), "").unwrap().ends_with("ifconfig-push 10.8.0.7 255.255.255.0\n"));
+        assert!(!render(&plain, "alice", None, &Vars::new(), "").unwrap().contains("ifconfig-push"));
+    }
+
+    #[test]
+    fn render_merges_extra_over_template() {
+        let tpl = template("compress lz4\npush \"route 10.1.0.0 255.255.0.0\"\n", &[]);
+        let text = render(&tpl, "alice", Some(&ip()), &Vars::new(), "compress lz4-v2\r\niroute 10.9.0.0 255.255.0.0\r\n").unwrap();
+        assert_eq!(text.lines().skip(1).collect::<Vec<_>>(), [
+            "push \"route 10.1.0.0 255.255.0.0\"", "compress lz4-v2", "iroute 10.9.0.0 255.255.0.0",
+            "ifconfig-push 10.8.0.7 255.255.255.0",
+        ]);
+        // An ifconfig-push in `extra` wins over the allocation.
+        let own = render(&tpl, "alice", Some(&ip()), &Vars::new(), "ifconfig-push 10.8.0.9 255.255.255.0\n").unwrap();
+        assert_eq!(ccd::static_ip(&own).unwrap().to_string(), "10.8.0.9");
+
+        let e = render(&tpl, "alice", None, &Vars::new(), "frobnicate\n").unwrap_err();
+        assert_eq!((e[0].part, e[0].error.error.as_str()), ("extra", "unknown_directive"));
+    }
+}
@@ -0,0 +1,99 @@
+use anyhow::{anyhow, Result};
+use openssl::{nid::Nid, pkey::Id, x509::X509Req};
+use regex::Regex;
+use serde_json::json;
+
+use crate::{audit::{self, AuditCtx}, config::{CsrCfg, KeyType, ProfileCfg}, db, vpncertd, AppState};
+
+pub struct SignedCsr {
+    pub cn: String,
+    pub cert_pem: String,
+    pub serial: Option<String>,
+    pub not_after: Option<String>,
+    pub key_type: String,
+}
+
+/// Checks that the CSR is self-signed by its key, names exactly `cn` as its
+/// subject CN and carries a key the policy allows. Returns the key type,
+/// e.g. `rsa3072`, `ec-prime256v1` or `ed25519`.
+pub fn inspect(cfg: &CsrCfg, pem: &str, cn: &str) -> Result<String> {
+    let req = X509Req::from_pem(pem.as_bytes()).map_err(|_| anyhow!("csr_invalid"))?;
+    let key = req.public_key().map_err(|_| anyhow!("csr_invalid"))?;
+    if !req.verify(&key).unwrap_or(false) {
+        return Err(anyhow!("csr_bad_signature"));
+    }
+
+    let cns: Vec<String> = req.subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .filter_map(|e| e.data().as_utf8().ok().map(|s| s.to_string()))
+        .collect();
+    if cns.len() != 1 || cns[0] != cn {
+        return Err(anyhow!("csr_cn_mismatch"));
+    }
+
+    match key.id() {
+        Id::RSA => {
+            let bits = key.bits();
+            if bits < cfg.min_rsa_bits { return Err(anyhow!("csr_key_weak")); }
+            Ok(format!("rsa{bits}"))
+        }
+        Id::EC => {
+            let curve = key.ec_key().ok()
+                .and_then(|k| k.group().curve_name())
+                .and_then(|n| n.short_name().ok().map(str::to_string))
+                .ok_or_else(|| anyhow!("csr_key_weak"))?;
+            if !cfg.ec_curves.iter().any(|c| c == &curve) { return Err(anyhow!("csr_key_weak")); }
+            Ok(format!("ec-{curve}"))
+        }
+        Id::ED25519 if cfg.allow_ed25519 => Ok("ed25519".into()),
+        _ => Err(anyhow!("csr_key_weak")),
+    }
+}
+
+/// Validates `pem` and has vpncertd sign it for `cn`.
+/// The profile key type a CSR key corresponds to, if it is one vpncertd
+/// could have generated itself.
+fn profile_key_type(key_type: &str) -> Option<KeyType> {
+    match key_type {
+        "ec-prime256v1" => Some(KeyType::EcdsaP256),
+        "ec-secp384r1" => Some(KeyType::EcdsaP384),
+        k => KeyType::parse(k),
+    }
+}
+
+pub async fn sign(st: &AppState, ctx: &AuditCtx, cn: &str, pem: &str, profile: &ProfileCfg, renew: bool) -> Result<SignedCsr> {
+    let re = Regex::new(&st.cfg.ovpn.cn_pattern).unwrap();
+    if !re.is_match(cn) {
+        return Err(anyhow!("invalid_cn"));
+    }
+    let key_type = inspect(&st.cfg.csr, pem, cn)?;
+    // Keys outside the generated set are governed by `[csr]` alone.
+    if let Some(k) = profile_key_type(&key_type) && !profile.key_types.contains(&k) {
+        return Err(anyhow!("key_type_not_allowed"));
+    }
+
+    let profile = profile.name.as_str();
+    let signed = vpncertd::sign_csr(&st.cfg.ovpn.socket_path, cn, profile, pem, renew).await?;
+    super::record_issued(st, &db::issued::NewIssued {
+        serial: signed.serial.as_deref(), cn, profile, key_type: &key_type, via: "csr",
+        not_after: signed.not_after.as_deref(), created_by: &ctx.actor,
+    }).await;
+
+    ctx.record(&st.db, "CLIENT_CREATE", cn, json!({
+        "serial": signed.serial,
+        "not_after": signed.not_after,
+        "profile": profile,
+        "key_type": key_type,
+        "via": "csr",
+        "csr_sha256": audit::sha256_hex(pem.as_bytes()),
+        "renew": renew,
+    })).await.ok();
+
+    Ok(SignedCsr {
+        cn: cn.to_string(),
+        cert_pem: signed.cert_pem,
+        serial: signed.serial,
+        not_after: signed.not_after,
+        key_type,
+    })
+}
@@ -0,0 +1,237 @@
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::time::Duration;
+use time::{
+    format_description::well_known::Rfc3339, macros::format_description, OffsetDateTime, PrimitiveDateTime,
+};
+
+use super::{list_issued_with_status, IssuedWithStatus};
+use crate::{audit::AuditCtx, config::ExpiryCfg, db, AppState};
+
+/// Parses `not_after` as reported by vpncertd into unix seconds. Accepts
+/// RFC 3339, OpenSSL's `Jan  2 15:04:05 2026 GMT`, ASN.1 UTCTime and
+/// GeneralizedTime, `YYYY-MM-DD HH:MM:SS` in UTC, and plain unix seconds.
+pub fn parse_not_after(s: &str) -> Option<i64> {
+    let s = s.trim();
+    if let Ok(n) = s.parse::<i64>() {
+        // Past year 9999 it is more likely a timestamp missing its `Z`.
+        return (0..=253402300799).contains(&n).then_some(n);
+    }
+    if let Ok(t) = OffsetDateTime::parse(s, &Rfc3339) {
+        return Some(t.unix_timestamp());
+    }
+    let utc = |t: PrimitiveDateTime| Some(t.assume_utc().unix_timestamp());
+    if let Some(v) = s.strip_suffix(" GMT") {
+        let fd = format_description!("[month repr:short] [day padding:space] [hour]:[minute]:[second] [year]");
+        if let Ok(t) = PrimitiveDateTime::parse(v, fd) { return utc(t); }
+    }
+    if let Some(v) = s.strip_suffix('Z') && v.bytes().all(|b| b.is_ascii_digit()) {
+        let v = match v.len() {
+            14 => v.to_string(),
+            // UTCTime: 50..99 are 19xx, 00..49 are 20xx (RFC 5280 4.1.2.5.1)
+            12 => format!("{}{v}", if &v[..2] >= "50" { "19" } else { "20" }),
+            _ => return None,
+        };
+        let fd = format_description!("[year][month][day][hour][minute][second]");
+        if let Ok(t) = PrimitiveDateTime::parse(&v, fd) { return utc(t); }
+    }
+    let v = s.trim_end_matches(" UTC").trim_end_matches(" +0000");
+    let fd = format_description!("[year]-[month]-[day] [hour]:[minute]:[second]");
+    PrimitiveDateTime::parse(v, fd).ok().and_then(utc)
+}
+
+#[derive(Debug, Serialize)]
+pub struct Expiring {
+    pub serial: String,
+    pub cn: String,
+    pub profile: String,
+    pub not_after: String,
+    pub not_after_ts: i64,
+    /// Whole days until `not_after`; negative once expired.
+    pub days_left: i64,
+    pub expired: bool,
+}
+
+/// Unrevoked certificates with `from < not_after <= to`, soonest first. A
+/// certificate is left out when its CN already holds a newer unrevoked one,
+/// so a renewed client no longer shows up.
+fn select(issued: &[IssuedWithStatus], now: i64, from: i64, to: i64) -> Vec<Expiring> {
+    let mut latest: HashMap<&str, i64> = HashMap::new();
+    for it in issued.iter().filter(|it| !it.revoked) {
+        if let Some(ts) = it.not_after_ts {
+            let e = latest.entry(it.cn.as_str()).or_insert(ts);
+            *e = (*e).max(ts);
+        }
+    }
+
+    let mut out: Vec<Expiring> = issued.iter()
+        .filter(|it| !it.revoked)
+        .filter_map(|it| {
+            let ts = it.not_after_ts?;
+            if ts <= from || ts > to || latest.get(it.cn.as_str()).is_some_and(|&l| l > ts) {
+                return None;
+            }
+            Some(Expiring {
+                serial: it.serial.clone(),
+                cn: it.cn.clone(),
+                profile: it.profile.clone(),
+                not_after: it.not_after.clone(),
+                not_after_ts: ts,
+                days_left: (ts - now).div_euclid(86400),
+                expired: ts <= now,
+            })
+        })
+        .collect();
+    out.sort_by_key(|e| e.not_after_ts);
+    out
+}
+
+/// Certificates still valid that expire within `within_secs`.
+pub async fn expiring(st: &AppState, within_secs: i64) -> Result<Vec<Expiring>> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let issued = list_issued_with_status(st, None).await?;
+    Ok(select(&issued, now, now, now + within_secs))
+}
+
+/// Certificates that expired within the last `within_secs` and were not renewed.
+pub async fn expired(st: &AppState, within_secs: i64) -> Result<Vec<Expiring>> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let issued = list_issued_with_status(st, None).await?;
+    Ok(select(&issued, now, now - within_secs, now))
+}
+
+/// Raises one EXPIRY_WARNING per certificate for the tightest threshold it
+/// has crossed. Looser thresholds crossed at the same time are marked as
+/// sent without a warning of their own. Returns the number of warnings.
+pub async fn check(st: &AppState, cfg: &ExpiryCfg) -> Result<usize> {
+    let Some(&widest) = cfg.thresholds_days.iter().max() else { return Ok(0) };
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let ctx = AuditCtx::system();
+    let mut sent = 0;
+    // Certificates that lapsed within the widest threshold still get their
+    // warning if a run was missed; ones expired long before are left alone.
+    let issued = list_issued_with_status(st, None).await?;
+    for e in select(&issued, now, now - widest * 86400, now + widest * 86400) {
+        let left = e.not_after_ts - now;
+        let crossed: Vec<i64> = cfg.thresholds_days.iter().copied().filter(|t| left <= t * 86400).collect();
+        let Some(&tightest) = crossed.iter().min() else { continue };
+        for t in crossed {
+            let fresh = db::expiry::mark_warned(&st.db, &e.serial, &e.cn, t).await?;
+            if fresh && t == tightest {
+                ctx.record(&st.db, "EXPIRY_WARNING", &e.cn, json!({
+                    "serial": e.serial,
+                    "not_after": e.not_after,
+                    "days_left": e.days_left,
+                    "expired": e.expired,
+                    "threshold_days": t,
+                })).await?;
+                sent += 1;
+            }
+        }
+    }
+    Ok(sent)
+}
+
+/// Runs `check` every `interval_secs` while the server is up.
+pub fn spawn_scheduler(st: AppState) {
+    let cfg = st.cfg.expiry.clone();
+    if !cfg.enabled { return; }
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(Duration::from_secs(cfg.interval_secs.max(60)));
+        loop {
+            tick.tick().await;
+            match check(&st, &cfg).await {
+                Ok(0) => {}
+                Ok(n) => tracing::info!("expiry: raised {} warnings", n),
+                Err(e) => tracing::warn!("expiry: check failed: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const T: i64 = 1767366245; // 2026-01-02 15:04:05 UTC
+
+    #[test]
+    fn parses_daemon_formats() {
+        for s in [
+            "2026-01-02T15:04:05Z",
+            "2026-01-02T17:04:05+02:00",
+            "Jan  2 15:04:05 2026 GMT",
+            "260102150405Z",
+            "20260102150405Z",
+            "2026-01-02 15:04:05",
+            "2026-01-02 15:04:05 UTC",
+            "2026-01-02 15:04:05 +0000",
+            "1767366245",
+            "  1767366245\n",
+        ] {
+            assert_eq!(parse_not_after(s), Some(T), "{s:?}");
+        }
+        assert_eq!(parse_not_after("Nov  9 03:07:00 2026 GMT"), Some(1794193620));
+        assert_eq!(parse_not_after("Nov 19 03:07:00 2026 GMT"), Some(1794193620 + 10 * 86400));
+        // UTCTime pivots at 50.
+        assert_eq!(parse_not_after("991231235959Z"), Some(946684799));
+        assert_eq!(parse_not_after("490630000000Z"), Some(2508624000));
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        for s in [
+            "",
+            "soon",
+            "2026-01-02",
+            "2026-13-02 15:04:05",
+            "2026-01-02T15:04:05",
+            "Jan  2 15:04:05 2026",
+            "Foo  2 15:04:05 2026 GMT",
+            "2601021504Z",
+            "26010215040aZ",
+            "20260102150405",
+            "2026-01-02 15:04:05 +0200",
+        ] {
+            assert_eq!(parse_not_after(s), None, "{s:?}");
+        }
+    }
+
+    fn cert(serial: &str, cn: &str, not_after_ts: Option<i64>, revoked: bool) -> IssuedWithStatus {
+        IssuedWithStatus {
+            serial: serial.into(), cn: cn.into(), profile: "default".into(), key_type: None, via: None, issued_at: None,
+            not_after: String::new(), not_after_ts, revoked, revoked_at: None, revocation_reason: None,
+        }
+    }
+
+    #[test]
+    fn select_splits_expired_from_expiring() {
+        let now = T;
+        let day = 86400;
+        let issued = [
+            cert("01", "soon", Some(now + 3 * day), false),
+            cert("02", "later", Some(now + 60 * day), false),
+            cert("03", "lapsed", Some(now - 2 * day), false),
+            cert("04", "ancient", Some(now - 400 * day), false),
+            cert("05", "revoked", Some(now + day), true),
+            // Renewed: the old cert is superseded by a newer one for the same CN.
+            cert("06", "renewed", Some(now + day), false),
+            cert("07", "renewed", Some(now + 365 * day), false),
+            cert("08", "unparsed", None, false),
+            cert("09", "edge", Some(now), false),
+        ];
+        let serials = |v: Vec<Expiring>| v.into_iter().map(|e| e.serial).collect::<Vec<_>>();
+
+        let expiring = select(&issued, now, now, now + 30 * day);
+        assert!(expiring.iter().all(|e| !e.expired && e.days_left >= 0));
+        assert_eq!(serials(expiring), ["01"]);
+
+        let expired = select(&issued, now, now - 30 * day, now);
+        assert!(expired.iter().all(|e| e.expired && e.days_left < 0 || e.not_after_ts == now));
+        assert_eq!(serials(expired), ["03", "09"]);
+
+        assert_eq!(serials(select(&issued, now, now - 30 * day, now + 30 * day)), ["03", "09", "01"]);
+    }
+}
@@ -0,0 +1,448 @@
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::io::{Cursor, Write};
+
+use super::{build_bundle, ccd, ccd_etag, ccd_templates, create_client, list_issued_with_status, profiles, write_ccd, IssueOpts};
+use crate::{audit::AuditCtx, config::KeyType, db, jobs::{self, JobRun}, AppState};
+
+const MAX_ROWS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format { Csv, Json }
+
+impl Format {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            other => Err(anyhow!("unknown import format '{other}'")),
+        }
+    }
+}
+
+/// One client to provision. CSV files use the field names as headers.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ImportRow {
+    pub cn: String,
+    #[serde(default, alias = "email")]
+    pub owner_email: Option<String>,
+    #[serde(default)]
+    pub group: Option<String>,
+    /// CCD contents or a template name, as `NewClient.ccd`.
+    #[serde(default)]
+    pub ccd: Option<String>,
+    #[serde(default)]
+    pub validity_days: Option<u32>,
+    #[serde(default)]
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub key_type: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RowError {
+    /// 1-based, not counting a CSV header.
+    pub row: usize,
+    pub cn: String,
+    pub error: String,
+}
+
+/// A row that passed validation, with profile and key type resolved.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Planned {
+    #[serde(flatten)]
+    pub row: ImportRow,
+    pub resolved_profile: String,
+    pub resolved_key_type: KeyType,
+    pub resolved_validity_days: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonBody {
+    Rows(Vec<ImportRow>),
+    Wrapped { rows: Vec<ImportRow> },
+}
+
+pub fn parse(body: &[u8], format: Format) -> Result<Vec<ImportRow>> {
+    let rows = match format {
+        Format::Json => match serde_json::from_slice::<JsonBody>(body).map_err(|e| anyhow!("import_unparsable: {e}"))? {
+            JsonBody::Rows(r) | JsonBody::Wrapped { rows: r } => r,
+        },
+        Format::Csv => {
+            let mut rd = csv::ReaderBuilder::new().trim(csv::Trim::All).flexible(true).from_reader(body);
+            rd.deserialize().collect::<Result<Vec<ImportRow>, _>>().map_err(|e| anyhow!("import_unparsable: {e}"))?
+        }
+    };
+    Ok(rows.into_iter().map(|r| ImportRow {
+        cn: r.cn.trim().to_string(),
+        owner_email: clean(r.owner_email),
+        group: clean(r.group),
+        ccd: r.ccd.filter(|c| !c.trim().is_empty()),
+        profile: clean(r.profile),
+        key_type: clean(r.key_type),
+        validity_days: r.validity_days,
+    }).collect())
+}
+
+fn clean(v: Option<String>) -> Option<String> {
+    v.map(|s| s.trim().to_string()).filter(|s| !s.is_empty())
+}
+
+fn email_ok(e: &str) -> bool {
+    e.len() <= 254 && !e.chars().any(char::is_whitespace) && e.split_once('@').is_some_and(|(l, d)| !l.is_empty() && d.contains('.'))
+}
+
+/// Checks every row before anything is issued; any error rejects the whole import.
+pub async fn validate(st: &AppState, roles: &[String], rows: Vec<ImportRow>) -> Result<Result<Vec<Planned>, Vec<RowError>>> {
+    if rows.is_empty() {
+        return Err(anyhow!("import_empty"));
+    }
+    if rows.len() > MAX_ROWS {
+        return Err(anyhow!("too_many_items"));
+    }
+    let active: HashSet<String> = list_issued_with_status(st, None).await?
+        .into_iter().filter(|it| !it.revoked).map(|it| it.cn).collect();
+    check_rows(st, roles, rows, &active).await
+}
+
+/// `validate` against the CNs that hold an unrevoked certificate.
+async fn check_rows(
+    st: &AppState, roles: &[String], rows: Vec<ImportRow>, active: &HashSet<String>,
+) -> Result<Result<Vec<Planned>, Vec<RowError>>> {
+    let re = Regex::new(&st.cfg.ovpn.cn_pattern).unwrap();
+    let mut seen = HashSet::new();
+    let mut errors = Vec::new();
+    let mut planned = Vec::new();
+    for (i, row) in rows.into_iter().enumerate() {
+        let mut fail = |error: &str| errors.push(RowError { row: i + 1, cn: row.cn.clone(), error: error.into() });
+        if !re.is_match(&row.cn) { fail("invalid_cn"); continue; }
+        if !seen.insert(row.cn.clone()) { fail("duplicate_cn"); continue; }
+        if active.contains(&row.cn) { fail("cn_exists_active"); continue; }
+        if row.owner_email.as_deref().is_some_and(|e| !email_ok(e)) { fail("invalid_email"); continue; }
+        if let Some(name) = row.ccd.as_deref().and_then(ccd_templates::as_name) {
+            match ccd_templates::check(st, &row.cn, name).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => { fail(&format!("ccd_invalid: template {name}: line {}: {}", e[0].error.line, e[0].error.error)); continue; }
+                Err(e) => { fail(&e.to_string()); continue; }
+            }
+        } else if let Some(Err(e)) = row.ccd.as_deref().map(|c| ccd::parse(&c.replace("\r\n", "\n"))) {
+            fail(&ccd::summary(&e));
+            continue;
+        }
+        let resolved = profiles::resolve(&st.cfg.issuance, roles, row.profile.as_deref(), row.key_type.as_deref())
+            .and_then(|(p, k)| profiles::validity_days(p, row.validity_days, None).map(|d| (p.name.clone(), k, d)));
+        match resolved {
+            Ok((resolved_profile, resolved_key_type, resolved_validity_days)) => {
+                planned.push(Planned { row, resolved_profile, resolved_key_type, resolved_validity_days });
+            }
+            Err(e) => fail(&e.to_string()),
+        }
+    }
+    Ok(if errors.is_empty() { Ok(planned) } else { Err(errors) })
+}
+
+/// Queues the import and records the parent audit event; returns the job id.
+/// Bundles carry the private key only with `include_key`, as with a single
+/// client's bundle.
+pub async fn start(st: &AppState, ctx: &AuditCtx, rows: &[Planned], include_key: bool) -> Result<String> {
+    let job = jobs::enqueue(st, ctx, "import", rows.len(), &json!({ "rows": rows, "include_key": include_key })).await?;
+    ctx.record(&st.db, "CLIENT_IMPORT", &job, json!({
+        "job": job, "rows": rows.len(), "include_key": include_key,
+        "cns": rows.iter().map(|r| r.row.cn.as_str()).collect::<Vec<_>>(),
+    })).await.ok();
+    Ok(job)
+}
+
+#[derive(Serialize)]
+struct ReportLine<'a> {
+    row: usize,
+    cn: &'a str,
+    status: &'a str,
+    serial: Option<&'a str>,
+    not_after: Option<&'a str>,
+    passphrase: Option<&'a str>,
+    bundle: Option<&'a str>,
+    error: Option<&'a str>,
+}
+
+/// Issues each row, appending to `report.csv` (with passphrases) and keeping
+/// bundles in the job directory, then zips them into `bundles.zip`. Both
+/// survive a restart, so a resumed import picks up where it stopped; both are
+/// deleted after `[jobs].file_ttl_hours`.
+pub async fn run(st: &AppState, job: &JobRun) -> Result<Value> {
+    let rows: Vec<Planned> = serde_json::from_value(job.params["rows"].clone())?;
+    let include_key = job.params["include_key"].as_bool().unwrap_or(false);
+    let dir = job.dir(st);
+    let bundles = dir.join("bundles");
+    jobs::private_dir(&dir).await?;
+    jobs::private_dir(&bundles).await?;
+    let mut cancelled = false;
+    let active = match job.resumed() {
+        true => list_issued_with_status(st, None).await?.into_iter().filter(|it| !it.revoked).collect(),
+        false => Vec::new(),
+    };
+
+    for (i, p) in rows.iter().enumerate() {
+        if job.is_done(i) { continue; }
+        if job.cancelled(st).await {
+            cancelled = true;
+            break;
+        }
+        let r = &p.row;
+        // The cert may have been issued before the restart, its passphrase
+        // lost with the process; issuing again would leave two.
+        if job.interrupted(i) && let Some(it) = active.iter().find(|it| it.cn == r.cn) {
+            let msg = format!("interrupted: certificate {} was issued but its passphrase was lost; revoke it and import the row again", it.serial);
+            let line = csv_line(&ReportLine {
+                row: i + 1, cn: &r.cn, status: "failed", serial: Some(&it.serial), not_after: Some(&it.not_after),
+                passphrase: None, bundle: None, error: Some(&msg),
+            })?;
+            append_report(&dir.join("report.csv"), &line).await?;
+            job.item(st, i, &r.cn, "failed", &json!({ "cn": r.cn, "serial": it.serial, "error": msg })).await?;
+            continue;
+        }
+        job.start_item(st, i, &r.cn).await?;
+        let opts = IssueOpts { profile: &p.resolved_profile, key_type: p.resolved_key_type, validity_days: p.resolved_validity_days };
+        let (line, status, detail) = match provision(st, &job.ctx, r, &opts, include_key).await {
+            Ok((issue, bundle, warnings)) => {
+                let name = match bundle {
+                    Some((name, bytes)) => {
+                        jobs::write_private(&bundles, &name, &bytes).await?;
+                        Some(name)
+                    }
+                    None => None,
+                };
+                let (status, error) = match warnings.is_empty() {
+                    true => ("ok", None),
+                    false => ("created_with_warnings", Some(warnings.join("; "))),
+                };
+                let line = csv_line(&ReportLine {
+                    row: i + 1, cn: &r.cn, status, serial: issue.serial.as_deref(), not_after: issue.not_after.as_deref(),
+                    passphrase: Some(&issue.passphrase), bundle: name.as_deref(), error: error.as_deref(),
+                })?;
+                let detail = json!({
+                    "cn": r.cn, "serial": issue.serial, "not_after": issue.not_after, "bundle": name, "warnings": warnings,
+                });
+                (line, if warnings.is_empty() { "ok" } else { "warning" }, detail)
+            }
+            Err(e) => {
+                let msg = e.to_string();
+                let line = csv_line(&ReportLine {
+                    row: i + 1, cn: &r.cn, status: "failed", serial: None, not_after: None,
+                    passphrase: None, bundle: None, error: Some(&msg),
+                })?;
+                (line, "failed", json!({ "cn": r.cn, "error": msg }))
+            }
+        };
+        append_report(&dir.join("report.csv"), &line).await?;
+        job.item(st, i, &r.cn, status, &detail).await?;
+    }
+
+    let zip = zip_dir(&bundles).await?;
+    jobs::write_private(&dir, "bundles.zip", &zip).await?;
+    tokio::fs::remove_dir_all(&bundles).await.ok();
+    let (ok, warned, failed) = (job.tally(st, "ok").await?, job.tally(st, "warning").await?, job.tally(st, "failed").await?);
+    Ok(json!({
+        "created": ok + warned, "warnings": warned, "failed": failed, "cancelled": cancelled,
+        "files": ["report.csv", "bundles.zip"],
+    }))
+}
+
+fn csv_line(line: &ReportLine<'_>) -> Result<Vec<u8>> {
+    let mut w = csv::WriterBuilder::new().has_headers(false).from_writer(Vec::new());
+    w.serialize(line)?;
+    Ok(w.into_inner()?)
+}
+
+const REPORT_HEADER: &str = "row,cn,status,serial,not_after,passphrase,bundle,error\n";
+
+async fn append_report(path: &std::path::Path, line: &[u8]) -> Result<()> {
+    use tokio::io::AsyncWriteExt;
+    let fresh = !tokio::fs::try_exists(path).await?;
+    let mut f = jobs::private_options().append(true).open(path).await?;
+    if fresh { f.write_all(REPORT_HEADER.as_bytes()).await?; }
+    f.write_all(line).await?;
+    Ok(())
+}
+
+async fn zip_dir(dir: &std::path::Path) -> Result<Vec<u8>> {
+    let mut names = Vec::new();
+    let mut rd = tokio::fs::read_dir(dir).await?;
+    while let Some(e) = rd.next_entry().await? {
+        names.push(e.file_name().to_string_lossy().into_owned());
+    }
+    names.sort();
+    let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    let zopts = zip::write::SimpleFileOptions::default();
+    for name in names {
+        let bytes = tokio::fs::read(dir.join(&name)).await?;
+        zip.start_file(name.as_str(), zopts)?;
+        zip.write_all(&bytes)?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+/// Once the cert exists the row counts as created: failing to record the
+/// owner, group or CCD comes back as a warning so the passphrase and bundle
+/// still reach the report.
+async fn provision(
+    st: &AppState, ctx: &AuditCtx, r: &ImportRow, opts: &IssueOpts<'_>, include_key: bool,
+) -> Result<(super::ClientIssue, Option<(String, Vec<u8>)>, Vec<String>)> {
+    let issue = create_client(st, ctx, &r.cn, None, opts).await?;
+    let mut warnings = Vec::new();
+    if let Some(email) = r.owner_email.as_deref()
+        && let Err(e) = db::clients::set_owner_email(&st.db, &r.cn, Some(email)).await
+    {
+        warnings.push(format!("owner_email: {e}"));
+    }
+    if let Some(group) = r.group.as_deref()
+        && let Err(e) = db::clients::set_group(&st.db, &r.cn, Some(group)).await
+    {
+        warnings.push(format!("group: {e}"));
+    }
+    // Only a new or empty CCD file is written; a leftover one is kept.
+    let new_file = ccd_etag("");
+    let ccd_warning = |e: anyhow::Error| match e.to_string().as_str() {
+        "etag_mismatch" => "ccd_exists: the existing CCD file was kept".to_string(),
+        msg => format!("ccd: {msg}"),
+    };
+    if let Some(name) = r.ccd.as_deref().and_then(ccd_templates::as_name) {
+        match ccd_templates::attach(st, ctx, &r.cn, name, &Default::default(), "", Some(&new_file)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => warnings.push(format!("ccd_invalid: template {name}: line {}: {}", e[0].error.line, e[0].error.error)),
+            Err(e) => warnings.push(ccd_warning(e)),
+        }
+    } else if let Some(ccd) = r.ccd.as_deref()
+        && let Err(e) = write_ccd(st, ctx, &r.cn, ccd, Some(&new_file)).await
+    {
+        warnings.push(ccd_warning(e));
+    }
+    // Only the job's own copy is kept.
+    let bundle = match build_bundle(st, ctx, &r.cn, include_key).await {
+        Ok(b) => {
+            let bytes = tokio::fs::read(&b.path).await;
+            tokio::fs::remove_file(&b.path).await.ok();
+            bytes.map(|bytes| (b.filename, bytes)).map_err(anyhow::Error::from)
+        }
+        Err(e) => Err(e),
+    };
+    let bundle = match bundle {
+        Ok(b) => Some(b),
+        Err(e) => {
+            tracing::error!(cn=%r.cn, "import bundle: {}", e);
+            warnings.push(format!("bundle: {e}"));
+            None
+        }
+    };
+    Ok((issue, bundle, warnings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(cn: &str) -> ImportRow {
+        ImportRow { cn: cn.into(), ..Default::default() }
+    }
+
+    #[test]
+    fn csv_rows_are_read_by_header_and_trimmed() {
+        // Columns in any order, the `email` alias, blank cells as absent.
+        let csv = "group, cn ,email,validity_days,ccd\n\
+                   ops, alice , alice@example.org ,30,\n\
+                   ,bob,,,\"push \"\"route 10.1.0.0 255.255.0.0\"\"\"\n";
+        let rows = parse(csv.as_bytes(), Format::Csv).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].cn, "alice");
+        assert_eq!(rows[0].owner_email.as_deref(), Some("alice@example.org"));
+        assert_eq!(rows[0].group.as_deref(), Some("ops"));
+        assert_eq!(rows[0].validity_days, Some(30));
+        assert_eq!(rows[0].ccd, None);
+        assert_eq!((rows[1].owner_email.as_deref(), rows[1].group.as_deref()), (None, None));
+        assert_eq!(rows[1].ccd.as_deref(), Some("push \"route 10.1.0.0 255.255.0.0\""));
+
+        // A header without `cn`, or a cell that is not a number, fails the file.
+        assert!(parse(b"name,email\nalice,a@example.org\n", Format::Csv).unwrap_err().to_string().starts_with("import_unparsable"));
+        assert!(parse(b"cn,validity_days\nalice,soon\n", Format::Csv).unwrap_err().to_string().starts_with("import_unparsable"));
+        // A header alone is an empty import, rejected by `validate`.
+        assert!(parse(b"cn,email\n", Format::Csv).unwrap().is_empty());
+    }
+
+    #[test]
+    fn json_rows_are_a_list_or_wrapped() {
+        let bare = parse(br#"[{"cn":" alice ","email":"alice@example.org","profile":""}]"#, Format::Json).unwrap();
+        let wrapped = parse(br#"{"rows":[{"cn":"alice","owner_email":"alice@example.org"}]}"#, Format::Json).unwrap();
+        for rows in [bare, wrapped] {
+            assert_eq!(rows.len(), 1);
+            assert_eq!(rows[0].cn, "alice");
+            assert_eq!(rows[0].owner_email.as_deref(), Some("alice@example.org"));
+            assert_eq!(rows[0].profile, None);
+        }
+        assert!(parse(br#"{"clients":[]}"#, Format::Json).is_err());
+        assert!(parse(br#"[{"email":"alice@example.org"}]"#, Format::Json).is_err());
+        assert!(Format::parse("xlsx").is_err());
+    }
+
+    #[test]
+    fn email_check() {
+        for ok in ["a@example.org", "first.last+tag@sub.example.org"] {
+            assert!(email_ok(ok), "{ok}");
+        }
+        for bad in ["", "alice", "@example.org", "alice@localhost", "al ice@example.org", &format!("{}@example.org", "a".repeat(250))] {
+            assert!(!email_ok(bad), "{bad}");
+        }
+    }
+
+    #[tokio::test]
+    async fn validation_reports_every_bad_row() {
+        let st = crate::test_state().await;
+        let admin = ["ADMIN".to_string()];
+        let active = HashSet::from(["carol".to_string()]);
+        let rows = vec![
+            ImportRow { validity_days: Some(30), key_type: Some("ecdsa-p256".into()), ..row("alice") },
+            row("x"),
+            row("alice"),
+            row("carol"),
+            ImportRow { owner_email: Some("dave".into()), ..row("dave") },
+            ImportRow { ccd: Some("disable\nbogus-directive 1".into()), ..row("erin") },
+            ImportRow { ccd: Some("office".into()), ..row("frank") },
+            ImportRow { profile: Some("server".into()), ..row("grace") },
+            ImportRow { validity_days: Some(100_000), ..row("heidi") },
+        ];
+        let errors = check_rows(&st, &admin, rows, &active).await.unwrap().unwrap_err();
+        let got: Vec<_> = errors.iter().map(|e| (e.row, e.cn.as_str(), e.error.as_str())).collect();
+        assert_eq!(got.len(), 8);
+        assert_eq!(got[..4], [
+            (2, "x", "invalid_cn"),
+            (3, "alice", "duplicate_cn"),
+            (4, "carol", "cn_exists_active"),
+            (5, "dave", "invalid_email"),
+        ]);
+        assert_eq!((got[4].0, got[4].1), (6, "erin"));
+        assert!(got[4].2.starts_with("ccd_invalid: line 2:"), "{}", got[4].2);
+        assert_eq!(got[5..], [(7, "frank", "unknown_template"), (8, "grace", "profile_unknown"), (9, "heidi", "validity_too_long")]);
+    }
+
+    #[tokio::test]
+    async fn valid_rows_are_planned_with_defaults() {
+        let st = crate::test_state().await;
+        let rows = vec![
+            ImportRow { validity_days: Some(30), key_type: Some("ecdsa-p256".into()), ..row("alice") },
+            row("bob"),
+        ];
+        let planned = check_rows(&st, &["ADMIN".to_string()], rows, &HashSet::new()).await.unwrap().unwrap();
+        let p = &st.cfg.issuance.profiles[0];
+        assert_eq!(planned.len(), 2);
+        assert_eq!((planned[0].resolved_profile.as_str(), planned[0].resolved_key_type, planned[0].resolved_validity_days),
+                   ("client", KeyType::EcdsaP256, 30));
+        assert_eq!((planned[1].resolved_key_type, planned[1].resolved_validity_days),
+                   (p.default_key().unwrap(), p.default_validity()));
+        // A role without a usable profile has every row refused.
+        let refused = check_rows(&st, &["VIEWER".to_string()], vec![row("carol")], &HashSet::new()).await.unwrap().unwrap_err();
+        assert_eq!(refused[0].error, "profile_forbidden");
+    }
+}
@@ -0,0 +1,385 @@
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use tokio::sync::Mutex;
+
+use super::{ccd::{StaticIp, Structured}, ccd_etag, ccd_templates, list_ccd, read_ccd, write_ccd};
+use crate::{audit::AuditCtx, config::{IpamCfg, Topology}, db, AppState};
+
+// Serialises reserve/release so two admins cannot pick the same "next free".
+static ALLOC: Mutex<()> = Mutex::const_new(());
+
+/// The configured address pool.
+#[derive(Debug, Clone)]
+pub struct Pool {
+    network: u32,
+    prefix: u8,
+    topology: Topology,
+    reserved: Vec<(u32, u32)>,
+}
+
+impl Pool {
+    pub fn from_cfg(cfg: &IpamCfg) -> Result<Self> {
+        let (net, prefix) = cfg.subnet.split_once('/').ok_or_else(|| anyhow!("ipam.subnet must be a CIDR, e.g. 10.8.0.0/24"))?;
+        let network = u32::from(net.parse::<Ipv4Addr>().map_err(|_| anyhow!("ipam.subnet: invalid address '{net}'"))?);
+        let prefix: u8 = prefix.parse().ok().filter(|p| (8..=30).contains(p))
+            .ok_or_else(|| anyhow!("ipam.subnet: prefix must be between 8 and 30"))?;
+        if network & !mask(prefix) != 0 {
+            return Err(anyhow!("ipam.subnet: host bits set in '{}'", cfg.subnet));
+        }
+        let reserved = cfg.reserved.iter().map(|r| range(r).ok_or_else(|| anyhow!("ipam.reserved: invalid entry '{r}'")))
+            .collect::<Result<_>>()?;
+        Ok(Pool { network, prefix, topology: cfg.topology, reserved })
+    }
+
+    fn broadcast(&self) -> u32 {
+        self.network | !mask(self.prefix)
+    }
+
+    /// Every client address in order. The first address (subnet) or the first
+    /// /30 (net30) belongs to the server.
+    fn candidates(&self) -> Box<dyn Iterator<Item = u32> + '_> {
+        match self.topology {
+            Topology::Subnet => Box::new(self.network + 2..self.broadcast()),
+            Topology::Net30 => Box::new((self.network + 4..self.broadcast()).step_by(4).map(|b| b + 1)),
+        }
+    }
+
+    /// Whether `ip` is a client address of this pool at all.
+    fn fits(&self, ip: u32) -> bool {
+        match self.topology {
+            Topology::Subnet => ip > self.network + 1 && ip < self.broadcast(),
+            Topology::Net30 => ip > self.network + 4 && ip < self.broadcast() && (ip - self.network) % 4 == 1,
+        }
+    }
+
+    fn is_reserved(&self, ip: u32) -> bool {
+        self.reserved.iter().any(|&(a, b)| ip >= a && ip <= b)
+    }
+
+    /// Second `ifconfig-push` argument: netmask, or the peer end of the /30.
+    fn remote(&self, ip: u32) -> Ipv4Addr {
+        match self.topology {
+            Topology::Subnet => Ipv4Addr::from(mask(self.prefix)),
+            Topology::Net30 => Ipv4Addr::from(ip + 1),
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.candidates().filter(|&ip| !self.is_reserved(ip)).count()
+    }
+}
+
+fn mask(prefix: u8) -> u32 {
+    u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0)
+}
+
+/// `a.b.c.d`, `a.b.c.d-e.f.g.h` or `a.b.c.d/len`.
+fn range(s: &str) -> Option<(u32, u32)> {
+    let ip = |v: &str| v.trim().parse::<Ipv4Addr>().ok().map(u32::from);
+    if let Some((a, b)) = s.split_once('-') {
+        let (a, b) = (ip(a)?, ip(b)?);
+        return (a <= b).then_some((a, b));
+    }
+    if let Some((net, len)) = s.split_once('/') {
+        let len: u8 = len.parse().ok().filter(|l| *l <= 32)?;
+        let n = ip(net)? & mask(len);
+        return Some((n, n | !mask(len)));
+    }
+    ip(s).map(|a| (a, a))
+}
+
+pub fn pool(st: &AppState) -> Result<Pool> {
+    Pool::from_cfg(st.cfg.ipam.as_ref().ok_or_else(|| anyhow!("ipam_not_configured"))?)
+}
+
+/// The address the panel allocated to `cn`, as `ifconfig-push` arguments.
+pub async fn assigned(st: &AppState, cn: &str) -> Result<Option<StaticIp>> {
+    let (Some(cfg), Some(a)) = (st.cfg.ipam.as_ref(), db::ipam::for_cn(&st.db, cn).await?) else { return Ok(None) };
+    let Ok(ip) = a.ip.parse::<Ipv4Addr>() else { return Ok(None) };
+    Ok(Some(StaticIp { ip: a.ip, remote: Pool::from_cfg(cfg)?.remote(ip.into()).to_string(), alias: None }))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Holder {
+    pub cn: String,
+    /// Recorded by the panel.
+    pub allocated: bool,
+    /// Present as `ifconfig-push` in the CN's CCD.
+    pub in_ccd: bool,
+    pub allocated_by: Option<String>,
+    pub allocated_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Conflict {
+    pub ip: String,
+    pub cns: Vec<String>,
+    /// `duplicate`, `outside_pool`, `reserved` or `ccd_mismatch`.
+    pub issue: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Usage {
+    pub subnet: String,
+    pub topology: Topology,
+    pub capacity: usize,
+    pub used: usize,
+    pub free: usize,
+    pub utilisation_pct: f64,
+    pub addresses: BTreeMap<String, Vec<Holder>>,
+    pub conflicts: Vec<Conflict>,
+}
+
+/// Who holds which address, from the allocations table and every file in `ccd_dir`.
+async fn holders(st: &AppState) -> Result<BTreeMap<u32, Vec<Holder>>> {
+    let mut by_ip: BTreeMap<u32, Vec<Holder>> = BTreeMap::new();
+    for a in db::ipam::all(&st.db).await? {
+        let Ok(ip) = a.ip.parse::<Ipv4Addr>() else { continue };
+        by_ip.entry(ip.into()).or_default().push(Holder {
+            cn: a.cn, allocated: true, in_ccd: false, allocated_by: Some(a.created_by), allocated_at: Some(a.created_at),
+        });
+    }
+    if tokio::fs::try_exists(&st.cfg.ovpn.ccd_dir).await? {
+        for f in list_ccd(st).await? {
+            let Ok(text) = read_ccd(st, &f.cn).await else { continue };
+            let Some(ip) = super::ccd::static_ip(&text) else { continue };
+            let list = by_ip.entry(ip.into()).or_default();
+            match list.iter_mut().find(|h| h.cn == f.cn) {
+                Some(h) => h.in_ccd = true,
+                None => list.push(Holder { cn: f.cn, allocated: false, in_ccd: true, allocated_by: None, allocated_at: None }),
+            }
+        }
+    }
+    Ok(by_ip)
+}
+
+pub async fn usage(st: &AppState) -> Result<Usage> {
+    let cfg = st.cfg.ipam.as_ref().ok_or_else(|| anyhow!("ipam_not_configured"))?;
+    let pool = Pool::from_cfg(cfg)?;
+    let by_ip = holders(st).await?;
+
+    let mut conflicts = Vec::new();
+    let mut used = 0;
+    for (&ip, hs) in &by_ip {
+        let addr = Ipv4Addr::from(ip).to_string();
+        let cns: Vec<String> = hs.iter().map(|h| h.cn.clone()).collect();
+        if hs.len() > 1 {
+            conflicts.push(Conflict { ip: addr.clone(), cns: cns.clone(), issue: "duplicate" });
+        }
+        if !pool.fits(ip) {
+            conflicts.push(Conflict { ip: addr.clone(), cns: cns.clone(), issue: "outside_pool" });
+        } else if pool.is_reserved(ip) {
+            conflicts.push(Conflict { ip: addr.clone(), cns: cns.clone(), issue: "reserved" });
+        } else {
+            used += 1;
+        }
+        for h in hs.iter().filter(|h| h.allocated && !h.in_ccd) {
+            conflicts.push(Conflict { ip: addr.clone(), cns: vec![h.cn.clone()], issue: "ccd_mismatch" });
+        }
+    }
+    let capacity = pool.capacity();
+    Ok(Usage {
+        subnet: cfg.subnet.clone(),
+        topology: pool.topology,
+        capacity,
+        used,
+        free: capacity.saturating_sub(used),
+        utilisation_pct: if capacity == 0 { 0.0 } else { (used as f64 * 1000.0 / capacity as f64).round() / 10.0 },
+        addresses: by_ip.into_iter().map(|(ip, hs)| (Ipv4Addr::from(ip).to_string(), hs)).collect(),
+        conflicts,
+    })
+}
+
+#[derive(Debug, Serialize)]
+pub struct Reserved {
+    pub cn: String,
+    pub ip: String,
+    pub remote: String,
+}
+
+/// Reserves `want` (or the next free address) for `cn` and writes the
+/// matching `ifconfig-push` into its CCD. An address another CN uses in its
+/// CCD counts as taken even if the panel never handed it out. An
+/// `ifconfig-push` already in the CN's own CCD is adopted when it is usable
+/// and matches `want`; otherwise it is reported rather than replaced.
+pub async fn reserve(st: &AppState, ctx: &AuditCtx, cn: &str, want: Option<&str>) -> Result<Reserved> {
+    let pool = pool(st)?;
+    let _guard = ALLOC.lock().await;
+    if let Some(a) = db::ipam::for_cn(&st.db, cn).await? {
+        return Err(anyhow!("already_allocated: {}", a.ip));
+    }
+    let text = read_ccd(st, cn).await?;
+    let mut structured = Structured::from_text(&text.replace("\r\n", "\n"))
+        .map_err(|e| anyhow!(super::ccd::summary(&e)))?;
+
+    let by_ip = holders(st).await?;
+    let taken = |ip: u32| by_ip.get(&ip).is_some_and(|hs| hs.iter().any(|h| h.cn != cn));
+    if let Some(found @ (local, _)) = super::ccd::ifconfig_push(&text) {
+        let ip = u32::from(local);
+        let wanted = want.is_none_or(|w| w.parse::<Ipv4Addr>().is_ok_and(|w| w == local));
+        if !wanted || !pool.fits(ip) || pool.is_reserved(ip) || taken(ip) {
+            return Err(anyhow!("static_ip_in_ccd: {local}"));
+        }
+        return adopt(st, ctx, cn, &pool, &text, structured, found).await;
+    }
+    let ip = match want {
+        Some(w) => {
+            let ip = u32::from(w.parse::<Ipv4Addr>().map_err(|_| anyhow!("invalid_ip"))?);
+            if !pool.fits(ip) { return Err(anyhow!("ip_outside_pool")); }
+            if pool.is_reserved(ip) || taken(ip) { return Err(anyhow!("ip_unavailable")); }
+            ip
+        }
+        None => pool.candidates().find(|&ip| !pool.is_reserved(ip) && !taken(ip)).ok_or_else(|| anyhow!("pool_exhausted"))?,
+    };
+    let addr = Ipv4Addr::from(ip).to_string();
+    let remote = pool.remote(ip).to_string();
+
+    db::ipam::insert(&st.db, &addr, cn, &ctx.actor).await?;
+    let alias = structured.static_ip.take().and_then(|s| s.alias);
+    let static_ip = StaticIp { ip: addr.clone(), remote: remote.clone(), alias };
+    // A CCD generated from a template is rendered again rather than edited.
+    let written = match ccd_templates::regenerate(st, ctx, cn, Some(&static_ip)).await {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+            structured.static_ip = Some(static_ip);
+            match structured.to_text() {
+                Ok(t) => write_ccd(st, ctx, cn, &t, Some(&ccd_etag(&text))).await,
+                Err(e) => Err(anyhow!("ccd_invalid: {}", e.first().map(|f| f.error.as_str()).unwrap_or(""))),
+            }
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = written {
+        db::ipam::delete(&st.db, cn).await.ok();
+        return Err(e);
+    }
+    ctx.record(&st.db, "IPAM_RESERVE", cn, json!({ "ip": addr, "remote": remote, "requested": want })).await.ok();
+    Ok(Reserved { cn: cn.into(), ip: addr, remote })
+}
+
+/// Records an `ifconfig-push` the panel did not write, correcting its second
+/// argument to the pool's if it is on a line the form can edit.
+async fn adopt(
+    st: &AppState, ctx: &AuditCtx, cn: &str, pool: &Pool, text: &str, mut structured: Structured,
+    (local, current): (Ipv4Addr, Ipv4Addr),
+) -> Result<Reserved> {
+    let (addr, remote) = (local.to_string(), pool.remote(local.into()));
+    if current != remote {
+        // A commented line is kept verbatim in `extra`; leave its fix to a person.
+        let Some(s) = structured.static_ip.as_mut() else {
+            return Err(anyhow!("static_ip_in_ccd: {local} {current}"));
+        };
+        s.remote = remote.to_string();
+    }
+    db::ipam::insert(&st.db, &addr, cn, &ctx.actor).await?;
+    if current != remote {
+        let written = match structured.to_text() {
+            Ok(t) => write_ccd(st, ctx, cn, &t, Some(&ccd_etag(text))).await,
+            Err(e) => Err(anyhow!("ccd_invalid: {}", e.first().map(|f| f.error.as_str()).unwrap_or(""))),
+        };
+        if let Err(e) = written {
+            db::ipam::delete(&st.db, cn).await.ok();
+            return Err(e);
+        }
+    }
+    let remote = remote.to_string();
+    ctx.record(&st.db, "IPAM_RESERVE", cn, json!({ "ip": addr, "remote": remote, "adopted": true })).await.ok();
+    Ok(Reserved { cn: cn.into(), ip: addr, remote })
+}
+
+/// Drops the allocation and the `ifconfig-push` line. Also clears a static
+/// address the panel never recorded, so spreadsheet-era entries can be freed.
+pub async fn release(st: &AppState, ctx: &AuditCtx, cn: &str) -> Result<String> {
+    let _guard = ALLOC.lock().await;
+    let text = read_ccd(st, cn).await?;
+    let mut structured = Structured::from_text(&text.replace("\r\n", "\n"))
+        .map_err(|e| anyhow!(super::ccd::summary(&e)))?;
+    let alloc = db::ipam::for_cn(&st.db, cn).await?;
+    let ip = match (&alloc, super::ccd::static_ip(&text)) {
+        (Some(a), _) => a.ip.clone(),
+        (None, Some(ip)) => ip.to_string(),
+        (None, None) => return Err(anyhow!("not_allocated")),
+    };
+    // A template that needs the address fails to render and keeps it.
+    if !ccd_templates::regenerate(st, ctx, cn, None).await? {
+        // A commented `ifconfig-push` sits in `extra`.
+        let lines = structured.extra.len();
+        structured.extra.retain(|l| super::ccd::static_ip(l).is_none());
+        if structured.static_ip.take().is_some() | (structured.extra.len() != lines) {
+            let t = structured.to_text().map_err(|_| anyhow!("ccd_invalid"))?;
+            write_ccd(st, ctx, cn, &t, Some(&ccd_etag(&text))).await?;
+        }
+    }
+    db::ipam::delete(&st.db, cn).await?;
+    ctx.record(&st.db, "IPAM_RELEASE", cn, json!({ "ip": ip })).await.ok();
+    Ok(ip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(subnet: &str, topology: Topology, reserved: &[&str]) -> Result<Pool> {
+        Pool::from_cfg(&IpamCfg { subnet: subnet.into(), topology, reserved: reserved.iter().map(|r| r.to_string()).collect() })
+    }
+
+    fn ip(s: &str) -> u32 {
+        s.parse::<Ipv4Addr>().unwrap().into()
+    }
+
+    #[test]
+    fn subnet_pool() {
+        let p = pool("10.8.0.0/24", Topology::Subnet, &[]).unwrap();
+        let all: Vec<u32> = p.candidates().collect();
+        assert_eq!((all[0], *all.last().unwrap(), all.len()), (ip("10.8.0.2"), ip("10.8.0.254"), 253));
+        assert!(!p.fits(ip("10.8.0.0")) && !p.fits(ip("10.8.0.1")) && !p.fits(ip("10.8.0.255")));
+        assert!(p.fits(ip("10.8.0.2")) && p.fits(ip("10.8.0.254")));
+        assert!(!p.fits(ip("10.8.1.2")));
+        assert_eq!(p.remote(ip("10.8.0.9")), "255.255.255.0".parse::<Ipv4Addr>().unwrap());
+        assert_eq!(p.capacity(), 253);
+    }
+
+    #[test]
+    fn net30_steps_by_four() {
+        let p = pool("10.8.0.0/24", Topology::Net30, &[]).unwrap();
+        let all: Vec<u32> = p.candidates().collect();
+        // 10.8.0.0/30 is the server's; each client gets .1 of its own /30.
+        assert_eq!(&all[..3], &[ip("10.8.0.5"), ip("10.8.0.9"), ip("10.8.0.13")]);
+        assert_eq!((*all.last().unwrap(), all.len()), (ip("10.8.0.253"), 63));
+        assert!(all.iter().all(|&a| p.fits(a)));
+        for bad in ["10.8.0.1", "10.8.0.4", "10.8.0.6", "10.8.0.7", "10.8.0.255"] {
+            assert!(!p.fits(ip(bad)), "{bad}");
+        }
+        assert_eq!(p.remote(ip("10.8.0.5")), "10.8.0.6".parse::<Ipv4Addr>().unwrap());
+    }
+
+    #[test]
+    fn reserved_ranges() {
+        assert_eq!(range("10.8.0.5"), Some((ip("10.8.0.5"), ip("10.8.0.5"))));
+        assert_eq!(range("10.8.0.100 - 10.8.0.199"), Some((ip("10.8.0.100"), ip("10.8.0.199"))));
+        assert_eq!(range("10.8.0.199-10.8.0.100"), None);
+        // Host bits in a CIDR are ignored, as the kernel would.
+        assert_eq!(range("10.8.0.130/25"), Some((ip("10.8.0.128"), ip("10.8.0.255"))));
+        assert_eq!(range("10.8.0.0/33"), None);
+        assert_eq!(range("10.8.0"), None);
+
+        let p = pool("10.8.0.0/24", Topology::Subnet, &["10.8.0.2", "10.8.0.100-10.8.0.199", "10.8.0.240/28"]).unwrap();
+        assert!(p.is_reserved(ip("10.8.0.2")) && p.is_reserved(ip("10.8.0.150")) && p.is_reserved(ip("10.8.0.250")));
+        assert!(!p.is_reserved(ip("10.8.0.3")) && !p.is_reserved(ip("10.8.0.200")));
+        assert_eq!(p.capacity(), 253 - 1 - 100 - 15);
+    }
+
+    #[test]
+    fn masks_and_bad_config() {
+        assert_eq!(mask(24), 0xffff_ff00);
+        assert_eq!(mask(32), u32::MAX);
+        assert_eq!(mask(0), 0);
+        for (subnet, reserved) in [("10.8.0.0", ""), ("10.8.0.0/31", ""), ("10.8.0.1/24", ""), ("10.8.0.0/24", "nope")] {
+            let reserved: Vec<&str> = [reserved].into_iter().filter(|r| !r.is_empty()).collect();
+            assert!(pool(subnet, Topology::Subnet, &reserved).is_err(), "{subnet} {reserved:?}");
+        }
+    }
+}
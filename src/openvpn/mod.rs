@@ -1,20 +1,31 @@
-use crate::{db, vpncertd, AppState};
-use anyhow::{anyhow, Context, Result};
+pub mod autorenew;
+pub mod bulk;
+pub mod ccd;
+pub mod ccd_templates;
+pub mod csr;
+pub mod expiry;
+pub mod import;
+pub mod ipam;
+pub mod profiles;
+pub mod renew;
+
+use crate::{audit::{self, AuditCtx}, config::KeyType, db, vpncertd, AppState};
+use anyhow::{anyhow, Result};
 use base64::Engine;
 use rand::RngCore;
 use regex::Regex;
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
+use serde_json::json;
 use std::path::Path;
 use axum::body::Body;
 use axum::http::header::CONTENT_TYPE;
 use axum::http::{HeaderMap, HeaderValue};
-use tokio::{fs, io::AsyncWriteExt};
+use tokio::fs;
 use tokio_util::io::ReaderStream;
-use ulid::Ulid;
-use openssl::{x509::X509Crl};
+use openssl::x509::{ReasonCode, X509Crl};
+use openssl::asn1::Asn1TimeRef;
 use std::collections::HashMap;
 use std::time::UNIX_EPOCH;
-use openssl::asn1::Asn1TimeRef;
 
 fn cn_ok(re: &Regex, cn: &str) -> bool {
     re.is_match(cn)
@@ -35,9 +46,19 @@ pub struct IssuedWithStatus {
     pub serial: String,
     pub cn: String,
     pub profile: String,
+    /// As recorded by the panel at issuance; `None` for certs it did not issue.
+    pub key_type: Option<String>,
+    /// `genkey`, `csr` or `renew` for certs the panel issued.
+    pub via: Option<String>,
+    /// `not_before` from vpncertd, or when the panel issued it; unix seconds.
+    pub issued_at: Option<i64>,
     pub not_after: String,
+    /// `not_after` as unix seconds, when vpncertd's format could be parsed.
+    pub not_after_ts: Option<i64>,
     pub revoked: bool,
     pub revoked_at: Option<String>,
+    /// RFC 5280 reason from the CRL entry, when the daemon sets one.
+    pub revocation_reason: Option<String>,
 }
 
 
@@ -77,24 +98,46 @@ pub async fn list_ccd(st: &AppState) -> anyhow::Result<Vec<CcdMeta>> {
 }
 
 
-pub async fn create_client(st: &AppState, cn: &str, passphrase: Option<&str>) -> Result<ClientIssue> {
+fn gen_passphrase() -> String {
+    let mut raw = [0u8; 16];
+    rand::rngs::OsRng.fill_bytes(&mut raw);
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(raw)
+}
+
+/// What to issue with; resolved against the caller's roles beforehand.
+#[derive(Clone, Copy)]
+pub struct IssueOpts<'a> {
+    pub profile: &'a str,
+    pub key_type: KeyType,
+    pub validity_days: u32,
+}
+
+pub async fn create_client(
+    st: &AppState, ctx: &AuditCtx, cn: &str, passphrase: Option<&str>, opts: &IssueOpts<'_>,
+) -> Result<ClientIssue> {
+    let IssueOpts { profile, key_type, validity_days } = *opts;
     let re = Regex::new(&st.cfg.ovpn.cn_pattern).unwrap();
     if !re.is_match(cn) {
         return Err(anyhow!("invalid_cn"));
     }
 
-    let pass = match passphrase {
-        Some(p) => p.to_string(),
-        None => {
-            let mut raw = [0u8; 16];
-            rand::rngs::OsRng.fill_bytes(&mut raw);
-            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(raw)
-        }
-    };
-
-    let issued = vpncertd::genkey_and_sign(&st.cfg.ovpn.socket_path, cn, "client", "rsa4096", &pass).await?;
-
-    db::audit_record(&st.db, "system", "CLIENT_CREATE", cn, "-", "-", "{}").await.ok();
+    let pass = passphrase.map(str::to_string).unwrap_or_else(gen_passphrase);
+    let issued = vpncertd::genkey_and_sign(&st.cfg.ovpn.socket_path, &vpncertd::GenKeyReq {
+        cn, profile, key_type: key_type.as_str(), passphrase: &pass, renew: false, validity_days: Some(validity_days),
+    }).await?;
+    record_issued(st, &db::issued::NewIssued {
+        serial: issued.serial.as_deref(), cn, profile, key_type: key_type.as_str(), via: "genkey",
+        not_after: issued.not_after.as_deref(), created_by: &ctx.actor,
+    }).await;
+
+    ctx.record(&st.db, "CLIENT_CREATE", cn, json!({
+        "serial": issued.serial,
+        "not_after": issued.not_after,
+        "profile": profile,
+        "key_type": key_type.as_str(),
+        "validity_days": validity_days,
+        "passphrase_supplied": passphrase.is_some(),
+    })).await.ok();
 
     Ok(ClientIssue {
         cn: cn.to_string(),
@@ -106,21 +149,73 @@ pub async fn create_client(st: &AppState, cn: &str, passphrase: Option<&str>) ->
     })
 }
 
-pub async fn revoke_client(st: &AppState, cn: &str) -> Result<()> {
+/// Keeps the panel's own record of how a serial was issued; vpncertd only
+/// reports the profile.
+pub async fn record_issued(st: &AppState, rec: &db::issued::NewIssued<'_>) {
+    if let Err(e) = db::issued::record(&st.db, rec).await {
+        tracing::error!(cn=%rec.cn, serial=?rec.serial, "record issued cert: {}", e);
+    }
+}
+
+/// Revokes `serial`, or the newest unrevoked cert of `cn`, for an RFC 5280
+/// `reason`; returns the serial revoked.
+pub async fn revoke_client(st: &AppState, ctx: &AuditCtx, cn: &str, serial: Option<&str>, reason: &str) -> Result<String> {
     let re = Regex::new(&st.cfg.ovpn.cn_pattern).unwrap();
     if !cn_ok(&re, cn) {
         return Err(anyhow!("invalid CN"));
     }
-    vpncertd::revoke(&st.cfg.ovpn.socket_path, cn).await?;
-    db::audit_record(&st.db, "system", "CLIENT_REVOKE", cn, "-", "-", "{}")
+    if !vpncertd::REVOKE_REASONS.contains(&reason) {
+        return Err(anyhow!("invalid_reason"));
+    }
+    let target = match serial {
+        Some(serial) => {
+            let it = find_issued(st, cn, serial).await?;
+            if it.revoked {
+                return Err(anyhow!("already_revoked"));
+            }
+            it
+        }
+        None => renew::current(st, cn).await?.ok_or_else(|| anyhow!("not_found: cn"))?,
+    };
+    let serial = vpncertd::revoke(&st.cfg.ovpn.socket_path, &target.serial, reason).await?;
+    ctx.record(&st.db, "CLIENT_REVOKE", cn, json!({ "serial": serial, "reason": reason }))
+        .await
+        .ok();
+    Ok(serial)
+}
+
+/// Lifts a `certificateHold`. Other reasons are final.
+pub async fn unrevoke_client(st: &AppState, ctx: &AuditCtx, cn: &str, serial: &str) -> Result<()> {
+    let it = find_issued(st, cn, serial).await?;
+    if !it.revoked {
+        return Err(anyhow!("not_revoked"));
+    }
+    // Without a reason code on the CRL entry the daemon has the final word.
+    if it.revocation_reason.as_deref().is_some_and(|r| r != "certificateHold") {
+        return Err(anyhow!("not_on_hold"));
+    }
+    vpncertd::unrevoke(&st.cfg.ovpn.socket_path, serial).await.map_err(|e| {
+        if e.to_string().contains("unknown op") { anyhow!("unrevoke_unsupported") } else { e }
+    })?;
+    ctx.record(&st.db, "CLIENT_UNREVOKE", cn, json!({ "serial": serial, "reason": "removeFromCRL" }))
         .await
         .ok();
     Ok(())
 }
 
+async fn find_issued(st: &AppState, cn: &str, serial: &str) -> Result<IssuedWithStatus> {
+    let it = list_issued_with_status(st, None).await?
+        .into_iter().find(|it| it.serial == serial)
+        .ok_or_else(|| anyhow!("not_found: serial"))?;
+    if it.cn != cn {
+        return Err(anyhow!("serial_cn_mismatch"));
+    }
+    Ok(it)
+}
+
 pub struct BundleFile { pub path: String, pub filename: String }
 
-pub async fn build_bundle(st: &AppState, cn: &str, include_key: bool) -> anyhow::Result<BundleFile> {
+pub async fn build_bundle(st: &AppState, ctx: &AuditCtx, cn: &str, include_key: bool) -> anyhow::Result<BundleFile> {
     use std::path::Path;
     use tokio::{fs, io::AsyncWriteExt};
 
@@ -139,9 +234,30 @@ pub async fn build_bundle(st: &AppState, cn: &str, include_key: bool) -> anyhow:
     let path = dir.join(&filename);
     let mut f = fs::File::create(&path).await?;
     f.write_all(&bytes).await?;
+
+    ctx.record(&st.db, "CLIENT_BUNDLE", cn, json!({
+        "include_key": include_key,
+        "sha256": audit::sha256_hex(&bytes),
+        "size": bytes.len(),
+    })).await.ok();
     Ok(BundleFile { filename, path: path.to_string_lossy().into_owned() })
 }
 
+/// Background variant of `build_bundle`: the zip is moved into the job
+/// directory, owner-only, for download from `/jobs/:id/files/:name`.
+pub async fn bundle_job(st: &AppState, job: &crate::jobs::JobRun) -> Result<serde_json::Value> {
+    let cn = job.params["cn"].as_str().ok_or_else(|| anyhow!("job without cn"))?;
+    let include_key = job.params["include_key"].as_bool().unwrap_or(false);
+    let b = build_bundle(st, &job.ctx, cn, include_key).await?;
+    let bytes = fs::read(&b.path).await;
+    fs::remove_file(&b.path).await.ok();
+    let dir = job.dir(st);
+    crate::jobs::private_dir(&dir).await?;
+    crate::jobs::write_private(&dir, &b.filename, &bytes?).await?;
+    job.item(st, 0, cn, "ok", &json!({ "cn": cn, "include_key": include_key })).await?;
+    Ok(json!({ "files": [b.filename], "cancelled": false }))
+}
+
 pub async fn stream_file(path: &str) -> Result<(HeaderMap, Body)> {
     let file = tokio::fs::File::open(path).await?;
     let stream = ReaderStream::new(file);
@@ -153,6 +269,8 @@ pub async fn stream_file(path: &str) -> Result<(HeaderMap, Body)> {
 }
 
 
+/// The file as it is on disk, empty if there is none. Only reads; an edit
+/// made outside the panel is recorded when the CCD is next saved.
 pub async fn read_ccd(st: &AppState, cn: &str) -> Result<String> {
     let re = Regex::new(&st.cfg.ovpn.cn_pattern).unwrap();
     if !cn_ok(&re, cn) { return Err(anyhow!("invalid CN")); }
@@ -162,7 +280,68 @@ pub async fn read_ccd(st: &AppState, cn: &str) -> Result<String> {
     Ok(String::from_utf8_lossy(&bytes).into_owned())
 }
 
-pub async fn write_ccd(st: &AppState, cn: &str, content: &str) -> Result<()> {
+/// Records what is on disk as an `external` revision when it differs from
+/// the newest one we know of, before a save or rollback replaces it. The
+/// first sighting of a file is its baseline and is not audited.
+async fn note_external_edit(st: &AppState, cn: &str, content: &str) -> Result<()> {
+    let sha256 = audit::sha256_hex(content.as_bytes());
+    let Some((rev, latest)) = db::ccd::add_if_changed(&st.db, &db::ccd::NewRevision {
+        cn, content, sha256: &sha256, author: "external", source: "external", restored_from: None,
+    }).await? else {
+        return Ok(());
+    };
+    if let Some(prev) = latest {
+        tracing::warn!(cn=%cn, rev, "CCD changed outside the panel");
+        let ctx = AuditCtx { actor: "external".into(), ..AuditCtx::system() };
+        ctx.record(&st.db, "CCD_EXTERNAL_EDIT", cn, json!({
+            "rev": rev, "prev_rev": prev.rev, "prev_sha256": prev.sha256, "sha256": sha256, "size": content.len(),
+        })).await.ok();
+    }
+    Ok(())
+}
+
+/// Version tag of a CCD's content, as sent in `ETag`; a missing file is empty.
+pub fn ccd_etag(content: &str) -> String {
+    audit::sha256_hex(content.as_bytes())
+}
+
+// Makes the If-Match comparison and the write one step within this process.
+static CCD_WRITE: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// `if_match` is the `ccd_etag` the caller based its edit on; a different
+/// file on disk fails with `etag_mismatch` and nothing is written. `"*"`
+/// matches any existing file but fails when there is none. A CCD generated
+/// from a template fails with `template_linked`, since the next template
+/// change would overwrite the edit.
+pub async fn write_ccd(st: &AppState, ctx: &AuditCtx, cn: &str, content: &str, if_match: Option<&str>) -> Result<()> {
+    refuse_linked(st, cn).await?;
+    store_ccd(st, ctx, cn, content, if_match, "panel", None).await.map(drop)
+}
+
+/// `write_ccd` for text `ccd_templates` rendered.
+async fn write_generated(st: &AppState, ctx: &AuditCtx, cn: &str, content: &str, if_match: Option<&str>) -> Result<()> {
+    store_ccd(st, ctx, cn, content, if_match, "panel", None).await.map(drop)
+}
+
+async fn refuse_linked(st: &AppState, cn: &str) -> Result<()> {
+    match db::ccd_templates::link(&st.db, cn).await? {
+        Some(l) => Err(anyhow!("template_linked: {}", l.template)),
+        None => Ok(()),
+    }
+}
+
+/// Saves an old revision again; the result is a new revision.
+pub async fn rollback_ccd(st: &AppState, ctx: &AuditCtx, cn: &str, rev: i64, if_match: Option<&str>) -> Result<i64> {
+    refuse_linked(st, cn).await?;
+    let old = db::ccd::get(&st.db, cn, rev).await?.ok_or_else(|| anyhow!("not_found: revision"))?;
+    store_ccd(st, ctx, cn, &old.content, if_match, "rollback", Some(rev)).await
+}
+
+/// Writes the file and records a revision, after preserving any edit made
+/// on disk since the last one. Returns the newest revision number.
+async fn store_ccd(
+    st: &AppState, ctx: &AuditCtx, cn: &str, content: &str, if_match: Option<&str>, source: &str, restored_from: Option<i64>,
+) -> Result<i64> {
     let re = Regex::new(&st.cfg.ovpn.cn_pattern).unwrap();
     if !cn_ok(&re, cn) { return Err(anyhow!("invalid CN")); }
 
@@ -172,23 +351,85 @@ pub async fn write_ccd(st: &AppState, cn: &str, content: &str) -> Result<()> {
     let path = dir.join(cn);
 
     let normalized = content.replace("\r\n", "\n");
+    ccd::parse(&normalized).map_err(|e| anyhow!(ccd::summary(&e)))?;
+
+    let _guard = CCD_WRITE.lock().await;
+    let previous = fs::read(&path).await.ok();
+    let current = String::from_utf8_lossy(previous.as_deref().unwrap_or_default()).into_owned();
+    let matches = |tag: &str| if tag == "*" { previous.is_some() } else { tag == ccd_etag(&current) };
+    if if_match.is_some_and(|tag| !matches(tag)) {
+        return Err(anyhow!("etag_mismatch"));
+    }
+    note_external_edit(st, cn, &current).await?;
+    write_atomic(dir, cn, normalized.as_bytes()).await?;
+
+    let sha256 = audit::sha256_hex(normalized.as_bytes());
+    let rev = match db::ccd::latest(&st.db, cn).await? {
+        Some(r) if r.sha256 == sha256 && restored_from.is_none() => r.rev,
+        _ => db::ccd::add(&st.db, &db::ccd::NewRevision {
+            cn, content: &normalized, sha256: &sha256, author: &ctx.actor, source, restored_from,
+        }).await?,
+    };
 
-    fs::write(&path, normalized.as_bytes()).await?;
+    ctx.record(&st.db, "ADMIN_SAVE_CCD", cn, json!({
+        "prev_sha256": previous.as_deref().map(audit::sha256_hex),
+        "sha256": sha256,
+        "size": normalized.len(),
+        "rev": rev,
+        "source": source,
+        "restored_from": restored_from,
+    })).await.ok();
+    Ok(rev)
+}
 
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::PermissionsExt;
-        fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).await.ok();
-    }
-    Ok(())
+/// Temp file in the same directory, then rename, so OpenVPN never reads a
+/// half-written CCD. Dotfiles are skipped by `list_ccd`.
+async fn write_atomic(dir: &Path, name: &str, bytes: &[u8]) -> Result<()> {
+    use tokio::io::AsyncWriteExt;
+    let tmp = dir.join(format!(".{name}.{}.tmp", ulid::Ulid::new()));
+    let res = async {
+        let mut f = fs::File::create(&tmp).await?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o600)).await?;
+        }
+        f.write_all(bytes).await?;
+        f.sync_all().await?;
+        fs::rename(&tmp, dir.join(name)).await
+    }.await;
+    if res.is_err() { fs::remove_file(&tmp).await.ok(); }
+    Ok(res?)
 }
 
 
+struct CrlEntry {
+    at: String,
+    reason: Option<String>,
+}
+
+fn crl_reason_name(code: i64) -> Option<&'static str> {
+    Some(match code {
+        0 => "unspecified",
+        1 => "keyCompromise",
+        2 => "cACompromise",
+        3 => "affiliationChanged",
+        4 => "superseded",
+        5 => "cessationOfOperation",
+        6 => "certificateHold",
+        8 => "removeFromCRL",
+        9 => "privilegeWithdrawn",
+        10 => "aACompromise",
+        _ => return None,
+    })
+}
+
+#[allow(dead_code)]
 fn asn1_to_string(t: &Asn1TimeRef) -> String {
     t.to_string()
 }
 
-async fn crl_revoked_map_dec(st: &AppState) -> Result<HashMap<String, String>> {
+async fn crl_revoked_map_dec(st: &AppState) -> Result<HashMap<String, CrlEntry>> {
     let pem = vpncertd::get_crl(&st.cfg.ovpn.socket_path).await?;
     let crl = X509Crl::from_pem(pem.as_bytes())?;
 
@@ -196,14 +437,19 @@ async fn crl_revoked_map_dec(st: &AppState) -> Result<HashMap<String, String>> {
     if let Some(all) = crl.get_revoked() {
         for r in all {
             let dec = r.serial_number().to_bn()?.to_dec_str()?.to_string();
-            let when = r.revocation_date().to_string();
-            m.insert(dec, when);
+            let at = r.revocation_date().to_string();
+            let reason = r.extension::<ReasonCode>().ok().flatten()
+                .and_then(|(_, code)| code.get_i64().ok())
+                .and_then(crl_reason_name)
+                .map(str::to_string);
+            m.insert(dec, CrlEntry { at, reason });
         }
     }
     Ok(m)
 }
 
 
+#[allow(dead_code)]
 async fn revoked_hex_map_via_crl(st: &crate::AppState) -> Result<HashMap<String, String>> {
     let pem = crate::vpncertd::get_crl(&st.cfg.ovpn.socket_path).await?;
     let crl = X509Crl::from_pem(pem.as_bytes())
@@ -219,22 +465,34 @@ async fn revoked_hex_map_via_crl(st: &crate::AppState) -> Result<HashMap<String,
 }
 
 
-
 pub async fn list_issued_with_status(st: &AppState, limit: Option<usize>) -> Result<Vec<IssuedWithStatus>> {
     let issued = vpncertd::list_issued(&st.cfg.ovpn.socket_path, limit).await?;
-    let rev = crl_revoked_map_dec(st).await.unwrap_or_default();
+    let mut rev = crl_revoked_map_dec(st).await.unwrap_or_default();
+    let mut recorded = db::issued::all(&st.db).await.unwrap_or_default();
 
     let out = issued
         .into_iter()
         .map(|it| {
-            let revoked_at = rev.get(&it.serial).cloned();
+            let entry = rev.remove(&it.serial);
+            let revocation_reason = entry.as_ref().and_then(|e| e.reason.clone());
+            let revoked_at = entry.map(|e| e.at);
+            let rec = recorded.remove(&it.serial);
+            let via = rec.as_ref().map(|r| r.via.clone());
+            let issued_at = it.not_before.as_deref().and_then(expiry::parse_not_after)
+                .or_else(|| rec.as_ref().map(|r| r.created_at));
+            let key_type = it.key_type.or_else(|| rec.map(|r| r.key_type));
             IssuedWithStatus {
+                key_type,
+                via,
+                issued_at,
+                not_after_ts: expiry::parse_not_after(&it.not_after),
                 serial: it.serial,
                 cn: it.cn,
                 profile: it.profile,
                 not_after: it.not_after,
                 revoked: revoked_at.is_some(),
                 revoked_at,
+                revocation_reason,
             }
         })
         .collect();
@@ -0,0 +1,86 @@
+use anyhow::{anyhow, Result};
+use time::OffsetDateTime;
+
+use super::expiry;
+use crate::config::{IssuanceCfg, KeyType, ProfileCfg};
+
+/// Profiles any of `roles` may issue with.
+pub fn usable<'a>(cfg: &'a IssuanceCfg, roles: &[String]) -> Vec<&'a ProfileCfg> {
+    cfg.profiles.iter().filter(|p| p.roles.iter().any(|r| roles.contains(r))).collect()
+}
+
+/// The named (or default) profile, if `roles` may use it.
+pub fn resolve_profile<'a>(cfg: &'a IssuanceCfg, roles: &[String], profile: Option<&str>) -> Result<&'a ProfileCfg> {
+    let name = profile.filter(|p| !p.is_empty()).unwrap_or(&cfg.default_profile);
+    let p = cfg.profiles.iter().find(|p| p.name == name).ok_or_else(|| anyhow!("profile_unknown"))?;
+    if !p.roles.iter().any(|r| roles.contains(r)) {
+        return Err(anyhow!("profile_forbidden"));
+    }
+    Ok(p)
+}
+
+/// Picks the profile and key type for a request, falling back to the
+/// configured defaults, and checks both against what `roles` may use.
+pub fn resolve<'a>(
+    cfg: &'a IssuanceCfg, roles: &[String], profile: Option<&str>, key_type: Option<&str>,
+) -> Result<(&'a ProfileCfg, KeyType)> {
+    let p = resolve_profile(cfg, roles, profile)?;
+    let key = match key_type.filter(|k| !k.is_empty()) {
+        Some(k) => KeyType::parse(k).ok_or_else(|| anyhow!("key_type_unknown"))?,
+        None => p.default_key().ok_or_else(|| anyhow!("key_type_not_allowed"))?,
+    };
+    if !p.key_types.contains(&key) {
+        return Err(anyhow!("key_type_not_allowed"));
+    }
+    Ok((p, key))
+}
+
+/// Validity in days for a request giving either a day count, an explicit
+/// expiry or neither (the profile default). Whichever it is, the result is
+/// checked against the profile maximum, so vpncertd's own default never applies.
+pub fn validity_days(p: &ProfileCfg, days: Option<u32>, not_after: Option<&str>) -> Result<u32> {
+    let days = match (days, not_after.map(str::trim).filter(|s| !s.is_empty())) {
+        (Some(_), Some(_)) => return Err(anyhow!("validity_conflict")),
+        (Some(d), None) => d,
+        (None, Some(s)) => {
+            let ts = expiry::parse_not_after(s).ok_or_else(|| anyhow!("validity_invalid"))?;
+            let secs = ts - OffsetDateTime::now_utc().unix_timestamp();
+            if secs <= 0 {
+                return Err(anyhow!("validity_invalid"));
+            }
+            u32::try_from((secs + 86399) / 86400).unwrap_or(u32::MAX)
+        }
+        (None, None) => p.default_validity(),
+    };
+    if days == 0 {
+        return Err(anyhow!("validity_invalid"));
+    }
+    if days > p.max_validity_days {
+        return Err(anyhow!("validity_too_long"));
+    }
+    Ok(days)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(max: u32, default: Option<u32>) -> ProfileCfg {
+        ProfileCfg {
+            name: "client".into(), key_types: vec![KeyType::EcdsaP256], default_key_type: None,
+            roles: vec!["ADMIN".into()], max_validity_days: max, default_validity_days: default,
+        }
+    }
+
+    #[test]
+    fn omitted_validity_gets_the_default_and_the_cap() {
+        assert_eq!(validity_days(&profile(825, Some(365)), None, None).unwrap(), 365);
+        assert_eq!(validity_days(&profile(825, None), None, None).unwrap(), 825);
+        // A default above the cap is refused rather than passed through.
+        let e = validity_days(&profile(90, Some(365)), None, None).unwrap_err();
+        assert_eq!(e.to_string(), "validity_too_long");
+        assert_eq!(validity_days(&profile(90, None), Some(91), None).unwrap_err().to_string(), "validity_too_long");
+        assert_eq!(validity_days(&profile(90, None), Some(0), None).unwrap_err().to_string(), "validity_invalid");
+        assert_eq!(validity_days(&profile(90, None), Some(1), Some("2030-01-01")).unwrap_err().to_string(), "validity_conflict");
+    }
+}
@@ -0,0 +1,261 @@
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use serde_json::json;
+use std::time::Duration;
+use time::OffsetDateTime;
+
+use super::{build_bundle, profiles, gen_passphrase, list_issued_with_status, record_issued, ClientIssue, IssuedWithStatus};
+use crate::{audit::AuditCtx, config::{IssuanceCfg, KeyType, ProfileCfg}, db, vpncertd, AppState};
+
+const MAX_ATTEMPTS: i64 = 10;
+const RETRY_SECS: i64 = 300;
+
+pub struct Renewed {
+    pub issue: ClientIssue,
+    pub old_serial: String,
+    /// When the old cert is (or was) revoked; `None` keeps it until it expires.
+    pub old_revoke_at: Option<i64>,
+    pub bundle: Option<(String, Vec<u8>)>,
+}
+
+/// Newest unrevoked cert held by `cn`.
+pub async fn current(st: &AppState, cn: &str) -> Result<Option<IssuedWithStatus>> {
+    let issued = list_issued_with_status(st, None).await?;
+    Ok(issued.into_iter()
+        .filter(|it| it.cn == cn && !it.revoked)
+        .max_by_key(|it| it.serial.parse::<u128>().unwrap_or(0)))
+}
+
+/// A renewal keeps the profile and key type of the cert it replaces, as long
+/// as the profile still exists and allows that key type. A cert whose profile
+/// is gone moves to the default profile, but only if `roles` may issue with it.
+fn renewal_params<'a>(cfg: &'a IssuanceCfg, roles: &[String], old: &IssuedWithStatus) -> Result<(&'a ProfileCfg, KeyType)> {
+    let p = match cfg.profiles.iter().find(|p| p.name == old.profile) {
+        Some(p) => p,
+        None => profiles::resolve_profile(cfg, roles, None)?,
+    };
+    let key = old.key_type.as_deref().and_then(KeyType::parse)
+        .filter(|k| p.key_types.contains(k))
+        .or_else(|| p.default_key())
+        .ok_or_else(|| anyhow!("key_type_not_allowed"))?;
+    Ok((p, key))
+}
+
+/// The old cert's lifetime when it is known, else the profile default; never
+/// more than the profile allows now.
+fn renewal_validity(p: &ProfileCfg, old: &IssuedWithStatus) -> u32 {
+    let lifetime = old.issued_at.zip(old.not_after_ts)
+        .map(|(from, to)| (to - from + 43200).div_euclid(86400))
+        .filter(|&d| d > 0)
+        .and_then(|d| u32::try_from(d).ok());
+    lifetime.unwrap_or_else(|| p.default_validity()).min(p.max_validity_days)
+}
+
+/// Revokes a cert that a renewal replaced. If the daemon is unreachable the
+/// revocation is handed to the scheduler instead of being lost; returns
+/// whether it happened now.
+pub async fn revoke_superseded(
+    st: &AppState, ctx: &AuditCtx, cn: &str, serial: &str, replaced_by: Option<&str>, trigger: &str,
+) -> Result<bool> {
+    match vpncertd::revoke(&st.cfg.ovpn.socket_path, serial, "superseded").await {
+        Ok(_) => {
+            ctx.record(&st.db, "CLIENT_REVOKE", cn, json!({
+                "serial": serial, "reason": "superseded", "replaced_by": replaced_by, "trigger": trigger,
+            })).await.ok();
+            db::revocations::mark_done(&st.db, serial).await?;
+            db::renewals::mark_old_revoked(&st.db, serial).await?;
+            Ok(true)
+        }
+        Err(e) if e.to_string().contains("already") => {
+            db::revocations::mark_done(&st.db, serial).await?;
+            db::renewals::mark_old_revoked(&st.db, serial).await?;
+            Ok(false)
+        }
+        Err(e) => {
+            tracing::warn!(%cn, %serial, "revoke superseded cert failed, scheduling retry: {}", e);
+            let at = OffsetDateTime::now_utc().unix_timestamp() + RETRY_SECS;
+            db::revocations::schedule(&st.db, serial, cn, "superseded", at, &ctx.actor).await?;
+            Ok(false)
+        }
+    }
+}
+
+/// Issues a new cert for a CN that already holds one. The CCD is keyed by CN
+/// and stays as it is. The old serial is revoked after `revoke_old_after`
+/// seconds (0 = now), or left to expire when that is `None`. `roles` are the
+/// caller's, checked when the renewal has to change profile.
+pub async fn renew_client(
+    st: &AppState, ctx: &AuditCtx, roles: &[String], cn: &str, passphrase: Option<&str>, revoke_old_after: Option<i64>,
+    include_key: bool,
+) -> Result<Renewed> {
+    let re = Regex::new(&st.cfg.ovpn.cn_pattern).unwrap();
+    if !re.is_match(cn) {
+        return Err(anyhow!("invalid_cn"));
+    }
+    let old = current(st, cn).await?.ok_or_else(|| anyhow!("cn_not_found"))?;
+    let (p, key) = renewal_params(&st.cfg.issuance, roles, &old)?;
+    let validity_days = renewal_validity(p, &old);
+    let (profile, old_serial, key_type) = (p.name.as_str(), old.serial, key.as_str());
+
+    let pass = passphrase.map(str::to_string).unwrap_or_else(gen_passphrase);
+    let issued = vpncertd::genkey_and_sign(&st.cfg.ovpn.socket_path, &vpncertd::GenKeyReq {
+        cn, profile, key_type, passphrase: &pass, renew: true, validity_days: Some(validity_days),
+    }).await?;
+    record_issued(st, &db::issued::NewIssued {
+        serial: issued.serial.as_deref(), cn, profile, key_type, via: "renew",
+        not_after: issued.not_after.as_deref(), created_by: &ctx.actor,
+    }).await;
+
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let old_revoke_at = revoke_old_after.map(|s| now + s.max(0));
+    ctx.record(&st.db, "CLIENT_RENEW", cn, json!({
+        "serial": issued.serial,
+        "old_serial": old_serial,
+        "not_after": issued.not_after,
+        "profile": profile,
+        "key_type": key_type,
+        "validity_days": validity_days,
+        "passphrase_supplied": passphrase.is_some(),
+        "old_revoke_at": old_revoke_at,
+    })).await.ok();
+
+    match revoke_old_after {
+        Some(s) if s <= 0 => { revoke_superseded(st, ctx, cn, &old_serial, issued.serial.as_deref(), "renew").await?; }
+        Some(_) => db::revocations::schedule(&st.db, &old_serial, cn, "superseded", old_revoke_at.unwrap(), &ctx.actor).await?,
+        None => {}
+    }
+
+    let bundle = match build_bundle(st, ctx, cn, include_key).await {
+        Ok(b) => match tokio::fs::read(&b.path).await {
+            Ok(bytes) => Some((b.filename, bytes)),
+            Err(e) => {
+                tracing::error!(%cn, "read renewed bundle: {}", e);
+                None
+            }
+        },
+        Err(e) => {
+            tracing::error!(%cn, "bundle after renew: {}", e);
+            None
+        }
+    };
+
+    Ok(Renewed {
+        issue: ClientIssue {
+            cn: cn.to_string(),
+            passphrase: pass,
+            cert_pem: issued.cert_pem,
+            key_pem_encrypted: issued.key_pem_encrypted,
+            serial: issued.serial,
+            not_after: issued.not_after,
+        },
+        old_serial,
+        old_revoke_at,
+        bundle,
+    })
+}
+
+/// Carries out scheduled revocations once their deadline passes.
+pub fn spawn_revoker(st: AppState) {
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            tick.tick().await;
+            let now = OffsetDateTime::now_utc().unix_timestamp();
+            let due = match db::revocations::due(&st.db, now, 50).await {
+                Ok(d) => d,
+                Err(e) => {
+                    tracing::error!("revoker: load: {}", e);
+                    continue;
+                }
+            };
+            for r in due {
+                let res = match vpncertd::revoke(&st.cfg.ovpn.socket_path, &r.serial, &r.reason).await {
+                    Ok(_) => {
+                        AuditCtx::system().record(&st.db, "CLIENT_REVOKE", &r.cn, json!({
+                            "serial": r.serial,
+                            "reason": r.reason,
+                            "scheduled_by": r.created_by,
+                            "scheduled_for": r.revoke_at,
+                        })).await.ok();
+                        if let Err(e) = db::renewals::mark_old_revoked(&st.db, &r.serial).await {
+                            tracing::error!("revoker: {}", e);
+                        }
+                        db::revocations::mark_done(&st.db, &r.serial).await
+                    }
+                    // someone got there first; nothing left to do
+                    Err(e) if e.to_string().contains("already") => db::revocations::mark_done(&st.db, &r.serial).await,
+                    Err(e) => {
+                        tracing::warn!(serial=%r.serial, cn=%r.cn, "scheduled revoke failed: {}", e);
+                        let retry = (r.attempts + 1 < MAX_ATTEMPTS).then_some(now + RETRY_SECS);
+                        db::revocations::mark_failed(&st.db, &r.serial, &e.to_string(), retry).await
+                    }
+                };
+                if let Err(e) = res { tracing::error!("revoker: {}", e); }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(name: &str, roles: &[&str], key_types: &[KeyType]) -> ProfileCfg {
+        ProfileCfg {
+            name: name.into(), key_types: key_types.to_vec(), default_key_type: None,
+            roles: roles.iter().map(|r| r.to_string()).collect(), max_validity_days: 825,
+            default_validity_days: Some(365),
+        }
+    }
+
+    fn old(profile: &str, key_type: Option<&str>) -> IssuedWithStatus {
+        IssuedWithStatus {
+            serial: "01".into(), cn: "bob".into(), profile: profile.into(), key_type: key_type.map(str::to_string),
+            via: None, issued_at: None, not_after: String::new(), not_after_ts: None, revoked: false, revoked_at: None,
+            revocation_reason: None,
+        }
+    }
+
+    fn cfg() -> IssuanceCfg {
+        IssuanceCfg {
+            default_profile: "client".into(),
+            profiles: vec![
+                profile("client", &["ADMIN"], &[KeyType::EcdsaP256, KeyType::Rsa4096]),
+                profile("device", &["OPERATOR"], &[KeyType::Ed25519]),
+            ],
+        }
+    }
+
+    #[test]
+    fn keeps_profile_and_key_type() {
+        let cfg = cfg();
+        let op = ["OPERATOR".to_string()];
+        assert_eq!(renewal_params(&cfg, &op, &old("device", Some("ed25519"))).map(|(p, k)| (p.name.as_str(), k)).unwrap(), ("device", KeyType::Ed25519));
+        // A key type the profile no longer allows falls back to its default.
+        assert_eq!(renewal_params(&cfg, &op, &old("client", Some("rsa2048"))).map(|(p, k)| (p.name.as_str(), k)).unwrap(), ("client", KeyType::EcdsaP256));
+    }
+
+    #[test]
+    fn unknown_profile_falls_back_only_when_allowed() {
+        let cfg = cfg();
+        let admin = ["ADMIN".to_string()];
+        let op = ["OPERATOR".to_string()];
+        assert_eq!(renewal_params(&cfg, &admin, &old("gone", Some("rsa4096"))).map(|(p, k)| (p.name.as_str(), k)).unwrap(), ("client", KeyType::Rsa4096));
+        let e = renewal_params(&cfg, &op, &old("gone", Some("rsa4096"))).unwrap_err();
+        assert_eq!(e.to_string(), "profile_forbidden");
+    }
+
+    #[test]
+    fn renewal_keeps_the_old_lifetime_within_the_cap() {
+        let p = profile("client", &["ADMIN"], &[KeyType::EcdsaP256]);
+        let day = 86400;
+        let mut c = old("client", None);
+        // Unknown start: the profile default.
+        c.not_after_ts = Some(1_800_000_000);
+        assert_eq!(renewal_validity(&p, &c), 365);
+        c.issued_at = Some(1_800_000_000 - 90 * day - 3600);
+        assert_eq!(renewal_validity(&p, &c), 90);
+        c.issued_at = Some(1_800_000_000 - 3650 * day);
+        assert_eq!(renewal_validity(&p, &c), 825);
+    }
+}
@@ -0,0 +1,8 @@
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let pkey = PKey::hmac(key).expect("hmac key");
+    let mut signer = Signer::new(MessageDigest::sha256(), &pkey).expect("hmac signer");
+    signer.update(data).expect("hmac update");
+    signer.sign_to_vec().expect("hmac sign")
+}
@@ -1 +1,3 @@
 pub mod password;
+pub mod hmac;
+pub mod seal;
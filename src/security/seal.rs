@@ -0,0 +1,28 @@
+use anyhow::{anyhow, Result};
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+
+const NONCE: usize = 12;
+const TAG: usize = 16;
+
+/// AES-256-GCM; output is `nonce || ciphertext || tag`.
+pub fn seal(key: &[u8; 32], aad: &[u8], plain: &[u8]) -> Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE];
+    rand_bytes(&mut nonce)?;
+    let mut tag = [0u8; TAG];
+    let ct = encrypt_aead(Cipher::aes_256_gcm(), key, Some(&nonce), aad, plain, &mut tag)?;
+    let mut out = Vec::with_capacity(NONCE + ct.len() + TAG);
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&ct);
+    out.extend_from_slice(&tag);
+    Ok(out)
+}
+
+pub fn open(key: &[u8; 32], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+    if sealed.len() < NONCE + TAG {
+        return Err(anyhow!("sealed data too short"));
+    }
+    let (nonce, rest) = sealed.split_at(NONCE);
+    let (ct, tag) = rest.split_at(rest.len() - TAG);
+    decrypt_aead(Cipher::aes_256_gcm(), key, Some(nonce), aad, ct, tag).map_err(|_| anyhow!("sealed data rejected"))
+}
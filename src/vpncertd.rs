@@ -1,4 +1,4 @@
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, Result};
 use serde_json::{json, Value};
 use std::path::Path;
 use base64::Engine;
@@ -40,6 +40,11 @@ pub struct IssuedMeta {
     pub cn: String,
     pub profile: String,
     pub not_after: String,
+    /// Only from daemons that report it.
+    #[serde(default)]
+    pub not_before: Option<String>,
+    #[serde(default)]
+    pub key_type: Option<String>,
     #[serde(default)]
     pub sha256: Option<String>,
 }
@@ -51,16 +56,27 @@ pub struct IssueReply {
     pub not_after: Option<String>,
 }
 
-pub async fn genkey_and_sign(
-    socket: &str, cn: &str, profile: &str, key_type: &str, passphrase: &str,
-) -> Result<IssueReply> {
-    let req = json!({
+pub struct GenKeyReq<'a> {
+    pub cn: &'a str,
+    pub profile: &'a str,
+    pub key_type: &'a str,
+    pub passphrase: &'a str,
+    /// Issue even though `cn` still holds a valid cert.
+    pub renew: bool,
+    /// Daemon default when `None`.
+    pub validity_days: Option<u32>,
+}
+
+pub async fn genkey_and_sign(socket: &str, r: &GenKeyReq<'_>) -> Result<IssueReply> {
+    let mut req = json!({
         "op": "GENKEY_AND_SIGN",
-        "cn": cn,
-        "profile": profile,
-        "key_type": key_type,
-        "passphrase": passphrase
+        "cn": r.cn,
+        "profile": r.profile,
+        "key_type": r.key_type,
+        "passphrase": r.passphrase
     });
+    if r.renew { req["renew"] = json!(true); }
+    if let Some(d) = r.validity_days { req["validity_days"] = json!(d); }
     let v = call_raw(socket, &req).await?;
     let w: IssueWire = serde_json::from_value(v)?;
     Ok(IssueReply {
@@ -71,6 +87,33 @@ pub async fn genkey_and_sign(
     })
 }
 
+pub struct SignReply {
+    pub cert_pem: String,
+    pub serial: Option<String>,
+    pub not_after: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SignWire {
+    cert_pem: String,
+    #[serde(default)] serial: Option<String>,
+    #[serde(default)] not_after: Option<String>,
+}
+
+/// Signs a client-generated CSR; the private key never reaches the daemon.
+pub async fn sign_csr(socket: &str, cn: &str, profile: &str, csr_pem: &str, renew: bool) -> Result<SignReply> {
+    let mut req = json!({
+        "op": "SIGN_CSR",
+        "cn": cn,
+        "profile": profile,
+        "csr_pem": csr_pem
+    });
+    if renew { req["renew"] = json!(true); }
+    let v = call_raw(socket, &req).await?;
+    let w: SignWire = serde_json::from_value(v)?;
+    Ok(SignReply { cert_pem: w.cert_pem, serial: w.serial, not_after: w.not_after })
+}
+
 pub async fn build_bundle_bytes(
     socket: &str, cn: &str, include_key: bool, remote_host: &str, remote_port: u16, proto: &str,
 ) -> Result<Vec<u8>> {
@@ -94,13 +137,33 @@ pub async fn health(socket: &str) -> Result<()> {
     Ok(())
 }
 
+/// Optional ops the daemon lists under `ops` in its `HEALTH` reply; empty for
+/// daemons that list none, which must then only be sent the core ops.
+pub async fn capabilities(socket: &str) -> Result<Vec<String>> {
+    let v = call_raw(socket, &json!({"op":"HEALTH"})).await?;
+    Ok(v.get("ops").and_then(|o| o.as_array()).into_iter().flatten()
+        .filter_map(|o| o.as_str().map(str::to_string)).collect())
+}
+
+/// Advertised by daemons that accept `regen_crl: false` on `REVOKE` and a
+/// separate `GEN_CRL`.
+pub const OP_GEN_CRL: &str = "GEN_CRL";
+
 
 fn looks_like_serial(s: &str) -> bool {
     !s.is_empty() && s.chars().all(|c| c.is_ascii_digit())
 }
 
 
-pub async fn revoke(socket: &str, id: &str) -> Result<()> {
+/// RFC 5280 CRLReason names accepted by `REVOKE`. `removeFromCRL` is only
+/// ever sent by `unrevoke`.
+pub const REVOKE_REASONS: &[&str] = &[
+    "unspecified", "keyCompromise", "cACompromise", "affiliationChanged", "superseded",
+    "cessationOfOperation", "certificateHold", "privilegeWithdrawn", "aACompromise",
+];
+
+/// Revokes `id` (a serial, or the newest cert for a CN) and returns the serial revoked.
+pub async fn revoke(socket: &str, id: &str, reason: &str) -> Result<String> {
     let serial = if looks_like_serial(id) {
         id.to_string()
     } else {
@@ -115,15 +178,38 @@ pub async fn revoke(socket: &str, id: &str) -> Result<()> {
         last.serial.clone()
     };
 
-    let req = json!({
+    revoke_serial(socket, &serial, reason, true).await?;
+    Ok(serial)
+}
+
+/// With `regen_crl` false the daemon skips rebuilding the CRL, so a batch can
+/// end with a single `regen_crl` call. Only pass false to daemons advertising
+/// [`OP_GEN_CRL`].
+pub async fn revoke_serial(socket: &str, serial: &str, reason: &str, regen_crl: bool) -> Result<()> {
+    let mut req = json!({
         "op": "REVOKE",
         "serial": serial,
-        "reason": "keyCompromise",
+        "reason": reason,
     });
-    let _ = call_raw(socket, &req).await?;
+    if !regen_crl { req["regen_crl"] = json!(false); }
+    call_raw(socket, &req).await?;
+    Ok(())
+}
+
+/// Requires [`OP_GEN_CRL`].
+pub async fn regen_crl(socket: &str) -> Result<()> {
+    call_raw(socket, &json!({ "op": "GEN_CRL" })).await?;
     Ok(())
 }
 
+/// Takes a `certificateHold` serial back off the CRL.
+pub async fn unrevoke(socket: &str, serial: &str) -> Result<()> {
+    let req = json!({ "op": "UNREVOKE", "serial": serial, "reason": "removeFromCRL" });
+    call_raw(socket, &req).await?;
+    Ok(())
+}
+
+#[allow(dead_code)]
 pub async fn build_bundle(
     socket: &str,
     bundle_cn: &str,
@@ -163,9 +249,7 @@ pub async fn list_issued(socket: &str, limit: Option<usize>) -> Result<Vec<Issue
         .ok_or_else(|| anyhow!("missing issued in daemon response"))?;
     let mut rows: Vec<IssuedMeta> = serde_json::from_value(issued_v)?;
     if let Some(n) = limit {
-        if rows.len() > n {
-            rows.truncate(n);
-        }
+        rows.truncate(n);
     }
     Ok(rows)
 }
\ No newline at end of file
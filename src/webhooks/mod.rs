@@ -0,0 +1,266 @@
+use anyhow::Result;
+use serde_json::{json, Value};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::sync::{Notify, Semaphore};
+use tokio::task::JoinSet;
+use ulid::Ulid;
+
+use crate::audit::{self, AuditCtx};
+use crate::db::{webhooks as store, Db};
+use crate::security::hmac::hmac_sha256;
+
+/// Events a subscription can filter on; `*` matches all of them.
+pub const EVENTS: &[&str] = &["cert.issued", "cert.renewed", "cert.revoked", "cert.unrevoked", "cert.expiring", "ccd.changed", "ping"];
+
+const MAX_ATTEMPTS: i64 = 10;
+const BASE_BACKOFF_SECS: i64 = 30;
+const MAX_BACKOFF_SECS: i64 = 6 * 3600;
+
+static WAKE: OnceLock<Notify> = OnceLock::new();
+
+fn wake() -> &'static Notify {
+    WAKE.get_or_init(Notify::new)
+}
+
+/// Lifecycle event for an audit action, if subscribers care about it.
+pub fn event_for_action(action: &str) -> Option<&'static str> {
+    match action {
+        "CLIENT_CREATE" => Some("cert.issued"),
+        "CLIENT_RENEW" => Some("cert.renewed"),
+        "CLIENT_REVOKE" => Some("cert.revoked"),
+        "CLIENT_UNREVOKE" => Some("cert.unrevoked"),
+        "EXPIRY_WARNING" => Some("cert.expiring"),
+        "ADMIN_SAVE_CCD" => Some("ccd.changed"),
+        _ => None,
+    }
+}
+
+fn matches(filter: &[String], event: &str) -> bool {
+    event == "ping" || filter.iter().any(|f| f == "*" || f == event)
+}
+
+/// Queues `event` for every enabled subscription whose filter matches.
+pub async fn notify(pool: &Db, event: &str, ctx: &AuditCtx, target: &str, data: &Value) -> Result<()> {
+    let hooks = store::list(pool).await?;
+    let ts = OffsetDateTime::now_utc().unix_timestamp();
+    let mut queued = false;
+    for h in hooks.iter().filter(|h| h.enabled && matches(&h.events, event)) {
+        let id = Ulid::new().to_string();
+        let payload = json!({
+            "id": id,
+            "event": event,
+            "ts": ts,
+            "actor": ctx.actor,
+            "request_id": ctx.request_id,
+            "target": target,
+            "data": data,
+        });
+        store::enqueue(pool, &id, &h.id, event, &payload.to_string()).await?;
+        queued = true;
+    }
+    if queued { wake().notify_one(); }
+    Ok(())
+}
+
+/// Queues a `ping` for one subscription so admins can check the receiver.
+pub async fn ping(pool: &Db, hook: &store::Webhook, ctx: &AuditCtx) -> Result<String> {
+    let id = Ulid::new().to_string();
+    let payload = json!({
+        "id": id,
+        "event": "ping",
+        "ts": OffsetDateTime::now_utc().unix_timestamp(),
+        "actor": ctx.actor,
+        "request_id": ctx.request_id,
+        "target": hook.id,
+        "data": {},
+    });
+    store::enqueue(pool, &id, &hook.id, "ping", &payload.to_string()).await?;
+    wake().notify_one();
+    Ok(id)
+}
+
+/// `t=<unix>,v1=<hex hmac-sha256(secret, "<unix>.<body>")>`
+pub fn signature(secret: &str, ts: i64, body: &str) -> String {
+    let mac = hmac_sha256(secret.as_bytes(), format!("{ts}.{body}").as_bytes());
+    format!("t={ts},v1={}", audit::hex(&mac))
+}
+
+fn backoff(attempts: i64) -> i64 {
+    let shift = attempts.clamp(0, 20) as u32;
+    (BASE_BACKOFF_SECS << shift).min(MAX_BACKOFF_SECS)
+}
+
+/// Whole request, receiver's response included.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Deliveries in flight at once, so one slow receiver cannot hold up the rest.
+const CONCURRENCY: usize = 8;
+const BATCH: i64 = 50;
+
+fn client() -> reqwest::Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(REQUEST_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+}
+
+async fn deliver(client: &reqwest::Client, pool: &Db, d: store::Delivery) -> Result<()> {
+    let Some(hook) = store::get(pool, &d.webhook_id).await? else {
+        return store::mark_failed(pool, &d.id, None, "webhook deleted", None).await;
+    };
+    if !hook.enabled {
+        return store::mark_failed(pool, &d.id, None, "webhook disabled", None).await;
+    }
+
+    let ts = OffsetDateTime::now_utc().unix_timestamp();
+    let res = client.post(&hook.url)
+        .header("content-type", "application/json")
+        .header("user-agent", concat!("ovpn-admin/", env!("CARGO_PKG_VERSION")))
+        .header("x-ovpn-admin-event", &d.event)
+        .header("x-ovpn-admin-delivery", &d.id)
+        .header("x-ovpn-admin-signature", signature(&hook.secret, ts, &d.payload))
+        .body(d.payload.clone())
+        .send()
+        .await;
+
+    let (status, error) = match res {
+        Ok(r) if r.status().is_success() => {
+            return store::mark_delivered(pool, &d.id, r.status().as_u16() as i64).await;
+        }
+        Ok(r) => (Some(r.status().as_u16() as i64), format!("HTTP {}", r.status())),
+        Err(e) if e.is_timeout() => (None, "timed out".to_string()),
+        Err(e) => (None, e.to_string()),
+    };
+    let attempt = d.attempts + 1;
+    let next = (attempt < MAX_ATTEMPTS).then(|| ts + backoff(d.attempts));
+    tracing::warn!(webhook=%hook.id, delivery=%d.id, attempt, "webhook delivery failed: {}", error);
+    store::mark_failed(pool, &d.id, status, &error, next).await
+}
+
+/// Sends every delivery due at `now`, at most `CONCURRENCY` at a time, and
+/// returns once all of them have been recorded.
+async fn run_due(client: &reqwest::Client, pool: &Db, now: i64) -> Result<usize> {
+    let batch = store::due(pool, now, BATCH).await?;
+    let n = batch.len();
+    let permits = Arc::new(Semaphore::new(CONCURRENCY));
+    let mut tasks = JoinSet::new();
+    for d in batch {
+        let permit = permits.clone().acquire_owned().await?;
+        let (client, pool) = (client.clone(), pool.clone());
+        tasks.spawn(async move {
+            let _permit = permit;
+            if let Err(e) = deliver(&client, &pool, d).await {
+                tracing::error!("webhooks: {}", e);
+            }
+        });
+    }
+    while tasks.join_next().await.is_some() {}
+    Ok(n)
+}
+
+/// Delivers queued events until the process exits. Deliveries live in the
+/// database, so anything pending at shutdown is retried after a restart.
+pub fn spawn_worker(pool: Db) {
+    tokio::spawn(async move {
+        let client = match client() {
+            Ok(c) => c,
+            Err(e) => {
+                tracing::error!("webhooks: client init failed: {}", e);
+                return;
+            }
+        };
+        loop {
+            let now = OffsetDateTime::now_utc().unix_timestamp();
+            if let Err(e) = run_due(&client, &pool, now).await {
+                tracing::error!("webhooks: load queue: {}", e);
+            }
+            let _ = tokio::time::timeout(Duration::from_secs(5), wake().notified()).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::State, http::{HeaderMap, StatusCode}, routing::post, Router};
+    use std::sync::Mutex;
+
+    /// Answers 503 to the first request and 204 after that.
+    #[derive(Clone, Default)]
+    struct Receiver { hits: Arc<Mutex<Vec<(HeaderMap, String)>>> }
+
+    async fn receive(State(r): State<Receiver>, headers: HeaderMap, body: String) -> StatusCode {
+        let mut hits = r.hits.lock().unwrap();
+        hits.push((headers, body));
+        if hits.len() == 1 { StatusCode::SERVICE_UNAVAILABLE } else { StatusCode::NO_CONTENT }
+    }
+
+    fn receiver() -> (String, Receiver) {
+        let r = Receiver::default();
+        let app = Router::new().route("/hook", post(receive)).with_state(r.clone());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()));
+        (format!("http://{addr}/hook"), r)
+    }
+
+    #[tokio::test]
+    async fn signs_retries_and_records_history() {
+        let pool = crate::db::test_db().await;
+        let (url, rx) = receiver();
+        let secret = "0123456789abcdef-secret";
+        let id = store::create(&pool, &url, &["cert.revoked".into()], secret, "alice").await.unwrap();
+        notify(&pool, "cert.revoked", &AuditCtx::system(), "bob", &json!({ "serial": "01" })).await.unwrap();
+        notify(&pool, "cert.issued", &AuditCtx::system(), "bob", &json!({})).await.unwrap();
+
+        let client = client().unwrap();
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        assert_eq!(run_due(&client, &pool, now).await.unwrap(), 1);
+
+        let d = store::deliveries(&pool, &id, 10).await.unwrap();
+        assert_eq!(d.len(), 1, "filtered event must not be queued");
+        assert_eq!(d[0].status, "pending");
+        assert_eq!(d[0].attempts, 1);
+        assert_eq!(d[0].last_status, Some(503));
+        assert_eq!(d[0].last_error.as_deref(), Some("HTTP 503 Service Unavailable"));
+        assert!(d[0].next_attempt_at >= now + BASE_BACKOFF_SECS);
+
+        // Not retried before the backoff has passed.
+        assert_eq!(run_due(&client, &pool, now).await.unwrap(), 0);
+        assert_eq!(run_due(&client, &pool, d[0].next_attempt_at).await.unwrap(), 1);
+
+        let d = store::deliveries(&pool, &id, 10).await.unwrap();
+        assert_eq!(d[0].status, "delivered");
+        assert_eq!(d[0].attempts, 2);
+        assert_eq!(d[0].last_status, Some(204));
+        assert_eq!(d[0].last_error, None);
+        assert!(d[0].delivered_at.is_some());
+
+        let hits = rx.hits.lock().unwrap();
+        assert_eq!(hits.len(), 2);
+        for (headers, body) in hits.iter() {
+            assert_eq!(body, &d[0].payload);
+            assert_eq!(headers["x-ovpn-admin-event"], "cert.revoked");
+            assert_eq!(headers["x-ovpn-admin-delivery"], d[0].id.as_str());
+            let sig = headers["x-ovpn-admin-signature"].to_str().unwrap();
+            let ts: i64 = sig.strip_prefix("t=").and_then(|s| s.split(',').next()).unwrap().parse().unwrap();
+            assert_eq!(sig, signature(secret, ts, body));
+            assert_ne!(sig, signature("some-other-secret-value", ts, body));
+        }
+        let payload: Value = serde_json::from_str(&d[0].payload).unwrap();
+        assert_eq!(payload["event"], "cert.revoked");
+        assert_eq!(payload["target"], "bob");
+        assert_eq!(payload["data"]["serial"], "01");
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        assert_eq!(backoff(0), BASE_BACKOFF_SECS);
+        assert_eq!(backoff(1), BASE_BACKOFF_SECS * 2);
+        assert_eq!(backoff(3), BASE_BACKOFF_SECS * 8);
+        assert_eq!(backoff(MAX_ATTEMPTS), MAX_BACKOFF_SECS);
+    }
+}